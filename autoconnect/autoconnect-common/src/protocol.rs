@@ -41,6 +41,11 @@ pub enum ClientMessage {
         use_webpush: Option<bool>,
         #[serde(skip_serializing_if = "Option::is_none")]
         broadcasts: Option<HashMap<String, String>>,
+        /// Whether the client is on a constrained network (e.g. on battery
+        /// or a metered connection), allowing the delivery of `very-low` and
+        /// `low` Urgency Notifications to be deferred
+        #[serde(skip_serializing_if = "Option::is_none")]
+        constrained: Option<bool>,
     },
 
     Register {
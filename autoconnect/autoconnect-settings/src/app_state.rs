@@ -2,10 +2,19 @@ use std::{sync::Arc, time::Duration};
 
 #[cfg(feature = "bigtable")]
 use autopush_common::db::bigtable::BigTableClientImpl;
-#[cfg(all(feature = "bigtable", feature = "dynamodb"))]
 use autopush_common::db::dual::DualClientImpl;
 #[cfg(feature = "dynamodb")]
 use autopush_common::db::dynamodb::DdbClientImpl;
+#[cfg(feature = "faulty")]
+use autopush_common::db::faulty::FaultyDbClient;
+use autopush_common::db::instrumented::InstrumentedDbClient;
+use autopush_common::db::memory::MemoryClientImpl;
+#[cfg(feature = "postgres")]
+use autopush_common::db::postgres::PgClientImpl;
+#[cfg(feature = "redis")]
+use autopush_common::db::redis::RedisClientImpl;
+#[cfg(feature = "sqlite")]
+use autopush_common::db::sqlite::SqliteClientImpl;
 use cadence::StatsdClient;
 use fernet::{Fernet, MultiFernet};
 use tokio::sync::RwLock;
@@ -73,6 +82,8 @@ impl AppState {
         let db_settings = DbSettings {
             dsn: settings.db_dsn.clone(),
             db_settings: settings.db_settings.clone(),
+            key_ring: settings.db_key_ring.clone(),
+            compress_data: settings.db_compress_data,
         };
         let storage_type = StorageType::from_dsn(&db_settings.dsn);
         #[allow(unused)]
@@ -83,14 +94,27 @@ impl AppState {
             StorageType::BigTable => {
                 Box::new(BigTableClientImpl::new(metrics.clone(), &db_settings)?)
             }
-            #[cfg(all(feature = "bigtable", feature = "dynamodb"))]
             StorageType::Dual => Box::new(DualClientImpl::new(metrics.clone(), &db_settings)?),
+            #[cfg(feature = "postgres")]
+            StorageType::Postgres => Box::new(PgClientImpl::new(metrics.clone(), &db_settings)?),
+            #[cfg(feature = "redis")]
+            StorageType::Redis => Box::new(RedisClientImpl::new(metrics.clone(), &db_settings)?),
+            #[cfg(feature = "sqlite")]
+            StorageType::Sqlite => Box::new(SqliteClientImpl::new(metrics.clone(), &db_settings)?),
+            StorageType::Memory => Box::new(MemoryClientImpl::new(metrics.clone(), &db_settings)?),
+            #[cfg(feature = "faulty")]
+            StorageType::Faulty => Box::new(FaultyDbClient::new(metrics.clone(), &db_settings)?),
             _ => panic!(
                 "Invalid Storage type {:?}. Check {}__DB_DSN.",
                 storage_type,
                 ENV_PREFIX.to_uppercase()
             ),
         };
+        let db: Box<dyn DbClient> = if settings.db_operation_metrics {
+            Box::new(InstrumentedDbClient::new(db, metrics.clone()))
+        } else {
+            db
+        };
         let http = reqwest::Client::builder()
             .timeout(Duration::from_secs(1))
             .build()
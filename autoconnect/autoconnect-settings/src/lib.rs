@@ -87,6 +87,14 @@ pub struct Settings {
     pub db_dsn: Option<String>,
     /// JSON set of specific database settings (See data storage engines)
     pub db_settings: String,
+    /// JSON key ring encrypting stored message payloads (See
+    /// `autopush_common::db::encryption`)
+    pub db_key_ring: String,
+    /// Compress stored message payloads
+    pub db_compress_data: bool,
+    /// Time each data store operation, reporting it via the
+    /// `database.operation` metric
+    pub db_operation_metrics: bool,
     /// Server endpoint to pull Broadcast ID change values (Sent in Pings)
     pub megaphone_api_url: Option<String>,
     /// Broadcast token for authentication
@@ -99,6 +107,9 @@ pub struct Settings {
     /// Maximum allowed number of backlogged messages. Exceeding this number will
     /// trigger a user reset because the user may have been offline way too long.
     pub msg_limit: u32,
+    /// Maximum number of low Urgency notifications deferred for a client on a
+    /// constrained network. Exceeding this number moves them to storage.
+    pub max_deferred_notifs: usize,
     /// Sets the maximum number of concurrent connections per actix-web worker.
     ///
     /// All socket listeners will stop accepting connections when this limit is
@@ -132,11 +143,15 @@ impl Default for Settings {
             statsd_port: 8125,
             db_dsn: None,
             db_settings: "".to_owned(),
+            db_key_ring: "".to_owned(),
+            db_compress_data: false,
+            db_operation_metrics: false,
             megaphone_api_url: None,
             megaphone_api_token: None,
             megaphone_poll_interval: Duration::from_secs(30),
             human_logs: false,
             msg_limit: 100,
+            max_deferred_notifs: 100,
             actix_max_connections: None,
             actix_workers: None,
         }
@@ -14,6 +14,7 @@ use autoconnect_settings::{AppState, Settings};
 use autopush_common::{
     db::{error::DbResult, User, USER_RECORD_VERSION},
     notification::Notification,
+    receipt::{send_receipt, ReceiptStatus},
     util::{ms_since_epoch, user_agent::UserAgentInfo},
 };
 
@@ -198,6 +199,7 @@ impl WebPushClient {
             "stored_retrieved" => stats.stored_retrieved,
             "stored_acked" => stats.stored_acked,
             "nacks" => stats.nacks,
+            "deferred" => stats.deferred,
             "registers" => stats.registers,
             "unregisters" => stats.unregisters,
             "disconnect_reason" => reason.unwrap_or_else(|| "".to_owned()),
@@ -207,9 +209,11 @@ impl WebPushClient {
     /// Save any Direct unAck'd messages to the db (on shutdown)
     ///
     /// Direct messages are solely stored in memory until Ack'd by the Client,
-    /// so on shutdown, any not Ack'd are stored in the db to not be lost
+    /// so on shutdown, any not Ack'd (or still deferred) are stored in the db
+    /// to not be lost
     fn save_and_notify_unacked_direct_notifs(&mut self) {
         let mut notifs = mem::take(&mut self.ack_state.unacked_direct_notifs);
+        notifs.append(&mut self.ack_state.deferred_direct_notifs);
         trace!(
             "👁‍🗨WebPushClient::save_and_notify_unacked_direct_notifs len: {}",
             notifs.len()
@@ -254,6 +258,20 @@ impl WebPushClient {
         });
     }
 
+    /// Send the Notification's receipt to the application server, if one was
+    /// requested
+    fn send_receipt(&self, notif: &Notification, status: ReceiptStatus) {
+        let Some(url) = notif.receipt.clone() else {
+            return;
+        };
+        rt::spawn(send_receipt(
+            Arc::clone(&self.app_state.metrics),
+            url,
+            notif.version.clone(),
+            status,
+        ));
+    }
+
     /// Add User information and tags for this Client to a Sentry Event
     pub fn add_sentry_info(self, event: &mut sentry::protocol::Event) {
         event.user = Some(sentry::User {
@@ -339,6 +357,9 @@ pub struct ClientFlags {
     check_storage: bool,
     /// Flags the need to drop the user record
     old_record_version: bool,
+    /// Whether the client declared a constrained network in its Hello,
+    /// deferring the delivery of low Urgency Direct Notifications
+    pub(crate) constrained: bool,
 }
 
 impl Default for ClientFlags {
@@ -348,6 +369,7 @@ impl Default for ClientFlags {
             increment_storage: false,
             check_storage: false,
             old_record_version: false,
+            constrained: false,
         }
     }
 }
@@ -368,6 +390,8 @@ pub struct SessionStatistics {
     stored_acked: i32,
     /// Number of messages total that are not acknowledged.
     nacks: i32,
+    /// Number of low Urgency messages whose delivery was deferred
+    deferred: i32,
     /// Number of unregister requests
     unregisters: i32,
     /// Number of register requests
@@ -383,6 +407,9 @@ struct AckState {
     unacked_direct_notifs: Vec<Notification>,
     /// List of unAck'd sent notifications from storage
     unacked_stored_notifs: Vec<Notification>,
+    /// List of Direct notifications not yet sent to a constrained Client due
+    /// to their low Urgency
+    deferred_direct_notifs: Vec<Notification>,
     /// Either the `current_timestamp` value in storage (returned from
     /// `fetch_messages`) or the last unAck'd timestamp Message's
     /// `sortkey_timestamp` (returned from `fetch_timestamp_messages`).
@@ -422,14 +449,14 @@ mod tests {
         protocol::{ClientMessage, ServerMessage, ServerNotification},
         test_support::{DUMMY_CHID, DUMMY_UAID, UA},
     };
-    use autoconnect_settings::AppState;
+    use autoconnect_settings::{AppState, Settings};
     use autopush_common::{
         db::{client::FetchMessageResponse, mock::MockDbClient},
-        notification::Notification,
+        notification::{Notification, Urgency},
         util::{ms_since_epoch, sec_since_epoch},
     };
 
-    use super::WebPushClient;
+    use super::{ClientFlags, WebPushClient};
 
     async fn wpclient(uaid: Uuid, app_state: AppState) -> (WebPushClient, Vec<ServerMessage>) {
         WebPushClient::new(
@@ -464,6 +491,120 @@ mod tests {
         assert!(matches!(pong.as_slice(), [ServerMessage::Ping]));
     }
 
+    #[actix_rt::test]
+    async fn constrained_defers_low_urgency() {
+        let (mut client, _) = WebPushClient::new(
+            DUMMY_UAID,
+            UA.to_owned(),
+            Default::default(),
+            ClientFlags {
+                constrained: true,
+                ..Default::default()
+            },
+            ms_since_epoch(),
+            None,
+            None,
+            Default::default(),
+        )
+        .await
+        .unwrap();
+
+        let low = Notification {
+            urgency: Urgency::Low,
+            ..new_timestamp_notif(&DUMMY_CHID, 300)
+        };
+        let smsgs = client
+            .on_server_notif(ServerNotification::Notification(low))
+            .await
+            .unwrap();
+        assert!(smsgs.is_empty());
+
+        // The deferred notif is delivered along with the next normal one
+        let normal = new_timestamp_notif(&DUMMY_CHID, 300);
+        let smsgs = client
+            .on_server_notif(ServerNotification::Notification(normal))
+            .await
+            .unwrap();
+        assert_eq!(smsgs.len(), 2);
+        assert_eq!(client.ack_state.unacked_direct_notifs.len(), 2);
+        assert!(client.ack_state.deferred_direct_notifs.is_empty());
+    }
+
+    /// Generate a constrained client, deferring up to `max_deferred_notifs`
+    async fn constrained_wpclient(max_deferred_notifs: usize, db: MockDbClient) -> WebPushClient {
+        let (client, _) = WebPushClient::new(
+            DUMMY_UAID,
+            UA.to_owned(),
+            Default::default(),
+            ClientFlags {
+                constrained: true,
+                ..Default::default()
+            },
+            ms_since_epoch(),
+            None,
+            None,
+            Arc::new(AppState {
+                db: db.into_boxed_arc(),
+                settings: Settings {
+                    max_deferred_notifs,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap();
+        client
+    }
+
+    #[actix_rt::test]
+    async fn constrained_stores_excess_deferred() {
+        let mut db = MockDbClient::new();
+        db.expect_save_messages()
+            .times(1)
+            .withf(|uaid, notifs| uaid == &DUMMY_UAID && notifs.len() == 3)
+            .return_once(|_, _| Ok(()));
+        let mut client = constrained_wpclient(2, db).await;
+
+        for _ in 0..3 {
+            let low = Notification {
+                urgency: Urgency::VeryLow,
+                ..new_timestamp_notif(&DUMMY_CHID, 300)
+            };
+            let smsgs = client
+                .on_server_notif(ServerNotification::Notification(low))
+                .await
+                .unwrap();
+            assert!(smsgs.is_empty());
+        }
+        // All moved to storage, to be read on its next check
+        assert!(client.ack_state.deferred_direct_notifs.is_empty());
+        assert!(client.flags.check_storage);
+    }
+
+    #[actix_rt::test]
+    async fn ping_tick_delivers_deferred() {
+        let mut client = constrained_wpclient(100, MockDbClient::new()).await;
+
+        let low = Notification {
+            urgency: Urgency::Low,
+            ..new_timestamp_notif(&DUMMY_CHID, 300)
+        };
+        let smsgs = client
+            .on_server_notif(ServerNotification::Notification(low))
+            .await
+            .unwrap();
+        assert!(smsgs.is_empty());
+
+        // Deferred for no longer than the Ping interval
+        let smsgs = client.on_ping_tick().await.unwrap();
+        assert_eq!(smsgs.len(), 1);
+        assert_eq!(client.ack_state.unacked_direct_notifs.len(), 1);
+        assert!(client.ack_state.deferred_direct_notifs.is_empty());
+        // Nothing left to deliver
+        assert!(client.on_ping_tick().await.unwrap().is_empty());
+    }
+
     #[actix_rt::test]
     async fn expired_increments_storage() {
         let mut db = MockDbClient::new();
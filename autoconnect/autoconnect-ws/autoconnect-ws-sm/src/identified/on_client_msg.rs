@@ -7,7 +7,9 @@ use autoconnect_common::{
     broadcast::Broadcast,
     protocol::{BroadcastValue, ClientAck, ClientMessage, ServerMessage},
 };
-use autopush_common::{endpoint::make_endpoint, util::sec_since_epoch};
+use autopush_common::{
+    db::ChannelRecord, endpoint::make_endpoint, receipt::ReceiptStatus, util::sec_since_epoch,
+};
 
 use super::WebPushClient;
 use crate::error::{SMError, SMErrorKind};
@@ -89,15 +91,6 @@ impl WebPushClient {
         channel_id: &Uuid,
         key: Option<String>,
     ) -> Result<String, SMErrorKind> {
-        if let Some(user) = &self.deferred_add_user {
-            debug!(
-                "💬WebPushClient::register: User not yet registered: {}",
-                &user.uaid
-            );
-            self.app_state.db.add_user(user).await?;
-            self.deferred_add_user = None;
-        }
-
         let endpoint = make_endpoint(
             &self.uaid,
             channel_id,
@@ -106,10 +99,21 @@ impl WebPushClient {
             &self.app_state.fernet,
         )
         .map_err(SMErrorKind::MakeEndpoint)?;
-        self.app_state
-            .db
-            .add_channel(&self.uaid, channel_id)
-            .await?;
+        let channel =
+            ChannelRecord::new(*channel_id, key.as_deref()).map_err(SMErrorKind::MakeEndpoint)?;
+        if let Some(user) = &self.deferred_add_user {
+            debug!(
+                "💬WebPushClient::register: User not yet registered: {}",
+                &user.uaid
+            );
+            self.app_state
+                .db
+                .add_user_with_channels(user, vec![channel])
+                .await?;
+            self.deferred_add_user = None;
+        } else {
+            self.app_state.db.add_channel(&self.uaid, &channel).await?;
+        }
         Ok(endpoint)
     }
 
@@ -181,6 +185,8 @@ impl WebPushClient {
         trace!("✅ WebPushClient:ack");
         let _ = self.app_state.metrics.incr("ua.command.ack");
 
+        // Acked Topic messages, deleted in one batch
+        let mut acked_topic_sort_keys = vec![];
         for notif in updates {
             // Check the list of unacked "direct" (unstored) notifications. We only want to
             // ack messages we've not yet seen and we have the right version, otherwise we could
@@ -196,7 +202,8 @@ impl WebPushClient {
                        "channel_id" => notif.channel_id.as_hyphenated().to_string(),
                        "version" => &notif.version
                 );
-                self.ack_state.unacked_direct_notifs.remove(pos);
+                let n = self.ack_state.unacked_direct_notifs.remove(pos);
+                self.send_receipt(&n, ReceiptStatus::Delivered);
                 self.stats.direct_acked += 1;
                 continue;
             };
@@ -227,16 +234,20 @@ impl WebPushClient {
                         "✅ WebPushClient:ack removing Stored, sort_key: {}",
                         &n.chidmessageid()
                     );
-                    self.app_state
-                        .db
-                        .remove_message(&self.uaid, &n.chidmessageid())
-                        .await?;
+                    acked_topic_sort_keys.push(n.chidmessageid());
                 }
-                self.ack_state.unacked_stored_notifs.remove(pos);
+                let n = self.ack_state.unacked_stored_notifs.remove(pos);
+                self.send_receipt(&n, ReceiptStatus::Delivered);
                 self.stats.stored_acked += 1;
                 continue;
             };
         }
+        if !acked_topic_sort_keys.is_empty() {
+            self.app_state
+                .db
+                .remove_messages(&self.uaid, &acked_topic_sort_keys)
+                .await?;
+        }
 
         if self.ack_state.unacked_notifs() {
             // Wait for the Client to Ack all notifications before further
@@ -1,8 +1,13 @@
+use std::mem;
+
 use cadence::{Counted, CountedExt};
 
 use autoconnect_common::protocol::{ServerMessage, ServerNotification};
 use autopush_common::{
-    db::CheckStorageResponse, notification::Notification, util::sec_since_epoch,
+    db::CheckStorageResponse,
+    notification::Notification,
+    receipt::ReceiptStatus,
+    util::{ms_since_epoch, sec_since_epoch},
 };
 
 use super::WebPushClient;
@@ -21,8 +26,13 @@ impl WebPushClient {
         snotif: ServerNotification,
     ) -> Result<Vec<ServerMessage>, SMError> {
         match snotif {
-            ServerNotification::Notification(notif) => Ok(vec![self.notif(notif)?]),
-            ServerNotification::CheckStorage => self.check_storage().await,
+            ServerNotification::Notification(notif) => self.notif(notif).await,
+            ServerNotification::CheckStorage => {
+                // Deliver any deferred notifs along with the stored ones
+                let mut smsgs = self.flush_deferred_notifs();
+                smsgs.extend(self.check_storage().await?);
+                Ok(smsgs)
+            }
             ServerNotification::Disconnect => Err(SMErrorKind::Ghost.into()),
         }
     }
@@ -37,14 +47,93 @@ impl WebPushClient {
         }
     }
 
+    /// Handle a tick of the WebSocket Ping timer (every
+    /// `Settings::auto_ping_interval`)
+    ///
+    /// Sends any deferred Notifications, so they're held for no longer than
+    /// the interval, along with any previously moved to storage
+    pub async fn on_ping_tick(&mut self) -> Result<Vec<ServerMessage>, SMError> {
+        let smsgs = self.flush_deferred_notifs();
+        // Otherwise storage is checked once the Client Acks the flushed ones
+        if smsgs.is_empty() && self.flags.check_storage && !self.ack_state.unacked_notifs() {
+            return self.check_storage().await;
+        }
+        Ok(smsgs)
+    }
+
     /// Send a Direct Push Notification to this user
-    fn notif(&mut self, notif: Notification) -> Result<ServerMessage, SMError> {
+    ///
+    /// Clients on a constrained network have the delivery of low Urgency
+    /// Notifications deferred until a more urgent one's sent to them (or
+    /// storage is checked, or the Ping timer ticks), which then delivers them
+    /// all at once
+    async fn notif(&mut self, notif: Notification) -> Result<Vec<ServerMessage>, SMError> {
+        if self.flags.constrained && notif.ttl != 0 && notif.urgency.is_deferrable() {
+            trace!("WebPushClient::notif Deferring a direct notif");
+            self.stats.deferred += 1;
+            self.app_state
+                .metrics
+                .incr_with_tags("ua.notification.deferred")
+                .with_tag("urgency", notif.urgency.as_str())
+                .send();
+            self.ack_state.deferred_direct_notifs.push(notif);
+            if self.ack_state.deferred_direct_notifs.len()
+                > self.app_state.settings.max_deferred_notifs
+            {
+                self.store_deferred_notifs().await?;
+            }
+            return Ok(vec![]);
+        }
+        let mut smsgs = self.flush_deferred_notifs();
+        smsgs.push(self.direct_notif(notif));
+        Ok(smsgs)
+    }
+
+    /// Move the deferred Notifications to storage, to be read on the next
+    /// check of storage
+    async fn store_deferred_notifs(&mut self) -> Result<(), SMError> {
+        let mut notifs = mem::take(&mut self.ack_state.deferred_direct_notifs);
+        trace!("WebPushClient::store_deferred_notifs len: {}", notifs.len());
+        self.stats.direct_storage += notifs.len() as i32;
+        self.app_state
+            .metrics
+            .count("ua.notification.deferred_stored", notifs.len() as i64)
+            .ok();
+        // Give each a distinct sort key, as they're mostly for the same few
+        // channels
+        let now = ms_since_epoch();
+        for (i, notif) in notifs.iter_mut().enumerate() {
+            notif.sortkey_timestamp = Some(now + i as u64);
+        }
+        self.app_state.db.save_messages(&self.uaid, notifs).await?;
+        self.flags.check_storage = true;
+        self.flags.include_topic = true;
+        Ok(())
+    }
+
+    /// Send the Direct Push Notifications previously deferred due to their
+    /// low Urgency, dropping any that expired in the meantime
+    fn flush_deferred_notifs(&mut self) -> Vec<ServerMessage> {
+        let now_sec = sec_since_epoch();
+        let mut smsgs = vec![];
+        for notif in mem::take(&mut self.ack_state.deferred_direct_notifs) {
+            if notif.expired(now_sec) {
+                self.send_receipt(&notif, ReceiptStatus::Expired);
+            } else {
+                smsgs.push(self.direct_notif(notif));
+            }
+        }
+        smsgs
+    }
+
+    /// Send a Direct Push Notification, tracking it until Ack'd
+    fn direct_notif(&mut self, notif: Notification) -> ServerMessage {
         trace!("WebPushClient::notif Sending a direct notif");
         if notif.ttl != 0 {
             self.ack_state.unacked_direct_notifs.push(notif.clone());
         }
         self.emit_send_metrics(&notif, "Direct");
-        Ok(ServerMessage::Notification(notif))
+        ServerMessage::Notification(notif)
     }
 
     /// Top level read of Push Notifications from storage
@@ -121,17 +210,17 @@ impl WebPushClient {
             if !msg.expired(now_sec) {
                 return true;
             }
+            self.send_receipt(msg, ReceiptStatus::Expired);
             if msg.sortkey_timestamp.is_none() {
                 expired_topic_sort_keys.push(msg.chidmessageid());
             }
             false
         });
-        // TODO: A batch remove_messages would be nicer
-        for sort_key in expired_topic_sort_keys {
-            trace!("🉑 removing expired topic sort key: {sort_key}");
+        if !expired_topic_sort_keys.is_empty() {
+            trace!("🉑 removing expired topic sort keys: {expired_topic_sort_keys:?}");
             self.app_state
                 .db
-                .remove_message(&self.uaid, &sort_key)
+                .remove_messages(&self.uaid, &expired_topic_sort_keys)
                 .await?;
         }
 
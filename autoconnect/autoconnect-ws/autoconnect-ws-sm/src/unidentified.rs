@@ -54,6 +54,7 @@ impl UnidentifiedClient {
             uaid,
             use_webpush: Some(true),
             broadcasts,
+            constrained,
             ..
         } = msg
         else {
@@ -72,8 +73,9 @@ impl UnidentifiedClient {
         let GetOrCreateUser {
             user,
             existing_user,
-            flags,
+            mut flags,
         } = self.get_or_create_user(original_uaid).await?;
+        flags.constrained = constrained.unwrap_or(false);
         let uaid = user.uaid;
         debug!(
             "💬UnidentifiedClient::on_client_msg Hello! uaid: {} existing_user: {}",
@@ -255,6 +257,7 @@ mod tests {
             channel_ids: None,
             use_webpush: Some(true),
             broadcasts: None,
+            constrained: None,
         };
         client.on_client_msg(msg).await.expect("Hello failed");
     }
@@ -271,6 +274,7 @@ mod tests {
             channel_ids: None,
             use_webpush: Some(true),
             broadcasts: None,
+            constrained: None,
         };
         client.on_client_msg(msg).await.expect("Hello failed");
     }
@@ -283,6 +287,7 @@ mod tests {
             channel_ids: None,
             use_webpush: Some(true),
             broadcasts: None,
+            constrained: None,
         };
         client.on_client_msg(msg).await.expect("Hello failed");
     }
@@ -295,6 +300,7 @@ mod tests {
             channel_ids: None,
             use_webpush: Some(true),
             broadcasts: None,
+            constrained: None,
         };
         client.on_client_msg(msg).await.expect("Hello failed");
     }
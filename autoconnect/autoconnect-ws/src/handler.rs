@@ -129,7 +129,8 @@ async fn unidentified_ws(
 /// `WebPushClient::on_server_notif`.
 ///
 /// - ping_manager: A multi-purpose timer that ticks when either a WebSocket
-/// Ping (or WebPush Broadcast) should be sent to the Client or when we time
+/// Ping (or WebPush Broadcast) should be sent to the Client, along with any
+/// deferred notifications (see `WebPushClient::on_ping_tick`), or when we time
 /// out the Client for not responding to a previous Ping in time. The Ping
 /// encourages the connection to keep alive (it's more likely to be dropped if
 /// completely idle) and aids in detecting Clients that are no longer connected
@@ -195,6 +196,10 @@ async fn identified_ws(
                 trace!("identified_ws: ping_manager tick is_ok: {}", result.is_ok());
                 // Propagate PongTimeout
                 result?;
+                for smsg in client.on_ping_tick().await? {
+                    trace!("identified_ws: ping_manager tick, ServerMessage -> session {:#?}", smsg);
+                    session.text(smsg).await?;
+                }
                 ping_manager.ws_ping_or_broadcast(client, session).await?;
             }
         }
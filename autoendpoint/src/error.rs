@@ -1,6 +1,7 @@
 //! Error types and transformations
 // TODO: Collpase these into `autopush_common::error`
 
+use crate::headers::aes128gcm::Aes128GcmError;
 use crate::headers::vapid::VapidError;
 use crate::routers::RouterError;
 use actix_web::{
@@ -95,6 +96,15 @@ pub enum ApiErrorKind {
     #[error("{0}")]
     InvalidEncryption(String),
 
+    /// The header block of an aes128gcm payload is malformed
+    #[error("Invalid aes128gcm payload: {0}")]
+    InvalidPayload(#[from] Aes128GcmError),
+
+    /// The sender or UAID is over its rate limit, retry after the given
+    /// number of seconds
+    #[error("Too many notifications, retry after {0} seconds")]
+    RateLimited(u64),
+
     /// Used if the API version given is not v1 or v2
     #[error("Invalid API version")]
     InvalidApiVersion,
@@ -102,6 +112,14 @@ pub enum ApiErrorKind {
     #[error("Missing TTL value")]
     NoTTL,
 
+    /// The Urgency header isn't one of the RFC 8030 values
+    #[error("Invalid Urgency value")]
+    InvalidUrgency,
+
+    /// The Push-Receipt header isn't an https URL
+    #[error("Invalid Push-Receipt value")]
+    InvalidReceipt,
+
     #[error("Invalid router type")]
     InvalidRouterType,
 
@@ -133,7 +151,10 @@ impl ApiErrorKind {
 
             ApiErrorKind::Validation(_)
             | ApiErrorKind::InvalidEncryption(_)
+            | ApiErrorKind::InvalidPayload(_)
             | ApiErrorKind::NoTTL
+            | ApiErrorKind::InvalidUrgency
+            | ApiErrorKind::InvalidReceipt
             | ApiErrorKind::InvalidRouterType
             | ApiErrorKind::InvalidRouterToken
             | ApiErrorKind::InvalidMessageId => StatusCode::BAD_REQUEST,
@@ -148,6 +169,8 @@ impl ApiErrorKind {
 
             ApiErrorKind::NoUser | ApiErrorKind::NoSubscription => StatusCode::GONE,
 
+            ApiErrorKind::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+
             ApiErrorKind::LogCheck => StatusCode::IM_A_TEAPOT,
 
             ApiErrorKind::General(_)
@@ -167,7 +190,11 @@ impl ApiErrorKind {
 
             ApiErrorKind::Validation(_) => "validation",
             ApiErrorKind::InvalidEncryption(_) => "invalid_encryption",
+            ApiErrorKind::InvalidPayload(_) => "invalid_payload",
+            ApiErrorKind::RateLimited(_) => "rate_limited",
             ApiErrorKind::NoTTL => "no_ttl",
+            ApiErrorKind::InvalidUrgency => "invalid_urgency",
+            ApiErrorKind::InvalidReceipt => "invalid_receipt",
             ApiErrorKind::InvalidRouterType => "invalid_router_type",
             ApiErrorKind::InvalidRouterToken => "invalid_router_token",
             ApiErrorKind::InvalidMessageId => "invalid_message_id",
@@ -204,6 +231,8 @@ impl ApiErrorKind {
                 self,
                 // Ignore common webpush errors
                 ApiErrorKind::NoTTL | ApiErrorKind::InvalidEncryption(_) |
+                ApiErrorKind::InvalidUrgency | ApiErrorKind::InvalidReceipt |
+                ApiErrorKind::InvalidPayload(_) | ApiErrorKind::RateLimited(_) |
                 // Ignore common VAPID erros
                 ApiErrorKind::VapidError(_)
                 | ApiErrorKind::Jwt(_)
@@ -251,6 +280,14 @@ impl ApiErrorKind {
 
             ApiErrorKind::NoTTL => Some(111),
 
+            ApiErrorKind::InvalidUrgency => Some(115),
+
+            ApiErrorKind::InvalidReceipt => Some(116),
+
+            ApiErrorKind::InvalidPayload(_) => Some(117),
+
+            ApiErrorKind::RateLimited(_) => Some(118),
+
             ApiErrorKind::LogCheck => Some(999),
 
             ApiErrorKind::General(_)
@@ -300,6 +337,9 @@ impl ResponseError for ApiError {
     fn error_response(&self) -> HttpResponse {
         let mut builder = HttpResponse::build(self.kind.status());
 
+        if let ApiErrorKind::RateLimited(retry_after) = self.kind {
+            builder.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+        }
         match self.status_code() {
             StatusCode::GONE => {
                 builder.insert_header(CacheControl(vec![CacheDirective::MaxAge(86400)]));
@@ -17,11 +17,13 @@ pub enum MessageId {
         uaid: Uuid,
         channel_id: Uuid,
         topic: String,
+        receipt: bool,
     },
     WithoutTopic {
         uaid: Uuid,
         channel_id: Uuid,
         timestamp: u64,
+        receipt: bool,
     },
 }
 
@@ -50,23 +52,27 @@ impl MessageId {
                 uaid,
                 channel_id,
                 topic,
+                receipt,
             } => format!(
-                "{}:{}:{}:{}",
+                "{}:{}:{}:{}:{}",
                 TOPIC_NOTIFICATION_PREFIX,
                 &uaid.as_simple(),
                 &channel_id.as_simple(),
-                topic
+                topic,
+                u8::from(*receipt)
             ),
             MessageId::WithoutTopic {
                 uaid,
                 channel_id,
                 timestamp,
+                receipt,
             } => format!(
-                "{}:{}:{}:{}",
+                "{}:{}:{}:{}:{}",
                 STANDARD_NOTIFICATION_PREFIX,
                 uaid.as_simple(),
                 channel_id.as_simple(),
-                timestamp
+                timestamp,
+                u8::from(*receipt)
             ),
         };
 
@@ -81,9 +87,14 @@ impl MessageId {
         let decrypted_str = String::from_utf8_lossy(&decrypted_bytes);
         let segments: Vec<_> = decrypted_str.split(':').collect();
 
-        if segments.len() != 4 {
-            return Err(ApiErrorKind::InvalidMessageId.into());
-        }
+        // Message IDs issued before the receipt flag was added lack it, so
+        // may have a receipt
+        let receipt = match segments[..] {
+            [_, _, _, _] => true,
+            [_, _, _, _, "0"] => false,
+            [_, _, _, _, "1"] => true,
+            _ => return Err(ApiErrorKind::InvalidMessageId.into()),
+        };
 
         let (version, uaid, chid, topic_or_timestamp) =
             (segments[0], segments[1], segments[2], segments[3]);
@@ -93,6 +104,7 @@ impl MessageId {
                 uaid: Uuid::parse_str(uaid).map_err(|_| ApiErrorKind::InvalidMessageId)?,
                 channel_id: Uuid::parse_str(chid).map_err(|_| ApiErrorKind::InvalidMessageId)?,
                 topic: topic_or_timestamp.to_string(),
+                receipt,
             }),
             "02" => Ok(MessageId::WithoutTopic {
                 uaid: Uuid::parse_str(uaid).map_err(|_| ApiErrorKind::InvalidMessageId)?,
@@ -100,6 +112,7 @@ impl MessageId {
                 timestamp: topic_or_timestamp
                     .parse()
                     .map_err(|_| ApiErrorKind::InvalidMessageId)?,
+                receipt,
             }),
             _ => Err(ApiErrorKind::InvalidMessageId.into()),
         }
@@ -113,6 +126,15 @@ impl MessageId {
         }
     }
 
+    /// Whether a receipt may have been requested for the associated
+    /// notification
+    pub fn receipt(&self) -> bool {
+        match self {
+            MessageId::WithTopic { receipt, .. } => *receipt,
+            MessageId::WithoutTopic { receipt, .. } => *receipt,
+        }
+    }
+
     /// Get the sort-key for the associated notification
     pub fn sort_key(&self) -> String {
         match self {
@@ -137,3 +159,44 @@ impl MessageId {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use fernet::{Fernet, MultiFernet};
+    use uuid::Uuid;
+
+    use super::MessageId;
+
+    fn fernet() -> MultiFernet {
+        MultiFernet::new(vec![Fernet::new(&Fernet::generate_key()).unwrap()])
+    }
+
+    #[test]
+    fn receipt_roundtrip() {
+        let fernet = fernet();
+        for receipt in [false, true] {
+            let message_id = MessageId::WithoutTopic {
+                uaid: Uuid::new_v4(),
+                channel_id: Uuid::new_v4(),
+                timestamp: 1234,
+                receipt,
+            };
+            let decrypted = MessageId::decrypt(&fernet, &message_id.encrypt(&fernet)).unwrap();
+            assert_eq!(decrypted.receipt(), receipt);
+            assert_eq!(decrypted.sort_key(), message_id.sort_key());
+        }
+    }
+
+    /// Message IDs issued before the receipt flag may have a receipt
+    #[test]
+    fn legacy_receipt() {
+        let fernet = fernet();
+        let (uaid, chid) = (Uuid::new_v4(), Uuid::new_v4());
+        let id = format!("01:{}:{}:topic", uaid.simple(), chid.simple());
+        let legacy = fernet.encrypt(id.as_bytes());
+        assert!(MessageId::decrypt(&fernet, &legacy).unwrap().receipt());
+
+        let invalid = fernet.encrypt(format!("{id}:x").as_bytes());
+        assert!(MessageId::decrypt(&fernet, &invalid).is_err());
+    }
+}
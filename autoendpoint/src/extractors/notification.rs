@@ -2,6 +2,7 @@ use crate::error::{ApiError, ApiErrorKind};
 use crate::extractors::{
     message_id::MessageId, notification_headers::NotificationHeaders, subscription::Subscription,
 };
+use crate::headers::aes128gcm::Aes128GcmHeader;
 use crate::server::AppState;
 use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
 use autopush_common::util::{b64_encode_url, ms_since_epoch, sec_since_epoch};
@@ -50,6 +51,27 @@ impl FromRequest for Notification {
                     ApiErrorKind::PayloadError(e)
                 })?;
 
+            let headers = NotificationHeaders::from_request(&req, !data.is_empty())?;
+
+            // Only identified (VAPID) senders may have receipts sent on their
+            // behalf
+            if headers.receipt.is_some() && subscription.vapid.is_none() {
+                return Err(ApiErrorKind::InvalidReceipt.into());
+            }
+
+            // Reject malformed aes128gcm payloads, which the UA would
+            // otherwise silently drop
+            if !data.is_empty() && headers.encoding.as_deref() == Some("aes128gcm") {
+                if let Err(e) = Aes128GcmHeader::parse(&data) {
+                    app_state
+                        .metrics
+                        .incr_with_tags("updates.notification.invalid_payload")
+                        .with_tag("reason", e.metric_label())
+                        .send();
+                    return Err(ApiErrorKind::InvalidPayload(e).into());
+                }
+            }
+
             // Convert data to base64
             let data = if data.is_empty() {
                 None
@@ -57,7 +79,6 @@ impl FromRequest for Notification {
                 Some(b64_encode_url(&data.to_vec()))
             };
 
-            let headers = NotificationHeaders::from_request(&req, data.is_some())?;
             let timestamp = sec_since_epoch();
             let sort_key_timestamp = ms_since_epoch();
             let message_id = Self::generate_message_id(
@@ -66,6 +87,7 @@ impl FromRequest for Notification {
                 subscription.channel_id,
                 headers.topic.as_deref(),
                 sort_key_timestamp,
+                headers.receipt.is_some(),
             );
 
             // Record the encoding if we have an encrypted payload
@@ -100,6 +122,8 @@ impl From<Notification> for autopush_common::notification::Notification {
             version: notification.message_id,
             ttl: notification.headers.ttl as u64,
             topic,
+            urgency: notification.headers.urgency,
+            receipt: notification.headers.receipt.clone(),
             timestamp: notification.timestamp,
             data: notification.data,
             sortkey_timestamp,
@@ -121,29 +145,35 @@ impl Notification {
     /// For topic messages, a sort_key version of 01 is used, and the topic
     /// is included for reference:
     ///
-    ///     Encrypted('01' : uaid.hex : channel_id.hex : topic)
+    ///     Encrypted('01' : uaid.hex : channel_id.hex : topic : receipt)
     ///
     /// For non-topic messages, a sort_key version of 02 is used:
     ///
-    ///     Encrypted('02' : uaid.hex : channel_id.hex : timestamp)
+    ///     Encrypted('02' : uaid.hex : channel_id.hex : timestamp : receipt)
+    ///
+    /// `receipt` is 1 when a receipt was requested (otherwise 0), so that
+    /// deleting a message only reads it back when a receipt may be due.
     fn generate_message_id(
         fernet: &MultiFernet,
         uaid: Uuid,
         channel_id: Uuid,
         topic: Option<&str>,
         timestamp: u64,
+        receipt: bool,
     ) -> String {
         let message_id = if let Some(topic) = topic {
             MessageId::WithTopic {
                 uaid,
                 channel_id,
                 topic: topic.to_string(),
+                receipt,
             }
         } else {
             MessageId::WithoutTopic {
                 uaid,
                 channel_id,
                 timestamp,
+                receipt,
             }
         };
 
@@ -171,6 +201,13 @@ impl Notification {
         map.insert("ttl", serde_json::to_value(self.headers.ttl).unwrap());
         map.insert("topic", serde_json::to_value(&self.headers.topic).unwrap());
         map.insert("timestamp", serde_json::to_value(self.timestamp).unwrap());
+        map.insert(
+            "urgency",
+            serde_json::to_value(self.headers.urgency).unwrap(),
+        );
+        if let Some(receipt) = &self.headers.receipt {
+            map.insert("receipt", serde_json::to_value(receipt).unwrap());
+        }
 
         if let Some(data) = &self.data {
             map.insert("data", serde_json::to_value(data).unwrap());
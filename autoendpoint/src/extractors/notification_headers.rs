@@ -2,11 +2,15 @@ use crate::error::{ApiError, ApiErrorKind, ApiResult};
 use crate::headers::crypto_key::CryptoKeyHeader;
 use crate::headers::util::{get_header, get_owned_header};
 use actix_web::HttpRequest;
+use autopush_common::notification::Urgency;
+use autopush_common::receipt::is_public_address;
 use autopush_common::util::InsertOpt;
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::cmp::min;
 use std::collections::HashMap;
+use std::net::IpAddr;
+use url::{Host, Url};
 use validator::Validate;
 use validator_derive::Validate;
 
@@ -40,6 +44,13 @@ pub struct NotificationHeaders {
     )]
     pub topic: Option<String>,
 
+    /// The RFC 8030 Urgency, `normal` when not specified
+    pub urgency: Urgency,
+
+    /// The URL to send the message's receipt to, only set when the request
+    /// `Prefer`s `respond-async`
+    pub receipt: Option<String>,
+
     // These fields are validated separately, because the validation is complex
     // and based upon the content encoding
     pub encoding: Option<String>,
@@ -74,11 +85,19 @@ impl NotificationHeaders {
             .map(|ttl| min(ttl, MAX_TTL))
             .ok_or(ApiErrorKind::NoTTL)?;
         let topic = get_owned_header(req, "topic");
+        let urgency = get_header(req, "urgency")
+            .map(str::parse)
+            .transpose()
+            .map_err(|_| ApiErrorKind::InvalidUrgency)?
+            .unwrap_or_default();
+        let receipt = Self::receipt(req)?;
 
         let headers = if has_data {
             NotificationHeaders {
                 ttl,
                 topic,
+                urgency,
+                receipt,
                 encoding: get_owned_header(req, "content-encoding"),
                 encryption: get_owned_header(req, "encryption").map(Self::strip_header),
                 encryption_key: get_owned_header(req, "encryption-key"),
@@ -89,6 +108,8 @@ impl NotificationHeaders {
             NotificationHeaders {
                 ttl,
                 topic,
+                urgency,
+                receipt,
                 encoding: None,
                 encryption: None,
                 encryption_key: None,
@@ -108,6 +129,37 @@ impl NotificationHeaders {
         }
     }
 
+    /// Extract the requested receipt URL. Receipts are only sent when the
+    /// application server asks for an asynchronous response (RFC 8030 §5.1)
+    fn receipt(req: &HttpRequest) -> ApiResult<Option<String>> {
+        let respond_async = get_header(req, "prefer").map_or(false, |prefer| {
+            prefer
+                .split(',')
+                .any(|pref| pref.trim().eq_ignore_ascii_case("respond-async"))
+        });
+        if !respond_async {
+            return Ok(None);
+        }
+        let Some(receipt) = get_header(req, "push-receipt") else {
+            return Ok(None);
+        };
+        let url = match Url::parse(receipt) {
+            Ok(url) if url.scheme() == "https" => url,
+            _ => return Err(ApiErrorKind::InvalidReceipt.into()),
+        };
+        // Hostnames are checked once resolved, when the receipt is sent
+        let public = match url.host() {
+            Some(Host::Domain(_)) => true,
+            Some(Host::Ipv4(ip)) => is_public_address(&IpAddr::V4(ip)),
+            Some(Host::Ipv6(ip)) => is_public_address(&IpAddr::V6(ip)),
+            None => false,
+        };
+        if !public {
+            return Err(ApiErrorKind::InvalidReceipt.into());
+        }
+        Ok(Some(url.into()))
+    }
+
     /// Remove Base64 padding and double-quotes
     fn strip_header(header: String) -> String {
         let header = header.replace('"', "");
@@ -219,6 +271,7 @@ mod tests {
     use super::MAX_TTL;
     use crate::error::{ApiErrorKind, ApiResult};
     use actix_web::test::TestRequest;
+    use autopush_common::notification::Urgency;
 
     /// Assert that a result is a validation error and check its serialization
     /// against the JSON value.
@@ -333,6 +386,85 @@ mod tests {
         );
     }
 
+    /// A valid Urgency is parsed, regardless of case
+    #[test]
+    fn valid_urgency() {
+        let req = TestRequest::post()
+            .insert_header(("TTL", "10"))
+            .insert_header(("Urgency", "Very-Low"))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(&req, false);
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().urgency, Urgency::VeryLow);
+    }
+
+    /// Unknown Urgency values return an error
+    #[test]
+    fn invalid_urgency() {
+        let req = TestRequest::post()
+            .insert_header(("TTL", "10"))
+            .insert_header(("Urgency", "urgent"))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(&req, false);
+
+        assert!(matches!(
+            result.unwrap_err().kind,
+            ApiErrorKind::InvalidUrgency
+        ));
+    }
+
+    /// A receipt is only requested along with `Prefer: respond-async`
+    #[test]
+    fn valid_receipt() {
+        let req = TestRequest::post()
+            .insert_header(("TTL", "10"))
+            .insert_header(("Prefer", "respond-async"))
+            .insert_header(("Push-Receipt", "https://example.com/receipts/1"))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(&req, false);
+        assert_eq!(
+            result.unwrap().receipt.as_deref(),
+            Some("https://example.com/receipts/1")
+        );
+
+        let req = TestRequest::post()
+            .insert_header(("TTL", "10"))
+            .insert_header(("Push-Receipt", "https://example.com/receipts/1"))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(&req, false);
+        assert_eq!(result.unwrap().receipt, None);
+    }
+
+    /// Receipts are only sent to public https URLs
+    #[test]
+    fn invalid_receipt() {
+        let req = TestRequest::post()
+            .insert_header(("TTL", "10"))
+            .insert_header(("Prefer", "wait=5, respond-async"))
+            .insert_header(("Push-Receipt", "http://example.com/receipts/1"))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(&req, false);
+
+        assert!(matches!(
+            result.unwrap_err().kind,
+            ApiErrorKind::InvalidReceipt
+        ));
+
+        // Nor to private addresses
+        let req = TestRequest::post()
+            .insert_header(("TTL", "10"))
+            .insert_header(("Prefer", "respond-async"))
+            .insert_header(("Push-Receipt", "https://169.254.169.254/receipts/1"))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(&req, false);
+
+        assert!(matches!(
+            result.unwrap_err().kind,
+            ApiErrorKind::InvalidReceipt
+        ));
+    }
+
     /// If there is a payload, there must be a content encoding header
     #[test]
     fn payload_without_content_encoding() {
@@ -361,6 +493,8 @@ mod tests {
             NotificationHeaders {
                 ttl: 10,
                 topic: None,
+                urgency: Urgency::Normal,
+                receipt: None,
                 encoding: Some("aesgcm".to_string()),
                 encryption: Some("salt=foo".to_string()),
                 encryption_key: None,
@@ -386,6 +520,8 @@ mod tests {
             NotificationHeaders {
                 ttl: 10,
                 topic: None,
+                urgency: Urgency::Normal,
+                receipt: None,
                 encoding: Some("aes128gcm".to_string()),
                 encryption: Some("notsalt=foo".to_string()),
                 encryption_key: None,
@@ -412,6 +548,8 @@ mod tests {
             NotificationHeaders {
                 ttl: 10,
                 topic: None,
+                urgency: Urgency::Normal,
+                receipt: None,
                 encoding: Some("aesgcm".to_string()),
                 encryption: Some("salt=foo".to_string()),
                 encryption_key: None,
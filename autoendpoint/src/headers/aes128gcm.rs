@@ -0,0 +1,173 @@
+use thiserror::Error;
+
+/// Length of the salt beginning the header block
+const SALT_LEN: usize = 16;
+/// Length of the fixed part of the header block (salt, rs, idlen)
+const HEADER_LEN: usize = SALT_LEN + 4 + 1;
+/// Length of the AEAD authentication tag ending each record
+const TAG_LEN: usize = 16;
+/// The smallest ciphertext: its tag and a padding delimiter
+const MIN_CIPHERTEXT_LEN: usize = TAG_LEN + 1;
+/// Record sizes below this are invalid
+const MIN_RECORD_SIZE: u32 = 18;
+/// Length of an uncompressed P-256 public key
+const PUBLIC_KEY_LEN: usize = 65;
+
+/// Parses the header block beginning `aes128gcm` encoded payloads, described
+/// by `https://datatracker.ietf.org/doc/html/rfc8188#section-2.1`
+///
+/// ```text
+/// +-----------+--------+-----------+---------------+
+/// | salt (16) | rs (4) | idlen (1) | keyid (idlen) |
+/// +-----------+--------+-----------+---------------+
+/// ```
+///
+/// WebPush further requires the keyid to be the application server's public
+/// key and the payload to be a single record
+/// (`https://datatracker.ietf.org/doc/html/rfc8291#section-4`).
+#[derive(Debug, Eq, PartialEq)]
+pub struct Aes128GcmHeader {
+    pub salt: [u8; SALT_LEN],
+    pub record_size: u32,
+    pub keyid: Vec<u8>,
+}
+
+impl Aes128GcmHeader {
+    /// Parse and validate the header block of an `aes128gcm` payload
+    pub fn parse(payload: &[u8]) -> Result<Self, Aes128GcmError> {
+        if payload.len() < HEADER_LEN {
+            return Err(Aes128GcmError::Truncated);
+        }
+        let (salt, rest) = payload.split_at(SALT_LEN);
+        let (record_size, rest) = rest.split_at(4);
+        let record_size = u32::from_be_bytes(record_size.try_into().unwrap());
+        if record_size < MIN_RECORD_SIZE {
+            return Err(Aes128GcmError::InvalidRecordSize(record_size));
+        }
+
+        let keyid_len = rest[0] as usize;
+        let rest = &rest[1..];
+        if rest.len() < keyid_len {
+            return Err(Aes128GcmError::Truncated);
+        }
+        let (keyid, ciphertext) = rest.split_at(keyid_len);
+        // Uncompressed points are prefixed by 0x04
+        if keyid_len != PUBLIC_KEY_LEN || keyid[0] != 0x04 {
+            return Err(Aes128GcmError::InvalidKeyId(keyid_len));
+        }
+
+        if ciphertext.len() < MIN_CIPHERTEXT_LEN {
+            return Err(Aes128GcmError::Truncated);
+        }
+        if ciphertext.len() > record_size as usize {
+            return Err(Aes128GcmError::MultipleRecords);
+        }
+
+        Ok(Self {
+            salt: salt.try_into().unwrap(),
+            record_size,
+            keyid: keyid.to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum Aes128GcmError {
+    #[error("Payload is too short")]
+    Truncated,
+    #[error("Invalid record size: {0}")]
+    InvalidRecordSize(u32),
+    #[error("Invalid keyid of length {0}, expected an uncompressed public key")]
+    InvalidKeyId(usize),
+    #[error("Payload must be a single record")]
+    MultipleRecords,
+}
+
+impl Aes128GcmError {
+    /// The reason tag of the metric emitted on rejection
+    pub fn metric_label(&self) -> &'static str {
+        match self {
+            Aes128GcmError::Truncated => "truncated",
+            Aes128GcmError::InvalidRecordSize(_) => "record_size",
+            Aes128GcmError::InvalidKeyId(_) => "keyid",
+            Aes128GcmError::MultipleRecords => "multiple_records",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Aes128GcmError, Aes128GcmHeader};
+
+    /// Build a payload from its header block fields and ciphertext length
+    fn payload(record_size: u32, keyid: &[u8], ciphertext_len: usize) -> Vec<u8> {
+        let mut payload = vec![7; 16];
+        payload.extend(record_size.to_be_bytes());
+        payload.push(keyid.len() as u8);
+        payload.extend(keyid);
+        payload.extend(vec![0; ciphertext_len]);
+        payload
+    }
+
+    fn public_key() -> Vec<u8> {
+        let mut key = vec![0x04];
+        key.extend([1; 64]);
+        key
+    }
+
+    #[test]
+    fn valid_payload() {
+        let header = Aes128GcmHeader::parse(&payload(4096, &public_key(), 100)).unwrap();
+        assert_eq!(
+            header,
+            Aes128GcmHeader {
+                salt: [7; 16],
+                record_size: 4096,
+                keyid: public_key(),
+            }
+        );
+    }
+
+    #[test]
+    fn truncated_payload() {
+        let full = payload(4096, &public_key(), 100);
+        assert_eq!(
+            Aes128GcmHeader::parse(&full[..20]),
+            Err(Aes128GcmError::Truncated)
+        );
+        assert_eq!(
+            Aes128GcmHeader::parse(&full[..60]),
+            Err(Aes128GcmError::Truncated)
+        );
+        assert_eq!(
+            Aes128GcmHeader::parse(&payload(4096, &public_key(), 16)),
+            Err(Aes128GcmError::Truncated)
+        );
+    }
+
+    #[test]
+    fn invalid_record_size() {
+        assert_eq!(
+            Aes128GcmHeader::parse(&payload(17, &public_key(), 100)),
+            Err(Aes128GcmError::InvalidRecordSize(17))
+        );
+        assert_eq!(
+            Aes128GcmHeader::parse(&payload(50, &public_key(), 100)),
+            Err(Aes128GcmError::MultipleRecords)
+        );
+    }
+
+    #[test]
+    fn invalid_keyid() {
+        assert_eq!(
+            Aes128GcmHeader::parse(&payload(4096, b"a1", 100)),
+            Err(Aes128GcmError::InvalidKeyId(2))
+        );
+        let mut compressed = public_key();
+        compressed[0] = 0x02;
+        assert_eq!(
+            Aes128GcmHeader::parse(&payload(4096, &compressed, 100)),
+            Err(Aes128GcmError::InvalidKeyId(65))
+        );
+    }
+}
@@ -1,3 +1,4 @@
+pub mod aes128gcm;
 pub mod crypto_key;
 pub mod util;
 pub mod vapid;
@@ -9,6 +9,7 @@ mod error;
 mod extractors;
 mod headers;
 mod metrics;
+mod rate_limit;
 mod routers;
 mod routes;
 mod server;
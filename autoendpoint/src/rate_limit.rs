@@ -0,0 +1,325 @@
+//! Per-sender rate limiting of notifications.
+//!
+//! Every notification takes a token from two token buckets: its sender's
+//! (keyed by its VAPID public key, or by its subscription when sent without
+//! VAPID) and its UAID's. A bucket holds up to `burst` tokens and is refilled
+//! at `per_second` tokens per second. Notifications finding either bucket
+//! empty are rejected with a 429 whose `Retry-After` is when the next token
+//! is available, without taking a token from the other bucket.
+//!
+//! The buckets are kept in a [RateLimitStore]. [MemoryRateLimitStore] limits
+//! each autoendpoint instance separately, other stores may share the buckets
+//! (and so the limits) across instances.
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use cadence::{CountedExt, StatsdClient};
+use lru::LruCache;
+
+use autopush_common::util::ms_since_epoch;
+
+use crate::error::{ApiErrorKind, ApiResult};
+use crate::extractors::subscription::Subscription;
+use crate::settings::Settings;
+
+/// The size and refill rate of a token bucket
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TokenBucketLimit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl TokenBucketLimit {
+    /// A limit, unless it's disabled (by a `0` burst or refill rate)
+    pub fn new(burst: u32, per_second: f64) -> Option<Self> {
+        (burst > 0 && per_second > 0.0).then_some(Self { burst, per_second })
+    }
+}
+
+/// The state of a token bucket
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TokenBucket {
+    pub tokens: f64,
+    /// UNIX timestamp in milliseconds of the last refill
+    pub updated_at: u64,
+}
+
+impl TokenBucket {
+    pub fn full(limit: TokenBucketLimit, now: u64) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            updated_at: now,
+        }
+    }
+
+    /// Refill the bucket with the tokens accrued since its last refill
+    pub fn refill(&mut self, limit: TokenBucketLimit, now: u64) {
+        let elapsed = now.saturating_sub(self.updated_at) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.updated_at = self.updated_at.max(now);
+    }
+
+    /// How long until a token is available, when the bucket is empty
+    pub fn wait(&self, limit: TokenBucketLimit) -> Option<Duration> {
+        (self.tokens < 1.0).then(|| Duration::from_secs_f64((1.0 - self.tokens) / limit.per_second))
+    }
+
+    /// Refill the bucket then take a token from it, returning how long until
+    /// a token is available when it's empty
+    pub fn take(&mut self, limit: TokenBucketLimit, now: u64) -> Option<Duration> {
+        self.refill(limit, now);
+        let wait = self.wait(limit);
+        if wait.is_none() {
+            self.tokens -= 1.0;
+        }
+        wait
+    }
+}
+
+/// Storage of the token buckets
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Take a token from each of the keys' buckets (full ones if they're
+    /// unknown), but only when none of them are empty. Otherwise returns the
+    /// index of the empty bucket waiting the longest and how long until it
+    /// has a token.
+    async fn take(
+        &self,
+        buckets: &[(String, TokenBucketLimit)],
+        now: u64,
+    ) -> ApiResult<Option<(usize, Duration)>>;
+}
+
+/// Keeps the token buckets in memory, evicting the least recently used ones
+/// beyond `max_entries` (which are then considered full)
+pub struct MemoryRateLimitStore {
+    buckets: Mutex<LruCache<String, TokenBucket>>,
+}
+
+impl MemoryRateLimitStore {
+    pub fn new(max_entries: NonZeroUsize) -> Self {
+        Self {
+            buckets: Mutex::new(LruCache::new(max_entries)),
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryRateLimitStore {
+    async fn take(
+        &self,
+        buckets: &[(String, TokenBucketLimit)],
+        now: u64,
+    ) -> ApiResult<Option<(usize, Duration)>> {
+        let mut stored = self.buckets.lock().expect("Rate limit buckets poisoned");
+        let mut empty: Option<(usize, Duration)> = None;
+        for (i, (key, limit)) in buckets.iter().enumerate() {
+            let bucket = stored.get_or_insert_mut(key.clone(), || TokenBucket::full(*limit, now));
+            bucket.refill(*limit, now);
+            if let Some(wait) = bucket.wait(*limit) {
+                if !empty.is_some_and(|(_, longest)| longest >= wait) {
+                    empty = Some((i, wait));
+                }
+            }
+        }
+        if empty.is_some() {
+            return Ok(empty);
+        }
+        for (key, limit) in buckets {
+            if let Some(bucket) = stored.get_mut(key) {
+                bucket.take(*limit, now);
+            }
+        }
+        Ok(None)
+    }
+}
+
+pub struct RateLimiter {
+    store: Box<dyn RateLimitStore>,
+    /// The limit of each sender
+    sender: Option<TokenBucketLimit>,
+    /// The limit of each UAID
+    uaid: Option<TokenBucketLimit>,
+    metrics: Arc<StatsdClient>,
+}
+
+impl RateLimiter {
+    pub fn new(
+        store: Box<dyn RateLimitStore>,
+        sender: Option<TokenBucketLimit>,
+        uaid: Option<TokenBucketLimit>,
+        metrics: Arc<StatsdClient>,
+    ) -> Self {
+        Self {
+            store,
+            sender,
+            uaid,
+            metrics,
+        }
+    }
+
+    /// An in memory rate limiter, unless both limits are disabled
+    pub fn from_settings(settings: &Settings, metrics: Arc<StatsdClient>) -> Option<Self> {
+        let sender = TokenBucketLimit::new(
+            settings.rate_limit_sender_burst,
+            settings.rate_limit_sender_per_second,
+        );
+        let uaid = TokenBucketLimit::new(
+            settings.rate_limit_uaid_burst,
+            settings.rate_limit_uaid_per_second,
+        );
+        if sender.is_none() && uaid.is_none() {
+            return None;
+        }
+        let max_entries = NonZeroUsize::new(settings.rate_limit_max_entries.max(1)).unwrap();
+        Some(Self::new(
+            Box::new(MemoryRateLimitStore::new(max_entries)),
+            sender,
+            uaid,
+            metrics,
+        ))
+    }
+
+    /// Take a token for a notification to the subscription, failing with
+    /// `RateLimited` (and taking no tokens) if its sender or UAID is over its
+    /// limit
+    pub async fn check(&self, subscription: &Subscription) -> ApiResult<()> {
+        let mut kinds = Vec::with_capacity(2);
+        let mut buckets = Vec::with_capacity(2);
+        if let Some(limit) = self.sender {
+            let key = match &subscription.vapid {
+                Some(vapid) => format!("vapid:{}", vapid.public_key.trim_end_matches('=')),
+                None => format!("channel:{}", subscription.channel_id.as_simple()),
+            };
+            kinds.push("sender");
+            buckets.push((key, limit));
+        }
+        if let Some(limit) = self.uaid {
+            kinds.push("uaid");
+            buckets.push((
+                format!("uaid:{}", subscription.user.uaid.as_simple()),
+                limit,
+            ));
+        }
+
+        let empty = match self.store.take(&buckets, ms_since_epoch()).await {
+            Ok(empty) => empty,
+            Err(e) => {
+                // Don't reject notifications because of the store
+                warn!("Could not check the rate limit: {}", e);
+                None
+            }
+        };
+        let Some((i, retry_after)) = empty else {
+            return Ok(());
+        };
+        self.metrics
+            .incr_with_tags("notification.rate_limited")
+            .with_tag("limit", kinds[i])
+            .send();
+        // Round up to whole seconds, as the header requires
+        let retry_after = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        Err(ApiErrorKind::RateLimited(retry_after).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use autopush_common::db::User;
+    use cadence::NopMetricSink;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn limit(burst: u32, per_second: f64) -> TokenBucketLimit {
+        TokenBucketLimit::new(burst, per_second).unwrap()
+    }
+
+    fn subscription(uaid: Uuid) -> Subscription {
+        Subscription {
+            user: User {
+                uaid,
+                ..Default::default()
+            },
+            channel_id: Uuid::new_v4(),
+            vapid: None,
+        }
+    }
+
+    #[test]
+    fn bucket_refills() {
+        let limit = limit(2, 4.0);
+        let mut bucket = TokenBucket::full(limit, 1000);
+        assert_eq!(bucket.take(limit, 1000), None);
+        assert_eq!(bucket.take(limit, 1000), None);
+        assert_eq!(bucket.take(limit, 1000), Some(Duration::from_millis(250)));
+        assert_eq!(bucket.take(limit, 1250), None);
+        // Never refilled beyond the burst
+        bucket.take(limit, 60_000);
+        assert_eq!(bucket.tokens, 1.0);
+    }
+
+    #[test]
+    fn disabled_limit() {
+        assert_eq!(TokenBucketLimit::new(0, 1.0), None);
+        assert_eq!(TokenBucketLimit::new(1, 0.0), None);
+    }
+
+    #[actix_rt::test]
+    async fn limits_uaid() {
+        let limiter = RateLimiter::new(
+            Box::new(MemoryRateLimitStore::new(NonZeroUsize::new(10).unwrap())),
+            None,
+            Some(limit(1, 0.5)),
+            Arc::new(StatsdClient::builder("", NopMetricSink).build()),
+        );
+        let uaid = Uuid::new_v4();
+        assert!(limiter.check(&subscription(uaid)).await.is_ok());
+        // A different channel of the same UAID is limited
+        let err = limiter.check(&subscription(uaid)).await.unwrap_err();
+        assert!(matches!(err.kind, ApiErrorKind::RateLimited(2)));
+        // Other UAIDs aren't
+        assert!(limiter.check(&subscription(Uuid::new_v4())).await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn limits_sender_by_channel_without_vapid() {
+        let limiter = RateLimiter::new(
+            Box::new(MemoryRateLimitStore::new(NonZeroUsize::new(10).unwrap())),
+            Some(limit(1, 1.0)),
+            None,
+            Arc::new(StatsdClient::builder("", NopMetricSink).build()),
+        );
+        let subscription = subscription(Uuid::new_v4());
+        assert!(limiter.check(&subscription).await.is_ok());
+        assert!(limiter.check(&subscription).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn rejection_takes_no_tokens() {
+        let limiter = RateLimiter::new(
+            Box::new(MemoryRateLimitStore::new(NonZeroUsize::new(10).unwrap())),
+            Some(limit(2, 0.5)),
+            Some(limit(1, 0.5)),
+            Arc::new(StatsdClient::builder("", NopMetricSink).build()),
+        );
+        let uaid = Uuid::new_v4();
+        let subscription = subscription(uaid);
+        assert!(limiter.check(&subscription).await.is_ok());
+        // Rejected by the UAID's limit, leaving the sender's token
+        let err = limiter.check(&subscription).await.unwrap_err();
+        assert!(matches!(err.kind, ApiErrorKind::RateLimited(2)));
+        // so it can still notify other UAIDs
+        let other = Subscription {
+            user: User {
+                uaid: Uuid::new_v4(),
+                ..Default::default()
+            },
+            ..subscription
+        };
+        assert!(limiter.check(&other).await.is_ok());
+        assert!(limiter.check(&other).await.is_err());
+    }
+}
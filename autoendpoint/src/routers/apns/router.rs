@@ -1,4 +1,5 @@
 use autopush_common::db::client::DbClient;
+use autopush_common::notification::Urgency;
 
 use crate::error::{ApiError, ApiResult};
 use crate::extractors::notification::Notification;
@@ -173,6 +174,17 @@ impl ApnsRouter {
             .set_mutable_content()
     }
 
+    /// The APNs priority of a notification of the given urgency. APNs'
+    /// normal priority lets the device defer delivery to save power, so it's
+    /// reserved for the low urgencies.
+    fn priority(urgency: Urgency) -> Priority {
+        if urgency.is_deferrable() {
+            Priority::Normal
+        } else {
+            Priority::High
+        }
+    }
+
     /// Handle an error by logging, updating metrics, etc
     async fn handle_error(&self, error: a2::Error, uaid: Uuid, channel: &str) -> ApiError {
         match &error {
@@ -456,7 +468,7 @@ impl Router for ApnsRouter {
             token,
             NotificationOptions {
                 apns_id: None,
-                apns_priority: Some(Priority::High),
+                apns_priority: Some(Self::priority(notification.headers.urgency)),
                 apns_topic: Some(topic),
                 apns_collapse_id: None,
                 apns_expiration: Some(notification.timestamp + notification.headers.ttl as u64),
@@ -510,6 +522,7 @@ mod tests {
     use async_trait::async_trait;
     use autopush_common::db::client::DbClient;
     use autopush_common::db::mock::MockDbClient;
+    use autopush_common::notification::Urgency;
     use cadence::StatsdClient;
     use mockall::predicate;
     use std::collections::HashMap;
@@ -661,6 +674,26 @@ mod tests {
         );
     }
 
+    /// Low urgency notifications are sent with APNs' normal priority
+    #[tokio::test]
+    async fn low_urgency_routing() {
+        let client = MockApnsClient::new(|payload| {
+            assert!(matches!(
+                payload.options.apns_priority,
+                Some(a2::Priority::Normal)
+            ));
+
+            Ok(apns_success_response())
+        });
+        let db = MockDbClient::new().into_boxed_arc();
+        let router = make_router(client, db);
+        let mut notification = make_notification(default_router_data(), None, RouterType::APNS);
+        notification.headers.urgency = Urgency::Low;
+
+        let result = router.route_notification(&notification).await;
+        assert!(result.is_ok(), "result = {result:?}");
+    }
+
     /// If there is no client for the user's release channel, an error is
     /// returned and the APNS request is not sent.
     #[tokio::test]
@@ -191,6 +191,7 @@ pub mod tests {
     use crate::extractors::routers::RouterType;
     use crate::extractors::subscription::Subscription;
     use autopush_common::db::User;
+    use autopush_common::notification::Urgency;
     use std::collections::HashMap;
     use uuid::Uuid;
 
@@ -221,6 +222,8 @@ pub mod tests {
             headers: NotificationHeaders {
                 ttl: 0,
                 topic: Some("test-topic".to_string()),
+                urgency: Urgency::Normal,
+                receipt: None,
                 encoding: Some("test-encoding".to_string()),
                 encryption: Some("test-encryption".to_string()),
                 encryption_key: Some("test-encryption-key".to_string()),
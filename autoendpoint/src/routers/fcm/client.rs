@@ -2,6 +2,7 @@ use crate::routers::common::message_size_check;
 use crate::routers::fcm::error::FcmError;
 use crate::routers::fcm::settings::{FcmServerCredential, FcmSettings};
 use crate::routers::RouterError;
+use autopush_common::notification::Urgency;
 use reqwest::StatusCode;
 use serde::Deserialize;
 use std::collections::HashMap;
@@ -88,6 +89,7 @@ impl FcmClient {
         data: HashMap<&'static str, String>,
         routing_token: String,
         ttl: usize,
+        urgency: Urgency,
     ) -> Result<(), RouterError> {
         // Check the payload size. FCM only cares about the `data` field when
         // checking size.
@@ -95,7 +97,7 @@ impl FcmClient {
         message_size_check(data_json.as_bytes(), self.max_data)?;
 
         // Build the FCM message
-        let message = serde_json::json!({
+        let mut message = serde_json::json!({
             "message": {
                 "token": routing_token,
                 "android": {
@@ -104,6 +106,11 @@ impl FcmClient {
                 }
             }
         });
+        // FCM only distinguishes normal and high priority messages, the
+        // former being its default
+        if urgency == Urgency::High {
+            message["message"]["android"]["priority"] = "HIGH".into();
+        }
 
         let server_access_token = self
             .authenticator
@@ -183,6 +190,7 @@ pub mod tests {
     use crate::routers::fcm::client::FcmClient;
     use crate::routers::fcm::settings::{FcmServerCredential, FcmSettings};
     use crate::routers::RouterError;
+    use autopush_common::notification::Urgency;
     use std::collections::HashMap;
     use url::Url;
 
@@ -261,7 +269,33 @@ pub mod tests {
         let mut data = HashMap::new();
         data.insert("is_test", "true".to_string());
 
-        let result = client.send(data, "test-token".to_string(), 42).await;
+        let result = client
+            .send(data, "test-token".to_string(), 42, Urgency::Normal)
+            .await;
+        assert!(result.is_ok(), "result = {result:?}");
+        fcm_mock.assert();
+    }
+
+    /// High urgency messages are sent with a high priority
+    #[tokio::test]
+    async fn sends_high_priority_fcm_request() {
+        let client = make_client(FcmServerCredential {
+            project_id: PROJECT_ID.to_owned(),
+            is_gcm: None,
+            server_access_token: make_service_key(),
+        })
+        .await;
+        let _token_mock = mock_token_endpoint();
+        let fcm_mock = mock_fcm_endpoint_builder(PROJECT_ID)
+            .match_body(r#"{"message":{"android":{"data":{"is_test":"true"},"priority":"HIGH","ttl":"42s"},"token":"test-token"}}"#)
+            .create();
+
+        let mut data = HashMap::new();
+        data.insert("is_test", "true".to_string());
+
+        let result = client
+            .send(data, "test-token".to_string(), 42, Urgency::High)
+            .await;
         assert!(result.is_ok(), "result = {result:?}");
         fcm_mock.assert();
     }
@@ -282,7 +316,12 @@ pub mod tests {
             .create();
 
         let result = client
-            .send(HashMap::new(), "test-token".to_string(), 42)
+            .send(
+                HashMap::new(),
+                "test-token".to_string(),
+                42,
+                Urgency::Normal,
+            )
             .await;
         assert!(result.is_err());
         assert!(
@@ -307,7 +346,12 @@ pub mod tests {
             .create();
 
         let result = client
-            .send(HashMap::new(), "test-token".to_string(), 42)
+            .send(
+                HashMap::new(),
+                "test-token".to_string(),
+                42,
+                Urgency::Normal,
+            )
             .await;
         assert!(result.is_err());
         assert!(
@@ -332,7 +376,12 @@ pub mod tests {
             .create();
 
         let result = client
-            .send(HashMap::new(), "test-token".to_string(), 42)
+            .send(
+                HashMap::new(),
+                "test-token".to_string(),
+                42,
+                Urgency::Normal,
+            )
             .await;
         assert!(result.is_err());
         assert!(
@@ -361,7 +410,12 @@ pub mod tests {
             .create();
 
         let result = client
-            .send(HashMap::new(), "test-token".to_string(), 42)
+            .send(
+                HashMap::new(),
+                "test-token".to_string(),
+                42,
+                Urgency::Normal,
+            )
             .await;
         assert!(result.is_err());
         assert!(
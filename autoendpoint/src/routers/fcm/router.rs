@@ -167,7 +167,15 @@ impl Router for FcmRouter {
         let message_data = build_message_data(notification)?;
         let platform = "fcmv1";
         trace!("Sending message to {platform}: [{:?}]", &app_id);
-        if let Err(e) = client.send(message_data, routing_token, ttl).await {
+        if let Err(e) = client
+            .send(
+                message_data,
+                routing_token,
+                ttl,
+                notification.headers.urgency,
+            )
+            .await
+        {
             return Err(handle_error(
                 e,
                 &self.metrics,
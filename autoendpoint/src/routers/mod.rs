@@ -85,6 +85,9 @@ pub enum RouterError {
     #[error("Database error while saving notification")]
     SaveDb(#[source] DbError),
 
+    #[error("Too many messages are stored for this subscription")]
+    QueueFull,
+
     #[error("User was deleted during routing")]
     UserWasDeleted,
 
@@ -127,6 +130,8 @@ impl RouterError {
 
             RouterError::TooMuchData(_) => StatusCode::PAYLOAD_TOO_LARGE,
 
+            RouterError::QueueFull => StatusCode::TOO_MANY_REQUESTS,
+
             RouterError::Authentication
             | RouterError::GCMAuthentication
             | RouterError::RequestTimeout
@@ -148,6 +153,8 @@ impl RouterError {
 
             RouterError::NotFound => Some(106),
 
+            RouterError::QueueFull => Some(114),
+
             RouterError::SaveDb(_) => Some(201),
 
             RouterError::Authentication => Some(901),
@@ -177,6 +184,7 @@ impl RouterError {
                 "notification.bridge.error.fcm.badappid"
             }
             RouterError::TooMuchData(_) => "notification.bridge.error.too_much_data",
+            RouterError::QueueFull => "notification.message.queue_full",
             _ => "",
         };
         if !err.is_empty() {
@@ -199,6 +207,7 @@ impl RouterError {
             | RouterError::NotFound
             | RouterError::RequestTimeout
             | RouterError::TooMuchData(_)
+            | RouterError::QueueFull
             | RouterError::Upstream { .. } => false,
             _ => true,
         }
@@ -11,7 +11,9 @@ use crate::error::{ApiErrorKind, ApiResult};
 use crate::extractors::{notification::Notification, router_data_input::RouterDataInput};
 use crate::routers::{Router, RouterError, RouterResponse};
 
-use autopush_common::db::{client::DbClient, User};
+use autopush_common::db::{client::DbClient, error::DbError, User};
+use autopush_common::receipt::{send_receipt, ReceiptStatus};
+use autopush_common::util::InsertOpt;
 
 /// The router for desktop user agents.
 ///
@@ -91,6 +93,7 @@ impl Router for WebPushRouter {
                 // TODO: include `internal` if meta is set.
                 .with_tag("topic", &topic)
                 .send();
+            self.send_receipt(notification, ReceiptStatus::Expired);
             return Ok(self.make_delivered_response(notification));
         }
 
@@ -188,7 +191,11 @@ impl WebPushRouter {
                 notification.clone().into(),
             )
             .await
-            .map_err(|e| ApiErrorKind::Router(RouterError::SaveDb(e)).into())
+            .map_err(|e| match e {
+                // The user's message quota is full and set to reject
+                DbError::QuotaExceeded(_) => ApiErrorKind::Router(RouterError::QueueFull).into(),
+                e => ApiErrorKind::Router(RouterError::SaveDb(e)).into(),
+            })
     }
 
     /// Remove the node ID from a user. This is done if the user is no longer
@@ -205,6 +212,18 @@ impl WebPushRouter {
         Ok(())
     }
 
+    /// Send the notification's receipt in the background, if one was requested
+    fn send_receipt(&self, notification: &Notification, status: ReceiptStatus) {
+        if let Some(receipt) = &notification.headers.receipt {
+            actix_rt::spawn(send_receipt(
+                self.metrics.clone(),
+                receipt.clone(),
+                notification.message_id.clone(),
+                status,
+            ));
+        }
+    }
+
     /// Update metrics and create a response for when a notification has been directly forwarded to
     /// an autopush server.
     fn make_delivered_response(&self, notification: &Notification) -> RouterResponse {
@@ -232,6 +251,14 @@ impl WebPushRouter {
             .with_tag("destination", destination_tag)
             .send();
 
+        // The outcome of messages requesting a receipt is reported
+        // asynchronously
+        let status = if notification.headers.receipt.is_some() {
+            StatusCode::ACCEPTED
+        } else {
+            status
+        };
+
         RouterResponse {
             status,
             headers: {
@@ -244,6 +271,7 @@ impl WebPushRouter {
                         .to_string(),
                 );
                 map.insert("TTL", notification.headers.ttl.to_string());
+                map.insert_opt("Push-Receipt", notification.headers.receipt.clone());
                 map
             },
             body: None,
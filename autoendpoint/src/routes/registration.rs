@@ -13,7 +13,7 @@ use crate::extractors::{
 use crate::headers::util::get_header;
 use crate::server::AppState;
 
-use autopush_common::db::User;
+use autopush_common::db::{ChannelRecord, User};
 use autopush_common::endpoint::make_endpoint;
 
 /// Handle the `POST /v1/{router_type}/{app_id}/registration` route
@@ -45,8 +45,6 @@ pub async fn register_uaid_route(
     trace!("🌍 Creating user with UAID {}", user.uaid);
     trace!("🌍 user = {:?}", user);
     trace!("🌍 channel_id = {}", channel_id);
-    app_state.db.add_user(&user).await?;
-    app_state.db.add_channel(&user.uaid, &channel_id).await?;
 
     // Make the endpoint URL
     trace!("🌍 Creating endpoint for user");
@@ -60,6 +58,13 @@ pub async fn register_uaid_route(
     .map_err(ApiErrorKind::EndpointUrl)?;
     trace!("🌍 endpoint = {}", endpoint_url);
 
+    let channel = ChannelRecord::new(channel_id, router_data_input.key.as_deref())
+        .map_err(ApiErrorKind::EndpointUrl)?;
+    app_state
+        .db
+        .add_user_with_channels(&user, vec![channel])
+        .await?;
+
     // Create the secret
     trace!("🌍 Creating secret for UAID {}", user.uaid);
     let auth_keys = app_state.settings.auth_keys();
@@ -136,10 +141,6 @@ pub async fn new_channel_route(
     let channel_data = channel_data.map(Json::into_inner).unwrap_or_default();
     let channel_id = channel_data.channel_id.unwrap_or_else(Uuid::new_v4);
     trace!("🌍 channel_id = {}", channel_id);
-    app_state
-        .db
-        .add_channel(&path_args.uaid, &channel_id)
-        .await?;
 
     // Make the endpoint URL
     trace!("🌍 Creating endpoint for the new channel");
@@ -153,6 +154,10 @@ pub async fn new_channel_route(
     .map_err(ApiErrorKind::EndpointUrl)?;
     trace!("endpoint = {}", endpoint_url);
 
+    let channel = ChannelRecord::new(channel_id, channel_data.key.as_deref())
+        .map_err(ApiErrorKind::EndpointUrl)?;
+    app_state.db.add_channel(&path_args.uaid, &channel).await?;
+
     Ok(HttpResponse::Ok().json(serde_json::json!({
         "channelID": channel_id,
         "endpoint": endpoint_url,
@@ -166,11 +171,13 @@ pub async fn get_channels_route(
     app_state: Data<AppState>,
 ) -> ApiResult<HttpResponse> {
     debug!("🌍 Getting channel IDs for UAID {}", path_args.uaid);
-    let channel_ids = app_state.db.get_channels(&path_args.uaid).await?;
+    let channels = app_state.db.get_channel_records(&path_args.uaid).await?;
+    let channel_ids: Vec<Uuid> = channels.iter().map(|channel| channel.channel_id).collect();
 
     Ok(HttpResponse::Ok().json(serde_json::json!({
         "uaid": path_args.uaid,
-        "channelIDs": channel_ids
+        "channelIDs": channel_ids,
+        "channels": channels
     })))
 }
 
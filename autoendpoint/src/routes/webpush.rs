@@ -7,12 +7,15 @@ use crate::extractors::routers::{RouterType, Routers};
 use crate::server::AppState;
 use actix_web::web::Data;
 use actix_web::HttpResponse;
+use autopush_common::receipt::{send_receipt, ReceiptStatus};
+use autopush_common::util::sec_since_epoch;
+use cadence::CountedExt;
 
 /// Handle the `POST /wpush/{api_version}/{token}` and `POST /wpush/{token}` routes
 pub async fn webpush_route(
     notification: Notification,
     routers: Routers,
-    _app_state: Data<AppState>,
+    app_state: Data<AppState>,
 ) -> ApiResult<HttpResponse> {
     // TODO:
     sentry::configure_scope(|scope| {
@@ -21,11 +24,63 @@ pub async fn webpush_route(
             notification.subscription.user.uaid.to_string().into(),
         );
     });
+    if let Some(rate_limiter) = &app_state.rate_limiter {
+        rate_limiter.check(&notification.subscription).await?;
+    }
     let router = routers.get(
         RouterType::from_str(&notification.subscription.user.router_type)
             .map_err(|_| ApiErrorKind::InvalidRouterType)?,
     );
-    Ok(router.route_notification(&notification).await?.into())
+    let response = router.route_notification(&notification).await?;
+
+    if app_state.settings.record_channel_pushes {
+        // Recording the channel's activity is best effort: the notification
+        // has already been accepted, so don't delay the response on it
+        let db = app_state.db.clone();
+        let metrics = app_state.metrics.clone();
+        let uaid = notification.subscription.user.uaid;
+        let channel_id = notification.subscription.channel_id;
+        actix_rt::spawn(async move {
+            if let Err(e) = db.record_channel_push(&uaid, &channel_id).await {
+                warn!("Could not record channel push: {}", e);
+                metrics.incr("notification.channel_push.error").ok();
+            }
+        });
+    }
+    Ok(response.into())
+}
+
+/// Handle the `GET /m/{message_id}` route
+///
+/// Reports whether the notification is still pending in storage, or is
+/// `gone` (delivered, expired or deleted)
+pub async fn get_notification_route(
+    message_id: MessageId,
+    app_state: Data<AppState>,
+) -> ApiResult<HttpResponse> {
+    let sort_key = message_id.sort_key();
+    debug!("Looking up notification with sort-key {}", sort_key);
+    trace!("message_id = {:?}", message_id);
+    let Some(message) = app_state
+        .db
+        .get_message(&message_id.uaid(), &sort_key)
+        .await?
+    else {
+        return Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "gone" })));
+    };
+
+    // The data is stored as unpadded base64, report the size of the payload
+    // itself
+    let size = message
+        .data
+        .as_deref()
+        .map_or(0, |data| data.trim_end_matches('=').len() * 3 / 4);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "pending",
+        "ttl": (message.timestamp + message.ttl).saturating_sub(sec_since_epoch()),
+        "topic": message.topic,
+        "size": size,
+    })))
 }
 
 /// Handle the `DELETE /m/{message_id}` route
@@ -36,10 +91,236 @@ pub async fn delete_notification_route(
     let sort_key = message_id.sort_key();
     debug!("Deleting notification with sort-key {}", sort_key);
     trace!("message_id = {:?}", message_id);
-    app_state
-        .db
-        .remove_message(&message_id.uaid(), &sort_key)
-        .await?;
+    let uaid = message_id.uaid();
+    // Read the message first when its deletion may have to be reported
+    let message = if message_id.receipt() {
+        app_state.db.get_message(&uaid, &sort_key).await?
+    } else {
+        None
+    };
+    app_state.db.remove_message(&uaid, &sort_key).await?;
+
+    if let Some(message) = message {
+        if let Some(url) = message.receipt {
+            actix_rt::spawn(send_receipt(
+                app_state.metrics.clone(),
+                url,
+                message.version,
+                ReceiptStatus::Deleted,
+            ));
+        }
+    }
 
     Ok(HttpResponse::NoContent().finish())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{http::StatusCode, test, web, App};
+    use autopush_common::db::{client::DbClient, memory::MemoryClientImpl, DbSettings, User};
+    use autopush_common::notification::Notification;
+    use autopush_common::util::{ms_since_epoch, sec_since_epoch};
+    use cadence::{NopMetricSink, StatsdClient};
+    use fernet::{Fernet, MultiFernet};
+    use serde_json::Value;
+    use uuid::Uuid;
+
+    use super::get_notification_route;
+    use crate::extractors::message_id::MessageId;
+    use crate::routers::{
+        adm::router::AdmRouter, apns::router::ApnsRouter, fcm::router::FcmRouter,
+    };
+    use crate::server::AppState;
+    use crate::settings::Settings;
+
+    async fn app_state(db: Box<dyn DbClient>) -> AppState {
+        let metrics = Arc::new(StatsdClient::builder("", NopMetricSink).build());
+        let settings = Settings::default();
+        let http = reqwest::Client::new();
+        let endpoint_url = settings.endpoint_url();
+        AppState {
+            fcm_router: Arc::new(
+                FcmRouter::new(
+                    settings.fcm.clone(),
+                    endpoint_url.clone(),
+                    http.clone(),
+                    metrics.clone(),
+                    db.clone(),
+                )
+                .await
+                .unwrap(),
+            ),
+            apns_router: Arc::new(
+                ApnsRouter::new(
+                    settings.apns.clone(),
+                    endpoint_url.clone(),
+                    metrics.clone(),
+                    db.clone(),
+                )
+                .await
+                .unwrap(),
+            ),
+            adm_router: Arc::new(
+                AdmRouter::new(
+                    settings.adm.clone(),
+                    endpoint_url,
+                    http.clone(),
+                    metrics.clone(),
+                    db.clone(),
+                )
+                .unwrap(),
+            ),
+            fernet: MultiFernet::new(vec![Fernet::new(&Fernet::generate_key()).unwrap()]),
+            metrics,
+            settings,
+            db,
+            http,
+            rate_limiter: None,
+        }
+    }
+
+    /// Store a message for a new user, returning the `AppState` and the
+    /// message
+    async fn setup(topic: Option<&str>) -> (AppState, Uuid, Notification) {
+        let metrics = Arc::new(StatsdClient::builder("", NopMetricSink).build());
+        let db = MemoryClientImpl::new(
+            metrics,
+            &DbSettings {
+                dsn: Some("memory://".to_owned()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let uaid = Uuid::new_v4();
+        db.add_user(&User {
+            uaid,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let message = Notification {
+            channel_id: Uuid::new_v4(),
+            version: "version".to_owned(),
+            ttl: 300,
+            topic: topic.map(str::to_owned),
+            timestamp: sec_since_epoch(),
+            // 3 bytes of payload
+            data: Some("AAAA".to_owned()),
+            sortkey_timestamp: topic.is_none().then(ms_since_epoch),
+            ..Default::default()
+        };
+        db.save_message(&uaid, message.clone()).await.unwrap();
+        (app_state(Box::new(db)).await, uaid, message)
+    }
+
+    fn message_id(uaid: Uuid, message: &Notification) -> MessageId {
+        match &message.topic {
+            Some(topic) => MessageId::WithTopic {
+                uaid,
+                channel_id: message.channel_id,
+                topic: topic.clone(),
+                receipt: false,
+            },
+            None => MessageId::WithoutTopic {
+                uaid,
+                channel_id: message.channel_id,
+                timestamp: message.sortkey_timestamp.unwrap(),
+                receipt: false,
+            },
+        }
+    }
+
+    /// Request the status of the encrypted `message_id`
+    async fn get_status(app_state: &AppState, message_id: &str) -> (StatusCode, Value) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(app_state.clone()))
+                .route("/m/{message_id}", web::get().to(get_notification_route)),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/m/{message_id}"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let status = resp.status();
+        let body = test::read_body(resp).await;
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[actix_rt::test]
+    async fn pending_topic_message() {
+        let (app_state, uaid, message) = setup(Some("topic")).await;
+        let id = message_id(uaid, &message).encrypt(&app_state.fernet);
+
+        let (status, body) = get_status(&app_state, &id).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "pending");
+        assert_eq!(body["topic"], "topic");
+        assert_eq!(body["size"], 3);
+        let ttl = body["ttl"].as_u64().unwrap();
+        assert!(0 < ttl && ttl <= 300);
+    }
+
+    #[actix_rt::test]
+    async fn pending_timestamp_message() {
+        let (app_state, uaid, message) = setup(None).await;
+        let id = message_id(uaid, &message).encrypt(&app_state.fernet);
+
+        let (status, body) = get_status(&app_state, &id).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "pending");
+        assert_eq!(body["topic"], Value::Null);
+    }
+
+    #[actix_rt::test]
+    async fn gone_message() {
+        let (app_state, uaid, message) = setup(None).await;
+        let id = message_id(uaid, &message).encrypt(&app_state.fernet);
+        app_state
+            .db
+            .remove_message(&uaid, &message.chidmessageid())
+            .await
+            .unwrap();
+
+        let (status, body) = get_status(&app_state, &id).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({"status": "gone"}));
+    }
+
+    /// Another subscription's message ID can't reveal the message
+    #[actix_rt::test]
+    async fn other_subscription() {
+        let (app_state, uaid, message) = setup(Some("topic")).await;
+        for id in [
+            message_id(Uuid::new_v4(), &message),
+            MessageId::WithTopic {
+                uaid,
+                channel_id: Uuid::new_v4(),
+                topic: "topic".to_owned(),
+                receipt: false,
+            },
+        ] {
+            let (status, body) = get_status(&app_state, &id.encrypt(&app_state.fernet)).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["status"], "gone");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn invalid_message_id() {
+        let (app_state, uaid, message) = setup(Some("topic")).await;
+        let other_key = MultiFernet::new(vec![Fernet::new(&Fernet::generate_key()).unwrap()]);
+        for id in [
+            "invalid".to_owned(),
+            // not issued by this server
+            message_id(uaid, &message).encrypt(&other_key),
+            // not a message ID
+            app_state.fernet.encrypt(b"01:invalid"),
+        ] {
+            let (status, _) = get_status(&app_state, &id).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 //! Main application server
 #![forbid(unsafe_code)]
+use std::num::NonZeroUsize;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -9,8 +10,18 @@ use actix_web::{
 };
 #[cfg(feature = "bigtable")]
 use autopush_common::db::bigtable::BigTableClientImpl;
-#[cfg(feature = "dual")]
+use autopush_common::db::caching::CachingDbClient;
 use autopush_common::db::dual::DualClientImpl;
+#[cfg(feature = "faulty")]
+use autopush_common::db::faulty::FaultyDbClient;
+use autopush_common::db::instrumented::InstrumentedDbClient;
+use autopush_common::db::memory::MemoryClientImpl;
+#[cfg(feature = "postgres")]
+use autopush_common::db::postgres::PgClientImpl;
+#[cfg(feature = "redis")]
+use autopush_common::db::redis::RedisClientImpl;
+#[cfg(feature = "sqlite")]
+use autopush_common::db::sqlite::SqliteClientImpl;
 use cadence::StatsdClient;
 use fernet::MultiFernet;
 use serde_json::json;
@@ -25,6 +36,7 @@ use autopush_common::{
 
 use crate::error::{ApiError, ApiErrorKind, ApiResult};
 use crate::metrics;
+use crate::rate_limit::RateLimiter;
 use crate::routers::{adm::router::AdmRouter, apns::router::ApnsRouter, fcm::router::FcmRouter};
 use crate::routes::{
     health::{health_route, lb_heartbeat_route, log_check, status_route, version_route},
@@ -32,7 +44,7 @@ use crate::routes::{
         get_channels_route, new_channel_route, register_uaid_route, unregister_channel_route,
         unregister_user_route, update_token_route,
     },
-    webpush::{delete_notification_route, webpush_route},
+    webpush::{delete_notification_route, get_notification_route, webpush_route},
 };
 use crate::settings::Settings;
 
@@ -47,6 +59,7 @@ pub struct AppState {
     pub fcm_router: Arc<FcmRouter>,
     pub apns_router: Arc<ApnsRouter>,
     pub adm_router: Arc<AdmRouter>,
+    pub rate_limiter: Option<Arc<RateLimiter>>,
 }
 
 pub struct Server;
@@ -66,6 +79,8 @@ impl Server {
             } else {
                 settings.db_settings.clone()
             },
+            key_ring: settings.db_key_ring.clone(),
+            compress_data: settings.db_compress_data,
         };
         let db: Box<dyn DbClient> = match StorageType::from_dsn(&db_settings.dsn) {
             #[cfg(feature = "dynamodb")]
@@ -78,8 +93,31 @@ impl Server {
                 debug!("Using BigTable");
                 Box::new(BigTableClientImpl::new(metrics.clone(), &db_settings)?)
             }
-            #[cfg(all(feature = "bigtable", feature = "dual"))]
             StorageType::Dual => Box::new(DualClientImpl::new(metrics.clone(), &db_settings)?),
+            #[cfg(feature = "postgres")]
+            StorageType::Postgres => {
+                debug!("Using Postgres");
+                Box::new(PgClientImpl::new(metrics.clone(), &db_settings)?)
+            }
+            #[cfg(feature = "redis")]
+            StorageType::Redis => {
+                debug!("Using Redis");
+                Box::new(RedisClientImpl::new(metrics.clone(), &db_settings)?)
+            }
+            #[cfg(feature = "sqlite")]
+            StorageType::Sqlite => {
+                debug!("Using SQLite");
+                Box::new(SqliteClientImpl::new(metrics.clone(), &db_settings)?)
+            }
+            StorageType::Memory => {
+                debug!("Using Memory");
+                Box::new(MemoryClientImpl::new(metrics.clone(), &db_settings)?)
+            }
+            #[cfg(feature = "faulty")]
+            StorageType::Faulty => {
+                debug!("Using Faulty");
+                Box::new(FaultyDbClient::new(metrics.clone(), &db_settings)?)
+            }
             _ => {
                 debug!("No idea what {:?} is", &db_settings.dsn);
                 return Err(ApiErrorKind::General(
@@ -88,6 +126,20 @@ impl Server {
                 .into());
             }
         };
+        let db: Box<dyn DbClient> = if settings.db_operation_metrics {
+            Box::new(InstrumentedDbClient::new(db, metrics.clone()))
+        } else {
+            db
+        };
+        let db: Box<dyn DbClient> = match NonZeroUsize::new(settings.db_cache_max_entries) {
+            Some(max_entries) => Box::new(CachingDbClient::new(
+                db,
+                metrics.clone(),
+                max_entries,
+                Duration::from_millis(settings.db_cache_ttl_millis),
+            )),
+            None => db,
+        };
         let http = reqwest::ClientBuilder::new()
             .connect_timeout(Duration::from_millis(settings.connection_timeout_millis))
             .timeout(Duration::from_millis(settings.request_timeout_millis))
@@ -119,6 +171,7 @@ impl Server {
             metrics.clone(),
             db.clone(),
         )?);
+        let rate_limiter = RateLimiter::from_settings(&settings, metrics.clone()).map(Arc::new);
         let app_state = AppState {
             metrics: metrics.clone(),
             settings,
@@ -128,6 +181,7 @@ impl Server {
             fcm_router,
             apns_router,
             adm_router,
+            rate_limiter,
         };
 
         let server = HttpServer::new(move || {
@@ -163,6 +217,7 @@ impl Server {
                 )
                 .service(
                     web::resource("/m/{message_id}")
+                        .route(web::get().to(get_notification_route))
                         .route(web::delete().to(delete_notification_route)),
                 )
                 .service(
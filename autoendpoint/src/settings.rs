@@ -24,10 +24,38 @@ pub struct Settings {
     pub db_dsn: Option<String>,
     /// JSON set of specific database settings (See data storage engines)
     pub db_settings: String,
+    /// JSON key ring encrypting stored message payloads (See
+    /// `autopush_common::db::encryption`)
+    pub db_key_ring: String,
+    /// Compress stored message payloads
+    pub db_compress_data: bool,
+    /// Time each data store operation, reporting it via the
+    /// `database.operation` metric
+    pub db_operation_metrics: bool,
+    /// Max number of channel sets cached in memory. `0` disables the cache.
+    pub db_cache_max_entries: usize,
+    /// How long (in milliseconds) cached channel sets are used
+    pub db_cache_ttl_millis: u64,
+    /// Record each channel's last push time and push count (an additional
+    /// data store write per notification)
+    pub record_channel_pushes: bool,
 
     pub router_table_name: String,
     pub message_table_name: String,
 
+    /// Max burst of notifications from a single sender (identified by its
+    /// VAPID public key, or by the subscription without VAPID). `0` disables
+    /// the limit.
+    pub rate_limit_sender_burst: u32,
+    /// Rate (per second) a sender's burst allowance is refilled at
+    pub rate_limit_sender_per_second: f64,
+    /// Max burst of notifications to a single UAID. `0` disables the limit.
+    pub rate_limit_uaid_burst: u32,
+    /// Rate (per second) a UAID's burst allowance is refilled at
+    pub rate_limit_uaid_per_second: f64,
+    /// Max number of senders and UAIDs whose limits are tracked in memory
+    pub rate_limit_max_entries: usize,
+
     pub max_data_bytes: usize,
     pub crypto_keys: String,
     pub auth_keys: String,
@@ -54,8 +82,19 @@ impl Default for Settings {
             port: 8000,
             db_dsn: None,
             db_settings: "".to_owned(),
+            db_key_ring: "".to_owned(),
+            db_compress_data: false,
+            db_operation_metrics: false,
+            db_cache_max_entries: 0,
+            db_cache_ttl_millis: 5000,
+            record_channel_pushes: false,
             router_table_name: "router".to_string(),
             message_table_name: "message".to_string(),
+            rate_limit_sender_burst: 0,
+            rate_limit_sender_per_second: 10.0,
+            rate_limit_uaid_burst: 0,
+            rate_limit_uaid_per_second: 1.0,
+            rate_limit_max_entries: 100_000,
             // max data is a bit hard to figure out, due to encryption. Using something
             // like pywebpush, if you encode a block of 4096 bytes, you'll get a
             // 4216 byte data block. Since we're going to be receiving this, we have to
@@ -1,5 +1,10 @@
 pub fn main() {
-    if !(cfg!(feature = "dynamodb") || cfg!(feature = "bigtable")) {
-        panic!("No database defined! Please compile with either `features=dynamodb` or `features=bigtable`");
+    if !(cfg!(feature = "dynamodb")
+        || cfg!(feature = "bigtable")
+        || cfg!(feature = "postgres")
+        || cfg!(feature = "redis")
+        || cfg!(feature = "sqlite"))
+    {
+        panic!("No database defined! Please compile with either `features=dynamodb`, `features=bigtable`, `features=postgres`, `features=redis` or `features=sqlite`");
     }
 }
@@ -1,11 +1,12 @@
 use std::fmt::{self, Display};
+use std::str::FromStr;
 
 use backtrace::Backtrace;
 use thiserror::Error;
 
 use crate::errors::ReportableError;
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum MutateRowStatus {
     OK,
     Cancelled,
@@ -57,6 +58,34 @@ impl From<i32> for MutateRowStatus {
     }
 }
 
+/// Parse the canonical gRPC status code names (e.g. `UNAVAILABLE`)
+impl FromStr for MutateRowStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_uppercase().as_str() {
+            "OK" => Self::OK,
+            "CANCELLED" => Self::Cancelled,
+            "UNKNOWN" => Self::Unknown,
+            "INVALID_ARGUMENT" => Self::InvalidArgument,
+            "DEADLINE_EXCEEDED" => Self::DeadlineExceeded,
+            "NOT_FOUND" => Self::NotFound,
+            "ALREADY_EXISTS" => Self::AlreadyExists,
+            "PERMISSION_DENIED" => Self::PermissionDenied,
+            "RESOURCE_EXHAUSTED" => Self::ResourceExhausted,
+            "FAILED_PRECONDITION" => Self::FailedPrecondition,
+            "ABORTED" => Self::Aborted,
+            "OUT_OF_RANGE" => Self::OutOfRange,
+            "UNIMPLEMENTED" => Self::Unimplemented,
+            "INTERNAL" => Self::Internal,
+            "UNAVAILABLE" => Self::Unavailable,
+            "DATA_LOSS" => Self::DataLoss,
+            "UNAUTHENTICATED" => Self::Unauthenticated,
+            _ => return Err(format!("Unknown gRPC status code: {s}")),
+        })
+    }
+}
+
 impl Display for MutateRowStatus {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str(match self {
@@ -117,6 +146,25 @@ pub enum BigTableError {
     Pool(String),
 }
 
+impl BigTableError {
+    /// The gRPC status code the request failed with, if any
+    pub fn status_code(&self) -> Option<MutateRowStatus> {
+        match self {
+            BigTableError::InvalidRowResponse(e)
+            | BigTableError::Read(e)
+            | BigTableError::Write(e)
+            | BigTableError::GRPC(e) => match e {
+                grpcio::Error::RpcFailure(status) => {
+                    Some(MutateRowStatus::from(i32::from(status.code())))
+                }
+                _ => None,
+            },
+            BigTableError::Status(code, _) => Some(*code),
+            _ => None,
+        }
+    }
+}
+
 impl ReportableError for BigTableError {
     fn reportable_source(&self) -> Option<&(dyn ReportableError + 'static)> {
         None
@@ -6,7 +6,7 @@ use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 use async_trait::async_trait;
-use cadence::StatsdClient;
+use cadence::{Counted, StatsdClient};
 use futures_util::StreamExt;
 use google_cloud_rust_raw::bigtable::admin::v2::bigtable_table_admin::DropRowRangeRequest;
 use google_cloud_rust_raw::bigtable::admin::v2::bigtable_table_admin_grpc::BigtableTableAdminClient;
@@ -21,11 +21,17 @@ use uuid::Uuid;
 
 use crate::db::{
     client::{DbClient, FetchMessageResponse},
+    compression::{decompress_bytes, decompress_notification, Compressor},
+    encryption::KeyRing,
     error::{DbError, DbResult},
-    DbSettings, Notification, NotificationRecord, User, MAX_CHANNEL_TTL, MAX_ROUTER_TTL,
+    ChannelRecord, DbSettings, Notification, NotificationRecord, User, MAX_CHANNEL_TTL,
+    MAX_ROUTER_TTL,
 };
+use crate::notification::Urgency;
+use crate::util::sec_since_epoch;
 
 pub use self::metadata::MetadataBuilder;
+use self::retry::{retry_policy, retryable_error};
 use self::row::Row;
 use super::pool::BigTablePool;
 use super::BigTableDbSettings;
@@ -34,7 +40,9 @@ pub mod cell;
 pub mod error;
 pub(crate) mod merge;
 pub mod metadata;
+pub mod retry;
 pub mod row;
+pub mod schema;
 
 // these are normally Vec<u8>
 pub type RowKey = String;
@@ -50,6 +58,10 @@ const ROUTER_FAMILY: &str = "router";
 const MESSAGE_FAMILY: &str = "message"; // The default family for messages
 const MESSAGE_TOPIC_FAMILY: &str = "message_topic";
 
+/// How many times a channel's push is recorded before giving up to
+/// concurrent pushes
+const PUSH_RECORD_ATTEMPTS: usize = 3;
+
 /// Semi convenience wrapper to ensure that the UAID is formatted and displayed consistently.
 // TODO:Should we create something similar for ChannelID?
 struct Uaid(Uuid);
@@ -71,14 +83,20 @@ impl From<Uaid> for String {
 pub struct BigTableClientImpl {
     pub(crate) settings: BigTableDbSettings,
     /// Metrics client
-    _metrics: Arc<StatsdClient>,
+    metrics: Arc<StatsdClient>,
     /// Connection Channel (used for alternate calls)
     pool: BigTablePool,
     metadata: Metadata,
     admin_metadata: Metadata,
+    /// Encrypts stored message payloads
+    key_ring: KeyRing,
+    /// Compresses stored message payloads
+    compressor: Compressor,
 }
 
 /// Return a a RowFilter matching the GC policy of the router Column Family
+///
+/// (Must correspond with [schema]'s GC rule for the family)
 fn router_gc_policy_filter() -> data::RowFilter {
     let mut latest_cell_filter = data::RowFilter::default();
     latest_cell_filter.set_cells_per_column_limit_filter(1);
@@ -87,6 +105,8 @@ fn router_gc_policy_filter() -> data::RowFilter {
 
 /// Return a chain of RowFilters matching the GC policy of the message Column
 /// Families
+///
+/// (Must correspond with [schema]'s GC rule for the families)
 fn message_gc_policy_filter() -> Result<Vec<data::RowFilter>, error::BigTableError> {
     let mut timestamp_filter = data::RowFilter::default();
     let bt_now: i64 = SystemTime::now()
@@ -184,6 +204,51 @@ fn to_string(value: Vec<u8>, name: &str) -> Result<String, DbError> {
     })
 }
 
+/// Return the cells of a set of channels (see [BigTableClientImpl::add_channels])
+fn channels_to_cells(channels: Vec<ChannelRecord>) -> DbResult<Vec<cell::Cell>> {
+    let expiry = std::time::SystemTime::now() + Duration::from_secs(MAX_CHANNEL_TTL);
+    let mut cells = Vec::with_capacity(channels.len().min(100_000));
+    for (i, channel) in channels.into_iter().enumerate() {
+        // There is a limit of 100,000 mutations per batch for bigtable.
+        // https://cloud.google.com/bigtable/quotas
+        // If you have 100,000 channels, you have too many.
+        if i >= 100_000 {
+            break;
+        }
+        cells.push(cell::Cell {
+            qualifier: format!("chid:{}", channel.channel_id.as_hyphenated()),
+            value: serde_json::to_vec(&channel).map_err(|e| {
+                DbError::Serialization(format!("Could not serialize channel: {e:?}"))
+            })?,
+            timestamp: expiry,
+            ..Default::default()
+        });
+    }
+    Ok(cells)
+}
+
+/// Return the channel_id held by a channel cell's qualifier
+fn cell_to_channel_id(cell: &cell::Cell) -> DbResult<Uuid> {
+    let Some((_, chid)) = cell.qualifier.split_once("chid:") else {
+        return Err(DbError::Integrity(
+            "get_channels expected: chid:<chid>".to_owned(),
+        ));
+    };
+    Uuid::from_str(chid).map_err(|e| DbError::General(e.to_string()))
+}
+
+/// Convert a channel cell into a [ChannelRecord]
+fn cell_to_channel(cell: cell::Cell) -> DbResult<ChannelRecord> {
+    let channel_id = cell_to_channel_id(&cell)?;
+    // Channels added before their metadata was recorded hold no (or a
+    // single 0 byte) value
+    if cell.value.is_empty() || cell.value == [0] {
+        return Ok(ChannelRecord::from(channel_id));
+    }
+    serde_json::from_slice(&cell.value)
+        .map_err(|e| DbError::Serialization(format!("Could not deserialize channel: {e:?}")))
+}
+
 fn call_opts(metadata: Metadata) -> ::grpcio::CallOption {
     ::grpcio::CallOption::default().headers(metadata)
 }
@@ -218,20 +283,90 @@ impl BigTableClientImpl {
         debug!("🏊 BT Pool new");
         let db_settings = BigTableDbSettings::try_from(settings.db_settings.as_ref())?;
         info!("🉑 {:#?}", db_settings);
+        let key_ring = KeyRing::try_from(settings.key_ring.as_ref())?;
         let pool = BigTablePool::new(settings, &metrics)?;
 
         // create the metadata header blocks required by Google for accessing GRPC resources.
         let metadata = db_settings.metadata()?;
         let admin_metadata = db_settings.admin_metadata()?;
+        if db_settings.create_if_missing || db_settings.validate_schema {
+            let admin = BigtableTableAdminClient::new(pool.get_channel()?);
+            schema::ensure_schema(&admin, &db_settings, admin_metadata.clone())?;
+        }
+        let compressor = Compressor::new(metrics.clone(), settings);
         Ok(Self {
             settings: db_settings,
-            _metrics: metrics,
+            metrics,
             metadata,
             admin_metadata,
             pool,
+            key_ring,
+            compressor,
         })
     }
 
+    /// Read the (ordered) keys of the rows beginning with the UAID `prefix`
+    /// that match the `filter`, only reading enough of each row to know it
+    /// exists.
+    async fn read_row_keys(&self, prefix: &str, filter: RowFilter) -> DbResult<Vec<RowKey>> {
+        let mut req = ReadRowsRequest::default();
+        req.set_table_name(self.settings.table_name.clone());
+
+        // Row keys are either a bare (hex) UAID or a UAID followed by `#`, so
+        // every row beginning with `prefix` sorts before `prefix` + "g".
+        let mut rows = data::RowSet::default();
+        let mut row_range = data::RowRange::default();
+        row_range.set_start_key_closed(prefix.as_bytes().to_vec());
+        row_range.set_end_key_open(format!("{prefix}g").into_bytes());
+        let mut row_ranges = RepeatedField::default();
+        row_ranges.push(row_range);
+        rows.set_row_ranges(row_ranges);
+        req.set_rows(rows);
+
+        let mut limit_filter = RowFilter::default();
+        limit_filter.set_cells_per_row_limit_filter(1);
+        let mut strip_filter = RowFilter::default();
+        strip_filter.set_strip_value_transformer(true);
+        req.set_filter(filter_chain(vec![filter, limit_filter, strip_filter]));
+
+        Ok(self.read_rows(req).await?.into_keys().collect())
+    }
+
+    /// Read the latest cells of the user's channels whose column qualifiers
+    /// match the regex
+    async fn read_channel_cells(
+        &self,
+        uaid: &Uuid,
+        qualifier_regex: &str,
+    ) -> DbResult<Vec<cell::Cell>> {
+        let row_key = uaid.simple().to_string();
+        let mut req = self.read_row_request(&row_key);
+
+        let mut family_filter = data::RowFilter::default();
+        family_filter.set_family_name_regex_filter(format!("^{ROUTER_FAMILY}$"));
+
+        let mut cq_filter = data::RowFilter::default();
+        cq_filter.set_column_qualifier_regex_filter(qualifier_regex.as_bytes().to_vec());
+
+        req.set_filter(filter_chain(vec![
+            router_gc_policy_filter(),
+            family_filter,
+            cq_filter,
+        ]));
+
+        let mut rows = self.read_rows(req).await?;
+        Ok(rows
+            .remove(&row_key)
+            .map(|record| {
+                record
+                    .cells
+                    .into_values()
+                    .filter_map(|mut cells| cells.pop())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
     /// Return a ReadRowsRequest for a given row key
     fn read_row_request(&self, row_key: &str) -> bigtable::ReadRowsRequest {
         read_row_request(&self.settings.table_name, row_key)
@@ -253,32 +388,56 @@ impl BigTableClientImpl {
         req
     }
 
-    /// Read a given row from the row key.
+    /// Perform a MutateRowRequest, retrying transient failures.
+    ///
+    /// Our mutations specify explicit timestamps (or delete), so are
+    /// idempotent.
     async fn mutate_row(
         &self,
         req: bigtable::MutateRowRequest,
     ) -> Result<(), error::BigTableError> {
-        let bigtable = self.pool.get().await?;
-        bigtable
-            .conn
-            .mutate_row_async_opt(&req, call_opts(self.metadata.clone()))
-            .map_err(error::BigTableError::Write)?
+        retry_policy(&self.settings.retry)
+            .retry_if(
+                || async {
+                    let bigtable = self.pool.get().await?;
+                    bigtable
+                        .conn
+                        .mutate_row_async_opt(&req, call_opts(self.metadata.clone()))
+                        .map_err(error::BigTableError::Write)?
+                        .await
+                        .map_err(error::BigTableError::Write)?;
+                    Ok::<_, error::BigTableError>(())
+                },
+                retryable_error(self.metrics.clone(), &self.settings.retry, "mutate_row"),
+            )
             .await
-            .map_err(error::BigTableError::Write)?;
-        Ok(())
     }
 
-    /// Perform a MutateRowsRequest
-    #[allow(unused)]
+    /// Perform a MutateRowsRequest, retrying transient failures.
+    ///
+    /// A failure of any entry fails (and retries) the entire request: like
+    /// [Self::mutate_row], the mutations are idempotent.
     async fn mutate_rows(
         &self,
         req: bigtable::MutateRowsRequest,
+    ) -> Result<(), error::BigTableError> {
+        retry_policy(&self.settings.retry)
+            .retry_if(
+                || self.mutate_rows_once(&req),
+                retryable_error(self.metrics.clone(), &self.settings.retry, "mutate_rows"),
+            )
+            .await
+    }
+
+    async fn mutate_rows_once(
+        &self,
+        req: &bigtable::MutateRowsRequest,
     ) -> Result<(), error::BigTableError> {
         let bigtable = self.pool.get().await?;
         // ClientSStreamReceiver will cancel an operation if it's dropped before it's done.
         let resp = bigtable
             .conn
-            .mutate_rows_opt(&req, call_opts(self.metadata.clone()))
+            .mutate_rows_opt(req, call_opts(self.metadata.clone()))
             .map_err(error::BigTableError::Write)?;
 
         // Scan the returned stream looking for errors.
@@ -334,16 +493,24 @@ impl BigTableClientImpl {
     /// Take a big table ReadRowsRequest (containing the keys and filters) and return a set of row data indexed by row key.
     ///
     ///
+    /// Transient failures are retried (re-reading all of the rows).
     async fn read_rows(
         &self,
         req: ReadRowsRequest,
     ) -> Result<BTreeMap<RowKey, row::Row>, error::BigTableError> {
-        let bigtable = self.pool.get().await?;
-        let resp = bigtable
-            .conn
-            .read_rows_opt(&req, call_opts(self.metadata.clone()))
-            .map_err(error::BigTableError::Read)?;
-        merge::RowMerger::process_chunks(resp).await
+        retry_policy(&self.settings.retry)
+            .retry_if(
+                || async {
+                    let bigtable = self.pool.get().await?;
+                    let resp = bigtable
+                        .conn
+                        .read_rows_opt(&req, call_opts(self.metadata.clone()))
+                        .map_err(error::BigTableError::Read)?;
+                    merge::RowMerger::process_chunks(resp).await
+                },
+                retryable_error(self.metrics.clone(), &self.settings.retry, "read_rows"),
+            )
+            .await
     }
 
     /// write a given row.
@@ -413,6 +580,9 @@ impl BigTableClientImpl {
         self.check_and_mutate(req).await
     }
 
+    /// Perform a CheckAndMutateRowRequest.
+    ///
+    /// Not retried, as conditional mutations are not idempotent.
     async fn check_and_mutate(
         &self,
         req: bigtable::CheckAndMutateRowRequest,
@@ -525,11 +695,13 @@ impl BigTableClientImpl {
     }
 
     fn row_to_notification(&self, row_key: &str, mut row: Row) -> Result<Notification, DbError> {
-        let Some((_, chidmessageid)) = row_key.split_once('#') else {
+        let Some((uaid, chidmessageid)) = row_key.split_once('#') else {
             return Err(DbError::Integrity(
                 "rows_to_notification expected row_key: uaid:chidmessageid ".to_owned(),
             ));
         };
+        let uaid = Uuid::parse_str(uaid)
+            .map_err(|e| DbError::Integrity(format!("rows_to_notification expected uaid: {e}")))?;
         let range_key = NotificationRecord::parse_chidmessageid(chidmessageid).map_err(|e| {
             DbError::Integrity(format!("rows_to_notification expected chidmessageid: {e}"))
         })?;
@@ -545,7 +717,7 @@ impl BigTableClientImpl {
         };
 
         if let Some(cell) = row.take_cell("data") {
-            notif.data = Some(to_string(cell.value, "data")?);
+            notif.data = Some(decompress_bytes(cell.value)?);
         }
         if let Some(cell) = row.take_cell("headers") {
             notif.headers = Some(
@@ -553,6 +725,16 @@ impl BigTableClientImpl {
                     .map_err(|e| DbError::Serialization(e.to_string()))?,
             );
         }
+        if let Some(cell) = row.take_cell("urgency") {
+            notif.urgency = to_string(cell.value, "urgency")?
+                .parse()
+                .map_err(DbError::Integrity)?;
+        }
+        if let Some(cell) = row.take_cell("receipt") {
+            notif.receipt = Some(to_string(cell.value, "receipt")?);
+        }
+        self.key_ring.open_notification(&uaid, &mut notif)?;
+        decompress_notification(&mut notif)?;
 
         trace!("🚣  Deserialized message row: {:?}", &notif);
         Ok(notif)
@@ -671,13 +853,27 @@ impl BigtableDb {
 impl DbClient for BigTableClientImpl {
     /// add user to the database
     async fn add_user(&self, user: &User) -> DbResult<()> {
+        self.add_user_with_channels(user, Vec::new()).await
+    }
+
+    /// The user and their channels share a row, so are written in a single
+    /// mutation batch
+    async fn add_user_with_channels(
+        &self,
+        user: &User,
+        channels: Vec<ChannelRecord>,
+    ) -> DbResult<()> {
         trace!("🉑 Adding user");
         let Some(ref version) = user.version else {
             return Err(DbError::General(
                 "add_user expected a user version field".to_owned(),
             ));
         };
-        let row = self.user_to_row(user, version);
+        let mut row = self.user_to_row(user, version);
+        row.cells
+            .entry(ROUTER_FAMILY.to_owned())
+            .or_default()
+            .extend(channels_to_cells(channels)?);
 
         // Only add when the user doesn't already exist
         let mut row_key_filter = RowFilter::default();
@@ -765,14 +961,59 @@ impl DbClient for BigTableClientImpl {
         Ok(())
     }
 
-    async fn add_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
-        let channels = HashSet::from_iter([channel_id.to_owned()]);
-        self.add_channels(uaid, channels).await
+    async fn list_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        // Only the user rows
+        let mut row_key_filter = RowFilter::default();
+        row_key_filter.set_row_key_regex_filter(b"^[0-9a-f]{32}$".to_vec());
+        self.read_row_keys(prefix, row_key_filter)
+            .await?
+            .iter()
+            .map(|row_key| {
+                Uuid::parse_str(row_key).map_err(|e| DbError::Serialization(e.to_string()))
+            })
+            .collect()
+    }
+
+    async fn list_orphan_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        // The user rows holding a user record, rather than only channels
+        let mut row_key_filter = RowFilter::default();
+        row_key_filter.set_row_key_regex_filter(b"^[0-9a-f]{32}$".to_vec());
+        let mut family_filter = RowFilter::default();
+        family_filter.set_family_name_regex_filter(format!("^{ROUTER_FAMILY}$"));
+        let mut cq_filter = RowFilter::default();
+        cq_filter.set_column_qualifier_regex_filter(b"^connected_at$".to_vec());
+        let users: HashSet<String> = self
+            .read_row_keys(
+                prefix,
+                filter_chain(vec![row_key_filter, family_filter, cq_filter]),
+            )
+            .await?
+            .into_iter()
+            .collect();
+
+        // Every user and message row, whose keys begin with the UAID
+        let mut all_filter = RowFilter::default();
+        all_filter.set_pass_all_filter(true);
+        let mut uaids = Vec::new();
+        for row_key in self.read_row_keys(prefix, all_filter).await? {
+            let uaid = row_key.split('#').next().unwrap_or_default();
+            if !users.contains(uaid) {
+                uaids.push(
+                    Uuid::parse_str(uaid).map_err(|e| DbError::Serialization(e.to_string()))?,
+                );
+            }
+        }
+        uaids.dedup();
+        Ok(uaids)
+    }
+
+    async fn add_channel(&self, uaid: &Uuid, channel: &ChannelRecord) -> DbResult<()> {
+        self.add_channels(uaid, vec![channel.clone()]).await
     }
 
     /// Add channels in bulk (used mostly during migration)
     ///
-    async fn add_channels(&self, uaid: &Uuid, channels: HashSet<Uuid>) -> DbResult<()> {
+    async fn add_channels(&self, uaid: &Uuid, channels: Vec<ChannelRecord>) -> DbResult<()> {
         // channel_ids are stored as a set within one Bigtable row
         //
         // Bigtable allows "millions of columns in a table, as long as no row
@@ -781,71 +1022,76 @@ impl DbClient for BigTableClientImpl {
         //
         // The "set" of channel_ids consists of column qualifiers named
         // "chid:<chid value>" as set member entries (with their cell values
-        // being a single 0 byte).
+        // being the JSON encoded [ChannelRecord], or empty/a single 0 byte
+        // for channels written before their metadata was recorded).
         //
         // Storing the full set in a single row makes batch updates
         // (particularly to reset the GC expiry timestamps) potentially more
         // easy/efficient
         let row_key = uaid.simple().to_string();
         let mut row = Row::new(row_key);
-        let expiry = std::time::SystemTime::now() + Duration::from_secs(MAX_CHANNEL_TTL);
-
-        let mut cells = Vec::with_capacity(channels.len().min(100_000));
-        for (i, channel_id) in channels.into_iter().enumerate() {
-            // There is a limit of 100,000 mutations per batch for bigtable.
-            // https://cloud.google.com/bigtable/quotas
-            // If you have 100,000 channels, you have too many.
-            if i >= 100_000 {
-                break;
-            }
-            cells.push(cell::Cell {
-                qualifier: format!("chid:{}", channel_id.as_hyphenated()),
-                timestamp: expiry,
-                ..Default::default()
-            });
-        }
         // Note: updating the version column isn't necessary here because this
-        // write only adds a new (or updates an existing) column with a 0 byte
-        // value
-        row.add_cells(ROUTER_FAMILY, cells);
+        // write only adds new (or updates existing) channel columns
+        row.add_cells(ROUTER_FAMILY, channels_to_cells(channels)?);
 
         self.write_row(row).await?;
         Ok(())
     }
 
     async fn get_channels(&self, uaid: &Uuid) -> DbResult<HashSet<Uuid>> {
-        let row_key = uaid.simple().to_string();
-        let mut req = self.read_row_request(&row_key);
-
-        let mut family_filter = data::RowFilter::default();
-        family_filter.set_family_name_regex_filter(format!("^{ROUTER_FAMILY}$"));
-
-        let mut cq_filter = data::RowFilter::default();
-        cq_filter.set_column_qualifier_regex_filter("^chid:.*$".as_bytes().to_vec());
+        self.read_channel_cells(uaid, "^chid:.*$")
+            .await?
+            .iter()
+            .map(cell_to_channel_id)
+            .collect()
+    }
 
-        req.set_filter(filter_chain(vec![
-            router_gc_policy_filter(),
-            family_filter,
-            cq_filter,
-        ]));
+    async fn get_channel_records(&self, uaid: &Uuid) -> DbResult<Vec<ChannelRecord>> {
+        self.read_channel_cells(uaid, "^chid:.*$")
+            .await?
+            .into_iter()
+            .map(cell_to_channel)
+            .collect()
+    }
 
-        let mut rows = self.read_rows(req).await?;
-        let mut result = HashSet::new();
-        if let Some(record) = rows.remove(&row_key) {
-            for mut cells in record.cells.into_values() {
-                let Some(cell) = cells.pop() else {
-                    continue;
-                };
-                let Some((_, chid)) = cell.qualifier.split_once("chid:") else {
-                    return Err(DbError::Integrity(
-                        "get_channels expected: chid:<chid>".to_owned(),
-                    ));
-                };
-                result.insert(Uuid::from_str(chid).map_err(|e| DbError::General(e.to_string()))?);
+    /// The channel's cell is only rewritten if it's unchanged since it was
+    /// read, so a removed channel isn't recreated. Concurrent pushes are
+    /// retried, so are only undercounted after `PUSH_RECORD_ATTEMPTS`.
+    async fn record_channel_push(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
+        let row_key = uaid.simple().to_string();
+        let qualifier = format!("chid:{}", channel_id.as_hyphenated());
+        let qualifier_regex = format!("^{qualifier}$");
+        for _ in 0..PUSH_RECORD_ATTEMPTS {
+            let Some(cell) = self.read_channel_cells(uaid, &qualifier_regex).await?.pop() else {
+                return Ok(());
+            };
+            // Only matches the cell as it was read
+            let mut family_filter = data::RowFilter::default();
+            family_filter.set_family_name_regex_filter(format!("^{ROUTER_FAMILY}$"));
+            let mut cq_filter = data::RowFilter::default();
+            cq_filter.set_column_qualifier_regex_filter(qualifier_regex.as_bytes().to_vec());
+            let mut value_filter = data::RowFilter::default();
+            let mut value_regex = b"^".to_vec();
+            value_regex.extend(escape_bytes(&cell.value));
+            value_regex.push(b'$');
+            value_filter.set_value_regex_filter(value_regex);
+            let filter = filter_chain(vec![
+                router_gc_policy_filter(),
+                family_filter,
+                cq_filter,
+                value_filter,
+            ]);
+
+            let mut channel = cell_to_channel(cell)?;
+            channel.record_push(sec_since_epoch());
+            let mut row = Row::new(row_key.clone());
+            row.add_cells(ROUTER_FAMILY, channels_to_cells(vec![channel])?);
+            if self.check_and_mutate_row(row, filter, true).await? {
+                return Ok(());
             }
         }
-
-        Ok(result)
+        debug!("🉑 Gave up recording a push to {qualifier} of {row_key}");
+        Ok(())
     }
 
     /// Delete the channel. Does not delete its associated pending messages.
@@ -895,7 +1141,16 @@ impl DbClient for BigTableClientImpl {
     }
 
     /// Write the notification to storage.
-    async fn save_message(&self, uaid: &Uuid, message: Notification) -> DbResult<()> {
+    async fn save_message(&self, uaid: &Uuid, mut message: Notification) -> DbResult<()> {
+        self.settings
+            .quota
+            .enforce(self, &self.metrics, uaid, std::slice::from_ref(&message))
+            .await?;
+        if self.key_ring.is_active() {
+            // Encrypted data is incompressible, so compress it beforehand
+            self.compressor.compress_notification(&mut message);
+            self.key_ring.seal_notification(uaid, &mut message)?;
+        }
         let row_key = format!("{}#{}", uaid.simple(), message.chidmessageid());
         debug!("🗄️ Saving message {} :: {:?}", &row_key, &message);
         trace!(
@@ -952,10 +1207,27 @@ impl DbClient for BigTableClientImpl {
                 });
             }
         }
+        if message.urgency != Urgency::Normal {
+            cells.push(cell::Cell {
+                qualifier: "urgency".to_owned(),
+                value: message.urgency.as_str().as_bytes().to_vec(),
+                timestamp: expiry,
+                ..Default::default()
+            });
+        }
+        if let Some(receipt) = message.receipt {
+            cells.push(cell::Cell {
+                qualifier: "receipt".to_owned(),
+                value: receipt.into_bytes(),
+                timestamp: expiry,
+                ..Default::default()
+            });
+        }
         if let Some(data) = message.data {
             cells.push(cell::Cell {
                 qualifier: "data".to_owned(),
-                value: data.into_bytes(),
+                // A no-op for data compressed (and encrypted) above
+                value: self.compressor.compress_bytes(data),
                 timestamp: expiry,
                 ..Default::default()
             });
@@ -1014,6 +1286,20 @@ impl DbClient for BigTableClientImpl {
         self.write_row(row).await.map_err(|e| e.into())
     }
 
+    async fn get_message(
+        &self,
+        uaid: &Uuid,
+        chidmessageid: &str,
+    ) -> DbResult<Option<Notification>> {
+        let row_key = format!("{}#{}", uaid.simple(), chidmessageid);
+        let mut req = self.read_row_request(&row_key);
+        req.set_filter(filter_chain(message_gc_policy_filter()?));
+        let mut rows = self.read_rows(req).await?;
+        rows.remove(&row_key)
+            .map(|row| self.row_to_notification(&row_key, row))
+            .transpose()
+    }
+
     /// Delete the notification from storage.
     async fn remove_message(&self, uaid: &Uuid, chidmessageid: &str) -> DbResult<()> {
         trace!(
@@ -1026,6 +1312,34 @@ impl DbClient for BigTableClientImpl {
         self.delete_row(&row_key).await.map_err(|e| e.into())
     }
 
+    /// Delete the messages in a single MutateRows request
+    async fn remove_messages(&self, uaid: &Uuid, chidmessageids: &[String]) -> DbResult<()> {
+        if chidmessageids.is_empty() {
+            return Ok(());
+        }
+        let mut entries = protobuf::RepeatedField::default();
+        for chidmessageid in chidmessageids {
+            let row_key = format!("{}#{}", uaid.simple(), chidmessageid);
+            debug!("🉑🔥 Deleting message {}", &row_key);
+            let mut mutations = protobuf::RepeatedField::default();
+            let mut mutation = data::Mutation::default();
+            mutation.set_delete_from_row(data::Mutation_DeleteFromRow::default());
+            mutations.push(mutation);
+            let mut entry = bigtable::MutateRowsRequest_Entry::default();
+            entry.set_row_key(row_key.into_bytes());
+            entry.set_mutations(mutations);
+            entries.push(entry);
+        }
+        let mut req = bigtable::MutateRowsRequest::default();
+        req.set_table_name(self.settings.table_name.clone());
+        req.set_entries(entries);
+        self.mutate_rows(req).await?;
+        self.metrics
+            .count_with_tags("notification.message.deleted", chidmessageids.len() as i64)
+            .send();
+        Ok(())
+    }
+
     /// Return `limit` pending messages from storage. `limit=0` for all messages.
     async fn fetch_topic_messages(
         &self,
@@ -1133,6 +1447,71 @@ impl DbClient for BigTableClientImpl {
         })
     }
 
+    /// Read only the messages' `timestamp` cells (skipping their `data`),
+    /// fetching both the topic and unread timestamp messages in one request
+    async fn fetch_quota_messages(&self, uaid: &Uuid) -> DbResult<Vec<Notification>> {
+        let current_timestamp = self
+            .get_user(uaid)
+            .await?
+            .and_then(|user| user.current_timestamp);
+
+        let mut req = ReadRowsRequest::default();
+        req.set_table_name(self.settings.table_name.clone());
+
+        let timestamp_start = if let Some(ts) = current_timestamp {
+            format!("{}#02:{}z", uaid.simple(), ts)
+        } else {
+            format!("{}#02:", uaid.simple())
+        };
+        let mut row_ranges = RepeatedField::default();
+        for (start_key, end_key) in [
+            (
+                format!("{}#01:", uaid.simple()),
+                format!("{}#02:", uaid.simple()),
+            ),
+            (timestamp_start, format!("{}#03:", uaid.simple())),
+        ] {
+            let mut row_range = data::RowRange::default();
+            row_range.set_start_key_open(start_key.into_bytes());
+            row_range.set_end_key_open(end_key.into_bytes());
+            row_ranges.push(row_range);
+        }
+        let mut rows = data::RowSet::default();
+        rows.set_row_ranges(row_ranges);
+        req.set_rows(rows);
+
+        let mut cq_filter = data::RowFilter::default();
+        cq_filter.set_column_qualifier_regex_filter(b"^timestamp$".to_vec());
+        let mut filters = message_gc_policy_filter()?;
+        filters.push(cq_filter);
+        req.set_filter(filter_chain(filters));
+
+        let rows = self.read_rows(req).await?;
+        debug!("🉑 Fetch Quota Messages. Found {} row(s)", rows.len());
+        rows.into_iter()
+            .map(|(row_key, mut row)| {
+                let Some((_, chidmessageid)) = row_key.split_once('#') else {
+                    return Err(DbError::Integrity(
+                        "fetch_quota_messages expected row_key: uaid:chidmessageid".to_owned(),
+                    ));
+                };
+                let range_key =
+                    NotificationRecord::parse_chidmessageid(chidmessageid).map_err(|e| {
+                        DbError::Integrity(format!(
+                            "fetch_quota_messages expected chidmessageid: {e}"
+                        ))
+                    })?;
+                Ok(Notification {
+                    channel_id: range_key.channel_id,
+                    topic: range_key.topic,
+                    sortkey_timestamp: range_key.sortkey_timestamp,
+                    timestamp: to_u64(row.take_required_cell("timestamp")?.value, "timestamp")?,
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
     async fn health_check(&self) -> DbResult<bool> {
         self.pool
             .get()
@@ -1208,6 +1587,7 @@ mod tests {
             dsn: Some(env_dsn),
             db_settings: json!({"table_name": "projects/test/instances/test/tables/autopush"})
                 .to_string(),
+            ..Default::default()
         };
 
         let metrics = Arc::new(StatsdClient::builder("", cadence::NopMetricSink).build());
@@ -1281,7 +1661,9 @@ mod tests {
         let connected_at = ms_since_epoch();
 
         // can we add channels?
-        client.add_channel(&uaid, &chid).await?;
+        client
+            .add_channel(&uaid, &ChannelRecord::from(chid))
+            .await?;
         let channels = client.get_channels(&uaid).await?;
         assert!(channels.contains(&chid));
 
@@ -1293,7 +1675,12 @@ mod tests {
         }
         let chid_to_remove = uuid::Uuid::new_v4();
         new_channels.insert(chid_to_remove);
-        client.add_channels(&uaid, new_channels.clone()).await?;
+        client
+            .add_channels(
+                &uaid,
+                new_channels.iter().copied().map(Into::into).collect(),
+            )
+            .await?;
         let channels = client.get_channels(&uaid).await?;
         assert_eq!(channels, new_channels);
 
@@ -1507,4 +1894,77 @@ mod tests {
 
         client.remove_user(&uaid).await.unwrap();
     }
+
+    #[actix_rt::test]
+    async fn record_channel_push() -> DbResult<()> {
+        let client = new_client().unwrap();
+        let uaid = gen_test_uaid();
+        let chid = Uuid::new_v4();
+        client.remove_user(&uaid).await?;
+
+        client
+            .add_channel(&uaid, &ChannelRecord::from(chid))
+            .await?;
+        client.record_channel_push(&uaid, &chid).await?;
+        client.record_channel_push(&uaid, &chid).await?;
+        let records = client.get_channel_records(&uaid).await?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].push_count, 2);
+        assert!(records[0].last_push_at.is_some());
+
+        // A push after unsubscribing doesn't bring the channel back
+        client.remove_channel(&uaid, &chid).await?;
+        client.record_channel_push(&uaid, &chid).await?;
+        assert!(!client.get_channels(&uaid).await?.contains(&chid));
+
+        client.remove_user(&uaid).await
+    }
+
+    #[actix_rt::test]
+    async fn fetch_quota_messages() -> DbResult<()> {
+        let client = new_client().unwrap();
+        let uaid = gen_test_uaid();
+        let chid = Uuid::new_v4();
+        client.remove_user(&uaid).await?;
+        client
+            .add_user(&User {
+                uaid,
+                ..Default::default()
+            })
+            .await?;
+
+        let sortkey = ms_since_epoch();
+        let notif = |topic: Option<&str>, sortkey_timestamp: Option<u64>| Notification {
+            channel_id: chid,
+            version: Uuid::new_v4().simple().to_string(),
+            ttl: 300,
+            topic: topic.map(str::to_owned),
+            timestamp: now(),
+            sortkey_timestamp,
+            data: Some("payload".to_owned()),
+            ..Default::default()
+        };
+        client
+            .save_messages(
+                &uaid,
+                vec![
+                    notif(Some("topic"), None),
+                    notif(None, Some(sortkey)),
+                    notif(None, Some(sortkey + 1)),
+                ],
+            )
+            .await?;
+        client.increment_storage(&uaid, sortkey).await?;
+
+        // The read timestamp message isn't counted, nor is any data read
+        let messages = client.fetch_quota_messages(&uaid).await?;
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().any(|m| m.topic.as_deref() == Some("topic")));
+        assert!(messages
+            .iter()
+            .any(|m| m.sortkey_timestamp == Some(sortkey + 1)));
+        assert!(messages.iter().all(|m| m.data.is_none()));
+
+        client.remove_user(&uaid).await
+    }
 }
@@ -0,0 +1,38 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use again::RetryPolicy;
+use cadence::{CountedExt, StatsdClient};
+
+use super::error::BigTableError;
+use crate::db::bigtable::RetrySettings;
+
+/// Build the exponential retry policy described by the settings
+pub fn retry_policy(settings: &RetrySettings) -> RetryPolicy {
+    RetryPolicy::exponential(Duration::from_millis(settings.initial_backoff_ms))
+        .with_max_delay(Duration::from_millis(settings.max_backoff_ms))
+        .with_max_retries(settings.max_attempts.saturating_sub(1))
+        .with_jitter(settings.jitter)
+}
+
+/// Create a retry function for the given operation, retrying the errors
+/// carrying one of the configured gRPC status codes
+pub fn retryable_error(
+    metrics: Arc<StatsdClient>,
+    settings: &RetrySettings,
+    operation: &'static str,
+) -> impl Fn(&BigTableError) -> bool {
+    let retryable_codes = settings.retryable_codes.clone();
+    move |err| match err.status_code() {
+        Some(code) if retryable_codes.contains(&code) => {
+            debug!("🉑 retryable {} {:?}", operation, &err);
+            metrics
+                .incr_with_tags("database.retry")
+                .with_tag("error", operation)
+                .with_tag("code", &code.to_string())
+                .send();
+            true
+        }
+        _ => false,
+    }
+}
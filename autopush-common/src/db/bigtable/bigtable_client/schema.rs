@@ -0,0 +1,234 @@
+//! Creation and validation of the Bigtable table and its column families.
+//!
+//! This replaces the need to run `scripts/setup_bt.sh` (when
+//! `create_if_missing` is set) and guards against running against a table
+//! whose garbage collection policies don't match what the client's read
+//! filters expect (when `validate_schema` is set).
+use std::collections::HashMap;
+
+use google_cloud_rust_raw::bigtable::admin::v2::bigtable_table_admin::{
+    CreateTableRequest, GetTableRequest, ModifyColumnFamiliesRequest,
+    ModifyColumnFamiliesRequest_Modification,
+};
+use google_cloud_rust_raw::bigtable::admin::v2::bigtable_table_admin_grpc::BigtableTableAdminClient;
+use google_cloud_rust_raw::bigtable::admin::v2::table::{
+    ColumnFamily, GcRule, GcRule_Union, Table, Table_View,
+};
+use grpcio::{Metadata, RpcStatusCode};
+use protobuf::RepeatedField;
+
+use super::error::BigTableError;
+use super::{call_opts, MESSAGE_FAMILY, MESSAGE_TOPIC_FAMILY, ROUTER_FAMILY};
+use crate::db::bigtable::BigTableDbSettings;
+
+/// The GC rule of the router family: only the latest version of a cell is
+/// kept (see `router_gc_policy_filter`)
+fn router_gc_rule() -> GcRule {
+    let mut rule = GcRule::default();
+    rule.set_max_num_versions(1);
+    rule
+}
+
+/// The GC rule of the message families: only the latest version of a cell
+/// is kept, and cells are removed once their timestamp (their expiry) has
+/// passed (see `message_gc_policy_filter`)
+fn message_gc_rule() -> GcRule {
+    let mut max_age = protobuf::well_known_types::Duration::default();
+    max_age.set_seconds(1);
+    let mut age_rule = GcRule::default();
+    age_rule.set_max_age(max_age);
+
+    let mut union = GcRule_Union::default();
+    union.set_rules(RepeatedField::from_vec(vec![age_rule, router_gc_rule()]));
+    let mut rule = GcRule::default();
+    rule.set_union(union);
+    rule
+}
+
+/// The column families required, along with their GC rules
+fn expected_families() -> Vec<(&'static str, GcRule)> {
+    vec![
+        (ROUTER_FAMILY, router_gc_rule()),
+        (MESSAGE_FAMILY, message_gc_rule()),
+        (MESSAGE_TOPIC_FAMILY, message_gc_rule()),
+    ]
+}
+
+/// Describe a GC rule in the `cbt setgcpolicy` syntax, ordering the members
+/// of unions and intersections so that equivalent rules are described
+/// identically.
+fn describe_gc_rule(rule: &GcRule) -> String {
+    let join = |rules: &[GcRule], op: &str| {
+        let mut parts: Vec<String> = rules.iter().map(describe_gc_rule).collect();
+        parts.sort();
+        format!("({})", parts.join(op))
+    };
+    if rule.has_max_num_versions() {
+        format!("maxversions={}", rule.get_max_num_versions())
+    } else if rule.has_max_age() {
+        let age = rule.get_max_age();
+        if age.get_nanos() == 0 {
+            format!("maxage={}s", age.get_seconds())
+        } else {
+            format!("maxage={}.{:09}s", age.get_seconds(), age.get_nanos())
+        }
+    } else if rule.has_union() {
+        join(rule.get_union().get_rules(), " or ")
+    } else if rule.has_intersection() {
+        join(rule.get_intersection().get_rules(), " and ")
+    } else {
+        "never".to_owned()
+    }
+}
+
+fn column_family(gc_rule: GcRule) -> ColumnFamily {
+    let mut family = ColumnFamily::default();
+    family.set_gc_rule(gc_rule);
+    family
+}
+
+fn admin_error(message: String, e: grpcio::Error) -> BigTableError {
+    BigTableError::Admin(message, Some(e.to_string()))
+}
+
+/// Create and/or validate the table and its column families, per the
+/// `create_if_missing` and `validate_schema` settings.
+///
+/// This uses the (blocking) admin API, and is intended to be called once on
+/// startup.
+pub fn ensure_schema(
+    admin: &BigtableTableAdminClient,
+    settings: &BigTableDbSettings,
+    metadata: Metadata,
+) -> Result<(), BigTableError> {
+    if !(settings.create_if_missing || settings.validate_schema) {
+        return Ok(());
+    }
+    let table_name = &settings.table_name;
+    let Some((parent, table_id)) = table_name.split_once("/tables/") else {
+        return Err(BigTableError::Admin(
+            "Invalid table name specified".to_owned(),
+            None,
+        ));
+    };
+
+    let mut req = GetTableRequest::default();
+    req.set_name(table_name.clone());
+    req.set_view(Table_View::SCHEMA_VIEW);
+    let table = match admin.get_table_opt(&req, call_opts(metadata.clone())) {
+        Ok(table) => table,
+        Err(grpcio::Error::RpcFailure(status))
+            if settings.create_if_missing && status.code() == RpcStatusCode::NOT_FOUND =>
+        {
+            info!("🉑 Creating table {}", table_name);
+            let mut table = Table::default();
+            table.set_column_families(
+                expected_families()
+                    .into_iter()
+                    .map(|(name, rule)| (name.to_owned(), column_family(rule)))
+                    .collect::<HashMap<_, _>>(),
+            );
+            let mut req = CreateTableRequest::default();
+            req.set_parent(parent.to_owned());
+            req.set_table_id(table_id.to_owned());
+            req.set_table(table);
+            admin
+                .create_table_opt(&req, call_opts(metadata))
+                .map_err(|e| admin_error(format!("Could not create table {table_name}"), e))?;
+            return Ok(());
+        }
+        Err(e) => {
+            return Err(admin_error(
+                format!("Could not read the schema of table {table_name}"),
+                e,
+            ))
+        }
+    };
+
+    let families = table.get_column_families();
+    let mut missing = Vec::new();
+    for (name, expected) in expected_families() {
+        match families.get(name) {
+            Some(family) => {
+                let actual = describe_gc_rule(family.get_gc_rule());
+                let expected = describe_gc_rule(&expected);
+                if settings.validate_schema && actual != expected {
+                    return Err(BigTableError::Admin(
+                        format!(
+                            "Column family {name} of table {table_name} has GC policy \
+                             {actual:?}, expected {expected:?}"
+                        ),
+                        None,
+                    ));
+                }
+            }
+            None if settings.create_if_missing => missing.push((name, expected)),
+            None => {
+                return Err(BigTableError::Admin(
+                    format!("Table {table_name} is missing column family {name}"),
+                    None,
+                ))
+            }
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    info!(
+        "🉑 Creating column families {:?}",
+        missing.iter().map(|(name, _)| *name).collect::<Vec<_>>()
+    );
+    let modifications = missing
+        .into_iter()
+        .map(|(name, rule)| {
+            let mut modification = ModifyColumnFamiliesRequest_Modification::default();
+            modification.set_id(name.to_owned());
+            modification.set_create(column_family(rule));
+            modification
+        })
+        .collect();
+    let mut req = ModifyColumnFamiliesRequest::default();
+    req.set_name(table_name.clone());
+    req.set_modifications(RepeatedField::from_vec(modifications));
+    admin
+        .modify_column_families_opt(&req, call_opts(metadata))
+        .map_err(|e| {
+            admin_error(
+                format!("Could not create column families of table {table_name}"),
+                e,
+            )
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use google_cloud_rust_raw::bigtable::admin::v2::table::{GcRule, GcRule_Union};
+    use protobuf::RepeatedField;
+
+    use super::{describe_gc_rule, message_gc_rule, router_gc_rule};
+
+    #[test]
+    fn gc_rule_descriptions() {
+        assert_eq!(describe_gc_rule(&router_gc_rule()), "maxversions=1");
+        assert_eq!(
+            describe_gc_rule(&message_gc_rule()),
+            "(maxage=1s or maxversions=1)"
+        );
+
+        // the order of a union's rules is irrelevant
+        let mut rules = message_gc_rule().get_union().get_rules().to_vec();
+        rules.reverse();
+        let mut union = GcRule_Union::default();
+        union.set_rules(RepeatedField::from_vec(rules));
+        let mut reversed = GcRule::default();
+        reversed.set_union(union);
+        assert_eq!(
+            describe_gc_rule(&reversed),
+            describe_gc_rule(&message_gc_rule())
+        );
+
+        assert_eq!(describe_gc_rule(&GcRule::default()), "never");
+    }
+}
@@ -22,15 +22,16 @@
 mod bigtable_client;
 mod pool;
 
-pub use bigtable_client::error::BigTableError;
+pub use bigtable_client::error::{BigTableError, MutateRowStatus};
 pub use bigtable_client::BigTableClientImpl;
 
 use grpcio::Metadata;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use std::time::Duration;
 
 use crate::db::bigtable::bigtable_client::MetadataBuilder;
 use crate::db::error::DbError;
+use crate::db::quota::MessageQuota;
 use crate::util::deserialize_u32_to_duration;
 
 /// The settings for accessing the BigTable contents.
@@ -67,6 +68,70 @@ pub struct BigTableDbSettings {
     /// Include route to leader header in metadata
     #[serde(default)]
     pub route_to_leader: bool,
+    /// The retry policy for requests failing with a transient error
+    #[serde(default)]
+    pub retry: RetrySettings,
+    /// Create the table and any of its missing column families (along with
+    /// their GC policies) on startup
+    #[serde(default)]
+    pub create_if_missing: bool,
+    /// Verify on startup that the table's column families exist with the
+    /// expected GC policies, failing otherwise
+    #[serde(default)]
+    pub validate_schema: bool,
+    /// Limits on the number of stored messages
+    #[serde(default)]
+    pub quota: MessageQuota,
+}
+
+/// The retry policy for Bigtable requests.
+///
+/// Conditional mutations (`CheckAndMutateRow`) are not idempotent (a retry of
+/// a request that was applied but whose response was lost would report the
+/// condition as not matching), so they are never retried.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RetrySettings {
+    /// Maximum number of attempts at a request, including the first. `1`
+    /// disables retrying.
+    pub max_attempts: usize,
+    /// Delay (in milliseconds) before the first retry, doubling for each
+    /// subsequent retry
+    pub initial_backoff_ms: u64,
+    /// Maximum delay (in milliseconds) between retries
+    pub max_backoff_ms: u64,
+    /// Randomize the delays, to avoid synchronized retries across clients
+    pub jitter: bool,
+    /// The names of the gRPC status codes to retry (e.g. `"UNAVAILABLE"`)
+    #[serde(deserialize_with = "deserialize_status_codes")]
+    pub retryable_codes: Vec<MutateRowStatus>,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 50,
+            max_backoff_ms: 1000,
+            jitter: true,
+            retryable_codes: vec![
+                MutateRowStatus::Unavailable,
+                MutateRowStatus::DeadlineExceeded,
+                MutateRowStatus::Aborted,
+            ],
+        }
+    }
+}
+
+fn deserialize_status_codes<'de, D>(deserializer: D) -> Result<Vec<MutateRowStatus>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let names: Vec<String> = Deserialize::deserialize(deserializer)?;
+    names
+        .iter()
+        .map(|name| name.parse().map_err(serde::de::Error::custom))
+        .collect()
 }
 
 impl BigTableDbSettings {
@@ -110,3 +175,39 @@ impl TryFrom<&str> for BigTableDbSettings {
         Ok(me)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{BigTableDbSettings, MutateRowStatus};
+
+    #[test]
+    fn retry_settings() {
+        let settings =
+            BigTableDbSettings::try_from(r#"{"table_name": "projects/p/instances/i/tables/t"}"#)
+                .unwrap();
+        assert_eq!(settings.retry.max_attempts, 3);
+        assert!(settings
+            .retry
+            .retryable_codes
+            .contains(&MutateRowStatus::Unavailable));
+
+        let settings = BigTableDbSettings::try_from(
+            r#"{"table_name": "projects/p/instances/i/tables/t",
+                "retry": {"max_attempts": 5, "jitter": false, "retryable_codes": ["internal"]}}"#,
+        )
+        .unwrap();
+        assert_eq!(settings.retry.max_attempts, 5);
+        assert!(!settings.retry.jitter);
+        assert_eq!(settings.retry.initial_backoff_ms, 50);
+        assert_eq!(
+            settings.retry.retryable_codes,
+            vec![MutateRowStatus::Internal]
+        );
+
+        assert!(BigTableDbSettings::try_from(
+            r#"{"table_name": "projects/p/instances/i/tables/t",
+                "retry": {"retryable_codes": ["SOMETIMES"]}}"#,
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,449 @@
+//! Caching data store decorator.
+//!
+//! Wraps any [DbClient], caching the results of `get_channels` in a bounded
+//! LRU cache whose entries expire after a TTL. Entries are invalidated by
+//! writes made through this client, and concurrent lookups of the same UAID
+//! are coalesced into a single read of the underlying data store.
+//!
+//! Users aren't cached: autoconnect updates their `node_id`, `connected_at`
+//! and `version` from other processes, and routing to a stale `node_id`
+//! would leave a newly connected client unnotified. Channels registered by
+//! other processes are only seen once the cached entry expires, so the TTL
+//! should be kept short.
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use cadence::{CountedExt, StatsdClient};
+use lru::LruCache;
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+use uuid::Uuid;
+
+use crate::db::{
+    client::{DbClient, FetchMessageResponse},
+    error::DbResult,
+    ChannelRecord, Notification, User,
+};
+
+/// A cached value along with when it expires
+struct Cached<T> {
+    value: T,
+    expiry: Instant,
+}
+
+/// Locks held while reading a UAID's channels
+type Inflight = Arc<Mutex<HashMap<Uuid, InflightLock>>>;
+
+/// A lookup's lock, along with the number of lookups holding or waiting on it
+#[derive(Default)]
+struct InflightLock {
+    lock: Arc<AsyncMutex<()>>,
+    lookups: usize,
+}
+
+/// Held while performing a lookup. Dropping it (including when the lookup
+/// is cancelled) releases the lock, forgetting it when nothing else is
+/// waiting on it.
+struct LookupGuard {
+    inflight: Inflight,
+    key: Uuid,
+    held: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for LookupGuard {
+    fn drop(&mut self) {
+        self.held.take();
+        let mut inflight = self
+            .inflight
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(entry) = inflight.get_mut(&self.key) {
+            entry.lookups -= 1;
+            if entry.lookups == 0 {
+                inflight.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CachingDbClient {
+    inner: Box<dyn DbClient>,
+    channels: Arc<Mutex<LruCache<Uuid, Cached<HashSet<Uuid>>>>>,
+    /// Locks held while reading a UAID from the underlying data store, so
+    /// that concurrent lookups wait for (and then use) that read's result
+    inflight: Inflight,
+    /// Incremented (while holding the `channels` lock) on every invalidation.
+    /// Results read before an invalidation may be stale, so they're not
+    /// cached.
+    generation: Arc<AtomicU64>,
+    ttl: Duration,
+    metrics: Arc<StatsdClient>,
+}
+
+impl CachingDbClient {
+    /// Cache up to `max_entries` channel sets for `ttl`
+    pub fn new(
+        inner: Box<dyn DbClient>,
+        metrics: Arc<StatsdClient>,
+        max_entries: NonZeroUsize,
+        ttl: Duration,
+    ) -> Self {
+        debug!("💾 Caching {} lookups for {:?}", inner.name(), ttl);
+        Self {
+            inner,
+            channels: Arc::new(Mutex::new(LruCache::new(max_entries))),
+            inflight: Default::default(),
+            generation: Default::default(),
+            ttl,
+            metrics,
+        }
+    }
+
+    fn channels(&self) -> MutexGuard<'_, LruCache<Uuid, Cached<HashSet<Uuid>>>> {
+        // The cache is always left consistent, so recover from a panic while
+        // holding the lock
+        self.channels
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn cached_channels(&self, uaid: &Uuid) -> Option<HashSet<Uuid>> {
+        let mut channels = self.channels();
+        match channels.get(uaid) {
+            Some(cached) if Instant::now() < cached.expiry => Some(cached.value.clone()),
+            Some(_) => {
+                channels.pop(uaid);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Wait for any in-flight lookup of the UAID's channels, returning a
+    /// guard to hold while performing this lookup
+    async fn lock_lookup(&self, key: Uuid) -> LookupGuard {
+        let lock = {
+            let mut inflight = self
+                .inflight
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let entry = inflight.entry(key).or_default();
+            entry.lookups += 1;
+            Arc::clone(&entry.lock)
+        };
+        // Created before waiting, so that cancelling the wait cleans up
+        let mut guard = LookupGuard {
+            inflight: Arc::clone(&self.inflight),
+            key,
+            held: None,
+        };
+        guard.held = Some(lock.lock_owned().await);
+        guard
+    }
+
+    fn record(&self, hit: bool) {
+        let metric = if hit {
+            "database.cache.hit"
+        } else {
+            "database.cache.miss"
+        };
+        self.metrics
+            .incr_with_tags(metric)
+            .with_tag("lookup", "channels")
+            .send();
+    }
+
+    /// Drop any cached channels for the UAID
+    fn invalidate(&self, uaid: &Uuid) {
+        let mut channels = self.channels();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        channels.pop(uaid);
+    }
+}
+
+#[async_trait]
+impl DbClient for CachingDbClient {
+    async fn add_user(&self, user: &User) -> DbResult<()> {
+        self.inner.add_user(user).await
+    }
+
+    async fn add_user_with_channels(
+        &self,
+        user: &User,
+        channels: Vec<ChannelRecord>,
+    ) -> DbResult<()> {
+        let result = self.inner.add_user_with_channels(user, channels).await;
+        self.invalidate(&user.uaid);
+        result
+    }
+
+    async fn update_user(&self, user: &mut User) -> DbResult<bool> {
+        self.inner.update_user(user).await
+    }
+
+    async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>> {
+        self.inner.get_user(uaid).await
+    }
+
+    async fn remove_user(&self, uaid: &Uuid) -> DbResult<()> {
+        let result = self.inner.remove_user(uaid).await;
+        self.invalidate(uaid);
+        result
+    }
+
+    async fn list_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        self.inner.list_uaids(prefix).await
+    }
+
+    async fn list_orphan_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        self.inner.list_orphan_uaids(prefix).await
+    }
+
+    async fn add_channel(&self, uaid: &Uuid, channel: &ChannelRecord) -> DbResult<()> {
+        let result = self.inner.add_channel(uaid, channel).await;
+        self.invalidate(uaid);
+        result
+    }
+
+    async fn add_channels(&self, uaid: &Uuid, channels: Vec<ChannelRecord>) -> DbResult<()> {
+        let result = self.inner.add_channels(uaid, channels).await;
+        self.invalidate(uaid);
+        result
+    }
+
+    async fn get_channels(&self, uaid: &Uuid) -> DbResult<HashSet<Uuid>> {
+        if let Some(channels) = self.cached_channels(uaid) {
+            self.record(true);
+            return Ok(channels);
+        }
+        let guard = self.lock_lookup(*uaid).await;
+        // A concurrent lookup may have completed while waiting
+        if let Some(channels) = self.cached_channels(uaid) {
+            self.record(true);
+            return Ok(channels);
+        }
+        self.record(false);
+        let generation = self.generation.load(Ordering::SeqCst);
+        let result = self.inner.get_channels(uaid).await;
+        if let Ok(channels) = &result {
+            let mut cache = self.channels();
+            if generation == self.generation.load(Ordering::SeqCst) {
+                cache.put(
+                    *uaid,
+                    Cached {
+                        value: channels.clone(),
+                        expiry: Instant::now() + self.ttl,
+                    },
+                );
+            }
+        }
+        drop(guard);
+        result
+    }
+
+    /// Channel metadata isn't cached
+    async fn get_channel_records(&self, uaid: &Uuid) -> DbResult<Vec<ChannelRecord>> {
+        self.inner.get_channel_records(uaid).await
+    }
+
+    /// Only the channel's metadata changes, so the cached channel IDs remain
+    /// valid
+    async fn record_channel_push(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
+        self.inner.record_channel_push(uaid, channel_id).await
+    }
+
+    async fn remove_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
+        let result = self.inner.remove_channel(uaid, channel_id).await;
+        self.invalidate(uaid);
+        result
+    }
+
+    async fn remove_node_id(
+        &self,
+        uaid: &Uuid,
+        node_id: &str,
+        connected_at: u64,
+        version: &Option<Uuid>,
+    ) -> DbResult<bool> {
+        self.inner
+            .remove_node_id(uaid, node_id, connected_at, version)
+            .await
+    }
+
+    async fn save_message(&self, uaid: &Uuid, message: Notification) -> DbResult<()> {
+        self.inner.save_message(uaid, message).await
+    }
+
+    async fn save_messages(&self, uaid: &Uuid, messages: Vec<Notification>) -> DbResult<()> {
+        self.inner.save_messages(uaid, messages).await
+    }
+
+    async fn fetch_topic_messages(
+        &self,
+        uaid: &Uuid,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        self.inner.fetch_topic_messages(uaid, limit).await
+    }
+
+    async fn fetch_timestamp_messages(
+        &self,
+        uaid: &Uuid,
+        timestamp: Option<u64>,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        self.inner
+            .fetch_timestamp_messages(uaid, timestamp, limit)
+            .await
+    }
+
+    async fn fetch_quota_messages(&self, uaid: &Uuid) -> DbResult<Vec<Notification>> {
+        self.inner.fetch_quota_messages(uaid).await
+    }
+
+    async fn increment_storage(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
+        self.inner.increment_storage(uaid, timestamp).await
+    }
+
+    async fn get_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<Option<Notification>> {
+        self.inner.get_message(uaid, sort_key).await
+    }
+
+    async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()> {
+        self.inner.remove_message(uaid, sort_key).await
+    }
+
+    async fn remove_messages(&self, uaid: &Uuid, sort_keys: &[String]) -> DbResult<()> {
+        self.inner.remove_messages(uaid, sort_keys).await
+    }
+
+    async fn router_table_exists(&self) -> DbResult<bool> {
+        self.inner.router_table_exists().await
+    }
+
+    async fn message_table_exists(&self) -> DbResult<bool> {
+        self.inner.message_table_exists().await
+    }
+
+    async fn health_check(&self) -> DbResult<bool> {
+        self.inner.health_check().await
+    }
+
+    fn rotating_message_table(&self) -> Option<&str> {
+        self.inner.rotating_message_table()
+    }
+
+    fn box_clone(&self) -> Box<dyn DbClient> {
+        Box::new(self.clone())
+    }
+
+    /// The cache is transparent, so report the underlying data store
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cadence::StatsdClient;
+    use futures::future::join_all;
+    use futures::FutureExt;
+    use mockall::predicate;
+
+    use super::*;
+    use crate::db::mock::MockDbClient;
+
+    fn new_client(db: MockDbClient, ttl: Duration) -> CachingDbClient {
+        CachingDbClient::new(
+            Box::new(Arc::new(db)),
+            Arc::new(StatsdClient::builder("", cadence::NopMetricSink).build()),
+            NonZeroUsize::new(10).unwrap(),
+            ttl,
+        )
+    }
+
+    #[actix_rt::test]
+    async fn caches_lookups() -> DbResult<()> {
+        let uaid = Uuid::new_v4();
+        let chid = Uuid::new_v4();
+        let mut db = MockDbClient::new();
+        db.expect_get_channels()
+            .with(predicate::eq(uaid))
+            .times(2)
+            .returning(move |_| Ok(HashSet::from([chid])));
+        db.expect_add_channel().times(1).returning(|_, _| Ok(()));
+        let client = new_client(db, Duration::from_secs(60));
+
+        // concurrent lookups result in a single read
+        let channels = join_all((0..3).map(|_| client.get_channels(&uaid))).await;
+        for result in channels {
+            assert!(result?.contains(&chid));
+        }
+        assert!(client.inflight.lock().unwrap().is_empty());
+
+        // writes invalidate
+        client
+            .add_channel(&uaid, &ChannelRecord::from(chid))
+            .await?;
+        client.get_channels(&uaid).await?;
+        client.get_channels(&uaid).await?;
+        Ok(())
+    }
+
+    /// Users are routed by fields autoconnect updates, so are always read
+    #[actix_rt::test]
+    async fn reads_users() -> DbResult<()> {
+        let uaid = Uuid::new_v4();
+        let mut db = MockDbClient::new();
+        db.expect_get_user()
+            .with(predicate::eq(uaid))
+            .times(2)
+            .returning(move |uaid| {
+                Ok(Some(User {
+                    uaid: *uaid,
+                    node_id: Some(Uuid::new_v4().to_string()),
+                    ..Default::default()
+                }))
+            });
+        let client = new_client(db, Duration::from_secs(60));
+
+        let first = client.get_user(&uaid).await?.unwrap();
+        let second = client.get_user(&uaid).await?.unwrap();
+        assert_ne!(first.node_id, second.node_id);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn cancelled_lookups() -> DbResult<()> {
+        let uaid = Uuid::new_v4();
+        let client = new_client(MockDbClient::new(), Duration::from_secs(60));
+
+        let guard = client.lock_lookup(uaid).await;
+        // Cancelled while waiting on the in-flight lookup
+        assert!(client.get_channels(&uaid).now_or_never().is_none());
+        assert_eq!(client.inflight.lock().unwrap().len(), 1);
+        drop(guard);
+        assert!(client.inflight.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn expires_lookups() -> DbResult<()> {
+        let uaid = Uuid::new_v4();
+        let mut db = MockDbClient::new();
+        db.expect_get_channels()
+            .times(2)
+            .returning(|_| Ok(HashSet::new()));
+        let client = new_client(db, Duration::ZERO);
+
+        client.get_channels(&uaid).await?;
+        client.get_channels(&uaid).await?;
+        Ok(())
+    }
+}
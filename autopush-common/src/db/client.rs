@@ -6,7 +6,7 @@ use mockall::automock;
 use uuid::Uuid;
 
 use crate::db::error::DbResult;
-use crate::db::User;
+use crate::db::{ChannelRecord, User};
 use crate::notification::Notification;
 
 #[derive(Default, Debug)]
@@ -26,6 +26,16 @@ pub trait DbClient: Send + Sync {
     /// exists.
     async fn add_user(&self, user: &User) -> DbResult<()>;
 
+    /// Add a new user along with their initial channels. The write is atomic
+    /// where the data store allows it, so a failure doesn't leave behind a
+    /// user without their channels. An error will occur if the user already
+    /// exists.
+    async fn add_user_with_channels(
+        &self,
+        user: &User,
+        channels: Vec<ChannelRecord>,
+    ) -> DbResult<()>;
+
     /// Update a user in the database. Returns whether the update occurred. The
     /// update will not occur if the user does not already exist, has a
     /// different router type, or has a newer `connected_at` timestamp.
@@ -38,15 +48,34 @@ pub trait DbClient: Send + Sync {
     /// Delete a user from the router table
     async fn remove_user(&self, uaid: &Uuid) -> DbResult<()>;
 
-    /// Add a channel to a user
-    async fn add_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()>;
+    /// List the UAIDs of users whose (simple, lower case hex) UAID starts with
+    /// `prefix`. This is intended for bulk operations, such as migrating data
+    /// between data stores, and may be expensive.
+    async fn list_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>>;
+
+    /// List the UAIDs starting with `prefix` that still have channels or
+    /// messages stored without a user record (e.g. left behind by a user
+    /// record expiring). Like [DbClient::list_uaids], this is intended for
+    /// bulk operations and may be expensive.
+    async fn list_orphan_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>>;
+
+    /// Add a channel, along with its metadata, to a user. Adding an existing
+    /// channel replaces its metadata.
+    async fn add_channel(&self, uaid: &Uuid, channel: &ChannelRecord) -> DbResult<()>;
 
     /// Add a batch of channels to a user
-    async fn add_channels(&self, uaid: &Uuid, channels: HashSet<Uuid>) -> DbResult<()>;
+    async fn add_channels(&self, uaid: &Uuid, channels: Vec<ChannelRecord>) -> DbResult<()>;
 
     /// Get the set of channel IDs for a user
     async fn get_channels(&self, uaid: &Uuid) -> DbResult<HashSet<Uuid>>;
 
+    /// Get a user's channels along with their metadata
+    async fn get_channel_records(&self, uaid: &Uuid) -> DbResult<Vec<ChannelRecord>>;
+
+    /// Record a message pushed to the channel, updating its last push time
+    /// and push count. Does nothing if the channel doesn't exist.
+    async fn record_channel_push(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()>;
+
     /// Remove a channel from a user. Returns if the removed channel did exist.
     async fn remove_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool>;
 
@@ -67,14 +96,16 @@ pub trait DbClient: Send + Sync {
     /// Save multiple messages to the message table
     async fn save_messages(&self, uaid: &Uuid, messages: Vec<Notification>) -> DbResult<()>;
 
-    /// Fetch stored messages for a user
+    /// Fetch `limit` stored topic messages for a user. `limit=0` for all
+    /// messages.
     async fn fetch_topic_messages(
         &self,
         uaid: &Uuid,
         limit: usize,
     ) -> DbResult<FetchMessageResponse>;
 
-    /// Fetch stored messages later than a given
+    /// Fetch `limit` stored messages later than a given timestamp.
+    /// `limit=0` for all messages.
     async fn fetch_timestamp_messages(
         &self,
         uaid: &Uuid,
@@ -82,12 +113,45 @@ pub trait DbClient: Send + Sync {
         limit: usize,
     ) -> DbResult<FetchMessageResponse>;
 
+    /// Fetch the stored messages counted towards a user's
+    /// [crate::db::quota::MessageQuota]: their topic messages and their
+    /// timestamp messages after the `current_timestamp`.
+    ///
+    /// Only the messages' keys and `timestamp` are required, so data stores
+    /// should override this to skip reading the rest (e.g. `data`).
+    async fn fetch_quota_messages(&self, uaid: &Uuid) -> DbResult<Vec<Notification>> {
+        let topic = self.fetch_topic_messages(uaid, 0).await?;
+        // Only DynamoDB returns the `current_timestamp` along with the topic
+        // messages, the other data stores keep it in the user record
+        let current_timestamp = match topic.timestamp {
+            Some(timestamp) => Some(timestamp),
+            None => self
+                .get_user(uaid)
+                .await?
+                .and_then(|user| user.current_timestamp),
+        };
+        let mut messages = topic.messages;
+        messages.extend(
+            self.fetch_timestamp_messages(uaid, current_timestamp, 0)
+                .await?
+                .messages,
+        );
+        Ok(messages)
+    }
+
     /// Update the last read timestamp for a user
     async fn increment_storage(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()>;
 
+    /// Read a pending (unexpired) notification by its `sort_key` (its
+    /// `chidmessageid`)
+    async fn get_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<Option<Notification>>;
+
     /// Delete a notification
     async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()>;
 
+    /// Delete a batch of notifications
+    async fn remove_messages(&self, uaid: &Uuid, sort_keys: &[String]) -> DbResult<()>;
+
     /// Check if the router table exists
     async fn router_table_exists(&self) -> DbResult<bool>;
 
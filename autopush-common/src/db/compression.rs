@@ -0,0 +1,208 @@
+//! Compression of stored message payloads.
+//!
+//! Enabled per data store via [DbSettings::compress_data](crate::db::DbSettings).
+//! Message `data` (base64url encoded by autoendpoint) is decoded and deflated
+//! before being written. Text based data stores store the result base64url
+//! encoded and tagged with [COMPRESSED_PREFIX], while binary ones (Bigtable)
+//! store the tagged, deflated bytes as is. Data is only ever stored
+//! compressed when that's smaller.
+//!
+//! Compressed data is detected by its tag (which never prefixes base64url
+//! data) and decompressed on read regardless of the setting, so compressed
+//! and uncompressed records may be mixed.
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use cadence::{Counted, Histogrammed, StatsdClient};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+
+use crate::db::error::{DbError, DbResult};
+use crate::db::DbSettings;
+use crate::notification::Notification;
+use crate::util::{b64_decode_url, b64_encode_url};
+
+/// Prefix identifying compressed data (and its format version)
+pub const COMPRESSED_PREFIX: &str = "z1:";
+
+/// Upper bound on decompressed data, well above the largest payload
+/// autoendpoint accepts
+const MAX_DECOMPRESSED_LEN: u64 = 64 * 1024;
+
+#[derive(Clone)]
+pub struct Compressor {
+    enabled: bool,
+    metrics: Arc<StatsdClient>,
+}
+
+impl Compressor {
+    pub fn new(metrics: Arc<StatsdClient>, db_settings: &DbSettings) -> Self {
+        Self {
+            enabled: db_settings.compress_data,
+            metrics,
+        }
+    }
+
+    /// Compress the data for a text based data store
+    pub fn compress(&self, data: String) -> String {
+        let Some(deflated) = self.deflate(&data) else {
+            return data;
+        };
+        let compressed = format!("{COMPRESSED_PREFIX}{}", b64_encode_url(&deflated));
+        if compressed.len() < data.len() {
+            self.record(data.len(), compressed.len());
+            compressed
+        } else {
+            self.record(data.len(), data.len());
+            data
+        }
+    }
+
+    /// Compress the data for a binary data store
+    pub fn compress_bytes(&self, data: String) -> Vec<u8> {
+        let Some(deflated) = self.deflate(&data) else {
+            return data.into_bytes();
+        };
+        if COMPRESSED_PREFIX.len() + deflated.len() < data.len() {
+            let mut compressed = COMPRESSED_PREFIX.as_bytes().to_vec();
+            compressed.extend(deflated);
+            self.record(data.len(), compressed.len());
+            compressed
+        } else {
+            self.record(data.len(), data.len());
+            data.into_bytes()
+        }
+    }
+
+    /// Compress the Notification's data for a text based data store
+    pub fn compress_notification(&self, notif: &mut Notification) {
+        if let Some(data) = notif.data.take() {
+            notif.data = Some(self.compress(data));
+        }
+    }
+
+    /// Deflate the data's decoded bytes. `None` when compression's disabled
+    /// or the data isn't (canonical) base64url, so couldn't be restored
+    /// exactly.
+    fn deflate(&self, data: &str) -> Option<Vec<u8>> {
+        if !self.enabled {
+            return None;
+        }
+        let raw = b64_decode_url(data)
+            .ok()
+            .filter(|raw| b64_encode_url(raw) == data)?;
+        let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&raw).ok()?;
+        encoder.finish().ok()
+    }
+
+    fn record(&self, original_len: usize, stored_len: usize) {
+        self.metrics
+            .histogram_with_tags(
+                "notification.message.compression_ratio",
+                (stored_len * 100 / original_len.max(1)) as u64,
+            )
+            .send();
+        self.metrics
+            .count_with_tags(
+                "notification.message.compression_saved",
+                (original_len - stored_len) as i64,
+            )
+            .send();
+    }
+}
+
+/// Decompress data read from a text based data store. Uncompressed data is
+/// returned untouched.
+pub fn decompress(data: String) -> DbResult<String> {
+    let Some(compressed) = data.strip_prefix(COMPRESSED_PREFIX) else {
+        return Ok(data);
+    };
+    let deflated = b64_decode_url(compressed)
+        .map_err(|e| DbError::Serialization(format!("Could not decode compressed data: {e}")))?;
+    inflate(&deflated)
+}
+
+/// Decompress data read from a binary data store
+pub fn decompress_bytes(value: Vec<u8>) -> DbResult<String> {
+    if let Some(deflated) = value.strip_prefix(COMPRESSED_PREFIX.as_bytes()) {
+        return inflate(deflated);
+    }
+    String::from_utf8(value)
+        .map_err(|e| DbError::Serialization(format!("Could not read data: {e}")))
+}
+
+/// Decompress the stored Notification's data
+pub fn decompress_notification(notif: &mut Notification) -> DbResult<()> {
+    if let Some(data) = notif.data.take() {
+        notif.data = Some(decompress(data)?);
+    }
+    Ok(())
+}
+
+/// Inflate deflated bytes, returning them base64url encoded
+fn inflate(deflated: &[u8]) -> DbResult<String> {
+    let mut raw = Vec::new();
+    DeflateDecoder::new(deflated)
+        .take(MAX_DECOMPRESSED_LEN + 1)
+        .read_to_end(&mut raw)
+        .map_err(|e| DbError::Serialization(format!("Could not decompress data: {e}")))?;
+    if raw.len() as u64 > MAX_DECOMPRESSED_LEN {
+        return Err(DbError::Serialization(
+            "Decompressed data is too large".to_owned(),
+        ));
+    }
+    Ok(b64_encode_url(&raw))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cadence::{NopMetricSink, StatsdClient};
+
+    use super::*;
+
+    fn compressor(compress_data: bool) -> Compressor {
+        Compressor::new(
+            Arc::new(StatsdClient::builder("", NopMetricSink).build()),
+            &DbSettings {
+                compress_data,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn compress_and_decompress() -> DbResult<()> {
+        let data = b64_encode_url(&b"compressible ".repeat(100));
+        let compressed = compressor(true).compress(data.clone());
+        assert!(compressed.starts_with(COMPRESSED_PREFIX));
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(compressed)?, data);
+
+        let compressed = compressor(true).compress_bytes(data.clone());
+        assert!(compressed.starts_with(COMPRESSED_PREFIX.as_bytes()));
+        assert_eq!(decompress_bytes(compressed)?, data);
+
+        // Disabled, or data written before compression was enabled
+        let uncompressed = compressor(false).compress(data.clone());
+        assert_eq!(uncompressed, data);
+        assert_eq!(decompress(uncompressed)?, data);
+        assert_eq!(decompress_bytes(data.clone().into_bytes())?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn skips_incompressible() -> DbResult<()> {
+        // Not base64url
+        let data = "not base64 data".to_owned();
+        assert_eq!(compressor(true).compress(data.clone()), data);
+        // Padded, so wouldn't be restored exactly
+        let data = format!("{}==", b64_encode_url(&b"compressible ".repeat(100)));
+        assert_eq!(compressor(true).compress(data.clone()), data);
+        // Doesn't shrink
+        let data = b64_encode_url(&(0..=255u8).collect::<Vec<u8>>());
+        assert_eq!(compressor(true).compress(data.clone()), data);
+        Ok(())
+    }
+}
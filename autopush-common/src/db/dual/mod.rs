@@ -4,39 +4,47 @@
 //! read operation fails for the primary, the secondary is automatically
 //! used. All write operations ONLY go to the primary.
 //!
-//! This requires both the `dynamodb` and `bigtable` features.
+//! Either data store may be any of the (non "dual") data stores compiled in,
+//! as specified by their respective `dsn` and `db_settings`.
 //!
 use std::collections::HashSet;
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use cadence::{CountedExt, StatsdClient};
+use futures::join;
+use openssl::hash::MessageDigest;
 use serde::Deserialize;
 use serde_json::from_str;
 use uuid::Uuid;
 
 use crate::db::{
-    bigtable::BigTableClientImpl,
     client::{DbClient, FetchMessageResponse},
-    dynamodb::DdbClientImpl,
+    client_from_settings,
     error::{DbError, DbResult},
-    DbSettings, Notification, User,
+    ChannelRecord, DbSettings, Notification, User,
 };
+use crate::util::sec_since_epoch;
 
 use super::StorageType;
 
 #[derive(Clone)]
 pub struct DualClientImpl {
-    /// The primary data store, which will always be Bigtable.
-    primary: BigTableClientImpl,
-    /// The secondary data store, which will always be DynamoDB.
-    secondary: DdbClientImpl,
+    /// The primary data store
+    primary: Box<dyn DbClient>,
+    /// The secondary data store
+    secondary: Box<dyn DbClient>,
     /// Write changes to the secondary, including messages and updates
     /// as well as account and channel additions/deletions.
     write_to_secondary: bool,
     /// Hex value to use to specify the first byte of the median offset.
     /// e.g. "0a" will start from include all UUIDs upto and including "0a"
     median: Option<u8>,
+    /// Move users found only in the secondary (along with their channels and
+    /// pending messages) to the primary, removing them from the secondary.
+    migrate_on_read: bool,
+    /// Also read from the secondary, comparing its results to the primary's.
+    shadow_read: bool,
     metrics: Arc<StatsdClient>,
 }
 
@@ -46,9 +54,9 @@ fn default_true() -> bool {
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct DualDbSettings {
-    /// The primary data store, which will always be Bigtable.
+    /// The primary data store
     primary: DbSettings,
-    /// The secondary data store, which will always be DynamoDB.
+    /// The secondary data store
     secondary: DbSettings,
     /// Write changes to the secondary, including messages and updates
     /// as well as account and channel additions/deletions.
@@ -58,25 +66,54 @@ pub struct DualDbSettings {
     /// e.g. "0a" will start from include all UUIDs upto and including "0a"
     #[serde(default)]
     median: Option<String>,
+    /// Move users found only in the secondary (along with their channels and
+    /// pending messages) to the primary, removing them from the secondary
+    /// once the copy has been verified. Requires `write_to_secondary` to be
+    /// off, as the secondary would otherwise be left with orphaned writes
+    /// and couldn't be rolled back to.
+    #[serde(default)]
+    migrate_on_read: bool,
+    /// Concurrently read users, channels and messages from both the primary
+    /// and the secondary, reporting any differences between them. The
+    /// primary's result is always returned.
+    #[serde(default)]
+    shadow_read: bool,
 }
 
 impl DualClientImpl {
     pub fn new(metrics: Arc<StatsdClient>, settings: &DbSettings) -> DbResult<Self> {
         // Not really sure we need the dsn here.
         info!("⚖ Trying: {:?}", settings.db_settings);
-        let db_settings: DualDbSettings = from_str(&settings.db_settings).map_err(|e| {
+        let mut db_settings: DualDbSettings = from_str(&settings.db_settings).map_err(|e| {
             DbError::General(format!("Could not parse DualDBSettings string {:?}", e))
         })?;
+        // Both data stores share the key ring and compression unless given
+        // their own
+        for store in [&mut db_settings.primary, &mut db_settings.secondary] {
+            if store.key_ring.is_empty() {
+                store.key_ring = settings.key_ring.clone();
+            }
+            store.compress_data |= settings.compress_data;
+        }
         info!("⚖ {:?}", &db_settings);
-        if StorageType::from_dsn(&db_settings.primary.dsn) != StorageType::BigTable {
+        if db_settings.migrate_on_read && db_settings.write_to_secondary {
             return Err(DbError::General(
-                "Invalid primary DSN specified (must be BigTable type)".to_owned(),
+                "migrate_on_read requires write_to_secondary to be false".to_owned(),
             ));
         }
-        if StorageType::from_dsn(&db_settings.secondary.dsn) != StorageType::DynamoDb {
-            return Err(DbError::General(
-                "Invalid secondary DSN specified (must be DynamoDB type)".to_owned(),
-            ));
+        for (label, settings) in [
+            ("primary", &db_settings.primary),
+            ("secondary", &db_settings.secondary),
+        ] {
+            if matches!(
+                StorageType::from_dsn(&settings.dsn),
+                StorageType::Dual | StorageType::INVALID
+            ) {
+                return Err(DbError::General(format!(
+                    "Invalid {} DSN specified: {:?}",
+                    label, settings.dsn
+                )));
+            }
         }
         // determine which uaids to move based on the first byte of their UAID, which (hopefully)
         // should be sufficiently random based on it being a UUID4.
@@ -96,14 +133,20 @@ impl DualClientImpl {
         } else {
             None
         };
-        let primary = BigTableClientImpl::new(metrics.clone(), &db_settings.primary)?;
-        let secondary = DdbClientImpl::new(metrics.clone(), &db_settings.secondary)?;
-        debug!("⚖ Got primary and secondary");
+        let primary = client_from_settings(metrics.clone(), &db_settings.primary)?;
+        let secondary = client_from_settings(metrics.clone(), &db_settings.secondary)?;
+        debug!(
+            "⚖ Got primary ({}) and secondary ({})",
+            primary.name(),
+            secondary.name()
+        );
         Ok(Self {
             primary,
-            secondary: secondary.clone(),
+            secondary,
             median,
             write_to_secondary: db_settings.write_to_secondary,
+            migrate_on_read: db_settings.migrate_on_read,
+            shadow_read: db_settings.shadow_read,
             metrics,
         })
     }
@@ -118,15 +161,15 @@ impl DualClientImpl {
     async fn allot<'a>(&'a self, uaid: &Uuid) -> DbResult<(Box<&'a dyn DbClient>, bool)> {
         let target: (Box<&'a dyn DbClient>, bool) = if let Some(median) = self.median {
             if uaid.as_bytes()[0] <= median {
-                debug!("⚖ Routing user to Bigtable");
+                debug!("⚖ Routing user to {}", self.primary.name());
                 // These are migrations so the metrics should appear as
                 // `auto[endpoint|connect].migrate`.
-                (Box::new(&self.primary), true)
+                (Box::new(self.primary.as_ref()), true)
             } else {
-                (Box::new(&self.secondary), false)
+                (Box::new(self.secondary.as_ref()), false)
             }
         } else {
-            (Box::new(&self.primary), true)
+            (Box::new(self.primary.as_ref()), true)
         };
         self.metrics
             .incr_with_tags("database.dual.error")
@@ -135,6 +178,211 @@ impl DualClientImpl {
         debug!("⚖ alloting to {}", target.0.name());
         Ok(target)
     }
+
+    /// Compare the results of a shadow read, reporting any difference
+    /// between the primary and secondary.
+    fn shadow_compare<T, F>(
+        &self,
+        func: &str,
+        uaid: &Uuid,
+        primary: &DbResult<T>,
+        secondary: &DbResult<T>,
+        matches: F,
+    ) where
+        F: FnOnce(&T, &T) -> bool,
+    {
+        let result = match (primary, secondary) {
+            (Ok(primary), Ok(secondary)) => {
+                if matches(primary, secondary) {
+                    "match"
+                } else {
+                    "mismatch"
+                }
+            }
+            _ => "error",
+        };
+        if result != "match" {
+            warn!(
+                "⚖ Shadow read {}", result;
+                "func" => func,
+                "uaid_hash" => hash_uaid(uaid),
+                "primary_error" => primary.as_ref().err().map(|e| e.to_string()),
+                "secondary_error" => secondary.as_ref().err().map(|e| e.to_string())
+            );
+        }
+        self.metrics
+            .incr_with_tags("database.dual.shadow")
+            .with_tag("func", func)
+            .with_tag("result", result)
+            .send();
+    }
+
+    /// Record the outcome of a step of a migration
+    fn migrate_metric(&self, step: &str, success: bool) {
+        self.metrics
+            .incr_with_tags("database.dual.migrate")
+            .with_tag("step", step)
+            .with_tag("result", if success { "success" } else { "error" })
+            .send();
+    }
+
+    /// Copy the pending messages for the user from the secondary to the
+    /// primary, verify the copy, then remove the user from the secondary.
+    /// Run in the background of the read that found the user.
+    ///
+    /// Failures are logged and reported, leaving the secondary untouched, as
+    /// the user's record has already been copied to the primary.
+    async fn migrate_user(&self, uaid: &Uuid, channels: &HashSet<Uuid>) {
+        let result = self.copy_messages(uaid).await;
+        self.migrate_metric("messages", result.is_ok());
+        let messages = match result {
+            Ok(messages) => messages,
+            Err(e) => {
+                warn!("⚖ Could not copy messages for migration: {:?}", e);
+                return;
+            }
+        };
+
+        let result = self.verify_migration(uaid, channels, &messages).await;
+        self.migrate_metric("verify", matches!(result, Ok(true)));
+        match result {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("⚖ Migrated user did not match the secondary, not removing");
+                return;
+            }
+            Err(e) => {
+                warn!("⚖ Could not verify migration: {:?}", e);
+                return;
+            }
+        }
+
+        let result = self.remove_from_secondary(uaid, channels, &messages).await;
+        self.migrate_metric("delete", result.is_ok());
+        if let Err(e) = result {
+            warn!("⚖ Could not remove migrated user from secondary: {:?}", e);
+        }
+    }
+
+    /// Copy the unexpired pending messages from the secondary to the
+    /// primary, returning their sort keys.
+    ///
+    /// Messages acked while being copied are removed from both data stores,
+    /// but may then be recreated on the primary by the copy. So the
+    /// secondary is read again afterwards, removing the copies of any
+    /// messages it no longer holds.
+    async fn copy_messages(&self, uaid: &Uuid) -> DbResult<Vec<String>> {
+        let now = sec_since_epoch();
+        let mut messages = fetch_all_messages(self.secondary.as_ref(), uaid).await?;
+        messages.retain(|message| !message.expired(now));
+        if messages.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut sort_keys: Vec<String> = messages.iter().map(|m| m.chidmessageid()).collect();
+        self.primary.save_messages(uaid, messages).await?;
+
+        let remaining: HashSet<String> = fetch_all_messages(self.secondary.as_ref(), uaid)
+            .await?
+            .iter()
+            .map(|m| m.chidmessageid())
+            .collect();
+        let acked: Vec<String> = sort_keys
+            .iter()
+            .filter(|sort_key| !remaining.contains(*sort_key))
+            .cloned()
+            .collect();
+        if !acked.is_empty() {
+            self.primary.remove_messages(uaid, &acked).await?;
+            sort_keys.retain(|sort_key| remaining.contains(sort_key));
+        }
+        Ok(sort_keys)
+    }
+
+    /// Check that the primary now holds the migrated channels and messages
+    async fn verify_migration(
+        &self,
+        uaid: &Uuid,
+        channels: &HashSet<Uuid>,
+        messages: &[String],
+    ) -> DbResult<bool> {
+        if self.primary.get_user(uaid).await?.is_none() {
+            return Ok(false);
+        }
+        if !self.primary.get_channels(uaid).await?.is_superset(channels) {
+            return Ok(false);
+        }
+        let migrated: HashSet<String> = fetch_all_messages(self.primary.as_ref(), uaid)
+            .await?
+            .iter()
+            .map(|m| m.chidmessageid())
+            .collect();
+        Ok(messages.iter().all(|sort_key| migrated.contains(sort_key)))
+    }
+
+    /// Remove the migrated user, channels and messages from the secondary
+    async fn remove_from_secondary(
+        &self,
+        uaid: &Uuid,
+        channels: &HashSet<Uuid>,
+        messages: &[String],
+    ) -> DbResult<()> {
+        self.secondary.remove_messages(uaid, messages).await?;
+        // Not all data stores remove a user's channels along with the user
+        for channel_id in channels {
+            self.secondary.remove_channel(uaid, channel_id).await?;
+        }
+        self.secondary.remove_user(uaid).await
+    }
+}
+
+/// Fetch all of the user's pending topic and timestamp messages
+async fn fetch_all_messages(db: &dyn DbClient, uaid: &Uuid) -> DbResult<Vec<Notification>> {
+    let mut messages = db.fetch_topic_messages(uaid, 0).await?.messages;
+    messages.extend(db.fetch_timestamp_messages(uaid, None, 0).await?.messages);
+    Ok(messages)
+}
+
+/// A truncated SHA-256 digest of the UAID, so that shadow read differences
+/// may be correlated without logging the UAID itself.
+fn hash_uaid(uaid: &Uuid) -> String {
+    openssl::hash::hash(MessageDigest::sha256(), uaid.as_bytes())
+        .map(|digest| hex::encode(&digest[..8]))
+        .unwrap_or_default()
+}
+
+/// Whether the same channels are registered. Their push activity may
+/// legitimately differ, as each data store only records its own.
+fn channels_match(primary: &[ChannelRecord], secondary: &[ChannelRecord]) -> bool {
+    let ids = |channels: &[ChannelRecord]| -> HashSet<Uuid> {
+        channels.iter().map(|channel| channel.channel_id).collect()
+    };
+    ids(primary) == ids(secondary)
+}
+
+/// Whether the user records match, ignoring the data store specific fields
+fn users_match(primary: &Option<User>, secondary: &Option<User>) -> bool {
+    match (primary, secondary) {
+        (Some(primary), Some(secondary)) => {
+            primary.router_type == secondary.router_type
+                && primary.connected_at == secondary.connected_at
+                && primary.node_id == secondary.node_id
+                && primary.router_data == secondary.router_data
+        }
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Whether the same messages were fetched
+fn messages_match(primary: &FetchMessageResponse, secondary: &FetchMessageResponse) -> bool {
+    let sort_keys = |response: &FetchMessageResponse| -> Vec<String> {
+        response
+            .messages
+            .iter()
+            .map(|m| m.chidmessageid())
+            .collect()
+    };
+    sort_keys(primary) == sort_keys(secondary)
 }
 
 #[async_trait]
@@ -157,6 +405,33 @@ impl DbClient for DualClientImpl {
         Ok(result)
     }
 
+    async fn add_user_with_channels(
+        &self,
+        user: &User,
+        channels: Vec<ChannelRecord>,
+    ) -> DbResult<()> {
+        let (target, is_primary) = self.allot(&user.uaid).await?;
+        debug!("⚖ adding user with channels to {}...", target.name());
+        target
+            .add_user_with_channels(user, channels.clone())
+            .await?;
+        if is_primary && self.write_to_secondary {
+            let _ = self
+                .secondary
+                .add_user_with_channels(user, channels)
+                .await
+                .map_err(|e| {
+                    error!("⚖ Error: {:?}", e);
+                    self.metrics
+                        .incr_with_tags("database.dual.error")
+                        .with_tag("func", "add_user_with_channels")
+                        .send();
+                    e
+                });
+        }
+        Ok(())
+    }
+
     async fn update_user(&self, user: &mut User) -> DbResult<bool> {
         //  If the UAID is in the allowance, move them to the new data store
         let (target, is_primary) = self.allot(&user.uaid).await?;
@@ -176,21 +451,52 @@ impl DbClient for DualClientImpl {
 
     async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>> {
         let (target, is_primary) = self.allot(uaid).await?;
-        match target.get_user(uaid).await {
+        let (result, shadow) = if is_primary && self.shadow_read {
+            let (result, shadow) = join!(target.get_user(uaid), self.secondary.get_user(uaid));
+            self.shadow_compare("get_user", uaid, &result, &shadow, users_match);
+            (result, Some(shadow))
+        } else {
+            (target.get_user(uaid).await, None)
+        };
+        match result {
             Ok(Some(user)) => Ok(Some(user)),
             Ok(None) => {
                 if is_primary {
                     // The user wasn't in the current primary, so fetch them from the secondary.
-                    if let Ok(Some(mut user)) = self.secondary.get_user(uaid).await {
+                    let secondary = match shadow {
+                        Some(shadow) => shadow,
+                        None => self.secondary.get_user(uaid).await,
+                    };
+                    if let Ok(Some(mut user)) = secondary {
                         // copy the user record over to the new data store.
                         debug!("⚖ Found user record in secondary, moving to primary");
-                        // Users read from DynamoDB lack a version field needed
-                        // for Bigtable
-                        debug_assert!(user.version.is_none());
-                        user.version = Some(Uuid::new_v4());
-                        self.primary.add_user(&user).await?;
-                        let channels = self.secondary.get_channels(uaid).await?;
-                        self.primary.add_channels(uaid, channels).await?;
+                        // Users read from some data stores (e.g. DynamoDB)
+                        // lack the version field needed by others
+                        if user.version.is_none() {
+                            user.version = Some(Uuid::new_v4());
+                        }
+                        let result = self.primary.add_user(&user).await;
+                        if self.migrate_on_read {
+                            self.migrate_metric("user", result.is_ok());
+                        }
+                        result?;
+                        let channels = self.secondary.get_channel_records(uaid).await?;
+                        let channel_ids: HashSet<Uuid> =
+                            channels.iter().map(|channel| channel.channel_id).collect();
+                        let result = self.primary.add_channels(uaid, channels).await;
+                        if self.migrate_on_read {
+                            self.migrate_metric("channels", result.is_ok());
+                        }
+                        result?;
+                        if self.migrate_on_read {
+                            // Moving the messages may take a while, so don't
+                            // hold up this read on it
+                            let dual = self.clone();
+                            let uaid = *uaid;
+                            tokio::spawn(async move {
+                                dual.migrate_user(&uaid, &channel_ids).await;
+                            });
+                        }
                         return Ok(Some(user));
                     }
                 }
@@ -218,15 +524,33 @@ impl DbClient for DualClientImpl {
         Ok(result)
     }
 
-    async fn add_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
+    /// Lists the users held by either data store.
+    async fn list_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        let mut uaids = self.primary.list_uaids(prefix).await?;
+        uaids.extend(self.secondary.list_uaids(prefix).await?);
+        uaids.sort();
+        uaids.dedup();
+        Ok(uaids)
+    }
+
+    /// Lists the orphaned UAIDs of either data store.
+    async fn list_orphan_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        let mut uaids = self.primary.list_orphan_uaids(prefix).await?;
+        uaids.extend(self.secondary.list_orphan_uaids(prefix).await?);
+        uaids.sort();
+        uaids.dedup();
+        Ok(uaids)
+    }
+
+    async fn add_channel(&self, uaid: &Uuid, channel: &ChannelRecord) -> DbResult<()> {
         debug!("⚖ getting target");
         let (target, is_primary) = self.allot(uaid).await?;
         debug!("⚖ Adding channel to {}", target.name());
-        let result = target.add_channel(uaid, channel_id).await;
+        let result = target.add_channel(uaid, channel).await;
         if is_primary && self.write_to_secondary {
             let _ = self
                 .secondary
-                .add_channel(uaid, channel_id)
+                .add_channel(uaid, channel)
                 .await
                 .map_err(|e| {
                     self.metrics
@@ -239,7 +563,7 @@ impl DbClient for DualClientImpl {
         result
     }
 
-    async fn add_channels(&self, uaid: &Uuid, channels: HashSet<Uuid>) -> DbResult<()> {
+    async fn add_channels(&self, uaid: &Uuid, channels: Vec<ChannelRecord>) -> DbResult<()> {
         let (target, is_primary) = self.allot(uaid).await?;
         let result = target.add_channels(uaid, channels.clone()).await;
         if is_primary && self.write_to_secondary {
@@ -259,10 +583,50 @@ impl DbClient for DualClientImpl {
     }
 
     async fn get_channels(&self, uaid: &Uuid) -> DbResult<HashSet<Uuid>> {
-        let (target, _is_primary) = self.allot(uaid).await?;
+        let (target, is_primary) = self.allot(uaid).await?;
+        if is_primary && self.shadow_read {
+            let (result, shadow) =
+                join!(target.get_channels(uaid), self.secondary.get_channels(uaid));
+            self.shadow_compare("get_channels", uaid, &result, &shadow, |a, b| a == b);
+            return result;
+        }
         target.get_channels(uaid).await
     }
 
+    async fn get_channel_records(&self, uaid: &Uuid) -> DbResult<Vec<ChannelRecord>> {
+        let (target, is_primary) = self.allot(uaid).await?;
+        if is_primary && self.shadow_read {
+            let (result, shadow) = join!(
+                target.get_channel_records(uaid),
+                self.secondary.get_channel_records(uaid)
+            );
+            self.shadow_compare("get_channel_records", uaid, &result, &shadow, |a, b| {
+                channels_match(a, b)
+            });
+            return result;
+        }
+        target.get_channel_records(uaid).await
+    }
+
+    async fn record_channel_push(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
+        let (target, is_primary) = self.allot(uaid).await?;
+        let result = target.record_channel_push(uaid, channel_id).await;
+        if is_primary && self.write_to_secondary {
+            let _ = self
+                .secondary
+                .record_channel_push(uaid, channel_id)
+                .await
+                .map_err(|e| {
+                    self.metrics
+                        .incr_with_tags("database.dual.error")
+                        .with_tag("func", "record_channel_push")
+                        .send();
+                    e
+                });
+        }
+        result
+    }
+
     async fn remove_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
         let (target, is_primary) = self.allot(uaid).await?;
         let result = target.remove_channel(uaid, channel_id).await?;
@@ -324,6 +688,15 @@ impl DbClient for DualClientImpl {
         target.save_message(uaid, message).await
     }
 
+    async fn get_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<Option<Notification>> {
+        let (target, is_primary) = self.allot(uaid).await?;
+        let result = target.get_message(uaid, sort_key).await?;
+        if result.is_none() && is_primary {
+            return self.secondary.get_message(uaid, sort_key).await;
+        }
+        Ok(result)
+    }
+
     async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()> {
         let (target, is_primary) = self.allot(uaid).await?;
         let result = target.remove_message(uaid, sort_key).await?;
@@ -346,12 +719,50 @@ impl DbClient for DualClientImpl {
         Ok(result)
     }
 
+    async fn remove_messages(&self, uaid: &Uuid, sort_keys: &[String]) -> DbResult<()> {
+        let (target, is_primary) = self.allot(uaid).await?;
+        target.remove_messages(uaid, sort_keys).await?;
+        if is_primary {
+            let _ = self
+                .secondary
+                .remove_messages(uaid, sort_keys)
+                .await
+                .map_err(|e| {
+                    debug!("⚖ Secondary remove_messages error: {:?}", e);
+                    self.metrics
+                        .incr_with_tags("database.dual.error")
+                        .with_tag("func", "remove_messages")
+                        .send();
+                    e
+                });
+        }
+        Ok(())
+    }
+
     async fn fetch_topic_messages(
         &self,
         uaid: &Uuid,
         limit: usize,
     ) -> DbResult<FetchMessageResponse> {
         let (target, is_primary) = self.allot(uaid).await?;
+        if is_primary && self.shadow_read {
+            let (result, shadow) = join!(
+                target.fetch_topic_messages(uaid, limit),
+                self.secondary.fetch_topic_messages(uaid, limit)
+            );
+            self.shadow_compare(
+                "fetch_topic_messages",
+                uaid,
+                &result,
+                &shadow,
+                messages_match,
+            );
+            let result = result?;
+            if result.messages.is_empty() {
+                return shadow;
+            }
+            return Ok(result);
+        }
         let result = target.fetch_topic_messages(uaid, limit).await?;
         if result.messages.is_empty() && is_primary {
             return self.secondary.fetch_topic_messages(uaid, limit).await;
@@ -366,6 +777,25 @@ impl DbClient for DualClientImpl {
         limit: usize,
     ) -> DbResult<FetchMessageResponse> {
         let (target, is_primary) = self.allot(uaid).await?;
+        if is_primary && self.shadow_read {
+            let (result, shadow) = join!(
+                target.fetch_timestamp_messages(uaid, timestamp, limit),
+                self.secondary
+                    .fetch_timestamp_messages(uaid, timestamp, limit)
+            );
+            self.shadow_compare(
+                "fetch_timestamp_messages",
+                uaid,
+                &result,
+                &shadow,
+                messages_match,
+            );
+            let result = result?;
+            if result.messages.is_empty() {
+                return shadow;
+            }
+            return Ok(result);
+        }
         let result = target
             .fetch_timestamp_messages(uaid, timestamp, limit)
             .await?;
@@ -430,13 +860,14 @@ impl DbClient for DualClientImpl {
     }
 }
 
-#[cfg(all(test, feature = "bigtable", feature = "dynamodb"))]
+#[cfg(test)]
 mod test {
     use super::*;
     use cadence::{NopMetricSink, StatsdClient};
     use serde_json::json;
     use std::str::FromStr;
 
+    #[cfg(all(feature = "bigtable", feature = "dynamodb"))]
     fn test_args(median: Option<&str>) -> String {
         json!({
             "primary": {
@@ -460,8 +891,32 @@ mod test {
         .to_string()
     }
 
+    /// Settings for a dual client wrapping two in-memory data stores
+    fn memory_args(median: Option<&str>, write_to_secondary: bool) -> String {
+        json!({
+            "primary": {"dsn": "memory://", "db_settings": ""},
+            "secondary": {"dsn": "memory://", "db_settings": ""},
+            "median": median.to_owned(),
+            "write_to_secondary": write_to_secondary,
+        })
+        .to_string()
+    }
+
+    fn new_dual(db_settings: String) -> DbResult<DualClientImpl> {
+        let metrics = Arc::new(StatsdClient::builder("", NopMetricSink).build());
+        DualClientImpl::new(
+            metrics,
+            &DbSettings {
+                dsn: Some("dual".to_owned()),
+                db_settings,
+                ..Default::default()
+            },
+        )
+    }
+
     /// This test checks the dual parser, but also serves as a bit of
     /// documentation for how the db_settings argument should be structured
+    #[cfg(all(feature = "bigtable", feature = "dynamodb"))]
     #[test]
     fn arg_parsing() -> DbResult<()> {
         let arg_str = test_args(None);
@@ -477,6 +932,7 @@ mod test {
         let dual_settings = DbSettings {
             dsn: Some("dual".to_owned()),
             db_settings: arg_str,
+            ..Default::default()
         };
         let metrics = Arc::new(StatsdClient::builder("", NopMetricSink).build());
 
@@ -484,12 +940,13 @@ mod test {
         // Specify "BIGTABLE_EMULATOR_HOST" to skip credential check for emulator.
         let dual = DualClientImpl::new(metrics, &dual_settings)?;
 
-        // for sanity sake, make sure the passed message_family isn't the default value.
-        assert_eq!(&dual.primary.settings.message_family, "messageFamily");
+        assert_eq!(dual.primary.name(), "Bigtable");
+        assert_eq!(dual.secondary.name(), "DynamoDb");
 
         Ok(())
     }
 
+    #[cfg(all(feature = "bigtable", feature = "dynamodb"))]
     #[actix_rt::test]
     async fn allocation() -> DbResult<()> {
         let arg_str = test_args(Some("0A"));
@@ -497,6 +954,7 @@ mod test {
         let dual_settings = DbSettings {
             dsn: Some("dual".to_owned()),
             db_settings: arg_str,
+            ..Default::default()
         };
         let dual = DualClientImpl::new(metrics, &dual_settings)?;
 
@@ -512,4 +970,256 @@ mod test {
         assert!(!is_primary);
         Ok(())
     }
+
+    #[test]
+    fn invalid_dsn() {
+        let nested = json!({
+            "primary": {"dsn": "dual", "db_settings": memory_args(None, false)},
+            "secondary": {"dsn": "memory://", "db_settings": ""},
+        })
+        .to_string();
+        assert!(new_dual(nested).is_err());
+        let unknown = json!({
+            "primary": {"dsn": "memory://", "db_settings": ""},
+            "secondary": {"dsn": "unknown://", "db_settings": ""},
+        })
+        .to_string();
+        assert!(new_dual(unknown).is_err());
+    }
+
+    #[test]
+    fn migrate_on_read_with_write_to_secondary() {
+        // write_to_secondary defaults to true
+        let db_settings = json!({
+            "primary": {"dsn": "memory://", "db_settings": ""},
+            "secondary": {"dsn": "memory://", "db_settings": ""},
+            "migrate_on_read": true,
+        })
+        .to_string();
+        assert!(new_dual(db_settings).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn memory_allocation() -> DbResult<()> {
+        let dual = new_dual(memory_args(Some("0A"), false))?;
+        let low_uaid = Uuid::from_str("04DDDDDD-2040-4b4d-be3d-a340fc2d15a6").unwrap();
+        let hi_uaid = Uuid::from_str("0BDDDDDD-2040-4b4d-be3d-a340fc2d15a6").unwrap();
+
+        // Users above the median remain on the secondary
+        dual.add_user(&User {
+            uaid: hi_uaid,
+            ..Default::default()
+        })
+        .await?;
+        assert!(dual.secondary.get_user(&hi_uaid).await?.is_some());
+        assert!(dual.primary.get_user(&hi_uaid).await?.is_none());
+
+        // Users below it are copied from the secondary to the primary on read
+        let chid = Uuid::new_v4();
+        dual.secondary
+            .add_user(&User {
+                uaid: low_uaid,
+                ..Default::default()
+            })
+            .await?;
+        dual.secondary
+            .add_channel(&low_uaid, &ChannelRecord::from(chid))
+            .await?;
+        assert!(dual.get_user(&low_uaid).await?.is_some());
+        assert!(dual.primary.get_user(&low_uaid).await?.is_some());
+        assert!(dual.primary.get_channels(&low_uaid).await?.contains(&chid));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn memory_write_to_secondary() -> DbResult<()> {
+        let uaid = Uuid::new_v4();
+        let chid = Uuid::new_v4();
+
+        let dual = new_dual(memory_args(None, true))?;
+        dual.add_user(&User {
+            uaid,
+            ..Default::default()
+        })
+        .await?;
+        dual.add_channel(&uaid, &ChannelRecord::from(chid)).await?;
+        assert!(dual.secondary.get_user(&uaid).await?.is_some());
+        assert!(dual.secondary.get_channels(&uaid).await?.contains(&chid));
+
+        let dual = new_dual(memory_args(None, false))?;
+        dual.add_user(&User {
+            uaid,
+            ..Default::default()
+        })
+        .await?;
+        dual.add_channel(&uaid, &ChannelRecord::from(chid)).await?;
+        assert!(dual.secondary.get_user(&uaid).await?.is_none());
+        assert!(dual.secondary.get_channels(&uaid).await?.is_empty());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn memory_migrate_on_read() -> DbResult<()> {
+        let uaid = Uuid::new_v4();
+        let chid = Uuid::new_v4();
+        let db_settings = json!({
+            "primary": {"dsn": "memory://", "db_settings": ""},
+            "secondary": {"dsn": "memory://", "db_settings": ""},
+            "write_to_secondary": false,
+            "migrate_on_read": true,
+        })
+        .to_string();
+        let dual = new_dual(db_settings)?;
+
+        dual.secondary
+            .add_user(&User {
+                uaid,
+                ..Default::default()
+            })
+            .await?;
+        dual.secondary
+            .add_channel(&uaid, &ChannelRecord::from(chid))
+            .await?;
+        let message = Notification {
+            channel_id: chid,
+            version: "test".to_owned(),
+            ttl: 300,
+            timestamp: sec_since_epoch(),
+            sortkey_timestamp: Some(crate::util::ms_since_epoch()),
+            ..Default::default()
+        };
+        dual.secondary.save_message(&uaid, message.clone()).await?;
+
+        assert!(dual.get_user(&uaid).await?.is_some());
+        // The messages are moved in the background
+        for _ in 0..100 {
+            if dual.secondary.get_user(&uaid).await?.is_none() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+
+        // Everything has moved to the primary
+        assert!(dual.primary.get_user(&uaid).await?.is_some());
+        assert!(dual.primary.get_channels(&uaid).await?.contains(&chid));
+        let fetched = dual
+            .primary
+            .fetch_timestamp_messages(&uaid, None, 0)
+            .await?;
+        assert_eq!(fetched.messages.len(), 1);
+        assert_eq!(fetched.messages[0].chidmessageid(), message.chidmessageid());
+
+        // and is gone from the secondary
+        assert!(dual.secondary.get_user(&uaid).await?.is_none());
+        assert!(dual.secondary.get_channels(&uaid).await?.is_empty());
+        assert!(dual
+            .secondary
+            .fetch_timestamp_messages(&uaid, None, 0)
+            .await?
+            .messages
+            .is_empty());
+        Ok(())
+    }
+
+    /// A message acked after being read from the secondary, but before being
+    /// saved to the primary, isn't left on the primary
+    #[actix_rt::test]
+    async fn ack_during_migration() -> DbResult<()> {
+        let uaid = Uuid::new_v4();
+        let message = Notification {
+            channel_id: Uuid::new_v4(),
+            version: "test".to_owned(),
+            ttl: 300,
+            topic: Some("topic".to_owned()),
+            timestamp: sec_since_epoch(),
+            ..Default::default()
+        };
+        let mut secondary = crate::db::mock::MockDbClient::new();
+        let mut reads = 0;
+        let read = message.clone();
+        secondary
+            .expect_fetch_topic_messages()
+            .times(2)
+            .returning(move |_, _| {
+                reads += 1;
+                // Acked once the first read has returned
+                let messages = if reads == 1 {
+                    vec![read.clone()]
+                } else {
+                    vec![]
+                };
+                Ok(FetchMessageResponse {
+                    messages,
+                    timestamp: None,
+                })
+            });
+        secondary
+            .expect_fetch_timestamp_messages()
+            .times(2)
+            .returning(|_, _, _| Ok(FetchMessageResponse::default()));
+
+        let metrics = Arc::new(StatsdClient::builder("", NopMetricSink).build());
+        let primary = client_from_settings(
+            metrics.clone(),
+            &DbSettings {
+                dsn: Some("memory://".to_owned()),
+                ..Default::default()
+            },
+        )?;
+        let dual = DualClientImpl {
+            primary,
+            secondary: Box::new(Arc::new(secondary)),
+            write_to_secondary: false,
+            median: None,
+            migrate_on_read: true,
+            shadow_read: false,
+            metrics,
+        };
+
+        assert!(dual.copy_messages(&uaid).await?.is_empty());
+        assert!(dual
+            .primary
+            .fetch_topic_messages(&uaid, 0)
+            .await?
+            .messages
+            .is_empty());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn memory_shadow_read() -> DbResult<()> {
+        let uaid = Uuid::new_v4();
+        let db_settings = json!({
+            "primary": {"dsn": "memory://", "db_settings": ""},
+            "secondary": {"dsn": "memory://", "db_settings": ""},
+            "write_to_secondary": false,
+            "shadow_read": true,
+        })
+        .to_string();
+        let dual = new_dual(db_settings)?;
+
+        let user = User {
+            uaid,
+            node_id: Some("primary".to_owned()),
+            ..Default::default()
+        };
+        dual.add_user(&user).await?;
+        dual.secondary
+            .add_user(&User {
+                node_id: Some("secondary".to_owned()),
+                ..user.clone()
+            })
+            .await?;
+        dual.add_channel(&uaid, &ChannelRecord::from(Uuid::new_v4()))
+            .await?;
+
+        // The primary's results are returned despite the differences
+        assert_eq!(dual.get_user(&uaid).await?, Some(user));
+        assert_eq!(dual.get_channels(&uaid).await?.len(), 1);
+        assert!(!users_match(
+            &dual.primary.get_user(&uaid).await?,
+            &dual.secondary.get_user(&uaid).await?
+        ));
+        Ok(())
+    }
 }
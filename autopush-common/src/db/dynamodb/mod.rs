@@ -1,30 +1,36 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fmt::{Debug, Display};
 use std::result::Result as StdResult;
 use std::sync::Arc;
 
 use crate::db::client::DbClient;
+use crate::db::compression::Compressor;
 use crate::db::dynamodb::retry::{
     retry_policy, retryable_batchwriteitem_error, retryable_delete_error,
     retryable_describe_table_error, retryable_getitem_error, retryable_putitem_error,
-    retryable_query_error, retryable_updateitem_error,
+    retryable_query_error, retryable_scan_error, retryable_transactwriteitems_error,
+    retryable_updateitem_error,
 };
+use crate::db::encryption::KeyRing;
 use crate::db::error::{DbError, DbResult};
+use crate::db::quota::MessageQuota;
 use crate::db::{
-    client::FetchMessageResponse, DbSettings, NotificationRecord, User, MAX_CHANNEL_TTL, MAX_EXPIRY,
+    client::FetchMessageResponse, ChannelRecord, DbSettings, NotificationRecord, User,
+    MAX_CHANNEL_TTL, MAX_EXPIRY,
 };
 use crate::notification::Notification;
 use crate::util::sec_since_epoch;
 
 use async_trait::async_trait;
-use cadence::{CountedExt, StatsdClient};
+use cadence::{Counted, CountedExt, StatsdClient};
 use chrono::Utc;
 use rusoto_core::credential::StaticProvider;
 use rusoto_core::{HttpClient, Region, RusotoError};
 use rusoto_dynamodb::{
-    AttributeValue, BatchWriteItemInput, DeleteItemInput, DescribeTableError, DescribeTableInput,
-    DynamoDb, DynamoDbClient, GetItemInput, ListTablesInput, PutItemInput, PutRequest, QueryInput,
+    AttributeValue, BatchWriteItemInput, DeleteItemInput, DeleteRequest, DescribeTableError,
+    DescribeTableInput, DynamoDb, DynamoDbClient, GetItemInput, ListTablesInput, Put, PutItemInput,
+    PutRequest, QueryInput, ScanInput, TransactWriteItem, TransactWriteItemsInput, Update,
     UpdateItemError, UpdateItemInput, WriteRequest,
 };
 use serde::{Deserialize, Serialize};
@@ -42,6 +48,9 @@ pub struct DynamoDbSettings {
     pub message_table: String,
     #[serde(default)]
     pub db_routing_table: Option<String>,
+    /// Limits on the number of stored messages
+    #[serde(default)]
+    pub quota: MessageQuota,
 }
 
 impl TryFrom<&str> for DynamoDbSettings {
@@ -57,6 +66,8 @@ pub struct DdbClientImpl {
     db_client: DynamoDbClient,
     metrics: Arc<StatsdClient>,
     settings: DynamoDbSettings,
+    key_ring: KeyRing,
+    compressor: Compressor,
 }
 
 impl DdbClientImpl {
@@ -76,10 +87,14 @@ impl DdbClientImpl {
         };
 
         let settings = DynamoDbSettings::try_from(db_settings.db_settings.as_ref())?;
+        let key_ring = KeyRing::try_from(db_settings.key_ring.as_ref())?;
+        let compressor = Compressor::new(metrics.clone(), db_settings);
         Ok(Self {
             db_client,
             metrics,
             settings,
+            key_ring,
+            compressor,
         })
     }
 
@@ -109,6 +124,97 @@ impl DdbClientImpl {
 
         Ok(["CREATING", "UPDATING", "ACTIVE"].contains(&status.as_str()))
     }
+
+    /// Read the user's channel item. Channel IDs (and their records) are
+    /// stored in a special row in the message table, where
+    /// chidmessageid = " "
+    async fn get_channels_item(
+        &self,
+        uaid: &Uuid,
+    ) -> DbResult<Option<HashMap<String, AttributeValue>>> {
+        let input = GetItemInput {
+            table_name: self.settings.message_table.clone(),
+            consistent_read: Some(true),
+            key: ddb_item! {
+                uaid: s => uaid.simple().to_string(),
+                chidmessageid: s => " ".to_string()
+            },
+            ..Default::default()
+        };
+
+        Ok(retry_policy()
+            .retry_if(
+                || self.db_client.get_item(input.clone()),
+                retryable_getitem_error(self.metrics.clone()),
+            )
+            .await?
+            .item)
+    }
+
+    /// Scan the table for the (sorted, unique) UAIDs starting with `prefix`
+    async fn scan_uaids(&self, table_name: &str, prefix: &str) -> DbResult<Vec<Uuid>> {
+        let mut uaids = Vec::new();
+        let mut input = ScanInput {
+            table_name: table_name.to_owned(),
+            projection_expression: Some("uaid".to_string()),
+            filter_expression: Some("begins_with(uaid, :prefix)".to_string()),
+            expression_attribute_values: Some(hashmap! {
+                ":prefix".to_string() => val!(S => prefix)
+            }),
+            ..Default::default()
+        };
+        loop {
+            let output = retry_policy()
+                .retry_if(
+                    || self.db_client.scan(input.clone()),
+                    retryable_scan_error(self.metrics.clone()),
+                )
+                .await?;
+            for item in output.items.unwrap_or_default() {
+                if let Some(uaid) = item.get("uaid").and_then(|v| v.s.as_ref()) {
+                    uaids.push(Uuid::parse_str(uaid).map_err(|e| DbError::General(e.to_string()))?);
+                }
+            }
+            match output.last_evaluated_key {
+                Some(key) if !key.is_empty() => input.exclusive_start_key = Some(key),
+                _ => break,
+            }
+        }
+        uaids.sort();
+        uaids.dedup();
+        Ok(uaids)
+    }
+
+    /// Run a query, following its pages until `limit` items are read.
+    /// `limit=0` for all items.
+    async fn query_items(
+        &self,
+        mut input: QueryInput,
+        limit: usize,
+    ) -> DbResult<Vec<HashMap<String, AttributeValue>>> {
+        let mut items = Vec::new();
+        loop {
+            // DynamoDB rejects a `Limit` of 0, so only set it when limited
+            if limit > 0 {
+                input.limit = Some((limit - items.len()) as i64);
+            }
+            let output = retry_policy()
+                .retry_if(
+                    || self.db_client.query(input.clone()),
+                    retryable_query_error(self.metrics.clone()),
+                )
+                .await?;
+            items.extend(output.items.unwrap_or_default());
+            if limit > 0 && items.len() >= limit {
+                break;
+            }
+            match output.last_evaluated_key {
+                Some(key) if !key.is_empty() => input.exclusive_start_key = Some(key),
+                _ => break,
+            }
+        }
+        Ok(items)
+    }
 }
 
 /// Like Result::ok, convert from Result<T, E> to Option<T> but applying a
@@ -126,6 +232,59 @@ where
     }
 }
 
+/// The name of the attribute holding a channel's JSON encoded [ChannelRecord]
+/// within the user's channel item (where chidmessageid = " ")
+fn channel_record_attribute(channel_id: &Uuid) -> String {
+    format!("chrecord:{}", channel_id.as_hyphenated())
+}
+
+/// An update expression along with its attribute names and values
+type UpdateExpression = (
+    String,
+    HashMap<String, String>,
+    HashMap<String, AttributeValue>,
+);
+
+/// Return the update expression, along with its attribute names and values,
+/// that adds the channels (and their records) to the user's channel item
+fn add_channels_update(channels: &[ChannelRecord]) -> DbResult<UpdateExpression> {
+    let channel_ids: Vec<Uuid> = channels.iter().map(|channel| channel.channel_id).collect();
+    let mut expression = "ADD chids :channel_ids SET expiry = :expiry".to_owned();
+    let mut names = HashMap::new();
+    let mut values = hashmap! {
+        ":channel_ids".to_string() => val!(SS => channel_ids),
+        ":expiry".to_string() => val!(N => sec_since_epoch() + MAX_CHANNEL_TTL)
+    };
+    for (i, channel) in channels.iter().enumerate() {
+        let record = serde_json::to_string(channel)
+            .map_err(|e| DbError::Serialization(format!("Could not serialize channel: {e:?}")))?;
+        expression.push_str(&format!(", #record{i} = :record{i}"));
+        names.insert(
+            format!("#record{i}"),
+            channel_record_attribute(&channel.channel_id),
+        );
+        values.insert(format!(":record{i}"), val!(S => record));
+    }
+    Ok((expression, names, values))
+}
+
+/// Convert the channel's attribute within the user's channel item into a
+/// [ChannelRecord]. Channels added before their metadata was recorded lack
+/// one.
+fn item_to_channel(
+    item: &HashMap<String, AttributeValue>,
+    channel_id: Uuid,
+) -> DbResult<ChannelRecord> {
+    match item
+        .get(&channel_record_attribute(&channel_id))
+        .and_then(|value| value.s.as_ref())
+    {
+        Some(record) => serde_json::from_str(record)
+            .map_err(|e| DbError::Serialization(format!("Could not deserialize channel: {e:?}"))),
+        None => Ok(ChannelRecord::from(channel_id)),
+    }
+}
+
 /// Log/metric errors during conversions to Notification
 fn conversion_err<E, F>(metrics: &StatsdClient, err: E, item: F, name: &'static str)
 where
@@ -159,6 +318,54 @@ impl DbClient for DdbClientImpl {
         Ok(())
     }
 
+    /// Written as a single transaction across the router and message tables
+    async fn add_user_with_channels(
+        &self,
+        user: &User,
+        channels: Vec<ChannelRecord>,
+    ) -> DbResult<()> {
+        if channels.is_empty() {
+            return self.add_user(user).await;
+        }
+        let (update_expression, names, values) = add_channels_update(&channels)?;
+        let input = TransactWriteItemsInput {
+            transact_items: vec![
+                TransactWriteItem {
+                    put: Some(Put {
+                        table_name: self.settings.router_table.clone(),
+                        item: serde_dynamodb::to_hashmap(user)?,
+                        condition_expression: Some("attribute_not_exists(uaid)".to_string()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                TransactWriteItem {
+                    update: Some(Update {
+                        table_name: self.settings.message_table.clone(),
+                        key: ddb_item! {
+                            uaid: s => user.uaid.simple().to_string(),
+                            chidmessageid: s => " ".to_string()
+                        },
+                        update_expression,
+                        expression_attribute_names: Some(names),
+                        expression_attribute_values: Some(values),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        retry_policy()
+            .retry_if(
+                || self.db_client.transact_write_items(input.clone()),
+                retryable_transactwriteitems_error(self.metrics.clone()),
+            )
+            .await?;
+        Ok(())
+    }
+
     async fn update_user(&self, user: &mut User) -> DbResult<bool> {
         let mut user_map = serde_dynamodb::to_hashmap(&user)?;
         user_map.remove("uaid");
@@ -244,18 +451,34 @@ impl DbClient for DdbClientImpl {
         Ok(())
     }
 
-    async fn add_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
+    /// Note: this performs a full (paginated) Scan of the router table.
+    async fn list_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        self.scan_uaids(&self.settings.router_table, prefix).await
+    }
+
+    /// Note: this performs a full (paginated) Scan of both the router and
+    /// message tables.
+    async fn list_orphan_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        let users: HashSet<Uuid> = self.list_uaids(prefix).await?.into_iter().collect();
+        let mut uaids = self
+            .scan_uaids(&self.settings.message_table, prefix)
+            .await?;
+        uaids.retain(|uaid| !users.contains(uaid));
+        Ok(uaids)
+    }
+
+    async fn add_channel(&self, uaid: &Uuid, channel: &ChannelRecord) -> DbResult<()> {
+        let (update_expression, names, values) =
+            add_channels_update(std::slice::from_ref(channel))?;
         let input = UpdateItemInput {
             table_name: self.settings.message_table.clone(),
             key: ddb_item! {
                 uaid: s => uaid.simple().to_string(),
                 chidmessageid: s => " ".to_string()
             },
-            update_expression: Some("ADD chids :channel_id SET expiry = :expiry".to_string()),
-            expression_attribute_values: Some(hashmap! {
-                ":channel_id".to_string() => val!(SS => Some(channel_id)),
-                ":expiry".to_string() => val!(N => sec_since_epoch() + MAX_CHANNEL_TTL)
-            }),
+            update_expression: Some(update_expression),
+            expression_attribute_names: Some(names),
+            expression_attribute_values: Some(values),
             ..Default::default()
         };
 
@@ -269,36 +492,18 @@ impl DbClient for DdbClientImpl {
     }
 
     /// Hopefully, this is never called. It is provided for completion sake.
-    async fn add_channels(&self, uaid: &Uuid, channels: HashSet<Uuid>) -> DbResult<()> {
-        for channel_id in channels {
-            self.add_channel(uaid, &channel_id).await?;
+    async fn add_channels(&self, uaid: &Uuid, channels: Vec<ChannelRecord>) -> DbResult<()> {
+        for channel in channels {
+            self.add_channel(uaid, &channel).await?;
         }
         Ok(())
     }
 
     async fn get_channels(&self, uaid: &Uuid) -> DbResult<HashSet<Uuid>> {
-        // Channel IDs are stored in a special row in the message table, where
-        // chidmessageid = " "
-        let input = GetItemInput {
-            table_name: self.settings.message_table.clone(),
-            consistent_read: Some(true),
-            key: ddb_item! {
-                uaid: s => uaid.simple().to_string(),
-                chidmessageid: s => " ".to_string()
-            },
-            ..Default::default()
-        };
-
-        let output = retry_policy()
-            .retry_if(
-                || self.db_client.get_item(input.clone()),
-                retryable_getitem_error(self.metrics.clone()),
-            )
-            .await?;
-
         // The channel IDs are in the notification's `chids` field
-        let channels = output
-            .item
+        let channels = self
+            .get_channels_item(uaid)
+            .await?
             // Deserialize the notification
             .map(serde_dynamodb::from_hashmap::<NotificationRecord, _>)
             .transpose()?
@@ -315,6 +520,60 @@ impl DbClient for DdbClientImpl {
         Ok(channels)
     }
 
+    async fn get_channel_records(&self, uaid: &Uuid) -> DbResult<Vec<ChannelRecord>> {
+        let Some(item) = self.get_channels_item(uaid).await? else {
+            return Ok(Vec::new());
+        };
+        item.get("chids")
+            .and_then(|value| value.ss.as_ref())
+            .into_iter()
+            .flatten()
+            .filter_map(|s| Uuid::parse_str(s).ok())
+            .map(|channel_id| item_to_channel(&item, channel_id))
+            .collect()
+    }
+
+    /// The channel's record is read and then rewritten, so concurrent pushes
+    /// may be undercounted
+    async fn record_channel_push(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
+        let Some(item) = self.get_channels_item(uaid).await? else {
+            return Ok(());
+        };
+        let mut channel = item_to_channel(&item, *channel_id)?;
+        channel.record_push(sec_since_epoch());
+        let record = serde_json::to_string(&channel)
+            .map_err(|e| DbError::Serialization(format!("Could not serialize channel: {e:?}")))?;
+        let input = UpdateItemInput {
+            table_name: self.settings.message_table.clone(),
+            key: ddb_item! {
+                uaid: s => uaid.simple().to_string(),
+                chidmessageid: s => " ".to_string()
+            },
+            update_expression: Some("SET #record = :record".to_string()),
+            condition_expression: Some("contains(chids, :channel_id)".to_string()),
+            expression_attribute_names: Some(hashmap! {
+                "#record".to_string() => channel_record_attribute(channel_id)
+            }),
+            expression_attribute_values: Some(hashmap! {
+                ":record".to_string() => val!(S => record),
+                ":channel_id".to_string() => val!(S => channel_id)
+            }),
+            ..Default::default()
+        };
+
+        let result = retry_policy()
+            .retry_if(
+                || self.db_client.update_item(input.clone()),
+                retryable_updateitem_error(self.metrics.clone()),
+            )
+            .await;
+        match result {
+            // The channel was removed (or never existed)
+            Ok(_) | Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     async fn remove_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
         let input = UpdateItemInput {
             table_name: self.settings.message_table.clone(),
@@ -322,7 +581,12 @@ impl DbClient for DdbClientImpl {
                 uaid: s => uaid.simple().to_string(),
                 chidmessageid: s => " ".to_string()
             },
-            update_expression: Some("DELETE chids :channel_id SET expiry = :expiry".to_string()),
+            update_expression: Some(
+                "DELETE chids :channel_id REMOVE #record SET expiry = :expiry".to_string(),
+            ),
+            expression_attribute_names: Some(hashmap! {
+                "#record".to_string() => channel_record_attribute(channel_id)
+            }),
             expression_attribute_values: Some(hashmap! {
                 ":channel_id".to_string() => val!(SS => Some(channel_id)),
                 ":expiry".to_string() => val!(N => sec_since_epoch() + MAX_CHANNEL_TTL)
@@ -395,30 +659,21 @@ impl DbClient for DdbClientImpl {
             expression_attribute_values: Some(attr_values),
             table_name: self.settings.message_table.to_string(),
             consistent_read: Some(true),
-            limit: Some(limit as i64),
             ..Default::default()
         };
 
-        let output = retry_policy()
-            .retry_if(
-                || self.db_client.query(input.clone()),
-                retryable_query_error(self.metrics.clone()),
-            )
-            .await?;
-
-        let mut notifs: Vec<NotificationRecord> = output.items.map_or_else(Vec::new, |items| {
-            debug!("Got response of: {:?}", items);
-            items
-                .into_iter()
-                .inspect(|i| debug!("Item: {:?}", i))
-                .filter_map(|item| {
-                    let item2 = item.clone();
-                    ok_or_inspect(serde_dynamodb::from_hashmap(item), |e| {
-                        conversion_err(&self.metrics, e, item2, "serde_dynamodb_from_hashmap")
-                    })
+        let items = self.query_items(input, limit).await?;
+        debug!("Got response of: {:?}", items);
+        let mut notifs: Vec<NotificationRecord> = items
+            .into_iter()
+            .inspect(|i| debug!("Item: {:?}", i))
+            .filter_map(|item| {
+                let item2 = item.clone();
+                ok_or_inspect(serde_dynamodb::from_hashmap(item), |e| {
+                    conversion_err(&self.metrics, e, item2, "serde_dynamodb_from_hashmap")
                 })
-                .collect()
-        });
+            })
+            .collect();
         if notifs.is_empty() {
             return Ok(Default::default());
         }
@@ -431,7 +686,7 @@ impl DbClient for DdbClientImpl {
             .into_iter()
             .filter_map(|ddb_notif| {
                 let ddb_notif2 = ddb_notif.clone();
-                ok_or_inspect(ddb_notif.into_notif(), |e| {
+                ok_or_inspect(ddb_notif.into_notif(&self.key_ring), |e| {
                     conversion_err(&self.metrics, e, ddb_notif2, "into_notif")
                 })
             })
@@ -464,35 +719,26 @@ impl DbClient for DdbClientImpl {
             expression_attribute_values: Some(attr_values),
             table_name: self.settings.message_table.to_string(),
             consistent_read: Some(true),
-            limit: Some(limit as i64),
             ..Default::default()
         };
 
-        let output = retry_policy()
-            .retry_if(
-                || self.db_client.query(input.clone()),
-                retryable_query_error(self.metrics.clone()),
-            )
-            .await?;
-
-        let messages = output.items.map_or_else(Vec::new, |items| {
-            debug!("Got response of: {:?}", items);
-            items
-                .into_iter()
-                .filter_map(|item| {
-                    let item2 = item.clone();
-                    ok_or_inspect(serde_dynamodb::from_hashmap(item), |e| {
-                        conversion_err(&self.metrics, e, item2, "serde_dynamodb_from_hashmap")
-                    })
+        let items = self.query_items(input, limit).await?;
+        debug!("Got response of: {:?}", items);
+        let messages = items
+            .into_iter()
+            .filter_map(|item| {
+                let item2 = item.clone();
+                ok_or_inspect(serde_dynamodb::from_hashmap(item), |e| {
+                    conversion_err(&self.metrics, e, item2, "serde_dynamodb_from_hashmap")
                 })
-                .filter_map(|ddb_notif: NotificationRecord| {
-                    let ddb_notif2 = ddb_notif.clone();
-                    ok_or_inspect(ddb_notif.into_notif(), |e| {
-                        conversion_err(&self.metrics, e, ddb_notif2, "into_notif")
-                    })
+            })
+            .filter_map(|ddb_notif: NotificationRecord| {
+                let ddb_notif2 = ddb_notif.clone();
+                ok_or_inspect(ddb_notif.into_notif(&self.key_ring), |e| {
+                    conversion_err(&self.metrics, e, ddb_notif2, "into_notif")
                 })
-                .collect()
-        });
+            })
+            .collect();
         let timestamp = messages.iter().filter_map(|m| m.sortkey_timestamp).max();
         Ok(FetchMessageResponse {
             timestamp,
@@ -530,9 +776,18 @@ impl DbClient for DdbClientImpl {
     }
 
     async fn save_message(&self, uaid: &Uuid, message: Notification) -> DbResult<()> {
+        self.settings
+            .quota
+            .enforce(self, &self.metrics, uaid, std::slice::from_ref(&message))
+            .await?;
         let topic = message.topic.is_some().to_string();
         let input = PutItemInput {
-            item: serde_dynamodb::to_hashmap(&NotificationRecord::from_notif(uaid, message))?,
+            item: serde_dynamodb::to_hashmap(&NotificationRecord::from_notif(
+                uaid,
+                message,
+                &self.key_ring,
+                &self.compressor,
+            )?)?,
             table_name: self.settings.message_table.clone(),
             ..Default::default()
         };
@@ -552,15 +807,25 @@ impl DbClient for DdbClientImpl {
     }
 
     async fn save_messages(&self, uaid: &Uuid, messages: Vec<Notification>) -> DbResult<()> {
-        let put_items: Vec<WriteRequest> = messages
+        self.settings
+            .quota
+            .enforce(self, &self.metrics, uaid, &messages)
+            .await?;
+        let records = messages
             .into_iter()
-            .filter_map(|n| {
+            .map(|n| {
                 // eventually include `internal` if `meta` defined.
                 self.metrics
                     .incr_with_tags("notification.message.stored")
                     .with_tag("topic", &n.topic.is_some().to_string())
                     .send();
-                serde_dynamodb::to_hashmap(&NotificationRecord::from_notif(uaid, n))
+                NotificationRecord::from_notif(uaid, n, &self.key_ring, &self.compressor)
+            })
+            .collect::<DbResult<Vec<_>>>()?;
+        let put_items: Vec<WriteRequest> = records
+            .iter()
+            .filter_map(|record| {
+                serde_dynamodb::to_hashmap(record)
                     .ok()
                     .map(|hm| WriteRequest {
                         put_request: Some(PutRequest { item: hm }),
@@ -582,6 +847,36 @@ impl DbClient for DdbClientImpl {
         Ok(())
     }
 
+    /// DynamoDB only removes expired items eventually, so they're filtered out
+    /// here
+    async fn get_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<Option<Notification>> {
+        let input = GetItemInput {
+            table_name: self.settings.message_table.clone(),
+            consistent_read: Some(true),
+            key: ddb_item! {
+                uaid: s => uaid.simple().to_string(),
+                chidmessageid: s => sort_key.to_owned()
+            },
+            ..Default::default()
+        };
+
+        let Some(item) = retry_policy()
+            .retry_if(
+                || self.db_client.get_item(input.clone()),
+                retryable_getitem_error(self.metrics.clone()),
+            )
+            .await?
+            .item
+        else {
+            return Ok(None);
+        };
+        let record: NotificationRecord = serde_dynamodb::from_hashmap(item)?;
+        let message = record
+            .into_notif(&self.key_ring)
+            .map_err(|e| DbError::Integrity(format!("get_message expected a notification: {e}")))?;
+        Ok((!message.expired(sec_since_epoch())).then_some(message))
+    }
+
     async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()> {
         let input = DeleteItemInput {
             table_name: self.settings.message_table.clone(),
@@ -604,6 +899,55 @@ impl DbClient for DdbClientImpl {
         Ok(())
     }
 
+    /// Delete the messages via BatchWriteItem, in batches of its maximum of
+    /// 25 items
+    async fn remove_messages(&self, uaid: &Uuid, sort_keys: &[String]) -> DbResult<()> {
+        for chunk in sort_keys.chunks(25) {
+            let delete_items: Vec<WriteRequest> = chunk
+                .iter()
+                .map(|sort_key| WriteRequest {
+                    put_request: None,
+                    delete_request: Some(DeleteRequest {
+                        key: ddb_item! {
+                            uaid: s => uaid.simple().to_string(),
+                            chidmessageid: s => sort_key.to_owned()
+                        },
+                    }),
+                })
+                .collect();
+            let mut request_items =
+                hashmap! { self.settings.message_table.clone() => delete_items };
+            // Retry any items DynamoDB could not process (e.g. due to
+            // throttling) a limited number of times
+            for _ in 0..3 {
+                let batch_input = BatchWriteItemInput {
+                    request_items,
+                    ..Default::default()
+                };
+                let output = retry_policy()
+                    .retry_if(
+                        || self.db_client.batch_write_item(batch_input.clone()),
+                        retryable_batchwriteitem_error(self.metrics.clone()),
+                    )
+                    .await?;
+                request_items = output.unprocessed_items.unwrap_or_default();
+                if request_items.is_empty() {
+                    break;
+                }
+            }
+            let unprocessed = request_items.values().map(Vec::len).sum::<usize>();
+            if unprocessed > 0 {
+                return Err(DbError::General(format!(
+                    "BatchWriteItem left {unprocessed} message(s) unprocessed"
+                )));
+            }
+            self.metrics
+                .count_with_tags("notification.message.deleted", chunk.len() as i64)
+                .send();
+        }
+        Ok(())
+    }
+
     async fn router_table_exists(&self) -> DbResult<bool> {
         self.table_exists(self.settings.router_table.clone()).await
     }
@@ -3,7 +3,7 @@ use cadence::{CountedExt, StatsdClient};
 use rusoto_core::RusotoError;
 use rusoto_dynamodb::{
     BatchWriteItemError, DeleteItemError, DescribeTableError, GetItemError, PutItemError,
-    QueryError, UpdateItemError,
+    QueryError, ScanError, TransactWriteItemsError, UpdateItemError,
 };
 use std::sync::Arc;
 
@@ -29,6 +29,7 @@ macro_rules! retryable_error {
 }
 
 retryable_error!(retryable_query_error, QueryError, "query");
+retryable_error!(retryable_scan_error, ScanError, "scan");
 retryable_error!(retryable_getitem_error, GetItemError, "get_item");
 retryable_error!(retryable_updateitem_error, UpdateItemError, "update_item");
 retryable_error!(retryable_putitem_error, PutItemError, "put_item");
@@ -38,6 +39,11 @@ retryable_error!(
     BatchWriteItemError,
     "batch_write_item"
 );
+retryable_error!(
+    retryable_transactwriteitems_error,
+    TransactWriteItemsError,
+    "transact_write_items"
+);
 
 // DescribeTableError does not have a ProvisionedThroughputExceeded variant
 pub fn retryable_describe_table_error(
@@ -0,0 +1,355 @@
+//! Envelope encryption of stored message payloads.
+//!
+//! A [KeyRing] is configured via [DbSettings::key_ring](crate::db::DbSettings)
+//! as a JSON formatted dictionary of key encryption keys, e.g.
+//! `{"active":"2024-02","keys":{"2024-01":"<base64 key>","2024-02":"<base64 key>"}}`.
+//! Keys are 32 byte (AES-256) values, base64 encoded.
+//!
+//! Each sealed value is encrypted with its own, random data key, which is in
+//! turn encrypted ("wrapped") with the `active` key. The envelope records the
+//! id of the wrapping key, so keys may be rotated by adding a new key, making
+//! it `active`, and only removing the old key once every message it wrapped
+//! has expired. Values lacking the envelope prefix are plaintext (written
+//! before encryption was enabled) and are returned untouched, so encrypted
+//! and plaintext records may be mixed during a rollout. A key ring without an
+//! `active` key only decrypts, which allows rolling back a rollout.
+use std::collections::HashMap;
+use std::fmt;
+
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde_derive::Deserialize;
+use uuid::Uuid;
+
+use crate::db::error::{DbError, DbResult};
+use crate::db::DbSettings;
+use crate::notification::Notification;
+use crate::util::{b64_decode_std, b64_decode_url, b64_encode_url};
+
+/// Prefix identifying an encrypted value (and its format version)
+pub const ENVELOPE_PREFIX: &str = "enc1:";
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+struct KeyRingSettings {
+    /// The id of the key used to encrypt new values
+    active: Option<String>,
+    /// Map of key ids to base64 encoded keys
+    keys: HashMap<String, String>,
+}
+
+#[derive(Clone, Default)]
+pub struct KeyRing {
+    active: Option<String>,
+    keys: HashMap<String, Vec<u8>>,
+}
+
+/// Never log the keys themselves
+impl fmt::Debug for KeyRing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut key_ids: Vec<&String> = self.keys.keys().collect();
+        key_ids.sort();
+        f.debug_struct("KeyRing")
+            .field("active", &self.active)
+            .field("keys", &key_ids)
+            .finish()
+    }
+}
+
+impl TryFrom<&str> for KeyRing {
+    type Error = DbError;
+    fn try_from(setting_string: &str) -> Result<Self, Self::Error> {
+        if setting_string.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        let settings: KeyRingSettings = serde_json::from_str(setting_string)
+            .map_err(|e| DbError::General(format!("Could not parse KeyRing: {:?}", e)))?;
+        let mut keys = HashMap::new();
+        for (key_id, key) in settings.keys {
+            if key_id.is_empty() || key_id.contains(':') {
+                return Err(DbError::General(format!(
+                    "Invalid KeyRing key id {key_id:?}"
+                )));
+            }
+            let key = b64_decode_std(&key)
+                .ok()
+                .filter(|key| key.len() == KEY_LEN)
+                .ok_or_else(|| {
+                    DbError::General(format!(
+                        "KeyRing key {key_id:?} must be {KEY_LEN} base64 encoded bytes"
+                    ))
+                })?;
+            keys.insert(key_id, key);
+        }
+        if let Some(active) = &settings.active {
+            if !keys.contains_key(active) {
+                return Err(DbError::General(format!(
+                    "KeyRing active key {active:?} is not in its keys"
+                )));
+            }
+        }
+        Ok(Self {
+            active: settings.active,
+            keys,
+        })
+    }
+}
+
+impl KeyRing {
+    /// Whether new values are encrypted
+    pub fn is_active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    /// Encrypt a value belonging to the UAID with the active key.
+    ///
+    /// Values are returned untouched when there's no active key.
+    pub fn seal(&self, uaid: &Uuid, value: String) -> DbResult<String> {
+        let Some(key_id) = &self.active else {
+            return Ok(value);
+        };
+        let mut data_key = [0u8; KEY_LEN];
+        rand_bytes(&mut data_key).map_err(encryption_err)?;
+        // Both the data key and the value are bound to the UAID, so values
+        // can't be swapped between users
+        let wrapped_key = encrypt(&self.keys[key_id], uaid.as_bytes(), &data_key)?;
+        let sealed = encrypt(&data_key, uaid.as_bytes(), value.as_bytes())?;
+        Ok(format!(
+            "{ENVELOPE_PREFIX}{key_id}:{}:{}",
+            b64_encode_url(&wrapped_key),
+            b64_encode_url(&sealed)
+        ))
+    }
+
+    /// Decrypt a value belonging to the UAID.
+    ///
+    /// Plaintext values are returned untouched.
+    pub fn open(&self, uaid: &Uuid, value: String) -> DbResult<String> {
+        let Some(envelope) = value.strip_prefix(ENVELOPE_PREFIX) else {
+            return Ok(value);
+        };
+        let mut parts = envelope.splitn(3, ':');
+        let (Some(key_id), Some(wrapped_key), Some(sealed)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(DbError::Encryption("Malformed envelope".to_owned()));
+        };
+        let key = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| DbError::Encryption(format!("Unknown key id {key_id:?}")))?;
+        let decode = |part: &str| {
+            b64_decode_url(part)
+                .map_err(|e| DbError::Encryption(format!("Malformed envelope: {e}")))
+        };
+        let data_key = decrypt(key, uaid.as_bytes(), &decode(wrapped_key)?)?;
+        let value = decrypt(&data_key, uaid.as_bytes(), &decode(sealed)?)?;
+        String::from_utf8(value)
+            .map_err(|e| DbError::Encryption(format!("Invalid decrypted value: {e}")))
+    }
+
+    /// Encrypt the Notification's data and header values for storage
+    pub fn seal_notification(&self, uaid: &Uuid, notif: &mut Notification) -> DbResult<()> {
+        if !self.is_active() {
+            return Ok(());
+        }
+        if let Some(data) = notif.data.take() {
+            notif.data = Some(self.seal(uaid, data)?);
+        }
+        if let Some(headers) = notif.headers.as_mut() {
+            for value in headers.values_mut() {
+                *value = self.seal(uaid, std::mem::take(value))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decrypt the stored Notification's data and header values
+    pub fn open_notification(&self, uaid: &Uuid, notif: &mut Notification) -> DbResult<()> {
+        if let Some(data) = notif.data.take() {
+            notif.data = Some(self.open(uaid, data)?);
+        }
+        if let Some(headers) = notif.headers.as_mut() {
+            for value in headers.values_mut() {
+                *value = self.open(uaid, std::mem::take(value))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fail when a key ring is configured for a data store that doesn't encrypt
+/// its messages, rather than silently storing them as plaintext
+pub fn reject_key_ring(db_settings: &DbSettings, data_store: &str) -> DbResult<()> {
+    if db_settings.key_ring.trim().is_empty() {
+        return Ok(());
+    }
+    Err(DbError::General(format!(
+        "{data_store} does not support encrypting messages, remove the key ring"
+    )))
+}
+
+fn encryption_err(e: openssl::error::ErrorStack) -> DbError {
+    DbError::Encryption(e.to_string())
+}
+
+/// AES-256-GCM encrypt, returning the nonce, ciphertext and tag
+fn encrypt(key: &[u8], aad: &[u8], plaintext: &[u8]) -> DbResult<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand_bytes(&mut nonce).map_err(encryption_err)?;
+    let mut tag = [0u8; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&nonce),
+        aad,
+        plaintext,
+        &mut tag,
+    )
+    .map_err(encryption_err)?;
+    let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len() + TAG_LEN);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    sealed.extend_from_slice(&tag);
+    Ok(sealed)
+}
+
+/// AES-256-GCM decrypt the output of [encrypt]
+fn decrypt(key: &[u8], aad: &[u8], sealed: &[u8]) -> DbResult<Vec<u8>> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return Err(DbError::Encryption("Truncated envelope".to_owned()));
+    }
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        aad,
+        ciphertext,
+        tag,
+    )
+    .map_err(|_| DbError::Encryption("Could not decrypt envelope".to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use uuid::Uuid;
+
+    use super::{reject_key_ring, KeyRing, ENVELOPE_PREFIX};
+    use crate::db::error::DbError;
+    use crate::db::DbSettings;
+    use crate::notification::Notification;
+
+    const KEY_1: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+    const KEY_2: &str = "ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=";
+
+    fn key_ring(active: Option<&str>, key_ids: &[(&str, &str)]) -> KeyRing {
+        let keys: HashMap<&str, &str> = key_ids.iter().cloned().collect();
+        KeyRing::try_from(
+            serde_json::json!({"active": active, "keys": keys})
+                .to_string()
+                .as_str(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let uaid = Uuid::new_v4();
+        let ring = key_ring(Some("k1"), &[("k1", KEY_1)]);
+        let sealed = ring.seal(&uaid, "payload".to_owned()).unwrap();
+        assert!(sealed.starts_with(&format!("{ENVELOPE_PREFIX}k1:")));
+        assert!(!sealed.contains("payload"));
+        assert_eq!(ring.open(&uaid, sealed.clone()).unwrap(), "payload");
+
+        // Bound to the uaid
+        assert!(matches!(
+            ring.open(&Uuid::new_v4(), sealed),
+            Err(DbError::Encryption(_))
+        ));
+        // Plaintext passes through
+        assert_eq!(ring.open(&uaid, "plain".to_owned()).unwrap(), "plain");
+    }
+
+    #[test]
+    fn rotation() {
+        let uaid = Uuid::new_v4();
+        let old = key_ring(Some("k1"), &[("k1", KEY_1)]);
+        let sealed = old.seal(&uaid, "payload".to_owned()).unwrap();
+
+        // The new key encrypts, the old key still decrypts
+        let rotated = key_ring(Some("k2"), &[("k1", KEY_1), ("k2", KEY_2)]);
+        assert!(rotated
+            .seal(&uaid, "payload".to_owned())
+            .unwrap()
+            .starts_with(&format!("{ENVELOPE_PREFIX}k2:")));
+        assert_eq!(rotated.open(&uaid, sealed.clone()).unwrap(), "payload");
+
+        // Decrypt only
+        let retired = key_ring(None, &[("k1", KEY_1)]);
+        assert_eq!(
+            retired.seal(&uaid, "payload".to_owned()).unwrap(),
+            "payload"
+        );
+        assert_eq!(retired.open(&uaid, sealed.clone()).unwrap(), "payload");
+
+        let removed = key_ring(Some("k2"), &[("k2", KEY_2)]);
+        assert!(matches!(
+            removed.open(&uaid, sealed),
+            Err(DbError::Encryption(_))
+        ));
+    }
+
+    #[test]
+    fn notifications() {
+        let uaid = Uuid::new_v4();
+        let ring = key_ring(Some("k1"), &[("k1", KEY_1)]);
+        let notif = Notification {
+            data: Some("data".to_owned()),
+            headers: Some(HashMap::from([(
+                "encoding".to_owned(),
+                "aes128gcm".to_owned(),
+            )])),
+            ..Default::default()
+        };
+        let mut stored = notif.clone();
+        ring.seal_notification(&uaid, &mut stored).unwrap();
+        assert_ne!(stored.data, notif.data);
+        assert_ne!(stored.headers, notif.headers);
+        ring.open_notification(&uaid, &mut stored).unwrap();
+        assert_eq!(stored.data, notif.data);
+        assert_eq!(stored.headers, notif.headers);
+
+        // Records written before encryption was enabled
+        let mut stored = notif.clone();
+        ring.open_notification(&uaid, &mut stored).unwrap();
+        assert_eq!(stored.data, notif.data);
+        assert_eq!(stored.headers, notif.headers);
+    }
+
+    #[test]
+    fn invalid_settings() {
+        assert!(!KeyRing::try_from("").unwrap().is_active());
+        assert!(KeyRing::try_from(r#"{"active":"k1","keys":{}}"#).is_err());
+        assert!(KeyRing::try_from(r#"{"keys":{"k1":"c2hvcnQ="}}"#).is_err());
+        assert!(KeyRing::try_from(format!(r#"{{"keys":{{"k:1":"{KEY_1}"}}}}"#).as_str()).is_err());
+    }
+
+    #[test]
+    fn rejected_key_ring() {
+        let mut settings = DbSettings::default();
+        assert!(reject_key_ring(&settings, "Memory").is_ok());
+        settings.key_ring = format!(r#"{{"active":"k1","keys":{{"k1":"{KEY_1}"}}}}"#);
+        assert!(matches!(
+            reject_key_ring(&settings, "Memory"),
+            Err(DbError::General(_))
+        ));
+    }
+}
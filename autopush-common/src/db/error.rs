@@ -4,7 +4,7 @@ use rusoto_core::RusotoError;
 #[cfg(feature = "dynamodb")]
 use rusoto_dynamodb::{
     BatchWriteItemError, DeleteItemError, DescribeTableError, GetItemError, PutItemError,
-    QueryError, UpdateItemError,
+    QueryError, ScanError, TransactWriteItemsError, UpdateItemError,
 };
 use thiserror::Error;
 
@@ -36,6 +36,10 @@ pub enum DbError {
     #[error("Database error while performing BatchWriteItem")]
     DdbBatchWriteItem(#[from] RusotoError<BatchWriteItemError>),
 
+    #[cfg(feature = "dynamodb")]
+    #[error("Database error while performing TransactWriteItems")]
+    DdbTransactWriteItems(#[from] RusotoError<TransactWriteItemsError>),
+
     #[cfg(feature = "dynamodb")]
     #[error("Database error while performing DescribeTable")]
     DdbDescribeTable(#[from] RusotoError<DescribeTableError>),
@@ -44,6 +48,10 @@ pub enum DbError {
     #[error("Database error while performing Query")]
     DdbQuery(#[from] RusotoError<QueryError>),
 
+    #[cfg(feature = "dynamodb")]
+    #[error("Database error while performing Scan")]
+    DdbScan(#[from] RusotoError<ScanError>),
+
     #[cfg(feature = "dynamodb")]
     #[error("Error while performing DynamoDB (de)serialization: {0}")]
     DdbSerialization(#[from] serde_dynamodb::Error),
@@ -64,16 +72,30 @@ pub enum DbError {
     #[error("BigTable error {0}")]
     BTError(#[from] BigTableError),
 
-    /*
-    #[error("Postgres error")]
-    PgError(#[from] PgError),
-    */
+    #[cfg(feature = "postgres")]
+    #[error("Postgres error {0}")]
+    PgError(#[from] tokio_postgres::Error),
+
+    #[cfg(feature = "redis")]
+    #[error("Redis error {0}")]
+    RedisError(#[from] redis::RedisError),
+
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error {0}")]
+    SqliteError(#[from] rusqlite::Error),
+
     #[error("Connection failure {0}")]
     ConnectionError(String),
 
     #[error("The conditional request failed")]
     Conditional,
 
+    #[error("Message quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("Message encryption error: {0}")]
+    Encryption(String),
+
     #[error("Database integrity error: {}", _0)]
     Integrity(String),
 
@@ -81,6 +103,52 @@ pub enum DbError {
     General(String),
 }
 
+impl DbError {
+    /// A short, metric tag friendly name of the error's variant
+    pub fn kind(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "dynamodb")]
+            DbError::DdbGetItem(_) => "ddb_get_item",
+            #[cfg(feature = "dynamodb")]
+            DbError::DdbUpdateItem(_) => "ddb_update_item",
+            #[cfg(feature = "dynamodb")]
+            DbError::DdbPutItem(_) => "ddb_put_item",
+            #[cfg(feature = "dynamodb")]
+            DbError::DdbDeleteItem(_) => "ddb_delete_item",
+            #[cfg(feature = "dynamodb")]
+            DbError::DdbBatchWriteItem(_) => "ddb_batch_write_item",
+            #[cfg(feature = "dynamodb")]
+            DbError::DdbTransactWriteItems(_) => "ddb_transact_write_items",
+            #[cfg(feature = "dynamodb")]
+            DbError::DdbDescribeTable(_) => "ddb_describe_table",
+            #[cfg(feature = "dynamodb")]
+            DbError::DdbQuery(_) => "ddb_query",
+            #[cfg(feature = "dynamodb")]
+            DbError::DdbScan(_) => "ddb_scan",
+            #[cfg(feature = "dynamodb")]
+            DbError::DdbSerialization(_) => "ddb_serialization",
+            DbError::Serialization(_) => "serialization",
+            DbError::DeserializeU64(_) => "deserialize_u64",
+            DbError::DeserializeString(_) => "deserialize_string",
+            DbError::TableStatusUnknown => "table_status_unknown",
+            #[cfg(feature = "bigtable")]
+            DbError::BTError(_) => "bigtable",
+            #[cfg(feature = "postgres")]
+            DbError::PgError(_) => "postgres",
+            #[cfg(feature = "redis")]
+            DbError::RedisError(_) => "redis",
+            #[cfg(feature = "sqlite")]
+            DbError::SqliteError(_) => "sqlite",
+            DbError::ConnectionError(_) => "connection",
+            DbError::Conditional => "conditional",
+            DbError::QuotaExceeded(_) => "quota_exceeded",
+            DbError::Encryption(_) => "encryption",
+            DbError::Integrity(_) => "integrity",
+            DbError::General(_) => "general",
+        }
+    }
+}
+
 impl ReportableError for DbError {
     fn reportable_source(&self) -> Option<&(dyn ReportableError + 'static)> {
         match &self {
@@ -0,0 +1,460 @@
+//! Fault injecting data store decorator, for chaos and soak testing.
+//!
+//! Wraps another data store, specified by prefixing its DSN with `faulty+`
+//! (e.g. `faulty+memory://` or `faulty+grpc://localhost:8086`). The
+//! `db_settings` are passed through to the wrapped data store, with an
+//! additional `faults` dictionary (see [FaultSettings]) describing the
+//! misbehavior to inject, e.g.:
+//!
+//! `{"faults":{"latency_ms":[5,50],"error_rates":{"save_messages":0.1,"*":0.01},"conditional_rate":0.05,"partial_save_rate":0.1}}`
+//!
+//! Each injected fault is reported via the `database.fault` metric.
+//!
+//! This is only available with the `faulty` feature, and should never be
+//! used in production.
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use cadence::{CountedExt, StatsdClient};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_derive::Deserialize;
+use uuid::Uuid;
+
+use crate::db::{
+    client::{DbClient, FetchMessageResponse},
+    client_from_settings,
+    error::{DbError, DbResult},
+    ChannelRecord, DbSettings, Notification, StorageType, User,
+};
+
+/// The DSN prefix selecting the faulty data store
+pub const DSN_PREFIX: &str = "faulty+";
+
+/// The misbehavior to inject. All rates are probabilities between `0.0`
+/// (never) and `1.0` (always).
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct FaultSettings {
+    /// The range of latency (in milliseconds) added to every operation
+    pub latency_ms: Option<(u64, u64)>,
+    /// The rate at which each operation (keyed by its method name, e.g.
+    /// `get_user`, or `*` for any other) fails with an error
+    pub error_rates: HashMap<String, f64>,
+    /// The rate at which conditional writes lose a race: `add_user` fails
+    /// with [DbError::Conditional], `update_user` and `remove_node_id` report
+    /// that no update occurred
+    pub conditional_rate: f64,
+    /// The rate at which `save_messages` only saves a portion of the
+    /// messages before failing
+    pub partial_save_rate: f64,
+    /// Seed the random number generator, for reproducible runs
+    pub seed: Option<u64>,
+}
+
+impl FaultSettings {
+    fn validate(&self) -> DbResult<()> {
+        let rates = self
+            .error_rates
+            .iter()
+            .map(|(op, rate)| (op.as_str(), *rate))
+            .chain([
+                ("conditional_rate", self.conditional_rate),
+                ("partial_save_rate", self.partial_save_rate),
+            ]);
+        for (label, rate) in rates {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(DbError::General(format!(
+                    "Invalid fault rate for {label}: {rate}"
+                )));
+            }
+        }
+        if let Some((min, max)) = self.latency_ms {
+            if min > max {
+                return Err(DbError::General(format!(
+                    "Invalid fault latency_ms range: [{min}, {max}]"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct FaultyDbSettings {
+    #[serde(default)]
+    faults: FaultSettings,
+}
+
+#[derive(Clone)]
+pub struct FaultyDbClient {
+    inner: Box<dyn DbClient>,
+    faults: Arc<FaultSettings>,
+    rng: Arc<Mutex<StdRng>>,
+    metrics: Arc<StatsdClient>,
+}
+
+impl FaultyDbClient {
+    pub fn new(metrics: Arc<StatsdClient>, settings: &DbSettings) -> DbResult<Self> {
+        let dsn = settings
+            .dsn
+            .as_deref()
+            .and_then(|dsn| dsn.strip_prefix(DSN_PREFIX))
+            .ok_or_else(|| {
+                DbError::General(format!("Invalid faulty DSN specified: {:?}", settings.dsn))
+            })?;
+        let faults = if settings.db_settings.trim().is_empty() {
+            FaultSettings::default()
+        } else {
+            serde_json::from_str::<FaultyDbSettings>(&settings.db_settings)
+                .map_err(|e| {
+                    DbError::General(format!("Could not parse FaultyDbSettings: {:?}", e))
+                })?
+                .faults
+        };
+        faults.validate()?;
+        let inner_settings = DbSettings {
+            dsn: Some(dsn.to_owned()),
+            ..settings.clone()
+        };
+        if matches!(
+            StorageType::from_dsn(&inner_settings.dsn),
+            StorageType::Faulty | StorageType::INVALID
+        ) {
+            return Err(DbError::General(format!(
+                "Invalid DSN wrapped by faulty: {dsn:?}"
+            )));
+        }
+        let inner = client_from_settings(metrics.clone(), &inner_settings)?;
+        warn!("💥 Injecting faults into {}: {:?}", inner.name(), faults);
+        let rng = match faults.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Ok(Self {
+            inner,
+            faults: Arc::new(faults),
+            rng: Arc::new(Mutex::new(rng)),
+            metrics,
+        })
+    }
+
+    fn chance(&self, rate: f64) -> bool {
+        rate > 0.0
+            && self
+                .rng
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .gen_bool(rate)
+    }
+
+    fn record(&self, operation: &'static str, fault: &'static str) {
+        debug!("💥 Injecting {} fault into {}", fault, operation);
+        self.metrics
+            .incr_with_tags("database.fault")
+            .with_tag("operation", operation)
+            .with_tag("fault", fault)
+            .send();
+    }
+
+    /// Delay the operation, then possibly fail it
+    async fn inject(&self, operation: &'static str) -> DbResult<()> {
+        if let Some((min, max)) = self.faults.latency_ms {
+            let delay = self
+                .rng
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .gen_range(min..=max);
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+        let rate = self
+            .faults
+            .error_rates
+            .get(operation)
+            .or_else(|| self.faults.error_rates.get("*"))
+            .copied()
+            .unwrap_or_default();
+        if self.chance(rate) {
+            self.record(operation, "error");
+            return Err(DbError::General(format!("Injected failure of {operation}")));
+        }
+        Ok(())
+    }
+
+    /// Whether a conditional write should lose a race
+    fn lose_race(&self, operation: &'static str) -> bool {
+        let lost = self.chance(self.faults.conditional_rate);
+        if lost {
+            self.record(operation, "conditional");
+        }
+        lost
+    }
+}
+
+#[async_trait]
+impl DbClient for FaultyDbClient {
+    async fn add_user(&self, user: &User) -> DbResult<()> {
+        self.inject("add_user").await?;
+        if self.lose_race("add_user") {
+            return Err(DbError::Conditional);
+        }
+        self.inner.add_user(user).await
+    }
+
+    async fn add_user_with_channels(
+        &self,
+        user: &User,
+        channels: Vec<ChannelRecord>,
+    ) -> DbResult<()> {
+        self.inject("add_user_with_channels").await?;
+        if self.lose_race("add_user_with_channels") {
+            return Err(DbError::Conditional);
+        }
+        self.inner.add_user_with_channels(user, channels).await
+    }
+
+    async fn update_user(&self, user: &mut User) -> DbResult<bool> {
+        self.inject("update_user").await?;
+        if self.lose_race("update_user") {
+            return Ok(false);
+        }
+        self.inner.update_user(user).await
+    }
+
+    async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>> {
+        self.inject("get_user").await?;
+        self.inner.get_user(uaid).await
+    }
+
+    async fn remove_user(&self, uaid: &Uuid) -> DbResult<()> {
+        self.inject("remove_user").await?;
+        self.inner.remove_user(uaid).await
+    }
+
+    async fn list_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        self.inject("list_uaids").await?;
+        self.inner.list_uaids(prefix).await
+    }
+
+    async fn list_orphan_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        self.inject("list_orphan_uaids").await?;
+        self.inner.list_orphan_uaids(prefix).await
+    }
+
+    async fn add_channel(&self, uaid: &Uuid, channel: &ChannelRecord) -> DbResult<()> {
+        self.inject("add_channel").await?;
+        self.inner.add_channel(uaid, channel).await
+    }
+
+    async fn add_channels(&self, uaid: &Uuid, channels: Vec<ChannelRecord>) -> DbResult<()> {
+        self.inject("add_channels").await?;
+        self.inner.add_channels(uaid, channels).await
+    }
+
+    async fn get_channels(&self, uaid: &Uuid) -> DbResult<HashSet<Uuid>> {
+        self.inject("get_channels").await?;
+        self.inner.get_channels(uaid).await
+    }
+
+    async fn get_channel_records(&self, uaid: &Uuid) -> DbResult<Vec<ChannelRecord>> {
+        self.inject("get_channel_records").await?;
+        self.inner.get_channel_records(uaid).await
+    }
+
+    async fn record_channel_push(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
+        self.inject("record_channel_push").await?;
+        self.inner.record_channel_push(uaid, channel_id).await
+    }
+
+    async fn remove_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
+        self.inject("remove_channel").await?;
+        self.inner.remove_channel(uaid, channel_id).await
+    }
+
+    async fn remove_node_id(
+        &self,
+        uaid: &Uuid,
+        node_id: &str,
+        connected_at: u64,
+        version: &Option<Uuid>,
+    ) -> DbResult<bool> {
+        self.inject("remove_node_id").await?;
+        if self.lose_race("remove_node_id") {
+            return Ok(false);
+        }
+        self.inner
+            .remove_node_id(uaid, node_id, connected_at, version)
+            .await
+    }
+
+    async fn save_message(&self, uaid: &Uuid, message: Notification) -> DbResult<()> {
+        self.inject("save_message").await?;
+        self.inner.save_message(uaid, message).await
+    }
+
+    async fn save_messages(&self, uaid: &Uuid, mut messages: Vec<Notification>) -> DbResult<()> {
+        self.inject("save_messages").await?;
+        if messages.len() > 1 && self.chance(self.faults.partial_save_rate) {
+            self.record("save_messages", "partial");
+            let saved = self
+                .rng
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .gen_range(1..messages.len());
+            messages.truncate(saved);
+            self.inner.save_messages(uaid, messages).await?;
+            return Err(DbError::General(format!(
+                "Injected failure of save_messages after {saved} message(s)"
+            )));
+        }
+        self.inner.save_messages(uaid, messages).await
+    }
+
+    async fn fetch_topic_messages(
+        &self,
+        uaid: &Uuid,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        self.inject("fetch_topic_messages").await?;
+        self.inner.fetch_topic_messages(uaid, limit).await
+    }
+
+    async fn fetch_timestamp_messages(
+        &self,
+        uaid: &Uuid,
+        timestamp: Option<u64>,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        self.inject("fetch_timestamp_messages").await?;
+        self.inner
+            .fetch_timestamp_messages(uaid, timestamp, limit)
+            .await
+    }
+
+    async fn fetch_quota_messages(&self, uaid: &Uuid) -> DbResult<Vec<Notification>> {
+        self.inject("fetch_quota_messages").await?;
+        self.inner.fetch_quota_messages(uaid).await
+    }
+
+    async fn increment_storage(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
+        self.inject("increment_storage").await?;
+        self.inner.increment_storage(uaid, timestamp).await
+    }
+
+    async fn get_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<Option<Notification>> {
+        self.inject("get_message").await?;
+        self.inner.get_message(uaid, sort_key).await
+    }
+
+    async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()> {
+        self.inject("remove_message").await?;
+        self.inner.remove_message(uaid, sort_key).await
+    }
+
+    async fn remove_messages(&self, uaid: &Uuid, sort_keys: &[String]) -> DbResult<()> {
+        self.inject("remove_messages").await?;
+        self.inner.remove_messages(uaid, sort_keys).await
+    }
+
+    async fn router_table_exists(&self) -> DbResult<bool> {
+        self.inject("router_table_exists").await?;
+        self.inner.router_table_exists().await
+    }
+
+    async fn message_table_exists(&self) -> DbResult<bool> {
+        self.inject("message_table_exists").await?;
+        self.inner.message_table_exists().await
+    }
+
+    async fn health_check(&self) -> DbResult<bool> {
+        self.inject("health_check").await?;
+        self.inner.health_check().await
+    }
+
+    fn rotating_message_table(&self) -> Option<&str> {
+        self.inner.rotating_message_table()
+    }
+
+    fn box_clone(&self) -> Box<dyn DbClient> {
+        Box::new(self.clone())
+    }
+
+    fn name(&self) -> String {
+        format!("Faulty{}", self.inner.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cadence::StatsdClient;
+
+    use super::*;
+
+    fn new_client(db_settings: &str) -> DbResult<FaultyDbClient> {
+        FaultyDbClient::new(
+            Arc::new(StatsdClient::builder("", cadence::NopMetricSink).build()),
+            &DbSettings {
+                dsn: Some("faulty+memory://".to_owned()),
+                db_settings: db_settings.to_owned(),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[actix_rt::test]
+    async fn injects_faults() -> DbResult<()> {
+        let client = new_client(
+            r#"{"faults":{"error_rates":{"get_user":1.0},"conditional_rate":1.0,"seed":1}}"#,
+        )?;
+        assert_eq!(client.name(), "FaultyMemory");
+        let user = User::default();
+        assert!(matches!(
+            client.get_user(&user.uaid).await,
+            Err(DbError::General(_))
+        ));
+        assert!(matches!(
+            client.add_user(&user).await,
+            Err(DbError::Conditional)
+        ));
+        // other operations are unaffected
+        assert!(client.get_channels(&user.uaid).await?.is_empty());
+
+        let client = new_client(r#"{"faults":{"partial_save_rate":1.0,"seed":1}}"#)?;
+        let messages: Vec<Notification> = (0..4)
+            .map(|i| Notification {
+                channel_id: Uuid::new_v4(),
+                version: format!("v{i}"),
+                ttl: 300,
+                timestamp: crate::util::sec_since_epoch(),
+                sortkey_timestamp: Some(crate::util::ms_since_epoch() + i),
+                ..Default::default()
+            })
+            .collect();
+        assert!(client.save_messages(&user.uaid, messages).await.is_err());
+        let saved = client
+            .fetch_timestamp_messages(&user.uaid, None, 0)
+            .await?
+            .messages
+            .len();
+        assert!((1..4).contains(&saved));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_settings() {
+        assert!(new_client(r#"{"faults":{"error_rates":{"*":1.5}}}"#).is_err());
+        assert!(new_client(r#"{"faults":{"latency_ms":[10,5]}}"#).is_err());
+        assert!(FaultyDbClient::new(
+            Arc::new(StatsdClient::builder("", cadence::NopMetricSink).build()),
+            &DbSettings {
+                dsn: Some("faulty+faulty+memory://".to_owned()),
+                db_settings: "".to_owned(),
+                ..Default::default()
+            },
+        )
+        .is_err());
+    }
+}
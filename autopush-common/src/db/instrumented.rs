@@ -0,0 +1,294 @@
+//! Instrumented data store decorator.
+//!
+//! Wraps any [DbClient], timing each of its operations. Every call emits a
+//! `database.operation` timer tagged with the `operation` (the method name),
+//! the `backend` ([DbClient::name]) and its `outcome`: `ok`, or the failing
+//! [DbError](crate::db::error::DbError)'s [kind](crate::db::error::DbError::kind).
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use cadence::{StatsdClient, Timed};
+use uuid::Uuid;
+
+use crate::db::{
+    client::{DbClient, FetchMessageResponse},
+    error::DbResult,
+    ChannelRecord, Notification, User,
+};
+
+#[derive(Clone)]
+pub struct InstrumentedDbClient {
+    inner: Box<dyn DbClient>,
+    metrics: Arc<StatsdClient>,
+    /// The wrapped data store's name
+    backend: String,
+}
+
+impl InstrumentedDbClient {
+    pub fn new(inner: Box<dyn DbClient>, metrics: Arc<StatsdClient>) -> Self {
+        let backend = inner.name();
+        Self {
+            inner,
+            metrics,
+            backend,
+        }
+    }
+
+    /// Run the operation, recording how long it took and its outcome
+    async fn timed<T>(
+        &self,
+        operation: &'static str,
+        future: impl Future<Output = DbResult<T>>,
+    ) -> DbResult<T> {
+        let start = Instant::now();
+        let result = future.await;
+        let outcome = match &result {
+            Ok(_) => "ok",
+            Err(e) => e.kind(),
+        };
+        self.metrics
+            .time_with_tags("database.operation", start.elapsed().as_millis() as u64)
+            .with_tag("operation", operation)
+            .with_tag("backend", &self.backend)
+            .with_tag("outcome", outcome)
+            .send();
+        result
+    }
+}
+
+#[async_trait]
+impl DbClient for InstrumentedDbClient {
+    async fn add_user(&self, user: &User) -> DbResult<()> {
+        self.timed("add_user", self.inner.add_user(user)).await
+    }
+
+    async fn add_user_with_channels(
+        &self,
+        user: &User,
+        channels: Vec<ChannelRecord>,
+    ) -> DbResult<()> {
+        self.timed(
+            "add_user_with_channels",
+            self.inner.add_user_with_channels(user, channels),
+        )
+        .await
+    }
+
+    async fn update_user(&self, user: &mut User) -> DbResult<bool> {
+        self.timed("update_user", self.inner.update_user(user))
+            .await
+    }
+
+    async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>> {
+        self.timed("get_user", self.inner.get_user(uaid)).await
+    }
+
+    async fn remove_user(&self, uaid: &Uuid) -> DbResult<()> {
+        self.timed("remove_user", self.inner.remove_user(uaid))
+            .await
+    }
+
+    async fn list_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        self.timed("list_uaids", self.inner.list_uaids(prefix))
+            .await
+    }
+
+    async fn list_orphan_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        self.timed("list_orphan_uaids", self.inner.list_orphan_uaids(prefix))
+            .await
+    }
+
+    async fn add_channel(&self, uaid: &Uuid, channel: &ChannelRecord) -> DbResult<()> {
+        self.timed("add_channel", self.inner.add_channel(uaid, channel))
+            .await
+    }
+
+    async fn add_channels(&self, uaid: &Uuid, channels: Vec<ChannelRecord>) -> DbResult<()> {
+        self.timed("add_channels", self.inner.add_channels(uaid, channels))
+            .await
+    }
+
+    async fn get_channels(&self, uaid: &Uuid) -> DbResult<HashSet<Uuid>> {
+        self.timed("get_channels", self.inner.get_channels(uaid))
+            .await
+    }
+
+    async fn get_channel_records(&self, uaid: &Uuid) -> DbResult<Vec<ChannelRecord>> {
+        self.timed("get_channel_records", self.inner.get_channel_records(uaid))
+            .await
+    }
+
+    async fn record_channel_push(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
+        self.timed(
+            "record_channel_push",
+            self.inner.record_channel_push(uaid, channel_id),
+        )
+        .await
+    }
+
+    async fn remove_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
+        self.timed(
+            "remove_channel",
+            self.inner.remove_channel(uaid, channel_id),
+        )
+        .await
+    }
+
+    async fn remove_node_id(
+        &self,
+        uaid: &Uuid,
+        node_id: &str,
+        connected_at: u64,
+        version: &Option<Uuid>,
+    ) -> DbResult<bool> {
+        self.timed(
+            "remove_node_id",
+            self.inner
+                .remove_node_id(uaid, node_id, connected_at, version),
+        )
+        .await
+    }
+
+    async fn save_message(&self, uaid: &Uuid, message: Notification) -> DbResult<()> {
+        self.timed("save_message", self.inner.save_message(uaid, message))
+            .await
+    }
+
+    async fn save_messages(&self, uaid: &Uuid, messages: Vec<Notification>) -> DbResult<()> {
+        self.timed("save_messages", self.inner.save_messages(uaid, messages))
+            .await
+    }
+
+    async fn fetch_topic_messages(
+        &self,
+        uaid: &Uuid,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        self.timed(
+            "fetch_topic_messages",
+            self.inner.fetch_topic_messages(uaid, limit),
+        )
+        .await
+    }
+
+    async fn fetch_timestamp_messages(
+        &self,
+        uaid: &Uuid,
+        timestamp: Option<u64>,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        self.timed(
+            "fetch_timestamp_messages",
+            self.inner.fetch_timestamp_messages(uaid, timestamp, limit),
+        )
+        .await
+    }
+
+    async fn fetch_quota_messages(&self, uaid: &Uuid) -> DbResult<Vec<Notification>> {
+        self.timed(
+            "fetch_quota_messages",
+            self.inner.fetch_quota_messages(uaid),
+        )
+        .await
+    }
+
+    async fn increment_storage(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
+        self.timed(
+            "increment_storage",
+            self.inner.increment_storage(uaid, timestamp),
+        )
+        .await
+    }
+
+    async fn get_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<Option<Notification>> {
+        self.timed("get_message", self.inner.get_message(uaid, sort_key))
+            .await
+    }
+
+    async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()> {
+        self.timed("remove_message", self.inner.remove_message(uaid, sort_key))
+            .await
+    }
+
+    async fn remove_messages(&self, uaid: &Uuid, sort_keys: &[String]) -> DbResult<()> {
+        self.timed(
+            "remove_messages",
+            self.inner.remove_messages(uaid, sort_keys),
+        )
+        .await
+    }
+
+    async fn router_table_exists(&self) -> DbResult<bool> {
+        self.timed("router_table_exists", self.inner.router_table_exists())
+            .await
+    }
+
+    async fn message_table_exists(&self) -> DbResult<bool> {
+        self.timed("message_table_exists", self.inner.message_table_exists())
+            .await
+    }
+
+    async fn health_check(&self) -> DbResult<bool> {
+        self.timed("health_check", self.inner.health_check()).await
+    }
+
+    fn rotating_message_table(&self) -> Option<&str> {
+        self.inner.rotating_message_table()
+    }
+
+    fn box_clone(&self) -> Box<dyn DbClient> {
+        Box::new(self.clone())
+    }
+
+    /// The instrumentation is transparent, so report the underlying data
+    /// store
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cadence::{SpyMetricSink, StatsdClient};
+
+    use super::*;
+    use crate::db::{error::DbError, memory::MemoryClientImpl, DbSettings};
+
+    #[actix_rt::test]
+    async fn times_operations() -> DbResult<()> {
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = Arc::new(StatsdClient::builder("", sink).build());
+        let client = InstrumentedDbClient::new(
+            Box::new(MemoryClientImpl::new(
+                metrics.clone(),
+                &DbSettings::default(),
+            )?),
+            metrics,
+        );
+
+        let user = User::default();
+        client.add_user(&user).await?;
+        assert!(matches!(
+            client.add_user(&user).await,
+            Err(DbError::Conditional)
+        ));
+        assert!(client.get_user(&user.uaid).await?.is_some());
+        assert_eq!(client.name(), "Memory");
+
+        let sent: Vec<String> = rx
+            .try_iter()
+            .map(|metric| String::from_utf8(metric).unwrap())
+            .filter(|metric| metric.starts_with("database.operation:"))
+            .collect();
+        assert_eq!(sent.len(), 3);
+        assert!(sent[0].ends_with("|ms|#operation:add_user,backend:Memory,outcome:ok"));
+        assert!(sent[1].ends_with("|ms|#operation:add_user,backend:Memory,outcome:conditional"));
+        assert!(sent[2].ends_with("|ms|#operation:get_user,backend:Memory,outcome:ok"));
+        Ok(())
+    }
+}
@@ -0,0 +1,1026 @@
+//! In-memory data store.
+//!
+//! Holds all of the data within the process, which makes it useful for
+//! running autoendpoint and autoconnect locally or within unit tests without
+//! an emulator. Data is shared between clones of the client, but not between
+//! processes, and is lost on restart.
+//!
+//! The semantics follow the Bigtable data store: user updates are guarded by
+//! the record's `version`, topic messages replace their predecessor and
+//! records are expired with the same TTLs as Bigtable's garbage collection.
+//! Expired records are skipped on read and periodically swept.
+//!
+//! The `db_dsn` is `memory://`. The optional `db_settings` may only specify a
+//! message `quota` (see [crate::db::quota]).
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use async_trait::async_trait;
+use cadence::{Counted, CountedExt, StatsdClient};
+use serde_derive::Deserialize;
+use uuid::Uuid;
+
+use crate::db::{
+    client::{DbClient, FetchMessageResponse},
+    compression::{decompress_notification, Compressor},
+    encryption::reject_key_ring,
+    error::{DbError, DbResult},
+    quota::MessageQuota,
+    ChannelRecord, DbSettings, Notification, User, MAX_CHANNEL_TTL, MAX_EXPIRY, MAX_ROUTER_TTL,
+};
+use crate::notification::TOPIC_NOTIFICATION_PREFIX;
+use crate::util::sec_since_epoch;
+
+/// How often (in seconds) expired records are swept from storage
+const SWEEP_INTERVAL: u64 = 60;
+
+/// A stored value along with when it expires (in seconds since the epoch)
+#[derive(Clone, Debug)]
+struct Expiring<T> {
+    value: T,
+    expiry: u64,
+}
+
+impl<T> Expiring<T> {
+    fn new(value: T, ttl: u64) -> Self {
+        Self {
+            value,
+            expiry: sec_since_epoch() + ttl,
+        }
+    }
+
+    fn live(&self, now: u64) -> bool {
+        now < self.expiry
+    }
+}
+
+/// All of the data stored for a UAID
+#[derive(Debug, Default)]
+struct UserData {
+    user: Option<Expiring<User>>,
+    /// Channels keyed by their ID
+    channels: HashMap<Uuid, Expiring<ChannelRecord>>,
+    /// Topic messages keyed by their `chidmessageid`
+    topic_messages: HashMap<String, Expiring<Notification>>,
+    /// Timestamp messages keyed (and thus ordered) by their
+    /// `sortkey_timestamp` and `chidmessageid`
+    timestamp_messages: BTreeMap<(u64, String), Expiring<Notification>>,
+}
+
+impl UserData {
+    /// Drop any expired records
+    fn purge(&mut self, now: u64) {
+        if self.user.as_ref().map_or(false, |user| !user.live(now)) {
+            self.user = None;
+        }
+        self.channels.retain(|_, channel| channel.live(now));
+        self.topic_messages.retain(|_, message| message.live(now));
+        self.timestamp_messages
+            .retain(|_, message| message.live(now));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.user.is_none()
+            && self.channels.is_empty()
+            && self.topic_messages.is_empty()
+            && self.timestamp_messages.is_empty()
+    }
+
+    /// Whether any channels or messages are live
+    fn has_live_records(&self, now: u64) -> bool {
+        self.channels.values().any(|channel| channel.live(now))
+            || self
+                .topic_messages
+                .values()
+                .any(|message| message.live(now))
+            || self
+                .timestamp_messages
+                .values()
+                .any(|message| message.live(now))
+    }
+
+    /// Add (or replace) the channels, refreshing their expiry
+    fn add_channels(&mut self, channels: Vec<ChannelRecord>) {
+        self.channels.extend(
+            channels
+                .into_iter()
+                .map(|channel| (channel.channel_id, Expiring::new(channel, MAX_CHANNEL_TTL))),
+        );
+    }
+
+    /// Return the live user record
+    fn live_user(&mut self, now: u64) -> Option<&mut User> {
+        self.user
+            .as_mut()
+            .filter(|user| user.live(now))
+            .map(|user| &mut user.value)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct MemoryDbSettings {
+    /// Limits on the number of stored messages
+    pub quota: MessageQuota,
+}
+
+impl TryFrom<&str> for MemoryDbSettings {
+    type Error = DbError;
+    fn try_from(setting_string: &str) -> Result<Self, Self::Error> {
+        if setting_string.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        serde_json::from_str(setting_string)
+            .map_err(|e| DbError::General(format!("Could not parse MemoryDbSettings: {:?}", e)))
+    }
+}
+
+#[derive(Clone)]
+pub struct MemoryClientImpl {
+    store: Arc<RwLock<HashMap<Uuid, UserData>>>,
+    last_sweep: Arc<AtomicU64>,
+    metrics: Arc<StatsdClient>,
+    settings: MemoryDbSettings,
+    compressor: Compressor,
+}
+
+impl MemoryClientImpl {
+    pub fn new(metrics: Arc<StatsdClient>, db_settings: &DbSettings) -> DbResult<Self> {
+        debug!("🧠 Using in-memory storage");
+        reject_key_ring(db_settings, "Memory")?;
+        let settings = MemoryDbSettings::try_from(db_settings.db_settings.as_ref())?;
+        Ok(Self {
+            store: Default::default(),
+            last_sweep: Arc::new(AtomicU64::new(sec_since_epoch())),
+            compressor: Compressor::new(metrics.clone(), db_settings),
+            metrics,
+            settings,
+        })
+    }
+
+    fn read(&self) -> DbResult<RwLockReadGuard<'_, HashMap<Uuid, UserData>>> {
+        self.store
+            .read()
+            .map_err(|e| DbError::General(format!("Memory store lock poisoned: {e}")))
+    }
+
+    fn write(&self) -> DbResult<RwLockWriteGuard<'_, HashMap<Uuid, UserData>>> {
+        let now = sec_since_epoch();
+        let mut store = self
+            .store
+            .write()
+            .map_err(|e| DbError::General(format!("Memory store lock poisoned: {e}")))?;
+        let last_sweep = self.last_sweep.load(Ordering::Relaxed);
+        if now >= last_sweep + SWEEP_INTERVAL {
+            self.last_sweep.store(now, Ordering::Relaxed);
+            trace!("🧠 Sweeping expired records");
+            store.retain(|_, data| {
+                data.purge(now);
+                !data.is_empty()
+            });
+        }
+        Ok(store)
+    }
+}
+
+#[async_trait]
+impl DbClient for MemoryClientImpl {
+    async fn add_user(&self, user: &User) -> DbResult<()> {
+        self.add_user_with_channels(user, Vec::new()).await
+    }
+
+    /// Written under a single lock
+    async fn add_user_with_channels(
+        &self,
+        user: &User,
+        channels: Vec<ChannelRecord>,
+    ) -> DbResult<()> {
+        if user.version.is_none() {
+            return Err(DbError::General(
+                "add_user expected a user version field".to_owned(),
+            ));
+        }
+        let now = sec_since_epoch();
+        let mut store = self.write()?;
+        let data = store.entry(user.uaid).or_default();
+        if data.live_user(now).is_some() {
+            return Err(DbError::Conditional);
+        }
+        data.user = Some(Expiring::new(user.clone(), MAX_ROUTER_TTL));
+        data.add_channels(channels);
+        Ok(())
+    }
+
+    /// The update only occurs if the stored `version` and `router_type` match
+    /// the user's and the stored `connected_at` is not newer.
+    async fn update_user(&self, user: &mut User) -> DbResult<bool> {
+        let Some(version) = user.version else {
+            return Err(DbError::General(
+                "update_user expected a user version field".to_owned(),
+            ));
+        };
+        let new_version = Uuid::new_v4();
+        let mut store = self.write()?;
+        let updated = match store
+            .get_mut(&user.uaid)
+            .and_then(|data| data.live_user(sec_since_epoch()))
+        {
+            Some(current)
+                if current.version == Some(version)
+                    && current.router_type == user.router_type
+                    && current.connected_at <= user.connected_at =>
+            {
+                *current = User {
+                    version: Some(new_version),
+                    ..user.clone()
+                };
+                true
+            }
+            _ => false,
+        };
+        if updated {
+            if let Some(stored) = store.get_mut(&user.uaid).and_then(|d| d.user.as_mut()) {
+                // Refresh the expiry, as Bigtable's garbage collection would
+                stored.expiry = sec_since_epoch() + MAX_ROUTER_TTL;
+            }
+        }
+        user.version = Some(new_version);
+        Ok(updated)
+    }
+
+    async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>> {
+        let now = sec_since_epoch();
+        Ok(self
+            .read()?
+            .get(uaid)
+            .and_then(|data| data.user.as_ref())
+            .filter(|user| user.live(now))
+            .map(|user| user.value.clone()))
+    }
+
+    async fn remove_user(&self, uaid: &Uuid) -> DbResult<()> {
+        self.write()?.remove(uaid);
+        Ok(())
+    }
+
+    async fn list_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        let now = sec_since_epoch();
+        let mut uaids: Vec<Uuid> = self
+            .read()?
+            .iter()
+            .filter(|(uaid, data)| {
+                data.user.as_ref().map_or(false, |user| user.live(now))
+                    && uaid.simple().to_string().starts_with(prefix)
+            })
+            .map(|(uaid, _)| *uaid)
+            .collect();
+        uaids.sort();
+        Ok(uaids)
+    }
+
+    async fn list_orphan_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        let now = sec_since_epoch();
+        let mut uaids: Vec<Uuid> = self
+            .read()?
+            .iter()
+            .filter(|(uaid, data)| {
+                !data.user.as_ref().map_or(false, |user| user.live(now))
+                    && data.has_live_records(now)
+                    && uaid.simple().to_string().starts_with(prefix)
+            })
+            .map(|(uaid, _)| *uaid)
+            .collect();
+        uaids.sort();
+        Ok(uaids)
+    }
+
+    async fn add_channel(&self, uaid: &Uuid, channel: &ChannelRecord) -> DbResult<()> {
+        self.add_channels(uaid, vec![channel.clone()]).await
+    }
+
+    async fn add_channels(&self, uaid: &Uuid, channels: Vec<ChannelRecord>) -> DbResult<()> {
+        if channels.is_empty() {
+            return Ok(());
+        }
+        let mut store = self.write()?;
+        store.entry(*uaid).or_default().add_channels(channels);
+        Ok(())
+    }
+
+    async fn get_channels(&self, uaid: &Uuid) -> DbResult<HashSet<Uuid>> {
+        let now = sec_since_epoch();
+        Ok(self
+            .read()?
+            .get(uaid)
+            .map(|data| {
+                data.channels
+                    .iter()
+                    .filter(|(_, channel)| channel.live(now))
+                    .map(|(chid, _)| *chid)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn get_channel_records(&self, uaid: &Uuid) -> DbResult<Vec<ChannelRecord>> {
+        let now = sec_since_epoch();
+        Ok(self
+            .read()?
+            .get(uaid)
+            .map(|data| {
+                data.channels
+                    .values()
+                    .filter(|channel| channel.live(now))
+                    .map(|channel| channel.value.clone())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn record_channel_push(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
+        let now = sec_since_epoch();
+        let mut store = self.write()?;
+        if let Some(channel) = store
+            .get_mut(uaid)
+            .and_then(|data| data.channels.get_mut(channel_id))
+            .filter(|channel| channel.live(now))
+        {
+            channel.value.record_push(now);
+        }
+        Ok(())
+    }
+
+    /// Delete the channel. Does not delete its associated pending messages.
+    async fn remove_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
+        let now = sec_since_epoch();
+        let mut store = self.write()?;
+        let Some(data) = store.get_mut(uaid) else {
+            return Ok(false);
+        };
+        let removed = data
+            .channels
+            .remove(channel_id)
+            .map_or(false, |channel| channel.live(now));
+        // Write a new version, as Bigtable does, so that any pending
+        // `update_user` against the old record fails.
+        if let Some(user) = data.live_user(now) {
+            user.version = Some(Uuid::new_v4());
+        }
+        Ok(removed)
+    }
+
+    async fn remove_node_id(
+        &self,
+        uaid: &Uuid,
+        node_id: &str,
+        connected_at: u64,
+        version: &Option<Uuid>,
+    ) -> DbResult<bool> {
+        let mut store = self.write()?;
+        let Some(user) = store
+            .get_mut(uaid)
+            .and_then(|data| data.live_user(sec_since_epoch()))
+        else {
+            return Ok(false);
+        };
+        let matched = match version {
+            Some(_) => user.version == *version,
+            None => user.node_id.as_deref() == Some(node_id) && user.connected_at == connected_at,
+        };
+        if matched {
+            user.node_id = None;
+        }
+        Ok(matched)
+    }
+
+    /// Write the notification to storage.
+    async fn save_message(&self, uaid: &Uuid, message: Notification) -> DbResult<()> {
+        self.save_messages(uaid, vec![message]).await
+    }
+
+    /// Write the notifications to storage, expiring each one after its TTL.
+    ///
+    /// A topic message shares its `chidmessageid` with every message for that
+    /// topic, so a newer message replaces the prior one.
+    async fn save_messages(&self, uaid: &Uuid, messages: Vec<Notification>) -> DbResult<()> {
+        self.settings
+            .quota
+            .enforce(self, &self.metrics, uaid, &messages)
+            .await?;
+        let mut topics = Vec::with_capacity(messages.len());
+        {
+            let mut store = self.write()?;
+            let data = store.entry(*uaid).or_default();
+            for mut message in messages {
+                if message.ttl == 0 {
+                    // Would expire immediately
+                    trace!("🧠 Skipping message with a zero TTL");
+                    continue;
+                }
+                self.compressor.compress_notification(&mut message);
+                let chidmessageid = message.chidmessageid();
+                let is_topic = message.topic.is_some();
+                let sortkey_timestamp = message.sortkey_timestamp.unwrap_or_default();
+                let ttl = min(message.ttl, MAX_EXPIRY);
+                let message = Expiring::new(message, ttl);
+                if is_topic {
+                    data.topic_messages.insert(chidmessageid, message);
+                } else {
+                    data.timestamp_messages
+                        .insert((sortkey_timestamp, chidmessageid), message);
+                }
+                topics.push(is_topic);
+            }
+        }
+        for topic in topics {
+            self.metrics
+                .incr_with_tags("notification.message.stored")
+                .with_tag("topic", &topic.to_string())
+                .send();
+        }
+        Ok(())
+    }
+
+    /// Return `limit` pending topic messages. `limit=0` for all messages.
+    async fn fetch_topic_messages(
+        &self,
+        uaid: &Uuid,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        let now = sec_since_epoch();
+        let limit = if limit > 0 { limit } else { usize::MAX };
+        let mut messages: Vec<Notification> = self
+            .read()?
+            .get(uaid)
+            .map(|data| {
+                data.topic_messages
+                    .values()
+                    .filter(|message| message.live(now))
+                    .map(|message| message.value.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        // Return a stable order, as the other data stores do
+        messages.sort_by_key(|message| message.chidmessageid());
+        messages.truncate(limit);
+        for message in &mut messages {
+            decompress_notification(message)?;
+        }
+        // As with Bigtable, `current_timestamp` is read from [get_user]
+        Ok(FetchMessageResponse {
+            messages,
+            timestamp: None,
+        })
+    }
+
+    /// Return `limit` messages pending for a UAID that have a sortkey_timestamp after
+    /// what's specified. `limit=0` for all messages.
+    async fn fetch_timestamp_messages(
+        &self,
+        uaid: &Uuid,
+        timestamp: Option<u64>,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        let now = sec_since_epoch();
+        let limit = if limit > 0 { limit } else { usize::MAX };
+        let mut messages: Vec<Notification> = self
+            .read()?
+            .get(uaid)
+            .map(|data| {
+                data.timestamp_messages
+                    .iter()
+                    .filter(|((sortkey_timestamp, _), message)| {
+                        timestamp.map_or(true, |ts| *sortkey_timestamp > ts) && message.live(now)
+                    })
+                    .take(limit)
+                    .map(|(_, message)| message.value.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        for message in &mut messages {
+            decompress_notification(message)?;
+        }
+        // The timestamp of the last message read
+        let timestamp = messages.last().and_then(|m| m.sortkey_timestamp);
+        Ok(FetchMessageResponse {
+            messages,
+            timestamp,
+        })
+    }
+
+    /// Set the `current_timestamp` in the user record (writing a new
+    /// `version`).
+    async fn increment_storage(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
+        let mut store = self.write()?;
+        if let Some(user) = store
+            .get_mut(uaid)
+            .and_then(|data| data.live_user(sec_since_epoch()))
+        {
+            user.current_timestamp = Some(timestamp);
+            user.version = Some(Uuid::new_v4());
+        }
+        Ok(())
+    }
+
+    async fn get_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<Option<Notification>> {
+        let now = sec_since_epoch();
+        let message = self.read()?.get(uaid).and_then(|data| {
+            if sort_key.starts_with(&format!("{TOPIC_NOTIFICATION_PREFIX}:")) {
+                data.topic_messages.get(sort_key).cloned()
+            } else {
+                data.timestamp_messages
+                    .iter()
+                    .find(|((_, chidmessageid), _)| chidmessageid == sort_key)
+                    .map(|(_, message)| message.clone())
+            }
+        });
+        message
+            .filter(|message| message.live(now))
+            .map(|mut message| {
+                decompress_notification(&mut message.value)?;
+                Ok(message.value)
+            })
+            .transpose()
+    }
+
+    async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()> {
+        {
+            let mut store = self.write()?;
+            if let Some(data) = store.get_mut(uaid) {
+                if sort_key.starts_with(&format!("{TOPIC_NOTIFICATION_PREFIX}:")) {
+                    data.topic_messages.remove(sort_key);
+                } else {
+                    data.timestamp_messages
+                        .retain(|(_, chidmessageid), _| chidmessageid != sort_key);
+                }
+            }
+        }
+        self.metrics
+            .incr_with_tags("notification.message.deleted")
+            .send();
+        Ok(())
+    }
+
+    async fn remove_messages(&self, uaid: &Uuid, sort_keys: &[String]) -> DbResult<()> {
+        {
+            let mut store = self.write()?;
+            if let Some(data) = store.get_mut(uaid) {
+                for sort_key in sort_keys {
+                    data.topic_messages.remove(sort_key);
+                }
+                data.timestamp_messages
+                    .retain(|(_, chidmessageid), _| !sort_keys.contains(chidmessageid));
+            }
+        }
+        self.metrics
+            .count_with_tags("notification.message.deleted", sort_keys.len() as i64)
+            .send();
+        Ok(())
+    }
+
+    /// Returns true, because there are no tables.
+    async fn router_table_exists(&self) -> DbResult<bool> {
+        Ok(true)
+    }
+
+    /// Returns true, because there are no tables.
+    async fn message_table_exists(&self) -> DbResult<bool> {
+        Ok(true)
+    }
+
+    async fn health_check(&self) -> DbResult<bool> {
+        Ok(!self.store.is_poisoned())
+    }
+
+    /// In-memory storage does not support message table rotation
+    fn rotating_message_table(&self) -> Option<&str> {
+        None
+    }
+
+    fn box_clone(&self) -> Box<dyn DbClient> {
+        Box::new(self.clone())
+    }
+
+    fn name(&self) -> String {
+        "Memory".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cadence::StatsdClient;
+
+    use super::*;
+    use crate::util::ms_since_epoch;
+
+    fn new_client_with_settings(db_settings: &str) -> MemoryClientImpl {
+        MemoryClientImpl::new(
+            Arc::new(StatsdClient::builder("", cadence::NopMetricSink).build()),
+            &DbSettings {
+                dsn: Some("memory://".to_owned()),
+                db_settings: db_settings.to_owned(),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    }
+
+    fn new_client() -> MemoryClientImpl {
+        new_client_with_settings("")
+    }
+
+    fn notif(chid: Uuid, topic: Option<&str>, sortkey_timestamp: u64, ttl: u64) -> Notification {
+        Notification {
+            channel_id: chid,
+            version: Uuid::new_v4().simple().to_string(),
+            ttl,
+            topic: topic.map(str::to_owned),
+            timestamp: sec_since_epoch(),
+            data: Some("data".to_owned()),
+            sortkey_timestamp: Some(sortkey_timestamp),
+            ..Default::default()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn run_gauntlet() -> DbResult<()> {
+        let client = new_client();
+        let uaid = Uuid::new_v4();
+        let chid = Uuid::new_v4();
+        let node_id = "test_node".to_owned();
+        let connected_at = ms_since_epoch();
+
+        let test_user = User {
+            uaid,
+            connected_at,
+            node_id: Some(node_id.clone()),
+            ..Default::default()
+        };
+        client.add_user(&test_user).await?;
+        let err = client.add_user(&test_user).await.unwrap_err();
+        assert!(matches!(err, DbError::Conditional));
+        let fetched = client.get_user(&uaid).await?.unwrap();
+        assert_eq!(fetched, test_user);
+        let prefix = uaid.simple().to_string()[..4].to_owned();
+        assert_eq!(client.list_uaids(&prefix).await?, vec![uaid]);
+
+        // channels
+        let mut channels: HashSet<Uuid> = (0..10).map(|_| Uuid::new_v4()).collect();
+        channels.insert(chid);
+        client
+            .add_channels(&uaid, channels.iter().copied().map(Into::into).collect())
+            .await?;
+        assert_eq!(client.get_channels(&uaid).await?, channels);
+        assert!(client.remove_channel(&uaid, &chid).await?);
+        assert!(!client.remove_channel(&uaid, &chid).await?);
+        channels.remove(&chid);
+        assert_eq!(client.get_channels(&uaid).await?, channels);
+
+        // remove_channel wrote a new version: the prior one is stale
+        let mut stale = fetched.clone();
+        assert!(!client.update_user(&mut stale).await?);
+
+        // an older connected_at is refused
+        let mut fetched = client.get_user(&uaid).await?.unwrap();
+        let mut older = User {
+            connected_at: connected_at - 1,
+            ..fetched.clone()
+        };
+        assert!(!client.update_user(&mut older).await?);
+        assert_eq!(
+            client.get_user(&uaid).await?.unwrap().connected_at,
+            connected_at
+        );
+
+        // a newer one is accepted
+        fetched.connected_at = connected_at + 300;
+        assert!(client.update_user(&mut fetched).await?);
+        let updated = client.get_user(&uaid).await?.unwrap();
+        assert_eq!(updated.connected_at, connected_at + 300);
+        assert_eq!(updated.version, fetched.version);
+
+        // remove_node_id
+        assert!(
+            !client
+                .remove_node_id(&uaid, &node_id, connected_at, &Some(Uuid::new_v4()))
+                .await?
+        );
+        assert!(
+            client
+                .remove_node_id(&uaid, &node_id, connected_at, &updated.version)
+                .await?
+        );
+        assert_eq!(client.get_user(&uaid).await?.unwrap().node_id, None);
+
+        client.remove_user(&uaid).await?;
+        assert!(client.get_user(&uaid).await?.is_none());
+        assert!(client.get_channels(&uaid).await?.is_empty());
+        assert!(client.list_uaids(&prefix).await?.is_empty());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn list_orphan_uaids() -> DbResult<()> {
+        let client = new_client();
+        let user = User::default();
+        let orphan = Uuid::new_v4();
+        client
+            .add_user_with_channels(&user, vec![ChannelRecord::from(Uuid::new_v4())])
+            .await?;
+        client
+            .add_channel(&orphan, &ChannelRecord::from(Uuid::new_v4()))
+            .await?;
+
+        assert_eq!(client.list_orphan_uaids("").await?, vec![orphan]);
+        let prefix = orphan.simple().to_string()[..4].to_owned();
+        assert_eq!(client.list_orphan_uaids(&prefix).await?, vec![orphan]);
+        client.remove_user(&orphan).await?;
+        assert!(client.list_orphan_uaids("").await?.is_empty());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn add_user_with_channels() -> DbResult<()> {
+        let client = new_client();
+        let user = User::default();
+        let chid = Uuid::new_v4();
+
+        client
+            .add_user_with_channels(&user, vec![ChannelRecord::from(chid)])
+            .await?;
+        assert!(client.get_user(&user.uaid).await?.is_some());
+        assert_eq!(
+            client.get_channels(&user.uaid).await?,
+            HashSet::from([chid])
+        );
+
+        // Nothing is written for an existing user
+        let err = client
+            .add_user_with_channels(&user, vec![ChannelRecord::from(Uuid::new_v4())])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DbError::Conditional));
+        assert_eq!(
+            client.get_channels(&user.uaid).await?,
+            HashSet::from([chid])
+        );
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn channel_records() -> DbResult<()> {
+        let client = new_client();
+        let uaid = Uuid::new_v4();
+        let channel = ChannelRecord::new(Uuid::new_v4(), Some("BCDEF_0123456789")).unwrap();
+        assert!(channel.created_at.is_some());
+        assert_eq!(
+            channel.app_server_key_hash.as_ref().map(String::len),
+            Some(64)
+        );
+        let legacy = ChannelRecord::from(Uuid::new_v4());
+        client.add_channel(&uaid, &channel).await?;
+        client.add_channel(&uaid, &legacy).await?;
+
+        client
+            .record_channel_push(&uaid, &channel.channel_id)
+            .await?;
+        client
+            .record_channel_push(&uaid, &channel.channel_id)
+            .await?;
+        // unknown channels are ignored
+        client.record_channel_push(&uaid, &Uuid::new_v4()).await?;
+
+        let mut records = client.get_channel_records(&uaid).await?;
+        records.sort_by_key(|record| record.push_count);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0], legacy);
+        assert_eq!(records[1].app_server_key_hash, channel.app_server_key_hash);
+        assert_eq!(records[1].push_count, 2);
+        let last_push_at = records[1].last_push_at.unwrap();
+        assert_eq!(records[1].last_active(), Some(last_push_at));
+
+        // only channels with a known last activity may be stale
+        assert!(records[1].is_stale(last_push_at + 301, 300));
+        assert!(!records[1].is_stale(last_push_at + 300, 300));
+        assert!(!records[0].is_stale(last_push_at + 301, 300));
+
+        assert!(client.remove_channel(&uaid, &channel.channel_id).await?);
+        assert_eq!(client.get_channel_records(&uaid).await?, vec![legacy]);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn messages() -> DbResult<()> {
+        let client = new_client();
+        let uaid = Uuid::new_v4();
+        let chid = Uuid::new_v4();
+        let now = ms_since_epoch();
+
+        // timestamp messages are returned in order
+        client
+            .save_messages(
+                &uaid,
+                vec![
+                    notif(chid, None, now + 2, 300),
+                    notif(chid, None, now, 300),
+                    notif(chid, None, now + 1, 300),
+                    // never stored
+                    notif(chid, None, now + 3, 0),
+                ],
+            )
+            .await?;
+        let fetched = client.fetch_timestamp_messages(&uaid, None, 10).await?;
+        let sortkeys: Vec<_> = fetched
+            .messages
+            .iter()
+            .map(|m| m.sortkey_timestamp.unwrap())
+            .collect();
+        assert_eq!(sortkeys, vec![now, now + 1, now + 2]);
+        assert_eq!(fetched.timestamp, Some(now + 2));
+
+        let fetched = client.fetch_timestamp_messages(&uaid, Some(now), 1).await?;
+        assert_eq!(fetched.messages.len(), 1);
+        assert_eq!(fetched.timestamp, Some(now + 1));
+
+        let removed = notif(chid, None, now, 300).chidmessageid();
+        assert!(client.get_message(&uaid, &removed).await?.is_some());
+        client.remove_message(&uaid, &removed).await?;
+        let fetched = client.fetch_timestamp_messages(&uaid, None, 0).await?;
+        assert_eq!(fetched.messages.len(), 2);
+        assert!(client.get_message(&uaid, &removed).await?.is_none());
+
+        // topic messages replace their predecessor
+        let first = notif(chid, Some("topic"), now, 300);
+        let second = notif(chid, Some("topic"), now, 300);
+        client.save_message(&uaid, first.clone()).await?;
+        client.save_message(&uaid, second.clone()).await?;
+        let fetched = client.fetch_topic_messages(&uaid, 10).await?;
+        assert_eq!(fetched.messages.len(), 1);
+        assert_eq!(fetched.messages[0].version, second.version);
+        let pending = client.get_message(&uaid, &first.chidmessageid()).await?;
+        assert_eq!(pending.map(|m| m.version), Some(second.version.clone()));
+
+        client
+            .remove_message(&uaid, &second.chidmessageid())
+            .await?;
+        assert!(client
+            .fetch_topic_messages(&uaid, 10)
+            .await?
+            .messages
+            .is_empty());
+
+        // a batch of both kinds
+        let topic = notif(chid, Some("topic"), now, 300);
+        client.save_message(&uaid, topic.clone()).await?;
+        let mut sort_keys: Vec<_> = client
+            .fetch_timestamp_messages(&uaid, None, 0)
+            .await?
+            .messages
+            .iter()
+            .map(|m| m.chidmessageid())
+            .collect();
+        sort_keys.push(topic.chidmessageid());
+        client.remove_messages(&uaid, &sort_keys).await?;
+        assert!(client
+            .fetch_timestamp_messages(&uaid, None, 0)
+            .await?
+            .messages
+            .is_empty());
+        assert!(client
+            .fetch_topic_messages(&uaid, 0)
+            .await?
+            .messages
+            .is_empty());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn expiry() -> DbResult<()> {
+        let client = new_client();
+        let uaid = Uuid::new_v4();
+        let chid = Uuid::new_v4();
+        let now = ms_since_epoch();
+
+        client
+            .save_messages(
+                &uaid,
+                vec![
+                    notif(chid, None, now, 300),
+                    notif(chid, Some("topic"), now, 300),
+                ],
+            )
+            .await?;
+        // Age the stored messages past their TTL
+        for data in client.store.write().unwrap().values_mut() {
+            for message in data.topic_messages.values_mut() {
+                message.expiry = sec_since_epoch();
+            }
+            for message in data.timestamp_messages.values_mut() {
+                message.expiry = sec_since_epoch();
+            }
+        }
+        assert!(client
+            .fetch_timestamp_messages(&uaid, None, 10)
+            .await?
+            .messages
+            .is_empty());
+        assert!(client
+            .fetch_topic_messages(&uaid, 10)
+            .await?
+            .messages
+            .is_empty());
+        let topic = notif(chid, Some("topic"), now, 300).chidmessageid();
+        assert!(client.get_message(&uaid, &topic).await?.is_none());
+
+        // and are swept
+        client.last_sweep.store(0, Ordering::Relaxed);
+        client.remove_user(&Uuid::new_v4()).await?;
+        assert!(client.store.read().unwrap().get(&uaid).is_none());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn quota() -> DbResult<()> {
+        let client = new_client_with_settings(
+            r#"{"quota":{"max_messages_per_uaid":3,"max_messages_per_channel":2}}"#,
+        );
+        let uaid = Uuid::new_v4();
+        let chid = Uuid::new_v4();
+        let chid2 = Uuid::new_v4();
+        let now = ms_since_epoch();
+
+        // the oldest message of the channel is evicted
+        let oldest = notif(chid, None, now, 300);
+        client.save_message(&uaid, oldest.clone()).await?;
+        client
+            .save_message(&uaid, notif(chid, None, now + 1, 300))
+            .await?;
+        client
+            .save_message(&uaid, notif(chid, None, now + 2, 300))
+            .await?;
+        let fetched = client.fetch_timestamp_messages(&uaid, None, 0).await?;
+        assert_eq!(fetched.messages.len(), 2);
+        assert!(fetched
+            .messages
+            .iter()
+            .all(|m| m.chidmessageid() != oldest.chidmessageid()));
+
+        // replacing a topic message doesn't count against the quota
+        client
+            .save_message(&uaid, notif(chid2, Some("topic"), now + 3, 300))
+            .await?;
+        client
+            .save_message(&uaid, notif(chid2, Some("topic"), now + 3, 300))
+            .await?;
+        assert_eq!(
+            client
+                .fetch_timestamp_messages(&uaid, None, 0)
+                .await?
+                .messages
+                .len(),
+            2
+        );
+
+        // the oldest message of the UAID is evicted
+        client
+            .save_message(&uaid, notif(chid2, None, now + 4, 300))
+            .await?;
+        let fetched = client.fetch_timestamp_messages(&uaid, None, 0).await?;
+        assert_eq!(fetched.messages.len(), 2);
+        assert_eq!(fetched.messages[0].sortkey_timestamp, Some(now + 2));
+        assert_eq!(
+            client.fetch_topic_messages(&uaid, 0).await?.messages.len(),
+            1
+        );
+
+        let client = new_client_with_settings(
+            r#"{"quota":{"max_messages_per_channel":1,"on_overflow":"reject"}}"#,
+        );
+        client
+            .save_message(&uaid, notif(chid, None, now, 300))
+            .await?;
+        assert!(matches!(
+            client
+                .save_message(&uaid, notif(chid, None, now + 1, 300))
+                .await,
+            Err(DbError::QuotaExceeded(_))
+        ));
+        // other channels are unaffected
+        client
+            .save_message(&uaid, notif(chid2, None, now + 1, 300))
+            .await?;
+        assert_eq!(
+            client
+                .fetch_timestamp_messages(&uaid, None, 0)
+                .await?
+                .messages
+                .len(),
+            2
+        );
+        Ok(())
+    }
+}
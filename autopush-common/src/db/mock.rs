@@ -5,7 +5,7 @@
 use crate::db::client::DbClient;
 pub use crate::db::client::MockDbClient;
 use crate::db::error::DbResult;
-use crate::db::User;
+use crate::db::{ChannelRecord, User};
 use crate::notification::Notification;
 use async_trait::async_trait;
 use std::collections::HashSet;
@@ -20,6 +20,16 @@ impl DbClient for Arc<MockDbClient> {
         Arc::as_ref(self).add_user(user).await
     }
 
+    async fn add_user_with_channels(
+        &self,
+        user: &User,
+        channels: Vec<ChannelRecord>,
+    ) -> DbResult<()> {
+        Arc::as_ref(self)
+            .add_user_with_channels(user, channels)
+            .await
+    }
+
     async fn update_user(&self, user: &mut User) -> DbResult<bool> {
         Arc::as_ref(self).update_user(user).await
     }
@@ -32,11 +42,19 @@ impl DbClient for Arc<MockDbClient> {
         Arc::as_ref(self).remove_user(uaid).await
     }
 
-    async fn add_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
-        Arc::as_ref(self).add_channel(uaid, channel_id).await
+    async fn list_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        Arc::as_ref(self).list_uaids(prefix).await
     }
 
-    async fn add_channels(&self, uaid: &Uuid, channels: HashSet<Uuid>) -> DbResult<()> {
+    async fn list_orphan_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        Arc::as_ref(self).list_orphan_uaids(prefix).await
+    }
+
+    async fn add_channel(&self, uaid: &Uuid, channel: &ChannelRecord) -> DbResult<()> {
+        Arc::as_ref(self).add_channel(uaid, channel).await
+    }
+
+    async fn add_channels(&self, uaid: &Uuid, channels: Vec<ChannelRecord>) -> DbResult<()> {
         Arc::as_ref(self).add_channels(uaid, channels).await
     }
 
@@ -44,6 +62,16 @@ impl DbClient for Arc<MockDbClient> {
         Arc::as_ref(self).get_channels(uaid).await
     }
 
+    async fn get_channel_records(&self, uaid: &Uuid) -> DbResult<Vec<ChannelRecord>> {
+        Arc::as_ref(self).get_channel_records(uaid).await
+    }
+
+    async fn record_channel_push(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
+        Arc::as_ref(self)
+            .record_channel_push(uaid, channel_id)
+            .await
+    }
+
     async fn remove_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
         Arc::as_ref(self).remove_channel(uaid, channel_id).await
     }
@@ -87,14 +115,26 @@ impl DbClient for Arc<MockDbClient> {
             .await
     }
 
+    async fn fetch_quota_messages(&self, uaid: &Uuid) -> DbResult<Vec<Notification>> {
+        Arc::as_ref(self).fetch_quota_messages(uaid).await
+    }
+
     async fn increment_storage(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
         Arc::as_ref(self).increment_storage(uaid, timestamp).await
     }
 
+    async fn get_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<Option<Notification>> {
+        Arc::as_ref(self).get_message(uaid, sort_key).await
+    }
+
     async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()> {
         Arc::as_ref(self).remove_message(uaid, sort_key).await
     }
 
+    async fn remove_messages(&self, uaid: &Uuid, sort_keys: &[String]) -> DbResult<()> {
+        Arc::as_ref(self).remove_messages(uaid, sort_keys).await
+    }
+
     async fn router_table_exists(&self) -> DbResult<bool> {
         Arc::as_ref(self).router_table_exists().await
     }
@@ -11,7 +11,9 @@
 use std::cmp::min;
 use std::collections::{HashMap, HashSet};
 use std::result::Result as StdResult;
+use std::sync::Arc;
 
+use cadence::StatsdClient;
 use lazy_static::lazy_static;
 use regex::RegexSet;
 use serde::Serializer;
@@ -24,21 +26,41 @@ use util::generate_last_connect;
 
 #[cfg(feature = "bigtable")]
 pub mod bigtable;
+pub mod caching;
 pub mod client;
-#[cfg(all(feature = "bigtable", feature = "dynamodb"))]
+pub mod compression;
 pub mod dual;
 #[cfg(feature = "dynamodb")]
 pub mod dynamodb;
+pub mod encryption;
 pub mod error;
+#[cfg(feature = "faulty")]
+pub mod faulty;
+pub mod instrumented;
+pub mod memory;
 pub mod models;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod quota;
+#[cfg(feature = "redis")]
+pub mod redis;
 pub mod routing;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 mod util;
 
 // used by integration testing
 pub mod mock;
 
+use crate::db::client::DbClient;
+use crate::db::compression::{decompress_notification, Compressor};
+use crate::db::encryption::KeyRing;
+use crate::db::error::{DbError, DbResult};
+use crate::endpoint::app_server_key_digest;
 use crate::errors::{ApcErrorKind, Result};
-use crate::notification::{Notification, STANDARD_NOTIFICATION_PREFIX, TOPIC_NOTIFICATION_PREFIX};
+use crate::notification::{
+    Notification, Urgency, STANDARD_NOTIFICATION_PREFIX, TOPIC_NOTIFICATION_PREFIX,
+};
 use crate::util::timing::{ms_since_epoch, sec_since_epoch};
 use models::{NotificationHeaders, RangeKey};
 
@@ -56,9 +78,16 @@ pub enum StorageType {
     BigTable,
     #[cfg(feature = "dynamodb")]
     DynamoDb,
-    #[cfg(all(feature = "bigtable", feature = "dynamodb"))]
     Dual,
-    // Postgres,
+    #[cfg(feature = "faulty")]
+    Faulty,
+    #[cfg(feature = "postgres")]
+    Postgres,
+    #[cfg(feature = "redis")]
+    Redis,
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+    Memory,
 }
 
 impl From<&str> for StorageType {
@@ -66,10 +95,18 @@ impl From<&str> for StorageType {
         match name.to_lowercase().as_str() {
             #[cfg(feature = "bigtable")]
             "bigtable" => Self::BigTable,
-            #[cfg(feature = "dual")]
             "dual" => Self::Dual,
             #[cfg(feature = "dynamodb")]
             "dynamodb" => Self::DynamoDb,
+            #[cfg(feature = "faulty")]
+            "faulty" => Self::Faulty,
+            #[cfg(feature = "postgres")]
+            "postgres" => Self::Postgres,
+            #[cfg(feature = "redis")]
+            "redis" => Self::Redis,
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Self::Sqlite,
+            "memory" => Self::Memory,
             _ => Self::INVALID,
         }
     }
@@ -85,8 +122,16 @@ impl StorageType {
         result.push("DynamoDB");
         #[cfg(feature = "bigtable")]
         result.push("Bigtable");
-        #[cfg(all(feature = "bigtable", feature = "dynamodb"))]
+        #[cfg(feature = "postgres")]
+        result.push("Postgres");
+        #[cfg(feature = "redis")]
+        result.push("Redis");
+        #[cfg(feature = "sqlite")]
+        result.push("Sqlite");
+        result.push("Memory");
         result.push("Dual");
+        #[cfg(feature = "faulty")]
+        result.push("Faulty");
         result
     }
 
@@ -101,6 +146,11 @@ impl StorageType {
         let dsn = dsn
             .clone()
             .unwrap_or(std::env::var("AWS_LOCAL_DYNAMODB").unwrap_or_default());
+        #[cfg(feature = "faulty")]
+        if dsn.starts_with(faulty::DSN_PREFIX) {
+            trace!("Found faulty");
+            return Self::Faulty;
+        }
         #[cfg(feature = "dynamodb")]
         if dsn.starts_with("http") {
             trace!("Found http");
@@ -119,7 +169,25 @@ impl StorageType {
             }
             return Self::BigTable;
         }
-        #[cfg(all(feature = "bigtable", feature = "dynamodb"))]
+        #[cfg(feature = "postgres")]
+        if dsn.starts_with("postgres") {
+            trace!("Found postgres");
+            return Self::Postgres;
+        }
+        #[cfg(feature = "redis")]
+        if dsn.starts_with("redis") {
+            trace!("Found redis");
+            return Self::Redis;
+        }
+        #[cfg(feature = "sqlite")]
+        if dsn.starts_with("sqlite") {
+            trace!("Found sqlite");
+            return Self::Sqlite;
+        }
+        if dsn.starts_with("memory") {
+            trace!("Found memory");
+            return Self::Memory;
+        }
         if dsn.to_lowercase() == "dual" {
             trace!("Found Dual mode");
             return Self::Dual;
@@ -128,18 +196,74 @@ impl StorageType {
     }
 }
 
+/// Create the [DbClient] for the data store specified by the settings' `dsn`
+pub fn client_from_settings(
+    metrics: Arc<StatsdClient>,
+    settings: &DbSettings,
+) -> DbResult<Box<dyn DbClient>> {
+    Ok(match StorageType::from_dsn(&settings.dsn) {
+        #[cfg(feature = "dynamodb")]
+        StorageType::DynamoDb => Box::new(dynamodb::DdbClientImpl::new(metrics, settings)?),
+        #[cfg(feature = "bigtable")]
+        StorageType::BigTable => Box::new(bigtable::BigTableClientImpl::new(metrics, settings)?),
+        #[cfg(feature = "postgres")]
+        StorageType::Postgres => Box::new(postgres::PgClientImpl::new(metrics, settings)?),
+        #[cfg(feature = "redis")]
+        StorageType::Redis => Box::new(redis::RedisClientImpl::new(metrics, settings)?),
+        #[cfg(feature = "sqlite")]
+        StorageType::Sqlite => Box::new(sqlite::SqliteClientImpl::new(metrics, settings)?),
+        StorageType::Memory => Box::new(memory::MemoryClientImpl::new(metrics, settings)?),
+        StorageType::Dual => Box::new(dual::DualClientImpl::new(metrics, settings)?),
+        #[cfg(feature = "faulty")]
+        StorageType::Faulty => Box::new(faulty::FaultyDbClient::new(metrics, settings)?),
+        StorageType::INVALID => {
+            return Err(DbError::General(format!(
+                "Invalid or unsupported DSN specified: {:?}",
+                settings.dsn
+            )))
+        }
+    })
+}
+
 /// The universal settings for the database
 /// abstractor.
-#[derive(Clone, Debug, Default, Deserialize)]
+#[derive(Clone, Default, Deserialize)]
 pub struct DbSettings {
     /// Database connector string
     pub dsn: Option<String>,
     /// A JSON formatted dictionary containing Database settings that
     /// are specific to the type of Data storage specified in the `dsn`
     /// See the respective settings structures for
-    /// [crate::db::dynamodb::DynamoDbSettings]
-    /// and [crate::db::bigtable::BigTableDbSettings]
+    /// [crate::db::dynamodb::DynamoDbSettings],
+    /// [crate::db::bigtable::BigTableDbSettings],
+    /// [crate::db::postgres::PostgresDbSettings],
+    /// [crate::db::redis::RedisDbSettings],
+    /// [crate::db::sqlite::SqliteDbSettings]
+    /// and [crate::db::memory::MemoryDbSettings]
     pub db_settings: String,
+    /// A JSON formatted key ring used to encrypt stored message payloads.
+    /// See [crate::db::encryption::KeyRing]
+    #[serde(default)]
+    pub key_ring: String,
+    /// Compress stored message payloads.
+    /// See [crate::db::compression]
+    #[serde(default)]
+    pub compress_data: bool,
+}
+
+/// Never log the key ring's keys
+impl std::fmt::Debug for DbSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DbSettings")
+            .field("dsn", &self.dsn)
+            .field("db_settings", &self.db_settings)
+            .field(
+                "key_ring",
+                &(!self.key_ring.is_empty()).then_some("<redacted>"),
+            )
+            .field("compress_data", &self.compress_data)
+            .finish()
+    }
 }
 //TODO: add `From<autopush::settings::Settings> for DbSettings`?
 //TODO: add `From<autoendpoint::settings::Settings> for DbSettings`?
@@ -234,6 +358,72 @@ impl User {
     }
 }
 
+/// A user's channel (subscription) along with its metadata.
+///
+/// Channels stored before this metadata was recorded only carry their
+/// `channel_id`.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ChannelRecord {
+    pub channel_id: Uuid,
+    /// When the channel was created (in seconds since the epoch)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    /// The hex encoded SHA-256 digest of the application server (VAPID
+    /// public) key the channel is bound to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_server_key_hash: Option<String>,
+    /// When a message was last pushed to the channel (in seconds since the
+    /// epoch)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_push_at: Option<u64>,
+    /// The number of messages pushed to the channel
+    #[serde(default)]
+    pub push_count: u64,
+}
+
+impl ChannelRecord {
+    /// Create a new channel, optionally bound to the (base64url encoded)
+    /// application server key
+    pub fn new(channel_id: Uuid, app_server_key: Option<&str>) -> Result<Self> {
+        Ok(Self {
+            channel_id,
+            created_at: Some(sec_since_epoch()),
+            app_server_key_hash: app_server_key
+                .map(|key| app_server_key_digest(key).map(hex::encode))
+                .transpose()?,
+            ..Default::default()
+        })
+    }
+
+    /// Record a message pushed to the channel at `now`
+    pub fn record_push(&mut self, now: u64) {
+        self.last_push_at = Some(now);
+        self.push_count += 1;
+    }
+
+    /// When the channel was last pushed to (or otherwise created), if known
+    pub fn last_active(&self) -> Option<u64> {
+        self.last_push_at.or(self.created_at)
+    }
+
+    /// Whether the channel has been inactive for longer than `max_idle`
+    /// seconds. Channels lacking metadata are never considered stale.
+    pub fn is_stale(&self, now: u64, max_idle: u64) -> bool {
+        self.last_active()
+            .map_or(false, |at| now.saturating_sub(at) > max_idle)
+    }
+}
+
+impl From<Uuid> for ChannelRecord {
+    /// A channel lacking any metadata
+    fn from(channel_id: Uuid) -> Self {
+        Self {
+            channel_id,
+            ..Default::default()
+        }
+    }
+}
+
 /// A stored Notification record. This is a notification that is to be stored
 /// until the User Agent reconnects. These are then converted to publishable
 /// [crate::db::Notification] records.
@@ -279,6 +469,12 @@ pub struct NotificationRecord {
     /// value before sending it to storage or a connection node.
     #[serde(skip_serializing_if = "Option::is_none")]
     updateid: Option<String>,
+    /// The RFC 8030 Urgency, only stored when it isn't the default (normal)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    urgency: Option<Urgency>,
+    /// The URL to send the message's receipt to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    receipt: Option<String>,
 }
 
 impl NotificationRecord {
@@ -345,8 +541,9 @@ impl NotificationRecord {
         }
     }
 
-    /// Convert the stored notifications into publishable notifications
-    pub fn into_notif(self) -> Result<Notification> {
+    /// Convert the stored notifications into publishable notifications,
+    /// decrypting and decompressing their payloads
+    pub fn into_notif(self, key_ring: &KeyRing) -> Result<Notification> {
         let key = Self::parse_chidmessageid(&self.chidmessageid)?;
         let version = key
             .legacy_version
@@ -355,7 +552,7 @@ impl NotificationRecord {
                 "No valid updateid/version found".into(),
             ))?;
 
-        Ok(Notification {
+        let mut notif = Notification {
             channel_id: key.channel_id,
             version,
             ttl: self.ttl.unwrap_or(0),
@@ -367,12 +564,25 @@ impl NotificationRecord {
             data: self.data,
             headers: self.headers.map(|m| m.into()),
             sortkey_timestamp: key.sortkey_timestamp,
-        })
+            urgency: self.urgency.unwrap_or_default(),
+            receipt: self.receipt,
+        };
+        key_ring.open_notification(&self.uaid, &mut notif)?;
+        decompress_notification(&mut notif)?;
+        Ok(notif)
     }
 
-    /// Convert from a publishable Notification to a stored notification
-    pub fn from_notif(uaid: &Uuid, val: Notification) -> Self {
-        Self {
+    /// Convert from a publishable Notification to a stored notification,
+    /// compressing and encrypting its payload
+    pub fn from_notif(
+        uaid: &Uuid,
+        mut val: Notification,
+        key_ring: &KeyRing,
+        compressor: &Compressor,
+    ) -> DbResult<Self> {
+        compressor.compress_notification(&mut val);
+        key_ring.seal_notification(uaid, &mut val)?;
+        Ok(Self {
             uaid: *uaid,
             chidmessageid: val.chidmessageid(),
             timestamp: Some(val.timestamp),
@@ -381,7 +591,9 @@ impl NotificationRecord {
             data: val.data,
             headers: val.headers.map(|h| h.into()),
             updateid: Some(val.version),
+            urgency: (val.urgency != Urgency::Normal).then_some(val.urgency),
+            receipt: val.receipt,
             ..Default::default()
-        }
+        })
     }
 }
@@ -0,0 +1,354 @@
+//! Limits on the number of messages stored for a user agent.
+//!
+//! Each data store's settings may include a `quota` dictionary, e.g.
+//! `{"quota":{"max_messages_per_uaid":100,"max_messages_per_channel":20,"on_overflow":"evict"}}`.
+//! Quotas are enforced by the data store clients before writing messages, so
+//! that a single abusive (or forgotten) subscription can't grow a user's
+//! stored messages without bound.
+use std::collections::{HashMap, HashSet};
+
+use cadence::{Counted, CountedExt, StatsdClient};
+use serde_derive::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::db::client::DbClient;
+use crate::db::error::{DbError, DbResult};
+use crate::notification::Notification;
+
+/// What to do when storing a message would exceed a quota
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaPolicy {
+    /// Remove the oldest stored messages to make room
+    #[default]
+    Evict,
+    /// Refuse to store the new message(s) ([DbError::QuotaExceeded])
+    Reject,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct MessageQuota {
+    /// Maximum number of messages stored for a UAID (unlimited if unset)
+    pub max_messages_per_uaid: Option<usize>,
+    /// Maximum number of messages stored for a channel (unlimited if unset)
+    pub max_messages_per_channel: Option<usize>,
+    /// What to do when a quota would be exceeded
+    pub on_overflow: QuotaPolicy,
+}
+
+impl MessageQuota {
+    pub fn is_enabled(&self) -> bool {
+        self.max_messages_per_uaid.is_some() || self.max_messages_per_channel.is_some()
+    }
+
+    /// Ensure there's room to store `incoming` for the UAID.
+    ///
+    /// Stored messages that `incoming` would replace (topic messages sharing
+    /// a `chidmessageid`), and timestamp messages at or before the user's
+    /// `current_timestamp` (already read by the UA), don't count towards the
+    /// quotas. Depending on the `on_overflow` policy, the oldest stored
+    /// messages are then removed to make room, or [DbError::QuotaExceeded] is
+    /// returned. Messages that can't fit even once everything else is evicted
+    /// are always rejected.
+    ///
+    /// The quotas are approximate: the stored messages are counted (via
+    /// [DbClient::fetch_quota_messages]) before writing, without any locking,
+    /// so concurrent saves for the same UAID may each see room for their
+    /// messages and together overshoot the limit.
+    pub async fn enforce(
+        &self,
+        db: &dyn DbClient,
+        metrics: &StatsdClient,
+        uaid: &Uuid,
+        incoming: &[Notification],
+    ) -> DbResult<()> {
+        // Zero TTL messages are never stored
+        let incoming: Vec<&Notification> = incoming.iter().filter(|n| n.ttl > 0).collect();
+        if !self.is_enabled() || incoming.is_empty() {
+            return Ok(());
+        }
+        let replaced: HashSet<String> = incoming.iter().map(|n| n.chidmessageid()).collect();
+        let mut stored = db.fetch_quota_messages(uaid).await?;
+        stored.retain(|n| !replaced.contains(&n.chidmessageid()));
+        // Oldest first
+        stored.sort_by_key(|n| (n.timestamp, n.sortkey_timestamp.unwrap_or_default()));
+
+        let mut evicted = Vec::new();
+        if let Some(max) = self.max_messages_per_channel {
+            let mut per_channel: HashMap<Uuid, usize> = HashMap::new();
+            for n in &incoming {
+                *per_channel.entry(n.channel_id).or_default() += 1;
+            }
+            for (channel_id, count) in per_channel {
+                let channel_stored = stored.iter().filter(|n| n.channel_id == channel_id);
+                let over = (channel_stored.clone().count() + count).saturating_sub(max);
+                if over == 0 {
+                    continue;
+                }
+                if self.on_overflow == QuotaPolicy::Reject || count > max {
+                    return Err(self.exceeded(metrics, "channel", uaid));
+                }
+                evicted.extend(channel_stored.take(over).map(|n| n.chidmessageid()));
+            }
+            stored.retain(|n| !evicted.contains(&n.chidmessageid()));
+        }
+        if let Some(max) = self.max_messages_per_uaid {
+            let over = (stored.len() + incoming.len()).saturating_sub(max);
+            if over > 0 {
+                if self.on_overflow == QuotaPolicy::Reject || incoming.len() > max {
+                    return Err(self.exceeded(metrics, "uaid", uaid));
+                }
+                evicted.extend(stored.iter().take(over).map(|n| n.chidmessageid()));
+            }
+        }
+
+        if !evicted.is_empty() {
+            debug!("📦 Evicting {} message(s) over quota", evicted.len(); "uaid" => uaid.to_string());
+            db.remove_messages(uaid, &evicted).await?;
+            metrics
+                .count_with_tags("notification.message.evicted", evicted.len() as i64)
+                .send();
+        }
+        Ok(())
+    }
+
+    fn exceeded(&self, metrics: &StatsdClient, scope: &str, uaid: &Uuid) -> DbError {
+        debug!("📦 Rejecting message(s) over the {} quota", scope; "uaid" => uaid.to_string());
+        metrics
+            .incr_with_tags("notification.message.quota_exceeded")
+            .with_tag("scope", scope)
+            .send();
+        DbError::QuotaExceeded(format!("Too many messages stored for the {scope}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cadence::{NopMetricSink, StatsdClient};
+    use uuid::Uuid;
+
+    use super::{MessageQuota, QuotaPolicy};
+    use crate::db::client::DbClient;
+    use crate::db::error::{DbError, DbResult};
+    use crate::db::memory::MemoryClientImpl;
+    use crate::db::{DbSettings, User};
+    use crate::notification::Notification;
+    use crate::util::{ms_since_epoch, sec_since_epoch};
+
+    fn metrics() -> Arc<StatsdClient> {
+        Arc::new(StatsdClient::builder("", NopMetricSink).build())
+    }
+
+    /// A data store without a quota of its own, so only [MessageQuota::enforce]
+    /// removes messages
+    async fn new_client(uaid: &Uuid) -> DbResult<MemoryClientImpl> {
+        let client = MemoryClientImpl::new(
+            metrics(),
+            &DbSettings {
+                dsn: Some("memory://".to_owned()),
+                ..Default::default()
+            },
+        )?;
+        client
+            .add_user(&User {
+                uaid: *uaid,
+                ..Default::default()
+            })
+            .await?;
+        Ok(client)
+    }
+
+    fn notif(channel_id: Uuid, topic: Option<&str>, sortkey_timestamp: u64) -> Notification {
+        Notification {
+            channel_id,
+            version: Uuid::new_v4().simple().to_string(),
+            ttl: 300,
+            topic: topic.map(str::to_owned),
+            timestamp: sec_since_epoch(),
+            sortkey_timestamp: Some(sortkey_timestamp),
+            ..Default::default()
+        }
+    }
+
+    fn quota(uaid: Option<usize>, channel: Option<usize>, policy: QuotaPolicy) -> MessageQuota {
+        MessageQuota {
+            max_messages_per_uaid: uaid,
+            max_messages_per_channel: channel,
+            on_overflow: policy,
+        }
+    }
+
+    async fn stored(client: &MemoryClientImpl, uaid: &Uuid) -> DbResult<Vec<Notification>> {
+        let mut stored = client.fetch_topic_messages(uaid, 0).await?.messages;
+        stored.extend(
+            client
+                .fetch_timestamp_messages(uaid, None, 0)
+                .await?
+                .messages,
+        );
+        Ok(stored)
+    }
+
+    #[test]
+    fn quota_settings() {
+        let quota: MessageQuota = serde_json::from_str("{}").unwrap();
+        assert!(!quota.is_enabled());
+        assert_eq!(quota.on_overflow, QuotaPolicy::Evict);
+
+        let quota: MessageQuota =
+            serde_json::from_str(r#"{"max_messages_per_channel":5,"on_overflow":"reject"}"#)
+                .unwrap();
+        assert!(quota.is_enabled());
+        assert_eq!(quota.max_messages_per_channel, Some(5));
+        assert_eq!(quota.max_messages_per_uaid, None);
+        assert_eq!(quota.on_overflow, QuotaPolicy::Reject);
+    }
+
+    #[actix_rt::test]
+    async fn evicts_oldest() -> DbResult<()> {
+        let uaid = Uuid::new_v4();
+        let client = new_client(&uaid).await?;
+        let (chid, chid2) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = ms_since_epoch();
+        let oldest = notif(chid, None, now);
+        client
+            .save_messages(
+                &uaid,
+                vec![
+                    oldest.clone(),
+                    notif(chid, None, now + 1),
+                    notif(chid2, None, now + 2),
+                ],
+            )
+            .await?;
+
+        // The channel's oldest message makes room on the channel
+        quota(None, Some(2), QuotaPolicy::Evict)
+            .enforce(&client, &metrics(), &uaid, &[notif(chid, None, now + 3)])
+            .await?;
+        let stored = stored(&client, &uaid).await?;
+        assert_eq!(stored.len(), 2);
+        assert!(stored
+            .iter()
+            .all(|n| n.chidmessageid() != oldest.chidmessageid()));
+
+        // The UAID's oldest message makes room on the UAID
+        quota(Some(2), None, QuotaPolicy::Evict)
+            .enforce(&client, &metrics(), &uaid, &[notif(chid2, None, now + 3)])
+            .await?;
+        let stored = stored(&client, &uaid).await?;
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].sortkey_timestamp, Some(now + 2));
+
+        // Messages that can't fit are rejected regardless
+        let incoming = [notif(chid, None, now + 3), notif(chid, None, now + 4)];
+        assert!(matches!(
+            quota(None, Some(1), QuotaPolicy::Evict)
+                .enforce(&client, &metrics(), &uaid, &incoming)
+                .await,
+            Err(DbError::QuotaExceeded(_))
+        ));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn rejects() -> DbResult<()> {
+        let uaid = Uuid::new_v4();
+        let client = new_client(&uaid).await?;
+        let (chid, chid2) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = ms_since_epoch();
+        client.save_message(&uaid, notif(chid, None, now)).await?;
+
+        let quota = quota(Some(2), Some(1), QuotaPolicy::Reject);
+        assert!(matches!(
+            quota
+                .enforce(&client, &metrics(), &uaid, &[notif(chid, None, now + 1)])
+                .await,
+            Err(DbError::QuotaExceeded(_))
+        ));
+        // Other channels are unaffected until the UAID is full
+        quota
+            .enforce(&client, &metrics(), &uaid, &[notif(chid2, None, now + 1)])
+            .await?;
+        client
+            .save_message(&uaid, notif(chid2, None, now + 1))
+            .await?;
+        assert!(quota
+            .enforce(
+                &client,
+                &metrics(),
+                &uaid,
+                &[notif(Uuid::new_v4(), None, now + 2)]
+            )
+            .await
+            .is_err());
+        // Nothing was removed
+        assert_eq!(stored(&client, &uaid).await?.len(), 2);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn ignores_replaced_topic_messages() -> DbResult<()> {
+        let uaid = Uuid::new_v4();
+        let client = new_client(&uaid).await?;
+        let chid = Uuid::new_v4();
+        let now = ms_since_epoch();
+        client
+            .save_message(&uaid, notif(chid, Some("topic"), now))
+            .await?;
+
+        let quota = quota(Some(1), None, QuotaPolicy::Reject);
+        quota
+            .enforce(
+                &client,
+                &metrics(),
+                &uaid,
+                &[notif(chid, Some("topic"), now + 1)],
+            )
+            .await?;
+        assert!(quota
+            .enforce(
+                &client,
+                &metrics(),
+                &uaid,
+                &[notif(chid, Some("other"), now + 1)]
+            )
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn ignores_read_messages() -> DbResult<()> {
+        let uaid = Uuid::new_v4();
+        let client = new_client(&uaid).await?;
+        let chid = Uuid::new_v4();
+        let now = ms_since_epoch();
+        client
+            .save_messages(
+                &uaid,
+                vec![notif(chid, None, now), notif(chid, None, now + 1)],
+            )
+            .await?;
+        client.increment_storage(&uaid, now).await?;
+
+        // Only the message after the `current_timestamp` counts
+        let quota = quota(Some(2), None, QuotaPolicy::Reject);
+        quota
+            .enforce(&client, &metrics(), &uaid, &[notif(chid, None, now + 2)])
+            .await?;
+        client.increment_storage(&uaid, now + 1).await?;
+        quota
+            .enforce(
+                &client,
+                &metrics(),
+                &uaid,
+                &[notif(chid, None, now + 2), notif(chid, None, now + 3)],
+            )
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1176 @@
+//! Redis data store.
+//!
+//! All of a user's data is stored under keys sharing the `{uaid}` hash tag,
+//! so that they reside in the same slot when running against a Redis Cluster
+//! and may be modified together by the Lua scripts below.
+//!
+//! Keys for the data are
+//! `{prefix}:{uaid}:user` - a hash containing the [User] record
+//! `{prefix}:{uaid}:channels` - a set of the user's channel IDs
+//! `{prefix}:{uaid}:channel_records` - a hash of the channels' JSON encoded
+//!     [ChannelRecord]s, keyed by their IDs
+//! `{prefix}:{uaid}:topic` - a sorted set indexing the topic messages
+//! `{prefix}:{uaid}:timestamp` - a sorted set indexing the timestamp
+//!     messages, scored by their `sortkey_timestamp`
+//! `{prefix}:{uaid}:msg:{chidmessageid}` - a message record
+//!
+//! Messages are written with the native key expiry set from their TTL. The
+//! index entries of expired messages are pruned as they are read.
+//!
+//! The `db_dsn` is the standard Redis connection URL, e.g.
+//! `redis://localhost:6379/0`
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use cadence::{Counted, CountedExt, StatsdClient};
+use deadpool_redis::{Config, Connection, Pool, PoolConfig, Runtime};
+use lazy_static::lazy_static;
+use redis::Script;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::db::{
+    client::{DbClient, FetchMessageResponse},
+    compression::Compressor,
+    encryption::KeyRing,
+    error::{DbError, DbResult},
+    quota::MessageQuota,
+    ChannelRecord, DbSettings, Notification, NotificationRecord, User, MAX_CHANNEL_TTL, MAX_EXPIRY,
+    MAX_ROUTER_TTL,
+};
+use crate::notification::TOPIC_NOTIFICATION_PREFIX;
+use crate::util::sec_since_epoch;
+
+lazy_static! {
+    /// Add the user (along with their channels) only if they don't already
+    /// exist.
+    ///
+    /// KEYS[1]: user key, KEYS[2]: channels key, KEYS[3]: channel records
+    /// key, ARGV[1]: user expiry, ARGV[2]: channels expiry, ARGV[3]: number
+    /// of channels (n), ARGV[4..3+2n]: channel id/record pairs,
+    /// ARGV[4+2n..]: field/value pairs
+    static ref ADD_USER: Script = Script::new(
+        r"
+        if redis.call('EXISTS', KEYS[1]) == 1 then
+            return 0
+        end
+        local channels = tonumber(ARGV[3])
+        redis.call('HSET', KEYS[1], unpack(ARGV, 4 + 2 * channels))
+        redis.call('EXPIRE', KEYS[1], ARGV[1])
+        if channels > 0 then
+            for i = 4, 2 + 2 * channels, 2 do
+                redis.call('SADD', KEYS[2], ARGV[i])
+            end
+            redis.call('HSET', KEYS[3], unpack(ARGV, 4, 3 + 2 * channels))
+            redis.call('EXPIRE', KEYS[2], ARGV[2])
+            redis.call('EXPIRE', KEYS[3], ARGV[2])
+        end
+        return 1
+        "
+    );
+
+    /// Replace the user only if its `version` and `router_type` match and
+    /// its `connected_at` is not newer.
+    ///
+    /// KEYS[1]: user key, ARGV[1]: expected version, ARGV[2]: router_type,
+    /// ARGV[3]: connected_at, ARGV[4]: expiry, ARGV[5..]: field/value pairs
+    static ref UPDATE_USER: Script = Script::new(
+        r"
+        local current = redis.call('HMGET', KEYS[1], 'version', 'router_type', 'connected_at')
+        if current[1] ~= ARGV[1] or current[2] ~= ARGV[2]
+            or tonumber(current[3]) > tonumber(ARGV[3]) then
+            return 0
+        end
+        redis.call('DEL', KEYS[1])
+        redis.call('HSET', KEYS[1], unpack(ARGV, 5))
+        redis.call('EXPIRE', KEYS[1], ARGV[4])
+        return 1
+        "
+    );
+
+    /// Set fields on the user only if they exist.
+    ///
+    /// KEYS[1]: user key, ARGV: field/value pairs
+    static ref SET_USER_FIELDS: Script = Script::new(
+        r"
+        if redis.call('EXISTS', KEYS[1]) == 0 then
+            return 0
+        end
+        redis.call('HSET', KEYS[1], unpack(ARGV))
+        return 1
+        "
+    );
+
+    /// Remove the `node_id` if the `version` matches, or (when no version is
+    /// specified) the `node_id` and `connected_at` match.
+    ///
+    /// KEYS[1]: user key, ARGV[1]: version (or ''), ARGV[2]: node_id,
+    /// ARGV[3]: connected_at
+    static ref REMOVE_NODE_ID: Script = Script::new(
+        r"
+        local current = redis.call('HMGET', KEYS[1], 'version', 'node_id', 'connected_at')
+        if ARGV[1] ~= '' then
+            if current[1] ~= ARGV[1] then
+                return 0
+            end
+        elseif current[2] ~= ARGV[2] or current[3] ~= ARGV[3] then
+            return 0
+        end
+        redis.call('HDEL', KEYS[1], 'node_id')
+        return 1
+        "
+    );
+
+    /// Remove all of the user's data, including their messages, provided the
+    /// message indexes still hold exactly the given messages (so no message
+    /// key is left behind).
+    ///
+    /// KEYS[1..5]: user, channels, topic, timestamp and channel records
+    /// keys, KEYS[6..]: message keys, ARGV[1..]: the message keys' ids
+    static ref REMOVE_USER: Script = Script::new(
+        r"
+        if redis.call('ZCARD', KEYS[3]) + redis.call('ZCARD', KEYS[4]) ~= #ARGV then
+            return 0
+        end
+        for _, id in ipairs(ARGV) do
+            if not (redis.call('ZSCORE', KEYS[3], id) or redis.call('ZSCORE', KEYS[4], id)) then
+                return 0
+            end
+        end
+        for _, key in ipairs(KEYS) do
+            redis.call('DEL', key)
+        end
+        return 1
+        "
+    );
+
+    /// Record a message pushed to the channel, if it exists. Channels lacking
+    /// a record (added before they were stored) gain one.
+    ///
+    /// KEYS[1]: channels key, KEYS[2]: channel records key, ARGV[1]: channel
+    /// id, ARGV[2]: the current time
+    static ref RECORD_CHANNEL_PUSH: Script = Script::new(
+        r"
+        if redis.call('SISMEMBER', KEYS[1], ARGV[1]) == 0 then
+            return 0
+        end
+        local record = redis.call('HGET', KEYS[2], ARGV[1])
+        local channel = record and cjson.decode(record) or {channel_id = ARGV[1]}
+        channel.last_push_at = tonumber(ARGV[2])
+        channel.push_count = (channel.push_count or 0) + 1
+        redis.call('HSET', KEYS[2], ARGV[1], cjson.encode(channel))
+        return 1
+        "
+    );
+}
+
+/// How many times [RedisClientImpl::remove_user] rereads the user's messages
+/// when they change before they're removed
+const REMOVE_USER_ATTEMPTS: usize = 3;
+
+fn default_key_prefix() -> String {
+    "autopush".to_owned()
+}
+
+/// The settings for accessing the Redis contents.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RedisDbSettings {
+    /// Prefix applied to every key
+    #[serde(default = "default_key_prefix")]
+    pub key_prefix: String,
+    /// Max number of connections held by the pool
+    #[serde(default)]
+    pub database_pool_max_size: Option<u32>,
+    /// Limits on the number of stored messages
+    #[serde(default)]
+    pub quota: MessageQuota,
+}
+
+impl Default for RedisDbSettings {
+    fn default() -> Self {
+        Self {
+            key_prefix: default_key_prefix(),
+            database_pool_max_size: None,
+            quota: Default::default(),
+        }
+    }
+}
+
+impl TryFrom<&str> for RedisDbSettings {
+    type Error = DbError;
+    fn try_from(setting_string: &str) -> Result<Self, Self::Error> {
+        if setting_string.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        serde_json::from_str(setting_string)
+            .map_err(|e| DbError::General(format!("Could not parse RedisDbSettings: {:?}", e)))
+    }
+}
+
+#[derive(Clone)]
+pub struct RedisClientImpl {
+    pool: Pool,
+    metrics: Arc<StatsdClient>,
+    settings: RedisDbSettings,
+    key_ring: KeyRing,
+    compressor: Compressor,
+}
+
+impl RedisClientImpl {
+    pub fn new(metrics: Arc<StatsdClient>, db_settings: &DbSettings) -> DbResult<Self> {
+        let Some(dsn) = db_settings.dsn.clone() else {
+            return Err(DbError::ConnectionError(
+                "No DSN specified in settings".to_owned(),
+            ));
+        };
+        let settings = RedisDbSettings::try_from(db_settings.db_settings.as_ref())?;
+        debug!("🟥 Redis Settings {:?}", settings);
+        let key_ring = KeyRing::try_from(db_settings.key_ring.as_ref())?;
+        let compressor = Compressor::new(metrics.clone(), db_settings);
+
+        let mut config = Config::from_url(dsn);
+        if let Some(size) = settings.database_pool_max_size {
+            config.pool = Some(PoolConfig::new(size as usize));
+        }
+        let pool = config
+            .create_pool(Some(Runtime::Tokio1))
+            .map_err(|e| DbError::ConnectionError(format!("Could not create pool: {:?}", e)))?;
+
+        Ok(Self {
+            pool,
+            metrics,
+            settings,
+            key_ring,
+            compressor,
+        })
+    }
+
+    /// Get a connection from the pool
+    async fn conn(&self) -> DbResult<Connection> {
+        self.pool
+            .get()
+            .await
+            .map_err(|e| DbError::ConnectionError(format!("Redis pool error: {}", e)))
+    }
+
+    fn key(&self, uaid: &Uuid, suffix: &str) -> String {
+        format!(
+            "{}:{{{}}}:{}",
+            self.settings.key_prefix,
+            uaid.simple(),
+            suffix
+        )
+    }
+
+    /// The (sorted, unique) UAIDs of the keys matching the `SCAN` pattern
+    async fn scan_uaids(&self, pattern: &str) -> DbResult<Vec<Uuid>> {
+        let mut conn = self.conn().await?;
+        let mut uaids = Vec::new();
+        let mut cursor: u64 = 0;
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .cursor_arg(cursor)
+                .arg("MATCH")
+                .arg(pattern)
+                .arg("COUNT")
+                .arg(1000)
+                .query_async(&mut conn)
+                .await?;
+            for key in keys {
+                // keys are of the form "{key_prefix}:{<uaid>}:<suffix>"
+                let Some(uaid) = key
+                    .split_once(":{")
+                    .and_then(|(_, rest)| rest.split_once("}:"))
+                    .map(|(uaid, _)| uaid)
+                else {
+                    continue;
+                };
+                uaids.push(Uuid::parse_str(uaid).map_err(|e| DbError::General(e.to_string()))?);
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        uaids.sort();
+        uaids.dedup();
+        Ok(uaids)
+    }
+
+    fn user_key(&self, uaid: &Uuid) -> String {
+        self.key(uaid, "user")
+    }
+
+    fn channels_key(&self, uaid: &Uuid) -> String {
+        self.key(uaid, "channels")
+    }
+
+    fn channel_records_key(&self, uaid: &Uuid) -> String {
+        self.key(uaid, "channel_records")
+    }
+
+    fn topic_key(&self, uaid: &Uuid) -> String {
+        self.key(uaid, "topic")
+    }
+
+    fn timestamp_key(&self, uaid: &Uuid) -> String {
+        self.key(uaid, "timestamp")
+    }
+
+    fn message_key(&self, uaid: &Uuid, chidmessageid: &str) -> String {
+        self.key(uaid, &format!("msg:{}", chidmessageid))
+    }
+
+    /// Read the messages for the given index entries, pruning the entries of
+    /// any messages that have since expired.
+    ///
+    /// Returns the messages along with the number of pruned entries.
+    async fn read_messages(
+        &self,
+        conn: &mut Connection,
+        uaid: &Uuid,
+        index_key: &str,
+        ids: Vec<String>,
+    ) -> DbResult<(Vec<Notification>, usize)> {
+        if ids.is_empty() {
+            return Ok((Vec::new(), 0));
+        }
+        let keys: Vec<String> = ids.iter().map(|id| self.message_key(uaid, id)).collect();
+        let values: Vec<Option<String>> = redis::cmd("MGET").arg(keys).query_async(conn).await?;
+
+        let mut messages = Vec::with_capacity(values.len());
+        let mut expired = Vec::new();
+        for (id, value) in ids.into_iter().zip(values) {
+            let Some(value) = value else {
+                expired.push(id);
+                continue;
+            };
+            let record: NotificationRecord =
+                serde_json::from_str(&value).map_err(|e| DbError::Serialization(e.to_string()))?;
+            messages.push(record.into_notif(&self.key_ring).map_err(|e| {
+                DbError::Integrity(format!("read_messages expected a notification: {e}"))
+            })?);
+        }
+        let pruned = expired.len();
+        if pruned > 0 {
+            trace!("🟥 Pruning {} expired message(s)", pruned);
+            redis::cmd("ZREM")
+                .arg(index_key)
+                .arg(expired)
+                .query_async::<_, ()>(conn)
+                .await?;
+        }
+        Ok((messages, pruned))
+    }
+}
+
+/// Return the hash field/value pairs for a [User] and a `version`
+fn user_to_fields(user: &User, version: &Uuid) -> DbResult<Vec<(&'static str, String)>> {
+    let mut fields = vec![
+        ("connected_at", user.connected_at.to_string()),
+        ("router_type", user.router_type.clone()),
+        ("version", version.simple().to_string()),
+    ];
+    if let Some(router_data) = &user.router_data {
+        fields.push((
+            "router_data",
+            serde_json::to_string(router_data).map_err(|e| {
+                DbError::Serialization(format!("Could not serialize router_data: {e:?}"))
+            })?,
+        ));
+    }
+    if let Some(last_connect) = user.last_connect {
+        fields.push(("last_connect", last_connect.to_string()));
+    }
+    if let Some(node_id) = &user.node_id {
+        fields.push(("node_id", node_id.clone()));
+    }
+    if let Some(record_version) = user.record_version {
+        fields.push(("record_version", record_version.to_string()));
+    }
+    if let Some(current_timestamp) = user.current_timestamp {
+        fields.push(("current_timestamp", current_timestamp.to_string()));
+    }
+    Ok(fields)
+}
+
+/// Serialize the channels into their (hyphenated) ID and JSON record pairs
+fn channels_to_fields(channels: &[ChannelRecord]) -> DbResult<Vec<(String, String)>> {
+    channels
+        .iter()
+        .map(|channel| {
+            let record = serde_json::to_string(channel).map_err(|e| {
+                DbError::Serialization(format!("Could not serialize channel: {e:?}"))
+            })?;
+            Ok((channel.channel_id.as_hyphenated().to_string(), record))
+        })
+        .collect()
+}
+
+fn to_u64(fields: &mut HashMap<String, String>, name: &str) -> DbResult<Option<u64>> {
+    fields
+        .remove(name)
+        .map(|v| v.parse::<u64>())
+        .transpose()
+        .map_err(|_| DbError::DeserializeU64(name.to_owned()))
+}
+
+/// Convert the hash fields of a user record into a [User]
+fn fields_to_user(uaid: &Uuid, mut fields: HashMap<String, String>) -> DbResult<User> {
+    Ok(User {
+        uaid: *uaid,
+        connected_at: to_u64(&mut fields, "connected_at")?
+            .ok_or_else(|| DbError::Integrity("Missing connected_at".to_owned()))?,
+        router_type: fields
+            .remove("router_type")
+            .ok_or_else(|| DbError::Integrity("Missing router_type".to_owned()))?,
+        router_data: fields
+            .remove("router_data")
+            .map(|v| serde_json::from_str(&v))
+            .transpose()
+            .map_err(|e| {
+                DbError::Serialization(format!("Could not deserialize router_data: {e:?}"))
+            })?,
+        last_connect: to_u64(&mut fields, "last_connect")?,
+        node_id: fields.remove("node_id"),
+        record_version: to_u64(&mut fields, "record_version")?,
+        current_month: None,
+        current_timestamp: to_u64(&mut fields, "current_timestamp")?,
+        version: fields
+            .remove("version")
+            .map(|v| Uuid::parse_str(&v))
+            .transpose()
+            .map_err(|e| DbError::Serialization(format!("Could not deserialize version: {e:?}")))?,
+    })
+}
+
+#[async_trait]
+impl DbClient for RedisClientImpl {
+    async fn add_user(&self, user: &User) -> DbResult<()> {
+        self.add_user_with_channels(user, Vec::new()).await
+    }
+
+    /// Written by a single script, so atomically
+    async fn add_user_with_channels(
+        &self,
+        user: &User,
+        channels: Vec<ChannelRecord>,
+    ) -> DbResult<()> {
+        let Some(ref version) = user.version else {
+            return Err(DbError::General(
+                "add_user expected a user version field".to_owned(),
+            ));
+        };
+        let mut invocation = ADD_USER.key(self.user_key(&user.uaid));
+        invocation
+            .key(self.channels_key(&user.uaid))
+            .key(self.channel_records_key(&user.uaid))
+            .arg(MAX_ROUTER_TTL)
+            .arg(MAX_CHANNEL_TTL)
+            .arg(channels.len());
+        for (channel_id, record) in channels_to_fields(&channels)? {
+            invocation.arg(channel_id).arg(record);
+        }
+        for (field, value) in user_to_fields(user, version)? {
+            invocation.arg(field).arg(value);
+        }
+        let added: bool = invocation.invoke_async(&mut self.conn().await?).await?;
+        if !added {
+            return Err(DbError::Conditional);
+        }
+        Ok(())
+    }
+
+    /// The update is performed atomically, only if the stored `version`
+    /// matches the user's and the stored `connected_at` is not newer.
+    async fn update_user(&self, user: &mut User) -> DbResult<bool> {
+        let Some(ref version) = user.version else {
+            return Err(DbError::General(
+                "update_user expected a user version field".to_owned(),
+            ));
+        };
+        let new_version = Uuid::new_v4();
+        let mut invocation = UPDATE_USER.key(self.user_key(&user.uaid));
+        invocation
+            .arg(version.simple().to_string())
+            .arg(&user.router_type)
+            .arg(user.connected_at)
+            .arg(MAX_ROUTER_TTL);
+        for (field, value) in user_to_fields(user, &new_version)? {
+            invocation.arg(field).arg(value);
+        }
+        let updated: bool = invocation.invoke_async(&mut self.conn().await?).await?;
+        if updated {
+            user.version = Some(new_version);
+        }
+        Ok(updated)
+    }
+
+    async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>> {
+        let fields: HashMap<String, String> = redis::cmd("HGETALL")
+            .arg(self.user_key(uaid))
+            .query_async(&mut self.conn().await?)
+            .await?;
+        if fields.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(fields_to_user(uaid, fields)?))
+    }
+
+    /// The messages are read from their indexes beforehand, retrying should
+    /// they change before the removal
+    async fn remove_user(&self, uaid: &Uuid) -> DbResult<()> {
+        let mut conn = self.conn().await?;
+        let (topic_key, timestamp_key) = (self.topic_key(uaid), self.timestamp_key(uaid));
+        for _ in 0..REMOVE_USER_ATTEMPTS {
+            let mut ids: Vec<String> = redis::cmd("ZRANGE")
+                .arg(&topic_key)
+                .arg(0)
+                .arg(-1)
+                .query_async(&mut conn)
+                .await?;
+            let timestamp_ids: Vec<String> = redis::cmd("ZRANGE")
+                .arg(&timestamp_key)
+                .arg(0)
+                .arg(-1)
+                .query_async(&mut conn)
+                .await?;
+            ids.extend(timestamp_ids);
+
+            let mut invocation = REMOVE_USER.key(self.user_key(uaid));
+            invocation
+                .key(self.channels_key(uaid))
+                .key(&topic_key)
+                .key(&timestamp_key)
+                .key(self.channel_records_key(uaid));
+            for id in &ids {
+                invocation.key(self.message_key(uaid, id)).arg(id);
+            }
+            let removed: bool = invocation.invoke_async(&mut conn).await?;
+            if removed {
+                return Ok(());
+            }
+            trace!("🟥 Messages changed while removing user, retrying"; "uaid" => uaid.to_string());
+        }
+        Err(DbError::General(format!(
+            "Messages kept changing while removing user {uaid}"
+        )))
+    }
+
+    async fn list_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        self.scan_uaids(&format!(
+            "{}:{{{}*}}:user",
+            self.settings.key_prefix, prefix
+        ))
+        .await
+    }
+
+    async fn list_orphan_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        let users: HashSet<Uuid> = self.list_uaids(prefix).await?.into_iter().collect();
+        let mut uaids = self
+            .scan_uaids(&format!("{}:{{{}*}}:*", self.settings.key_prefix, prefix))
+            .await?;
+        uaids.retain(|uaid| !users.contains(uaid));
+        Ok(uaids)
+    }
+
+    async fn add_channel(&self, uaid: &Uuid, channel: &ChannelRecord) -> DbResult<()> {
+        self.add_channels(uaid, vec![channel.clone()]).await
+    }
+
+    async fn add_channels(&self, uaid: &Uuid, channels: Vec<ChannelRecord>) -> DbResult<()> {
+        if channels.is_empty() {
+            return Ok(());
+        }
+        let key = self.channels_key(uaid);
+        let records_key = self.channel_records_key(uaid);
+        let fields = channels_to_fields(&channels)?;
+        let channel_ids: Vec<&String> = fields.iter().map(|(chid, _)| chid).collect();
+        redis::pipe()
+            .atomic()
+            .cmd("SADD")
+            .arg(&key)
+            .arg(channel_ids)
+            .ignore()
+            .cmd("HSET")
+            .arg(&records_key)
+            .arg(&fields)
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(&key)
+            .arg(MAX_CHANNEL_TTL)
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(&records_key)
+            .arg(MAX_CHANNEL_TTL)
+            .ignore()
+            .query_async::<_, ()>(&mut self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_channels(&self, uaid: &Uuid) -> DbResult<HashSet<Uuid>> {
+        let channels: Vec<String> = redis::cmd("SMEMBERS")
+            .arg(self.channels_key(uaid))
+            .query_async(&mut self.conn().await?)
+            .await?;
+        channels
+            .iter()
+            .map(|chid| Uuid::parse_str(chid).map_err(|e| DbError::General(e.to_string())))
+            .collect()
+    }
+
+    async fn get_channel_records(&self, uaid: &Uuid) -> DbResult<Vec<ChannelRecord>> {
+        let (channels, mut records): (Vec<String>, HashMap<String, String>) = redis::pipe()
+            .atomic()
+            .cmd("SMEMBERS")
+            .arg(self.channels_key(uaid))
+            .cmd("HGETALL")
+            .arg(self.channel_records_key(uaid))
+            .query_async(&mut self.conn().await?)
+            .await?;
+        channels
+            .iter()
+            .map(|chid| match records.remove(chid) {
+                Some(record) => serde_json::from_str(&record).map_err(|e| {
+                    DbError::Serialization(format!("Could not deserialize channel: {e:?}"))
+                }),
+                None => Ok(ChannelRecord::from(
+                    Uuid::parse_str(chid).map_err(|e| DbError::General(e.to_string()))?,
+                )),
+            })
+            .collect()
+    }
+
+    /// Updated atomically by a script
+    async fn record_channel_push(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
+        RECORD_CHANNEL_PUSH
+            .key(self.channels_key(uaid))
+            .key(self.channel_records_key(uaid))
+            .arg(channel_id.as_hyphenated().to_string())
+            .arg(sec_since_epoch())
+            .invoke_async::<_, ()>(&mut self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    /// Delete the channel. Does not delete its associated pending messages.
+    async fn remove_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
+        let mut conn = self.conn().await?;
+        let channel_id = channel_id.as_hyphenated().to_string();
+        let (removed,): (u64,) = redis::pipe()
+            .atomic()
+            .cmd("SREM")
+            .arg(self.channels_key(uaid))
+            .arg(&channel_id)
+            .cmd("HDEL")
+            .arg(self.channel_records_key(uaid))
+            .arg(&channel_id)
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+        // Write a new version, as Bigtable does, so that any pending
+        // `update_user` against the old record fails.
+        SET_USER_FIELDS
+            .key(self.user_key(uaid))
+            .arg("version")
+            .arg(Uuid::new_v4().simple().to_string())
+            .invoke_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(removed > 0)
+    }
+
+    async fn remove_node_id(
+        &self,
+        uaid: &Uuid,
+        node_id: &str,
+        connected_at: u64,
+        version: &Option<Uuid>,
+    ) -> DbResult<bool> {
+        let version = version.map(|v| v.simple().to_string()).unwrap_or_default();
+        let removed: bool = REMOVE_NODE_ID
+            .key(self.user_key(uaid))
+            .arg(version)
+            .arg(node_id)
+            .arg(connected_at)
+            .invoke_async(&mut self.conn().await?)
+            .await?;
+        Ok(removed)
+    }
+
+    /// Write the notification to storage.
+    async fn save_message(&self, uaid: &Uuid, message: Notification) -> DbResult<()> {
+        self.save_messages(uaid, vec![message]).await
+    }
+
+    /// Write the notifications to storage, expiring each one after its TTL.
+    ///
+    /// A topic message's `chidmessageid` (and thus its key) is shared by
+    /// every message for that topic, so a newer message replaces the prior
+    /// one.
+    async fn save_messages(&self, uaid: &Uuid, messages: Vec<Notification>) -> DbResult<()> {
+        self.settings
+            .quota
+            .enforce(self, &self.metrics, uaid, &messages)
+            .await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        let mut topics = Vec::with_capacity(messages.len());
+        for message in messages {
+            if message.ttl == 0 {
+                // Would expire immediately
+                trace!("🟥 Skipping message with a zero TTL");
+                continue;
+            }
+            let chidmessageid = message.chidmessageid();
+            let (index_key, score) = if message.topic.is_some() {
+                (self.topic_key(uaid), 0)
+            } else {
+                (
+                    self.timestamp_key(uaid),
+                    message.sortkey_timestamp.unwrap_or_default(),
+                )
+            };
+            let ttl = min(message.ttl, MAX_EXPIRY);
+            topics.push(message.topic.is_some());
+            let record =
+                NotificationRecord::from_notif(uaid, message, &self.key_ring, &self.compressor)?;
+            let record = serde_json::to_string(&record)
+                .map_err(|e| DbError::Serialization(e.to_string()))?;
+            pipe.cmd("SET")
+                .arg(self.message_key(uaid, &chidmessageid))
+                .arg(record)
+                .arg("EX")
+                .arg(ttl)
+                .ignore()
+                .cmd("ZADD")
+                .arg(&index_key)
+                .arg(score)
+                .arg(chidmessageid)
+                .ignore()
+                .cmd("EXPIRE")
+                .arg(&index_key)
+                .arg(MAX_EXPIRY)
+                .ignore();
+        }
+        if topics.is_empty() {
+            return Ok(());
+        }
+        pipe.query_async::<_, ()>(&mut self.conn().await?).await?;
+        for topic in topics {
+            self.metrics
+                .incr_with_tags("notification.message.stored")
+                .with_tag("topic", &topic.to_string())
+                .send();
+        }
+        Ok(())
+    }
+
+    /// Return `limit` pending topic messages. `limit=0` for all messages.
+    async fn fetch_topic_messages(
+        &self,
+        uaid: &Uuid,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        let mut conn = self.conn().await?;
+        let index_key = self.topic_key(uaid);
+        let stop = if limit > 0 { limit as i64 - 1 } else { -1 };
+        loop {
+            let ids: Vec<String> = redis::cmd("ZRANGE")
+                .arg(&index_key)
+                .arg(0)
+                .arg(stop)
+                .query_async(&mut conn)
+                .await?;
+            let (messages, pruned) = self.read_messages(&mut conn, uaid, &index_key, ids).await?;
+            if pruned == 0 {
+                // As with Bigtable, `current_timestamp` is read from [get_user]
+                return Ok(FetchMessageResponse {
+                    messages,
+                    timestamp: None,
+                });
+            }
+        }
+    }
+
+    /// Return `limit` messages pending for a UAID that have a sortkey_timestamp after
+    /// what's specified. `limit=0` for all messages.
+    async fn fetch_timestamp_messages(
+        &self,
+        uaid: &Uuid,
+        timestamp: Option<u64>,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        let mut conn = self.conn().await?;
+        let index_key = self.timestamp_key(uaid);
+        let min_score = timestamp
+            .map(|ts| format!("({}", ts))
+            .unwrap_or_else(|| "-inf".to_owned());
+        loop {
+            let mut cmd = redis::cmd("ZRANGEBYSCORE");
+            cmd.arg(&index_key).arg(&min_score).arg("+inf");
+            if limit > 0 {
+                cmd.arg("LIMIT").arg(0).arg(limit);
+            }
+            let ids: Vec<String> = cmd.query_async(&mut conn).await?;
+            let (messages, pruned) = self.read_messages(&mut conn, uaid, &index_key, ids).await?;
+            if pruned == 0 {
+                // The timestamp of the last message read
+                let timestamp = messages.last().and_then(|m| m.sortkey_timestamp);
+                return Ok(FetchMessageResponse {
+                    messages,
+                    timestamp,
+                });
+            }
+        }
+    }
+
+    /// Set the `current_timestamp` in the user record (writing a new
+    /// `version`).
+    async fn increment_storage(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
+        SET_USER_FIELDS
+            .key(self.user_key(uaid))
+            .arg("current_timestamp")
+            .arg(timestamp)
+            .arg("version")
+            .arg(Uuid::new_v4().simple().to_string())
+            .invoke_async::<_, ()>(&mut self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    /// Messages expire along with their keys, so any found are pending
+    async fn get_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<Option<Notification>> {
+        let value: Option<String> = redis::cmd("GET")
+            .arg(self.message_key(uaid, sort_key))
+            .query_async(&mut self.conn().await?)
+            .await?;
+        value
+            .map(|value| {
+                let record: NotificationRecord = serde_json::from_str(&value)
+                    .map_err(|e| DbError::Serialization(e.to_string()))?;
+                record.into_notif(&self.key_ring).map_err(|e| {
+                    DbError::Integrity(format!("get_message expected a notification: {e}"))
+                })
+            })
+            .transpose()
+    }
+
+    async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()> {
+        let index_key = if sort_key.starts_with(&format!("{TOPIC_NOTIFICATION_PREFIX}:")) {
+            self.topic_key(uaid)
+        } else {
+            self.timestamp_key(uaid)
+        };
+        redis::pipe()
+            .atomic()
+            .cmd("DEL")
+            .arg(self.message_key(uaid, sort_key))
+            .ignore()
+            .cmd("ZREM")
+            .arg(index_key)
+            .arg(sort_key)
+            .ignore()
+            .query_async::<_, ()>(&mut self.conn().await?)
+            .await?;
+        self.metrics
+            .incr_with_tags("notification.message.deleted")
+            .send();
+        Ok(())
+    }
+
+    async fn remove_messages(&self, uaid: &Uuid, sort_keys: &[String]) -> DbResult<()> {
+        if sort_keys.is_empty() {
+            return Ok(());
+        }
+        let topic_prefix = format!("{TOPIC_NOTIFICATION_PREFIX}:");
+        let (topic, timestamp): (Vec<&String>, Vec<&String>) = sort_keys
+            .iter()
+            .partition(|sort_key| sort_key.starts_with(&topic_prefix));
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("DEL")
+            .arg(
+                sort_keys
+                    .iter()
+                    .map(|sort_key| self.message_key(uaid, sort_key))
+                    .collect::<Vec<_>>(),
+            )
+            .ignore();
+        for (index_key, members) in [
+            (self.topic_key(uaid), topic),
+            (self.timestamp_key(uaid), timestamp),
+        ] {
+            if !members.is_empty() {
+                pipe.cmd("ZREM").arg(index_key).arg(members).ignore();
+            }
+        }
+        pipe.query_async::<_, ()>(&mut self.conn().await?).await?;
+        self.metrics
+            .count_with_tags("notification.message.deleted", sort_keys.len() as i64)
+            .send();
+        Ok(())
+    }
+
+    /// Returns true, because Redis has no tables.
+    async fn router_table_exists(&self) -> DbResult<bool> {
+        Ok(true)
+    }
+
+    /// Returns true, because Redis has no tables.
+    async fn message_table_exists(&self) -> DbResult<bool> {
+        Ok(true)
+    }
+
+    async fn health_check(&self) -> DbResult<bool> {
+        let pong: String = redis::cmd("PING")
+            .query_async(&mut self.conn().await?)
+            .await?;
+        Ok(pong == "PONG")
+    }
+
+    /// Redis does not support message table rotation
+    fn rotating_message_table(&self) -> Option<&str> {
+        None
+    }
+
+    fn box_clone(&self) -> Box<dyn DbClient> {
+        Box::new(self.clone())
+    }
+
+    fn name(&self) -> String {
+        "Redis".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_fields_roundtrip() -> DbResult<()> {
+        let user = User {
+            node_id: Some("https://node:8082".to_owned()),
+            current_timestamp: Some(1234),
+            ..Default::default()
+        };
+        let version = user.version.unwrap();
+        let fields = user_to_fields(&user, &version)?
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), v))
+            .collect();
+        let fetched = fields_to_user(&user.uaid, fields)?;
+        assert_eq!(
+            fetched,
+            User {
+                current_month: None,
+                ..user
+            }
+        );
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "redis_emulator"))]
+mod emulator_tests {
+    //! These tests rely on having a Redis server running, found at
+    //! `REDIS_DSN` (see docs/src/install.md).
+    use crate::util::ms_since_epoch;
+
+    use super::*;
+
+    fn new_client() -> DbResult<RedisClientImpl> {
+        let settings = DbSettings {
+            dsn: Some(std::env::var("REDIS_DSN").unwrap_or("redis://localhost:6379".to_owned())),
+            db_settings: "".to_owned(),
+            ..Default::default()
+        };
+        let metrics = Arc::new(StatsdClient::builder("", cadence::NopMetricSink).build());
+        RedisClientImpl::new(metrics, &settings)
+    }
+
+    fn notification(channel_id: Uuid, sortkey_timestamp: u64) -> Notification {
+        Notification {
+            channel_id,
+            version: format!("v{sortkey_timestamp}"),
+            ttl: 300,
+            timestamp: sec_since_epoch(),
+            sortkey_timestamp: Some(sortkey_timestamp),
+            ..Default::default()
+        }
+    }
+
+    /// All of the keys stored for the user
+    async fn user_keys(client: &RedisClientImpl, uaid: &Uuid) -> DbResult<Vec<String>> {
+        Ok(redis::cmd("KEYS")
+            .arg(client.key(uaid, "*"))
+            .query_async(&mut client.conn().await?)
+            .await?)
+    }
+
+    #[actix_rt::test]
+    async fn run_gauntlet() -> DbResult<()> {
+        let client = new_client()?;
+
+        let uaid = Uuid::new_v4();
+        let chid = Uuid::new_v4();
+        let connected_at = ms_since_epoch();
+        let test_user = User {
+            uaid,
+            router_type: "webpush".to_owned(),
+            connected_at,
+            node_id: Some("test_node".to_owned()),
+            ..Default::default()
+        };
+
+        // can we add the user, but only once?
+        client.add_user(&test_user).await?;
+        assert!(matches!(
+            client.add_user(&test_user).await,
+            Err(DbError::Conditional)
+        ));
+        let fetched = client.get_user(&uaid).await?.unwrap();
+        assert_eq!(fetched.connected_at, connected_at);
+
+        // an older connected_at doesn't overwrite the user
+        let mut older = User {
+            connected_at: connected_at - 300,
+            ..fetched.clone()
+        };
+        assert!(!client.update_user(&mut older).await?);
+        assert_eq!(older.version, fetched.version);
+
+        // a newer one does, writing a new version
+        let mut newer = User {
+            connected_at: connected_at + 300,
+            ..fetched.clone()
+        };
+        assert!(client.update_user(&mut newer).await?);
+        let updated = client.get_user(&uaid).await?.unwrap();
+        assert_eq!(updated.connected_at, connected_at + 300);
+        assert_eq!(newer.version, updated.version);
+
+        // so a stale version can't update it, nor remove its node_id
+        let mut stale = User {
+            connected_at: connected_at + 600,
+            ..fetched.clone()
+        };
+        assert!(!client.update_user(&mut stale).await?);
+        assert!(
+            !client
+                .remove_node_id(&uaid, "test_node", connected_at, &fetched.version)
+                .await?
+        );
+        assert!(
+            client
+                .remove_node_id(&uaid, "test_node", connected_at, &updated.version)
+                .await?
+        );
+        assert!(client.get_user(&uaid).await?.unwrap().node_id.is_none());
+
+        // pushes are recorded to the channel, until it's removed
+        client
+            .add_channel(&uaid, &ChannelRecord::from(chid))
+            .await?;
+        client.record_channel_push(&uaid, &chid).await?;
+        client.record_channel_push(&uaid, &chid).await?;
+        let records = client.get_channel_records(&uaid).await?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].push_count, 2);
+        assert!(client.remove_channel(&uaid, &chid).await?);
+        client.record_channel_push(&uaid, &chid).await?;
+        assert!(client.get_channel_records(&uaid).await?.is_empty());
+        let records: u64 = redis::cmd("HLEN")
+            .arg(client.channel_records_key(&uaid))
+            .query_async(&mut client.conn().await?)
+            .await?;
+        assert_eq!(records, 0);
+        client
+            .add_channel(&uaid, &ChannelRecord::from(chid))
+            .await?;
+
+        // messages are returned in timestamp order, whatever the order they
+        // were stored in
+        let base = ms_since_epoch();
+        let messages: Vec<Notification> = [2, 0, 1]
+            .into_iter()
+            .map(|i| notification(chid, base + i))
+            .collect();
+        client.save_messages(&uaid, messages.clone()).await?;
+        let fetched = client.fetch_timestamp_messages(&uaid, None, 0).await?;
+        let sortkeys: Vec<_> = fetched
+            .messages
+            .iter()
+            .map(|m| m.sortkey_timestamp.unwrap())
+            .collect();
+        assert_eq!(sortkeys, vec![base, base + 1, base + 2]);
+
+        // and only those later than the given timestamp, up to the limit
+        let fetched = client
+            .fetch_timestamp_messages(&uaid, Some(base), 1)
+            .await?;
+        assert_eq!(fetched.messages.len(), 1);
+        assert_eq!(fetched.messages[0].sortkey_timestamp, Some(base + 1));
+
+        // messages are stored with their TTL as the key's expiry
+        let expiring = messages[0].chidmessageid();
+        let ttl: i64 = redis::cmd("TTL")
+            .arg(client.message_key(&uaid, &expiring))
+            .query_async(&mut client.conn().await?)
+            .await?;
+        assert!(0 < ttl && ttl <= 300);
+
+        // once expired, a message is no longer returned and its index entry
+        // is pruned
+        redis::cmd("DEL")
+            .arg(client.message_key(&uaid, &expiring))
+            .query_async::<_, ()>(&mut client.conn().await?)
+            .await?;
+        assert!(client.get_message(&uaid, &expiring).await?.is_none());
+        assert_eq!(
+            client
+                .fetch_timestamp_messages(&uaid, None, 0)
+                .await?
+                .messages
+                .len(),
+            2
+        );
+        let indexed: u64 = redis::cmd("ZCARD")
+            .arg(client.timestamp_key(&uaid))
+            .query_async(&mut client.conn().await?)
+            .await?;
+        assert_eq!(indexed, 2);
+
+        // a newer topic message replaces the prior one
+        let topic_message = Notification {
+            topic: Some("topic".to_owned()),
+            ..notification(chid, base)
+        };
+        client.save_message(&uaid, topic_message.clone()).await?;
+        client
+            .save_message(
+                &uaid,
+                Notification {
+                    version: "replaced".to_owned(),
+                    ..topic_message.clone()
+                },
+            )
+            .await?;
+        let fetched = client.fetch_topic_messages(&uaid, 0).await?;
+        assert_eq!(fetched.messages.len(), 1);
+        assert_eq!(fetched.messages[0].version, "replaced");
+
+        // can we clean up our toys, messages included?
+        client.remove_user(&uaid).await?;
+        assert!(client.get_user(&uaid).await?.is_none());
+        assert!(user_keys(&client, &uaid).await?.is_empty());
+
+        // nor does reading the storage position bring the user back
+        client.increment_storage(&uaid, base).await?;
+        assert!(client.get_user(&uaid).await?.is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1153 @@
+//! SQLite data store.
+//!
+//! Intended for small, single node installs. The user routing records, the
+//! channel set and the pending messages are stored into three tables within
+//! a single database file, which are created on first start.
+//!
+//! The `db_dsn` is the path to the database file, e.g.
+//! `sqlite:///var/lib/autopush/autopush.db` (or `sqlite://:memory:` for a
+//! transient database).
+//!
+//! SQLite has no native expiration of rows. Expired channels and messages
+//! are filtered out on read and are periodically deleted by a background
+//! sweeper.
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use cadence::{Counted, CountedExt, StatsdClient};
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::db::{
+    client::{DbClient, FetchMessageResponse},
+    compression::{decompress, Compressor},
+    encryption::reject_key_ring,
+    error::{DbError, DbResult},
+    quota::MessageQuota,
+    ChannelRecord, DbSettings, Notification, User, MAX_CHANNEL_TTL, MAX_EXPIRY,
+};
+use crate::util::sec_since_epoch;
+
+const DSN_PREFIX: &str = "sqlite://";
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS router (
+    uaid TEXT PRIMARY KEY,
+    connected_at INTEGER NOT NULL,
+    router_type TEXT NOT NULL,
+    router_data TEXT,
+    last_connect INTEGER,
+    node_id TEXT,
+    record_version INTEGER,
+    "current_timestamp" INTEGER,
+    version TEXT
+);
+CREATE TABLE IF NOT EXISTS channel (
+    uaid TEXT NOT NULL,
+    channel_id TEXT NOT NULL,
+    expiry INTEGER NOT NULL,
+    created_at INTEGER,
+    app_server_key_hash TEXT,
+    last_push_at INTEGER,
+    push_count INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (uaid, channel_id)
+);
+CREATE TABLE IF NOT EXISTS message (
+    uaid TEXT NOT NULL,
+    chidmessageid TEXT NOT NULL,
+    channel_id TEXT NOT NULL,
+    version TEXT NOT NULL,
+    ttl INTEGER NOT NULL,
+    expiry INTEGER NOT NULL,
+    topic TEXT,
+    timestamp INTEGER NOT NULL,
+    sortkey_timestamp INTEGER,
+    data TEXT,
+    headers TEXT,
+    urgency TEXT,
+    receipt TEXT,
+    PRIMARY KEY (uaid, chidmessageid)
+);
+CREATE INDEX IF NOT EXISTS message_expiry_idx ON message (expiry);
+CREATE INDEX IF NOT EXISTS channel_expiry_idx ON channel (expiry);
+"#;
+
+/// Channel table columns added since its creation, which are added to
+/// existing databases on start
+const CHANNEL_METADATA_COLUMNS: [(&str, &str); 4] = [
+    ("created_at", "INTEGER"),
+    ("app_server_key_hash", "TEXT"),
+    ("last_push_at", "INTEGER"),
+    ("push_count", "INTEGER NOT NULL DEFAULT 0"),
+];
+
+/// Message table columns added since its creation, which are added to
+/// existing databases on start
+const MESSAGE_ADDED_COLUMNS: [(&str, &str); 2] = [("urgency", "TEXT"), ("receipt", "TEXT")];
+
+const CHANNEL_COLUMNS: &str =
+    "channel_id, created_at, app_server_key_hash, last_push_at, push_count";
+
+const USER_COLUMNS: &str = r#"uaid, connected_at, router_type, router_data, last_connect,
+    node_id, record_version, "current_timestamp", version"#;
+
+const MESSAGE_COLUMNS: &str = "channel_id, version, ttl, topic, timestamp, sortkey_timestamp,
+    data, headers, urgency, receipt";
+
+/// The settings for accessing the SQLite contents.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SqliteDbSettings {
+    /// How often (in seconds) expired records are deleted. `0` disables the
+    /// sweeper.
+    pub sweep_interval: u64,
+    /// How long (in milliseconds) to wait on a locked database
+    pub busy_timeout: u64,
+    /// Limits on the number of stored messages
+    pub quota: MessageQuota,
+}
+
+impl Default for SqliteDbSettings {
+    fn default() -> Self {
+        Self {
+            sweep_interval: 60,
+            busy_timeout: 5000,
+            quota: Default::default(),
+        }
+    }
+}
+
+impl TryFrom<&str> for SqliteDbSettings {
+    type Error = DbError;
+    fn try_from(setting_string: &str) -> Result<Self, Self::Error> {
+        if setting_string.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        serde_json::from_str(setting_string)
+            .map_err(|e| DbError::General(format!("Could not parse SqliteDbSettings: {:?}", e)))
+    }
+}
+
+#[derive(Clone)]
+pub struct SqliteClientImpl {
+    conn: Arc<Mutex<Connection>>,
+    metrics: Arc<StatsdClient>,
+    quota: MessageQuota,
+    compressor: Compressor,
+}
+
+impl SqliteClientImpl {
+    pub fn new(metrics: Arc<StatsdClient>, db_settings: &DbSettings) -> DbResult<Self> {
+        let Some(path) = db_settings
+            .dsn
+            .as_deref()
+            .and_then(|dsn| dsn.strip_prefix(DSN_PREFIX))
+            .filter(|path| !path.is_empty())
+        else {
+            return Err(DbError::ConnectionError(
+                "No sqlite:// DSN specified in settings".to_owned(),
+            ));
+        };
+        reject_key_ring(db_settings, "SQLite")?;
+        let settings = SqliteDbSettings::try_from(db_settings.db_settings.as_ref())?;
+        debug!("🪶 SQLite Settings {:?}", settings);
+
+        let conn = Connection::open(path)
+            .map_err(|e| DbError::ConnectionError(format!("Could not open {path}: {e}")))?;
+        conn.busy_timeout(Duration::from_millis(settings.busy_timeout))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+        add_missing_columns(&conn, "channel", &CHANNEL_METADATA_COLUMNS)?;
+        add_missing_columns(&conn, "message", &MESSAGE_ADDED_COLUMNS)?;
+
+        let conn = Arc::new(Mutex::new(conn));
+        if settings.sweep_interval > 0 {
+            spawn_sweeper(
+                Arc::downgrade(&conn),
+                Duration::from_secs(settings.sweep_interval),
+            )?;
+        }
+        let compressor = Compressor::new(metrics.clone(), db_settings);
+        Ok(Self {
+            conn,
+            metrics,
+            quota: settings.quota,
+            compressor,
+        })
+    }
+
+    /// Run `f` against the connection on the blocking thread pool
+    async fn call<F, R>(&self, f: F) -> DbResult<R>
+    where
+        F: FnOnce(&mut Connection) -> DbResult<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn
+                .lock()
+                .map_err(|e| DbError::General(format!("SQLite connection poisoned: {e}")))?;
+            f(&mut conn)
+        })
+        .await
+        .map_err(|e| DbError::General(format!("SQLite task failed: {e}")))?
+    }
+}
+
+/// Periodically delete the expired channels and messages, until the
+/// connection is dropped.
+fn spawn_sweeper(conn: Weak<Mutex<Connection>>, interval: Duration) -> DbResult<()> {
+    thread::Builder::new()
+        .name("sqlite-sweeper".to_owned())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let Some(conn) = conn.upgrade() else {
+                return;
+            };
+            let Ok(conn) = conn.lock() else {
+                return;
+            };
+            match sweep(&conn, sec_since_epoch()) {
+                Ok(swept) => trace!("🪶 Swept {} expired record(s)", swept),
+                Err(e) => warn!("🪶 Could not sweep expired records: {}", e),
+            }
+        })
+        .map_err(|e| DbError::General(format!("Could not start sweeper: {e}")))?;
+    Ok(())
+}
+
+/// Delete the channels and messages that expired before `now`
+fn sweep(conn: &Connection, now: u64) -> DbResult<usize> {
+    let now = now as i64;
+    let messages = conn.execute("DELETE FROM message WHERE expiry < ?1", [now])?;
+    let channels = conn.execute("DELETE FROM channel WHERE expiry < ?1", [now])?;
+    Ok(messages + channels)
+}
+
+/// Add any of the `columns` (e.g. [CHANNEL_METADATA_COLUMNS]) missing from a
+/// `table` created before them
+fn add_missing_columns(conn: &Connection, table: &str, columns: &[(&str, &str)]) -> DbResult<()> {
+    let existing = conn
+        .prepare(&format!("SELECT name FROM pragma_table_info('{table}')"))?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<HashSet<String>, _>>()?;
+    for (name, definition) in columns {
+        if !existing.contains(*name) {
+            conn.execute_batch(&format!(
+                "ALTER TABLE {table} ADD COLUMN {name} {definition}"
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_uuid(value: String) -> DbResult<Uuid> {
+    Uuid::parse_str(&value).map_err(|e| DbError::Serialization(e.to_string()))
+}
+
+/// Convert a router table row into a [User]
+fn row_to_user(row: &Row) -> DbResult<User> {
+    let router_data: Option<String> = row.get("router_data")?;
+    let version: Option<String> = row.get("version")?;
+    Ok(User {
+        uaid: parse_uuid(row.get("uaid")?)?,
+        connected_at: row.get::<_, i64>("connected_at")? as u64,
+        router_type: row.get("router_type")?,
+        router_data: router_data
+            .map(|v| serde_json::from_str(&v))
+            .transpose()
+            .map_err(|e| {
+                DbError::Serialization(format!("Could not deserialize router_data: {e:?}"))
+            })?,
+        last_connect: row.get::<_, Option<i64>>("last_connect")?.map(|v| v as u64),
+        node_id: row.get("node_id")?,
+        record_version: row
+            .get::<_, Option<i64>>("record_version")?
+            .map(|v| v as u64),
+        current_month: None,
+        current_timestamp: row
+            .get::<_, Option<i64>>("current_timestamp")?
+            .map(|v| v as u64),
+        version: version.map(parse_uuid).transpose()?,
+    })
+}
+
+/// Convert a message table row into a [Notification]
+fn row_to_notification(row: &Row) -> DbResult<Notification> {
+    let headers: Option<String> = row.get("headers")?;
+    let urgency: Option<String> = row.get("urgency")?;
+    Ok(Notification {
+        channel_id: parse_uuid(row.get("channel_id")?)?,
+        version: row.get("version")?,
+        ttl: row.get::<_, i64>("ttl")? as u64,
+        topic: row.get("topic")?,
+        timestamp: row.get::<_, i64>("timestamp")? as u64,
+        data: row
+            .get::<_, Option<String>>("data")?
+            .map(decompress)
+            .transpose()?,
+        sortkey_timestamp: row
+            .get::<_, Option<i64>>("sortkey_timestamp")?
+            .map(|v| v as u64),
+        headers: headers
+            .map(|v| serde_json::from_str::<HashMap<String, String>>(&v))
+            .transpose()
+            .map_err(|e| DbError::Serialization(e.to_string()))?,
+        urgency: urgency
+            .map(|v| v.parse())
+            .transpose()
+            .map_err(DbError::Serialization)?
+            .unwrap_or_default(),
+        receipt: row.get("receipt")?,
+    })
+}
+
+/// Convert a channel table row into a [ChannelRecord]
+fn row_to_channel(row: &Row) -> DbResult<ChannelRecord> {
+    Ok(ChannelRecord {
+        channel_id: parse_uuid(row.get("channel_id")?)?,
+        created_at: row.get::<_, Option<i64>>("created_at")?.map(|v| v as u64),
+        app_server_key_hash: row.get("app_server_key_hash")?,
+        last_push_at: row.get::<_, Option<i64>>("last_push_at")?.map(|v| v as u64),
+        push_count: row.get::<_, i64>("push_count")? as u64,
+    })
+}
+
+/// Insert (or replace the metadata and refresh the expiry of) a user's
+/// channels
+fn insert_channels(
+    tx: &Transaction<'_>,
+    uaid: &Uuid,
+    channels: Vec<ChannelRecord>,
+) -> DbResult<()> {
+    let uaid = uaid.simple().to_string();
+    let expiry = (sec_since_epoch() + MAX_CHANNEL_TTL) as i64;
+    let mut statement = tx.prepare(&format!(
+        "INSERT INTO channel (uaid, expiry, {CHANNEL_COLUMNS})
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+        ON CONFLICT (uaid, channel_id) DO UPDATE SET expiry = excluded.expiry,
+            created_at = excluded.created_at,
+            app_server_key_hash = excluded.app_server_key_hash,
+            last_push_at = excluded.last_push_at, push_count = excluded.push_count"
+    ))?;
+    for channel in channels {
+        statement.execute(params![
+            uaid,
+            expiry,
+            channel.channel_id.simple().to_string(),
+            channel.created_at.map(|v| v as i64),
+            channel.app_server_key_hash,
+            channel.last_push_at.map(|v| v as i64),
+            channel.push_count as i64,
+        ])?;
+    }
+    Ok(())
+}
+
+/// Serialize a user's `router_data` for storage
+fn router_data_to_string(user: &User) -> DbResult<Option<String>> {
+    user.router_data
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()
+        .map_err(|e| DbError::Serialization(format!("Could not serialize router_data: {e:?}")))
+}
+
+/// Convert a `limit` into a SQLite `LIMIT` (where `-1` is unlimited)
+fn sql_limit(limit: usize) -> i64 {
+    if limit > 0 {
+        limit as i64
+    } else {
+        -1
+    }
+}
+
+#[async_trait]
+impl DbClient for SqliteClientImpl {
+    async fn add_user(&self, user: &User) -> DbResult<()> {
+        self.add_user_with_channels(user, Vec::new()).await
+    }
+
+    /// Written in a single transaction
+    async fn add_user_with_channels(
+        &self,
+        user: &User,
+        channels: Vec<ChannelRecord>,
+    ) -> DbResult<()> {
+        let Some(version) = user.version else {
+            return Err(DbError::General(
+                "add_user expected a user version field".to_owned(),
+            ));
+        };
+        let router_data = router_data_to_string(user)?;
+        let user = user.clone();
+        let inserted = self
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let inserted = tx.execute(
+                    &format!(
+                        "INSERT OR IGNORE INTO router ({USER_COLUMNS})
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"
+                    ),
+                    params![
+                        user.uaid.simple().to_string(),
+                        user.connected_at as i64,
+                        user.router_type,
+                        router_data,
+                        user.last_connect.map(|v| v as i64),
+                        user.node_id,
+                        user.record_version.map(|v| v as i64),
+                        user.current_timestamp.map(|v| v as i64),
+                        version.simple().to_string(),
+                    ],
+                )?;
+                if inserted == 0 {
+                    // Dropping the transaction rolls it back
+                    return Ok(inserted);
+                }
+                insert_channels(&tx, &user.uaid, channels)?;
+                tx.commit()?;
+                Ok(inserted)
+            })
+            .await?;
+        if inserted == 0 {
+            return Err(DbError::Conditional);
+        }
+        Ok(())
+    }
+
+    /// The update only occurs if the stored `version` matches the user's
+    /// (optimistic locking) and the stored `connected_at` is not newer.
+    async fn update_user(&self, user: &mut User) -> DbResult<bool> {
+        let Some(version) = user.version else {
+            return Err(DbError::General(
+                "update_user expected a user version field".to_owned(),
+            ));
+        };
+        let new_version = Uuid::new_v4();
+        let router_data = router_data_to_string(user)?;
+        let updating = user.clone();
+        let updated = self
+            .call(move |conn| {
+                Ok(conn.execute(
+                    r#"UPDATE router SET connected_at = ?2, router_data = ?4,
+                        last_connect = ?5, node_id = ?6, record_version = ?7,
+                        "current_timestamp" = ?8, version = ?9
+                    WHERE uaid = ?1 AND router_type = ?3 AND connected_at <= ?2
+                        AND version = ?10"#,
+                    params![
+                        updating.uaid.simple().to_string(),
+                        updating.connected_at as i64,
+                        updating.router_type,
+                        router_data,
+                        updating.last_connect.map(|v| v as i64),
+                        updating.node_id,
+                        updating.record_version.map(|v| v as i64),
+                        updating.current_timestamp.map(|v| v as i64),
+                        new_version.simple().to_string(),
+                        version.simple().to_string(),
+                    ],
+                )?)
+            })
+            .await?;
+        if updated == 0 {
+            return Ok(false);
+        }
+        user.version = Some(new_version);
+        Ok(true)
+    }
+
+    async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>> {
+        let uaid = uaid.simple().to_string();
+        self.call(move |conn| {
+            conn.query_row(
+                &format!("SELECT {USER_COLUMNS} FROM router WHERE uaid = ?1"),
+                [uaid],
+                |row| Ok(row_to_user(row)),
+            )
+            .optional()?
+            .transpose()
+        })
+        .await
+    }
+
+    /// Remove the user along with their channels and pending messages.
+    async fn remove_user(&self, uaid: &Uuid) -> DbResult<()> {
+        let uaid = uaid.simple().to_string();
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            for table in ["message", "channel", "router"] {
+                tx.execute(&format!("DELETE FROM {table} WHERE uaid = ?1"), [&uaid])?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        let pattern = format!("{prefix}%");
+        self.call(move |conn| {
+            let mut statement =
+                conn.prepare("SELECT uaid FROM router WHERE uaid LIKE ?1 ORDER BY uaid")?;
+            let rows = statement.query_map([pattern], |row| row.get::<_, String>(0))?;
+            rows.map(|uaid| parse_uuid(uaid?)).collect()
+        })
+        .await
+    }
+
+    async fn list_orphan_uaids(&self, prefix: &str) -> DbResult<Vec<Uuid>> {
+        let pattern = format!("{prefix}%");
+        self.call(move |conn| {
+            let mut statement = conn.prepare(
+                "SELECT uaid FROM channel WHERE uaid LIKE ?1
+                     AND uaid NOT IN (SELECT uaid FROM router)
+                 UNION
+                 SELECT uaid FROM message WHERE uaid LIKE ?1
+                     AND uaid NOT IN (SELECT uaid FROM router)
+                 ORDER BY uaid",
+            )?;
+            let rows = statement.query_map([pattern], |row| row.get::<_, String>(0))?;
+            rows.map(|uaid| parse_uuid(uaid?)).collect()
+        })
+        .await
+    }
+
+    async fn add_channel(&self, uaid: &Uuid, channel: &ChannelRecord) -> DbResult<()> {
+        self.add_channels(uaid, vec![channel.clone()]).await
+    }
+
+    async fn add_channels(&self, uaid: &Uuid, channels: Vec<ChannelRecord>) -> DbResult<()> {
+        if channels.is_empty() {
+            return Ok(());
+        }
+        let uaid = *uaid;
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            insert_channels(&tx, &uaid, channels)?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_channels(&self, uaid: &Uuid) -> DbResult<HashSet<Uuid>> {
+        let uaid = uaid.simple().to_string();
+        let now = sec_since_epoch() as i64;
+        self.call(move |conn| {
+            let mut statement =
+                conn.prepare("SELECT channel_id FROM channel WHERE uaid = ?1 AND expiry >= ?2")?;
+            let rows = statement.query_map(params![uaid, now], |row| row.get::<_, String>(0))?;
+            rows.map(|channel_id| parse_uuid(channel_id?)).collect()
+        })
+        .await
+    }
+
+    async fn get_channel_records(&self, uaid: &Uuid) -> DbResult<Vec<ChannelRecord>> {
+        let uaid = uaid.simple().to_string();
+        let now = sec_since_epoch() as i64;
+        self.call(move |conn| {
+            let mut statement = conn.prepare(&format!(
+                "SELECT {CHANNEL_COLUMNS} FROM channel WHERE uaid = ?1 AND expiry >= ?2"
+            ))?;
+            let rows = statement.query_map(params![uaid, now], |row| Ok(row_to_channel(row)))?;
+            rows.map(|channel| channel?).collect()
+        })
+        .await
+    }
+
+    async fn record_channel_push(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
+        let uaid = uaid.simple().to_string();
+        let channel_id = channel_id.simple().to_string();
+        let now = sec_since_epoch() as i64;
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE channel SET last_push_at = ?3, push_count = push_count + 1
+                WHERE uaid = ?1 AND channel_id = ?2 AND expiry >= ?3",
+                params![uaid, channel_id, now],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Delete the channel. Does not delete its associated pending messages.
+    async fn remove_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
+        let uaid = uaid.simple().to_string();
+        let channel_id = channel_id.simple().to_string();
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let deleted = tx.execute(
+                "DELETE FROM channel WHERE uaid = ?1 AND channel_id = ?2",
+                [&uaid, &channel_id],
+            )?;
+            // Write a new version, as Bigtable does, so that any pending
+            // `update_user` against the old record fails.
+            tx.execute(
+                "UPDATE router SET version = ?2 WHERE uaid = ?1",
+                [&uaid, &Uuid::new_v4().simple().to_string()],
+            )?;
+            tx.commit()?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    /// Remove the node_id. If a `version` is provided, it must match the
+    /// stored version, otherwise the `node_id` and `connected_at` must match.
+    async fn remove_node_id(
+        &self,
+        uaid: &Uuid,
+        node_id: &str,
+        connected_at: u64,
+        version: &Option<Uuid>,
+    ) -> DbResult<bool> {
+        let uaid = uaid.simple().to_string();
+        let node_id = node_id.to_owned();
+        let version = *version;
+        let updated = self
+            .call(move |conn| {
+                Ok(if let Some(version) = version {
+                    conn.execute(
+                        "UPDATE router SET node_id = NULL WHERE uaid = ?1 AND version = ?2",
+                        [&uaid, &version.simple().to_string()],
+                    )?
+                } else {
+                    conn.execute(
+                        "UPDATE router SET node_id = NULL
+                        WHERE uaid = ?1 AND node_id = ?2 AND connected_at = ?3",
+                        params![uaid, node_id, connected_at as i64],
+                    )?
+                })
+            })
+            .await?;
+        Ok(updated > 0)
+    }
+
+    /// Write the notification to storage. Topic messages share a
+    /// `chidmessageid`, so a newer one replaces any prior message.
+    async fn save_message(&self, uaid: &Uuid, message: Notification) -> DbResult<()> {
+        self.save_messages(uaid, vec![message]).await
+    }
+
+    async fn save_messages(&self, uaid: &Uuid, messages: Vec<Notification>) -> DbResult<()> {
+        self.quota
+            .enforce(self, &self.metrics, uaid, &messages)
+            .await?;
+        let uaid = uaid.simple().to_string();
+        let compressor = self.compressor.clone();
+        let topics = self
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let mut topics = Vec::with_capacity(messages.len());
+                {
+                    let mut statement = tx.prepare(&format!(
+                        "INSERT OR REPLACE INTO message (uaid, chidmessageid, expiry,
+                            {MESSAGE_COLUMNS})
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)"
+                    ))?;
+                    for mut message in messages {
+                        compressor.compress_notification(&mut message);
+                        let headers = message
+                            .headers
+                            .as_ref()
+                            .filter(|h| !h.is_empty())
+                            .map(serde_json::to_string)
+                            .transpose()
+                            .map_err(|e| DbError::Serialization(e.to_string()))?;
+                        let expiry = sec_since_epoch() + message.ttl.min(MAX_EXPIRY);
+                        statement.execute(params![
+                            uaid,
+                            message.chidmessageid(),
+                            expiry as i64,
+                            message.channel_id.simple().to_string(),
+                            message.version,
+                            message.ttl as i64,
+                            message.topic,
+                            message.timestamp as i64,
+                            message.sortkey_timestamp.map(|v| v as i64),
+                            message.data,
+                            headers,
+                            message.urgency.as_str(),
+                            message.receipt,
+                        ])?;
+                        topics.push(message.topic.is_some());
+                    }
+                }
+                tx.commit()?;
+                Ok(topics)
+            })
+            .await?;
+        for topic in topics {
+            self.metrics
+                .incr_with_tags("notification.message.stored")
+                .with_tag("topic", &topic.to_string())
+                .send();
+        }
+        Ok(())
+    }
+
+    /// Return `limit` pending topic messages. `limit=0` for all messages.
+    async fn fetch_topic_messages(
+        &self,
+        uaid: &Uuid,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        let uaid = uaid.simple().to_string();
+        let now = sec_since_epoch() as i64;
+        let messages = self
+            .call(move |conn| {
+                let mut statement = conn.prepare(&format!(
+                    "SELECT {MESSAGE_COLUMNS} FROM message
+                    WHERE uaid = ?1 AND topic IS NOT NULL AND expiry > ?2
+                    ORDER BY chidmessageid LIMIT ?3"
+                ))?;
+                let rows = statement.query_map(params![uaid, now, sql_limit(limit)], |row| {
+                    Ok(row_to_notification(row))
+                })?;
+                rows.map(|row| row?).collect::<DbResult<Vec<_>>>()
+            })
+            .await?;
+        // As with Bigtable, `current_timestamp` is read from [get_user]
+        Ok(FetchMessageResponse {
+            messages,
+            timestamp: None,
+        })
+    }
+
+    /// Return `limit` messages pending for a UAID that have a sortkey_timestamp after
+    /// what's specified. `limit=0` for all messages.
+    async fn fetch_timestamp_messages(
+        &self,
+        uaid: &Uuid,
+        timestamp: Option<u64>,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        let uaid = uaid.simple().to_string();
+        let now = sec_since_epoch() as i64;
+        let messages = self
+            .call(move |conn| {
+                let mut statement = conn.prepare(&format!(
+                    "SELECT {MESSAGE_COLUMNS} FROM message
+                    WHERE uaid = ?1 AND topic IS NULL AND sortkey_timestamp > ?2
+                        AND expiry > ?3
+                    ORDER BY sortkey_timestamp LIMIT ?4"
+                ))?;
+                let rows = statement.query_map(
+                    params![
+                        uaid,
+                        timestamp.map_or(-1, |v| v as i64),
+                        now,
+                        sql_limit(limit)
+                    ],
+                    |row| Ok(row_to_notification(row)),
+                )?;
+                rows.map(|row| row?).collect::<DbResult<Vec<_>>>()
+            })
+            .await?;
+        // The timestamp of the last message read
+        let timestamp = messages.last().and_then(|m| m.sortkey_timestamp);
+        Ok(FetchMessageResponse {
+            messages,
+            timestamp,
+        })
+    }
+
+    /// Set the `current_timestamp` in the user record (writing a new
+    /// `version`).
+    async fn increment_storage(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
+        let uaid = uaid.simple().to_string();
+        self.call(move |conn| {
+            conn.execute(
+                r#"UPDATE router SET "current_timestamp" = ?2, version = ?3 WHERE uaid = ?1"#,
+                params![uaid, timestamp as i64, Uuid::new_v4().simple().to_string()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<Option<Notification>> {
+        let uaid = uaid.simple().to_string();
+        let sort_key = sort_key.to_owned();
+        let now = sec_since_epoch() as i64;
+        self.call(move |conn| {
+            conn.query_row(
+                &format!(
+                    "SELECT {MESSAGE_COLUMNS} FROM message
+                    WHERE uaid = ?1 AND chidmessageid = ?2 AND expiry > ?3"
+                ),
+                params![uaid, sort_key, now],
+                |row| Ok(row_to_notification(row)),
+            )
+            .optional()?
+            .transpose()
+        })
+        .await
+    }
+
+    async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()> {
+        let uaid = uaid.simple().to_string();
+        let sort_key = sort_key.to_owned();
+        self.call(move |conn| {
+            conn.execute(
+                "DELETE FROM message WHERE uaid = ?1 AND chidmessageid = ?2",
+                [uaid, sort_key],
+            )?;
+            Ok(())
+        })
+        .await?;
+        self.metrics
+            .incr_with_tags("notification.message.deleted")
+            .send();
+        Ok(())
+    }
+
+    async fn remove_messages(&self, uaid: &Uuid, sort_keys: &[String]) -> DbResult<()> {
+        if sort_keys.is_empty() {
+            return Ok(());
+        }
+        let uaid = uaid.simple().to_string();
+        let sort_keys = sort_keys.to_vec();
+        let count = sort_keys.len();
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut statement =
+                    tx.prepare("DELETE FROM message WHERE uaid = ?1 AND chidmessageid = ?2")?;
+                for sort_key in sort_keys {
+                    statement.execute(params![uaid, sort_key])?;
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await?;
+        self.metrics
+            .count_with_tags("notification.message.deleted", count as i64)
+            .send();
+        Ok(())
+    }
+
+    /// Returns true, as the tables are created on start.
+    async fn router_table_exists(&self) -> DbResult<bool> {
+        Ok(true)
+    }
+
+    /// Returns true, as the tables are created on start.
+    async fn message_table_exists(&self) -> DbResult<bool> {
+        Ok(true)
+    }
+
+    async fn health_check(&self) -> DbResult<bool> {
+        self.call(|conn| Ok(conn.query_row("SELECT 1", [], |row| row.get::<_, i64>(0))? == 1))
+            .await
+    }
+
+    /// SQLite does not support message table rotation
+    fn rotating_message_table(&self) -> Option<&str> {
+        None
+    }
+
+    fn box_clone(&self) -> Box<dyn DbClient> {
+        Box::new(self.clone())
+    }
+
+    fn name(&self) -> String {
+        "SQLite".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use cadence::StatsdClient;
+
+    use super::*;
+    use crate::notification::Urgency;
+    use crate::util::ms_since_epoch;
+
+    const TEST_USER: &str = "DEADBEEF-0000-0000-0000-0123456789AB";
+    const TEST_CHID: &str = "DECAFBAD-0000-0000-0000-0123456789AB";
+    const TOPIC_CHID: &str = "DECAFBAD-1111-0000-0000-0123456789AB";
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn new_client(dsn: &str) -> DbResult<SqliteClientImpl> {
+        let settings = DbSettings {
+            dsn: Some(dsn.to_owned()),
+            db_settings: "".to_owned(),
+            ..Default::default()
+        };
+        let metrics = Arc::new(StatsdClient::builder("", cadence::NopMetricSink).build());
+        SqliteClientImpl::new(metrics, &settings)
+    }
+
+    /// The same scenarios as the Bigtable `run_gauntlet`
+    #[actix_rt::test]
+    async fn run_gauntlet() -> DbResult<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let client = new_client(&format!(
+            "{DSN_PREFIX}{}",
+            dir.path().join("autopush.db").display()
+        ))?;
+
+        let connected_at = ms_since_epoch();
+
+        let uaid = Uuid::parse_str(TEST_USER).unwrap();
+        let chid = Uuid::parse_str(TEST_CHID).unwrap();
+        let topic_chid = Uuid::parse_str(TOPIC_CHID).unwrap();
+
+        let node_id = "test_node".to_owned();
+
+        let test_user = User {
+            uaid,
+            router_type: "webpush".to_owned(),
+            connected_at,
+            router_data: None,
+            last_connect: Some(connected_at),
+            node_id: Some(node_id.clone()),
+            ..Default::default()
+        };
+
+        // can we add the user?
+        client.add_user(&test_user).await?;
+        let fetched = client.get_user(&uaid).await?;
+        assert!(fetched.is_some());
+        let fetched = fetched.unwrap();
+        assert_eq!(fetched.router_type, "webpush".to_owned());
+        assert!(matches!(
+            client.add_user(&test_user).await.unwrap_err(),
+            DbError::Conditional
+        ));
+
+        // Simulate a connected_at occuring before the following writes
+        let connected_at = ms_since_epoch();
+
+        // can we add channels?
+        client
+            .add_channel(&uaid, &ChannelRecord::from(chid))
+            .await?;
+        let channels = client.get_channels(&uaid).await?;
+        assert!(channels.contains(&chid));
+
+        // along with their metadata?
+        let channel = ChannelRecord::new(Uuid::new_v4(), None).unwrap();
+        client.add_channel(&uaid, &channel).await?;
+        client
+            .record_channel_push(&uaid, &channel.channel_id)
+            .await?;
+        let records = client.get_channel_records(&uaid).await?;
+        assert_eq!(records.len(), 2);
+        let record = records
+            .iter()
+            .find(|record| record.channel_id == channel.channel_id)
+            .unwrap();
+        assert_eq!(record.created_at, channel.created_at);
+        assert_eq!(record.push_count, 1);
+        assert!(record.last_push_at.is_some());
+        assert!(client.remove_channel(&uaid, &channel.channel_id).await?);
+
+        // can we add lots of channels?
+        let mut new_channels: HashSet<Uuid> = HashSet::new();
+        new_channels.insert(chid);
+        for _ in 1..10 {
+            new_channels.insert(uuid::Uuid::new_v4());
+        }
+        let chid_to_remove = uuid::Uuid::new_v4();
+        new_channels.insert(chid_to_remove);
+        client
+            .add_channels(
+                &uaid,
+                new_channels.iter().copied().map(Into::into).collect(),
+            )
+            .await?;
+        let channels = client.get_channels(&uaid).await?;
+        assert_eq!(channels, new_channels);
+
+        // can we remove a channel?
+        assert!(client.remove_channel(&uaid, &chid_to_remove).await?);
+        new_channels.remove(&chid_to_remove);
+        let channels = client.get_channels(&uaid).await?;
+        assert_eq!(channels, new_channels);
+
+        // now ensure that we can update a user that's after the time we set
+        // prior. first ensure that we can't update a user that's before the
+        // time we set prior to the last write
+        let mut updated = User {
+            connected_at,
+            ..test_user.clone()
+        };
+        let result = client.update_user(&mut updated).await;
+        assert!(result.is_ok());
+        assert!(!result.unwrap());
+        // a failed update leaves the caller's version untouched
+        assert_eq!(updated.version, test_user.version);
+
+        // Make sure that the `connected_at` wasn't modified
+        let fetched2 = client.get_user(&fetched.uaid).await?.unwrap();
+        assert_eq!(fetched.connected_at, fetched2.connected_at);
+
+        // and make sure we can update a record with a later connected_at time.
+        let mut updated = User {
+            connected_at: fetched.connected_at + 300,
+            ..fetched2
+        };
+        let result = client.update_user(&mut updated).await;
+        assert!(result.is_ok());
+        assert!(result.unwrap());
+        assert_ne!(
+            fetched2.connected_at,
+            client.get_user(&uaid).await?.unwrap().connected_at
+        );
+
+        // can we increment the storage for the user?
+        client.increment_storage(&fetched.uaid, now()).await?;
+
+        let test_data = "An_encrypted_pile_of_crap".to_owned();
+        let timestamp = now();
+        let sort_key = now();
+        // Can we store a message?
+        let test_notification = crate::db::Notification {
+            channel_id: chid,
+            version: "test".to_owned(),
+            ttl: 300,
+            timestamp,
+            data: Some(test_data.clone()),
+            sortkey_timestamp: Some(sort_key),
+            urgency: Urgency::Low,
+            receipt: Some("https://example.com/receipts/1".to_owned()),
+            ..Default::default()
+        };
+        let res = client.save_message(&uaid, test_notification.clone()).await;
+        assert!(res.is_ok());
+
+        let mut fetched = client.fetch_timestamp_messages(&uaid, None, 999).await?;
+        assert_ne!(fetched.messages.len(), 0);
+        let fm = fetched.messages.pop().unwrap();
+        assert_eq!(fm.channel_id, test_notification.channel_id);
+        assert_eq!(fm.data, Some(test_data));
+        assert_eq!(fm.urgency, Urgency::Low);
+        assert_eq!(fm.receipt, test_notification.receipt);
+        assert_eq!(
+            client
+                .get_message(&uaid, &test_notification.chidmessageid())
+                .await?
+                .map(|message| message.version),
+            Some(test_notification.version.clone())
+        );
+
+        // Grab all 1 of the messages that were submmited within the past 10 seconds.
+        let fetched = client
+            .fetch_timestamp_messages(&uaid, Some(timestamp - 10), 999)
+            .await?;
+        assert_ne!(fetched.messages.len(), 0);
+
+        // Try grabbing a message for 10 seconds from now.
+        let fetched = client
+            .fetch_timestamp_messages(&uaid, Some(timestamp + 10), 999)
+            .await?;
+        assert_eq!(fetched.messages.len(), 0);
+
+        // can we clean up our toys?
+        assert!(client
+            .remove_message(&uaid, &test_notification.chidmessageid())
+            .await
+            .is_ok());
+
+        assert!(client.remove_channel(&uaid, &chid).await.is_ok());
+
+        // Now, can we do all that with topic messages
+        let test_data = "An_encrypted_pile_of_crap_with_a_topic".to_owned();
+        let timestamp = now();
+        let sort_key = now();
+        // Can we store a message?
+        let test_notification = crate::db::Notification {
+            channel_id: topic_chid,
+            version: "test".to_owned(),
+            ttl: 300,
+            topic: Some("topic".to_owned()),
+            timestamp,
+            data: Some(test_data.clone()),
+            sortkey_timestamp: Some(sort_key),
+            ..Default::default()
+        };
+        assert!(client
+            .save_message(&uaid, test_notification.clone())
+            .await
+            .is_ok());
+        // A newer message for the topic replaces the prior one
+        assert!(client
+            .save_message(&uaid, test_notification.clone())
+            .await
+            .is_ok());
+
+        let mut fetched = client.fetch_topic_messages(&uaid, 999).await?;
+        assert_eq!(fetched.messages.len(), 1);
+        let fm = fetched.messages.pop().unwrap();
+        assert_eq!(fm.channel_id, test_notification.channel_id);
+        assert_eq!(fm.data, Some(test_data));
+
+        // can we clean up our toys?
+        assert!(client
+            .remove_message(&uaid, &test_notification.chidmessageid())
+            .await
+            .is_ok());
+
+        assert!(client.remove_channel(&uaid, &topic_chid).await.is_ok());
+
+        let msgs = client
+            .fetch_timestamp_messages(&uaid, None, 999)
+            .await?
+            .messages;
+        assert!(msgs.is_empty());
+
+        let fetched = client.get_user(&uaid).await?.unwrap();
+        assert!(client
+            .remove_node_id(&uaid, &node_id, connected_at, &fetched.version)
+            .await
+            .is_ok());
+        // did we remove it?
+        let fetched = client.get_user(&uaid).await?.unwrap();
+        assert_eq!(fetched.node_id, None);
+
+        assert!(client.remove_user(&uaid).await.is_ok());
+
+        assert!(client.get_user(&uaid).await?.is_none());
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn sweep_expired() -> DbResult<()> {
+        let client = new_client("sqlite://:memory:")?;
+        let uaid = Uuid::new_v4();
+        let message = Notification {
+            channel_id: Uuid::new_v4(),
+            version: "test".to_owned(),
+            ttl: 300,
+            timestamp: now(),
+            sortkey_timestamp: Some(ms_since_epoch()),
+            ..Default::default()
+        };
+        client.save_message(&uaid, message).await?;
+        client
+            .add_channel(&uaid, &ChannelRecord::from(Uuid::new_v4()))
+            .await?;
+
+        let conn = client.conn.lock().unwrap();
+        assert_eq!(sweep(&conn, now())?, 0);
+        assert_eq!(sweep(&conn, now() + MAX_CHANNEL_TTL + 1)?, 2);
+        Ok(())
+    }
+}
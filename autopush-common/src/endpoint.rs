@@ -23,15 +23,7 @@ pub fn make_endpoint(
     base.extend(chid.as_bytes());
 
     if let Some(k) = key {
-        let raw_key = b64_decode_url(k).map_err(|e| {
-            warn!("Payload: error decoding user provided VAPID key:{:?}", e);
-            ApcErrorKind::PayloadError("Error decoding VAPID key".to_owned())
-        })?;
-        let key_digest = hash::hash(hash::MessageDigest::sha256(), &raw_key).map_err(|e| {
-            warn!("Payload: Error creating digest for VAPID key: {:?}", e);
-            ApcErrorKind::PayloadError("Error creating message digest for key".to_owned())
-        })?;
-        base.extend(key_digest.iter());
+        base.extend(app_server_key_digest(k)?);
         let encrypted = fernet.encrypt(&base).trim_matches('=').to_string();
         let final_url = root.join(&format!("v2/{encrypted}")).map_err(|e| {
             ApcErrorKind::GeneralError(format!("Encrypted endpoint data is not URL-safe {:?}", e))
@@ -45,3 +37,17 @@ pub fn make_endpoint(
         Ok(final_url.to_string())
     }
 }
+
+/// Return the SHA-256 digest of the user provided (base64url encoded) VAPID
+/// public key, as embedded in v2 endpoints
+pub fn app_server_key_digest(key: &str) -> Result<Vec<u8>> {
+    let raw_key = b64_decode_url(key).map_err(|e| {
+        warn!("Payload: error decoding user provided VAPID key:{:?}", e);
+        ApcErrorKind::PayloadError("Error decoding VAPID key".to_owned())
+    })?;
+    let key_digest = hash::hash(hash::MessageDigest::sha256(), &raw_key).map_err(|e| {
+        warn!("Payload: Error creating digest for VAPID key: {:?}", e);
+        ApcErrorKind::PayloadError("Error creating message digest for key".to_owned())
+    })?;
+    Ok(key_digest.to_vec())
+}
@@ -13,6 +13,7 @@ pub mod logging;
 pub mod metrics;
 pub mod middleware;
 pub mod notification;
+pub mod receipt;
 pub mod sentry;
 pub mod tags;
 pub mod test_support;
@@ -1,5 +1,7 @@
 //! Notification protocol
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 
 use serde_derive::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -27,6 +29,64 @@ pub struct Notification {
     pub sortkey_timestamp: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub headers: Option<HashMap<String, String>>,
+    /// The RFC 8030 Urgency the sender requested. Only used for routing
+    /// decisions, so it's not sent to the UserAgent
+    #[serde(default, skip_serializing)]
+    pub urgency: Urgency,
+    /// The URL the application server requested this message's receipt be
+    /// sent to (see [crate::receipt])
+    #[serde(default, skip_serializing)]
+    pub receipt: Option<String>,
+}
+
+/// RFC 8030 message Urgency, ordered from least to most urgent
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Urgency {
+    VeryLow,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Urgency {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Urgency::VeryLow => "very-low",
+            Urgency::Low => "low",
+            Urgency::Normal => "normal",
+            Urgency::High => "high",
+        }
+    }
+
+    /// Whether delivery may be put off for a UserAgent on a constrained
+    /// network (e.g. on battery or a metered connection)
+    pub fn is_deferrable(&self) -> bool {
+        *self < Urgency::Normal
+    }
+}
+
+impl FromStr for Urgency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "very-low" => Ok(Urgency::VeryLow),
+            "low" => Ok(Urgency::Low),
+            "normal" => Ok(Urgency::Normal),
+            "high" => Ok(Urgency::High),
+            _ => Err(format!("Invalid Urgency: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Urgency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 pub const TOPIC_NOTIFICATION_PREFIX: &str = "01";
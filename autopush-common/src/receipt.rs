@@ -0,0 +1,260 @@
+//! RFC 8030 push message receipts.
+//!
+//! An application server requests a receipt by sending a message with
+//! `Prefer: respond-async` and a `Push-Receipt` URL. The outcome of the
+//! message (its delivery to the UA, its expiry or its deletion) is then
+//! `POST`ed to that URL as a [Receipt].
+//!
+//! As the URL is chosen by the application server, receipts are only sent to
+//! public addresses: the URL's host is resolved up front, refused if any of
+//! its addresses aren't public, and the request is then pinned to those
+//! addresses (without following redirects).
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use cadence::{CountedExt, StatsdClient};
+use serde_derive::{Deserialize, Serialize};
+use url::{Host, Url};
+
+use crate::util::sec_since_epoch;
+
+/// How long to wait on the application server's receipt URL
+const RECEIPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The final outcome of a message
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptStatus {
+    /// The UA acknowledged the message
+    Delivered,
+    /// The message's TTL elapsed before it could be delivered
+    Expired,
+    /// The application server deleted the message before it was delivered
+    Deleted,
+}
+
+impl ReceiptStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReceiptStatus::Delivered => "delivered",
+            ReceiptStatus::Expired => "expired",
+            ReceiptStatus::Deleted => "deleted",
+        }
+    }
+}
+
+/// The body of a receipt
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Receipt {
+    /// The message's id (as returned in its `Location`)
+    pub message_id: String,
+    pub status: ReceiptStatus,
+    /// UNIX timestamp in seconds of the outcome
+    pub timestamp: u64,
+}
+
+/// Send a message's receipt to the application server.
+///
+/// Receipts are best effort: failures are logged and counted but otherwise
+/// ignored. Emits a `notification.receipt` counter tagged with the `status`
+/// and the `outcome` (`sent`, `error` or `refused` for URLs that aren't
+/// public).
+pub async fn send_receipt(
+    metrics: Arc<StatsdClient>,
+    url: String,
+    message_id: String,
+    status: ReceiptStatus,
+) {
+    let receipt = Receipt {
+        message_id,
+        status,
+        timestamp: sec_since_epoch(),
+    };
+    let outcome = match receipt_client(&url).await {
+        Some(http) => post_receipt(&http, &url, &receipt).await,
+        None => "refused",
+    };
+    metrics
+        .incr_with_tags("notification.receipt")
+        .with_tag("status", status.as_str())
+        .with_tag("outcome", outcome)
+        .send();
+}
+
+/// `POST` the receipt, returning the outcome
+async fn post_receipt(http: &reqwest::Client, url: &str, receipt: &Receipt) -> &'static str {
+    let result = http
+        .post(url)
+        .json(receipt)
+        .timeout(RECEIPT_TIMEOUT)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    match result {
+        Ok(_) => "sent",
+        Err(e) => {
+            debug!("📬 Failed to send receipt: {:?}", e; "url" => url);
+            "error"
+        }
+    }
+}
+
+/// Build a client only able to reach the URL's host at its (public)
+/// addresses resolved now, so a later lookup can't redirect it elsewhere.
+async fn receipt_client(url: &str) -> Option<reqwest::Client> {
+    let parsed = Url::parse(url).ok()?;
+    let port = parsed.port_or_known_default()?;
+    let builder = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none());
+    let builder = match parsed.host()? {
+        Host::Domain(domain) => {
+            let addrs: Vec<SocketAddr> = match tokio::net::lookup_host((domain, port)).await {
+                Ok(addrs) => addrs.collect(),
+                Err(e) => {
+                    debug!("📬 Could not resolve the receipt host: {:?}", e; "url" => url);
+                    return None;
+                }
+            };
+            if addrs.is_empty() || !addrs.iter().all(|addr| is_public_address(&addr.ip())) {
+                debug!("📬 Refusing to send a receipt to a non-public address"; "url" => url);
+                return None;
+            }
+            builder.resolve_to_addrs(domain, &addrs)
+        }
+        Host::Ipv4(ip) if is_public_address(&IpAddr::V4(ip)) => builder,
+        Host::Ipv6(ip) if is_public_address(&IpAddr::V6(ip)) => builder,
+        _ => {
+            debug!("📬 Refusing to send a receipt to a non-public address"; "url" => url);
+            return None;
+        }
+    };
+    builder.build().ok()
+}
+
+/// Whether the address is publicly routable, i.e. not loopback, private,
+/// link local or otherwise reserved
+pub fn is_public_address(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ipv4(&ip);
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local (fc00::/7)
+                || first & 0xfe00 == 0xfc00
+                // Link local (fe80::/10)
+                || first & 0xffc0 == 0xfe80
+                // Documentation (2001:db8::/32)
+                || (first == 0x2001 && ip.segments()[1] == 0xdb8)
+                // IPv4 compatible (::/96)
+                || ip.segments()[..6] == [0; 6])
+        }
+    }
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // "This network" (0.0.0.0/8)
+        || a == 0
+        // Shared address space (100.64.0.0/10)
+        || (a == 100 && b & 0xc0 == 64)
+        // Benchmarking (198.18.0.0/15)
+        || (a == 198 && b & 0xfe == 18)
+        // Reserved (240.0.0.0/4)
+        || a >= 240
+        // Protocol assignments (192.0.0.0/24)
+        || (a == 192 && b == 0 && c == 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cadence::{SpyMetricSink, StatsdClient};
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn posts_receipt() {
+        let mock = mockito::mock("POST", "/receipt")
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "message_id": "abc",
+                "status": "delivered",
+            })))
+            .with_status(200)
+            .create();
+        let receipt = Receipt {
+            message_id: "abc".to_owned(),
+            status: ReceiptStatus::Delivered,
+            timestamp: sec_since_epoch(),
+        };
+
+        let outcome = post_receipt(
+            &reqwest::Client::new(),
+            &format!("{}/receipt", mockito::server_url()),
+            &receipt,
+        )
+        .await;
+
+        mock.assert();
+        assert_eq!(outcome, "sent");
+    }
+
+    /// The mock server listens on a loopback address, so is refused
+    #[actix_rt::test]
+    async fn refuses_private_receipt() {
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = Arc::new(StatsdClient::builder("", sink).build());
+        let mock = mockito::mock("POST", "/receipt").expect(0).create();
+
+        send_receipt(
+            metrics,
+            format!("{}/receipt", mockito::server_url()),
+            "abc".to_owned(),
+            ReceiptStatus::Delivered,
+        )
+        .await;
+
+        mock.assert();
+        let sent = String::from_utf8(rx.try_recv().unwrap()).unwrap();
+        assert_eq!(
+            sent,
+            "notification.receipt:1|c|#status:delivered,outcome:refused"
+        );
+    }
+
+    #[test]
+    fn public_addresses() {
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public_address(&ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+        ] {
+            assert!(!is_public_address(&ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
@@ -0,0 +1,125 @@
+#![warn(rust_2018_idioms)]
+#![forbid(unsafe_code)]
+
+#[macro_use]
+extern crate slog_scope;
+
+mod migrate;
+
+use std::error::Error;
+use std::sync::Arc;
+
+use cadence::{NopMetricSink, StatsdClient};
+use docopt::Docopt;
+use serde_derive::Deserialize;
+
+use autopush_common::db::{client_from_settings, DbSettings};
+use autopush_common::logging;
+
+use crate::migrate::{Checkpoint, Migrator};
+
+const USAGE: &str = "
+Copy users, their channels and their unexpired messages from one data store to
+another.
+
+Usage: autopush-migrate [options] --source-dsn=DSN --dest-dsn=DSN
+
+Options:
+    -h, --help                  Show this message
+    --source-dsn=DSN            DSN of the data store to copy from.
+    --source-settings=JSON      db_settings of the data store to copy from [default: ].
+    --dest-dsn=DSN              DSN of the data store to copy to.
+    --dest-settings=JSON        db_settings of the data store to copy to [default: ].
+    --key-ring=JSON             Key ring decrypting and encrypting the copied
+                                messages [default: ].
+    --compress-data             Compress the messages written to the destination.
+    --checkpoint=FILE           File recording the completed UAID prefixes, used to
+                                resume an interrupted migration.
+    --prefix-len=N              Number of hex digits of the UAID prefixes the
+                                migration is divided into (1-4) [default: 2].
+    --rate=N                    Maximum number of users copied per second, 0 for
+                                no limit [default: 100].
+    --dry-run                   Report what would be copied without writing.
+    --verify                    Compare each copied user against the source.
+    --human-logs                Use human readable logs.
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_source_dsn: String,
+    flag_source_settings: String,
+    flag_dest_dsn: String,
+    flag_dest_settings: String,
+    flag_key_ring: String,
+    flag_compress_data: bool,
+    flag_checkpoint: Option<String>,
+    flag_prefix_len: usize,
+    flag_rate: u32,
+    flag_dry_run: bool,
+    flag_verify: bool,
+    flag_human_logs: bool,
+}
+
+#[actix_rt::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    logging::init_logging(
+        !args.flag_human_logs,
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+    )
+    .expect("Logging failed to initialize");
+    if !(1..=4).contains(&args.flag_prefix_len) {
+        return Err("--prefix-len must be between 1 and 4".into());
+    }
+
+    let metrics = Arc::new(StatsdClient::builder("autopush-migrate", NopMetricSink).build());
+    let source = client_from_settings(
+        metrics.clone(),
+        &DbSettings {
+            dsn: Some(args.flag_source_dsn),
+            db_settings: args.flag_source_settings,
+            key_ring: args.flag_key_ring.clone(),
+            ..Default::default()
+        },
+    )?;
+    let dest = client_from_settings(
+        metrics,
+        &DbSettings {
+            dsn: Some(args.flag_dest_dsn),
+            db_settings: args.flag_dest_settings,
+            key_ring: args.flag_key_ring,
+            compress_data: args.flag_compress_data,
+        },
+    )?;
+    let mut checkpoint = Checkpoint::load(args.flag_checkpoint.as_deref())?;
+    info!(
+        "Migrating from {} to {}{}",
+        source.name(),
+        dest.name(),
+        if args.flag_dry_run { " (dry run)" } else { "" };
+        "completed_prefixes" => checkpoint.completed()
+    );
+
+    let mut migrator = Migrator::new(
+        source,
+        dest,
+        args.flag_rate,
+        args.flag_dry_run,
+        args.flag_verify,
+    );
+    let report = migrator.run(args.flag_prefix_len, &mut checkpoint).await?;
+    info!("Migration finished: {}", report);
+
+    logging::reset_logging();
+    if report.failed > 0 || report.mismatched > 0 {
+        return Err(format!(
+            "{} user(s) failed and {} user(s) did not verify, rerun to retry their prefixes",
+            report.failed, report.mismatched
+        )
+        .into());
+    }
+    Ok(())
+}
@@ -0,0 +1,444 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use autopush_common::db::client::DbClient;
+use autopush_common::db::error::{DbError, DbResult};
+use autopush_common::db::{ChannelRecord, User};
+use autopush_common::notification::Notification;
+use autopush_common::util::sec_since_epoch;
+
+/// The UAID prefixes whose users have all been copied.
+///
+/// Completed prefixes are appended, one per line, to the checkpoint file (if
+/// any) so that an interrupted migration may be resumed.
+pub struct Checkpoint {
+    done: HashSet<String>,
+    file: Option<File>,
+}
+
+impl Checkpoint {
+    pub fn load(path: Option<&str>) -> io::Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self {
+                done: HashSet::new(),
+                file: None,
+            });
+        };
+        let done = match fs::read_to_string(path) {
+            Ok(content) => content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            done,
+            file: Some(file),
+        })
+    }
+
+    pub fn completed(&self) -> usize {
+        self.done.len()
+    }
+
+    pub fn is_done(&self, prefix: &str) -> bool {
+        self.done.contains(prefix)
+    }
+
+    pub fn mark_done(&mut self, prefix: &str) -> io::Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            writeln!(file, "{prefix}")?;
+            file.sync_data()?;
+        }
+        self.done.insert(prefix.to_owned());
+        Ok(())
+    }
+}
+
+/// Totals for a migration run
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Users copied (or that would have been, for a dry run)
+    pub users: u64,
+    /// Users already present in the destination
+    pub existing: u64,
+    pub channels: u64,
+    pub messages: u64,
+    /// Users (or prefixes that could not be listed) that failed to copy
+    pub failed: u64,
+    /// Users whose copy did not match the source
+    pub mismatched: u64,
+    /// Prefixes skipped as already completed by a previous run
+    pub skipped_prefixes: u64,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "users: {}, already present: {}, channels: {}, messages: {}, failed: {}, \
+             mismatched: {}, skipped prefixes: {}",
+            self.users,
+            self.existing,
+            self.channels,
+            self.messages,
+            self.failed,
+            self.mismatched,
+            self.skipped_prefixes
+        )
+    }
+}
+
+/// Limits the number of users copied per second
+struct RateLimiter {
+    interval: Option<Duration>,
+    next: Instant,
+}
+
+impl RateLimiter {
+    fn new(per_second: u32) -> Self {
+        Self {
+            interval: (per_second > 0).then(|| Duration::from_secs(1) / per_second),
+            next: Instant::now(),
+        }
+    }
+
+    async fn wait(&mut self) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        let now = Instant::now();
+        if self.next > now {
+            actix_rt::time::sleep(self.next - now).await;
+        }
+        self.next = self.next.max(now) + interval;
+    }
+}
+
+/// The data read from the source for a single user
+struct UserData {
+    user: User,
+    channels: Vec<ChannelRecord>,
+    messages: Vec<Notification>,
+}
+
+pub struct Migrator {
+    source: Box<dyn DbClient>,
+    dest: Box<dyn DbClient>,
+    limiter: RateLimiter,
+    dry_run: bool,
+    verify: bool,
+}
+
+impl Migrator {
+    pub fn new(
+        source: Box<dyn DbClient>,
+        dest: Box<dyn DbClient>,
+        rate: u32,
+        dry_run: bool,
+        verify: bool,
+    ) -> Self {
+        Self {
+            source,
+            dest,
+            limiter: RateLimiter::new(rate),
+            dry_run,
+            verify,
+        }
+    }
+
+    /// Copy the users of every UAID prefix of `prefix_len` hex digits not
+    /// already recorded in the `checkpoint`.
+    ///
+    /// A prefix is only recorded once all of its users copied (and verified)
+    /// successfully, so that a rerun retries any failures. Dry runs record
+    /// nothing.
+    pub async fn run(
+        &mut self,
+        prefix_len: usize,
+        checkpoint: &mut Checkpoint,
+    ) -> io::Result<Report> {
+        let mut report = Report::default();
+        for i in 0..16u32.pow(prefix_len as u32) {
+            let prefix = format!("{:0width$x}", i, width = prefix_len);
+            if checkpoint.is_done(&prefix) {
+                report.skipped_prefixes += 1;
+                continue;
+            }
+            let uaids = match self.source.list_uaids(&prefix).await {
+                Ok(uaids) => uaids,
+                Err(e) => {
+                    warn!("Could not list users for prefix {}: {:?}", prefix, e);
+                    report.failed += 1;
+                    continue;
+                }
+            };
+            debug!("Migrating prefix {}", prefix; "users" => uaids.len());
+            let mut complete = true;
+            for uaid in uaids {
+                self.limiter.wait().await;
+                complete &= self.migrate_user(&uaid, &mut report).await;
+            }
+            if complete && !self.dry_run {
+                checkpoint.mark_done(&prefix)?;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Migrate a single user, returning whether it succeeded
+    async fn migrate_user(&self, uaid: &Uuid, report: &mut Report) -> bool {
+        let data = match self.read(uaid).await {
+            Ok(Some(data)) => data,
+            // Removed (or expired) since it was listed
+            Ok(None) => return true,
+            Err(e) => {
+                warn!("Could not read user {} from the source: {:?}", uaid, e);
+                report.failed += 1;
+                return false;
+            }
+        };
+        if self.dry_run {
+            report.users += 1;
+            report.channels += data.channels.len() as u64;
+            report.messages += data.messages.len() as u64;
+            return true;
+        }
+        match self.write(&data).await {
+            Ok(existed) => {
+                if existed {
+                    report.existing += 1;
+                } else {
+                    report.users += 1;
+                }
+                report.channels += data.channels.len() as u64;
+                report.messages += data.messages.len() as u64;
+            }
+            Err(e) => {
+                warn!("Could not write user {} to the destination: {:?}", uaid, e);
+                report.failed += 1;
+                return false;
+            }
+        }
+        if !self.verify {
+            return true;
+        }
+        match self.verify_user(&data).await {
+            Ok(true) => true,
+            Ok(false) => {
+                warn!("Copied user {} does not match the source", uaid);
+                report.mismatched += 1;
+                false
+            }
+            Err(e) => {
+                warn!("Could not verify user {}: {:?}", uaid, e);
+                report.failed += 1;
+                false
+            }
+        }
+    }
+
+    /// Read the user, their channels and their unexpired messages
+    async fn read(&self, uaid: &Uuid) -> DbResult<Option<UserData>> {
+        let Some(mut user) = self.source.get_user(uaid).await? else {
+            return Ok(None);
+        };
+        // Users read from some data stores (e.g. DynamoDB) lack the version
+        // field needed by others
+        if user.version.is_none() {
+            user.version = Some(Uuid::new_v4());
+        }
+        let channels = self.source.get_channel_records(uaid).await?;
+        let messages = fetch_messages(self.source.as_ref(), uaid).await?;
+        Ok(Some(UserData {
+            user,
+            channels,
+            messages,
+        }))
+    }
+
+    /// Write the user's data to the destination, returning whether the user
+    /// record was already present.
+    ///
+    /// An existing user record is left as is (it may have been updated since
+    /// by a client), but the channels and messages are still written: these
+    /// writes are idempotent.
+    async fn write(&self, data: &UserData) -> DbResult<bool> {
+        let existed = match self.dest.add_user(&data.user).await {
+            Ok(()) => false,
+            Err(DbError::Conditional) => true,
+            Err(e) => return Err(e),
+        };
+        if !data.channels.is_empty() {
+            self.dest
+                .add_channels(&data.user.uaid, data.channels.clone())
+                .await?;
+        }
+        if !data.messages.is_empty() {
+            self.dest
+                .save_messages(&data.user.uaid, data.messages.clone())
+                .await?;
+        }
+        Ok(existed)
+    }
+
+    /// Check that the destination holds the user's channels and messages
+    async fn verify_user(&self, data: &UserData) -> DbResult<bool> {
+        let uaid = &data.user.uaid;
+        if self.dest.get_user(uaid).await?.is_none() {
+            return Ok(false);
+        }
+        let channels = self.dest.get_channels(uaid).await?;
+        if !data
+            .channels
+            .iter()
+            .all(|channel| channels.contains(&channel.channel_id))
+        {
+            return Ok(false);
+        }
+        let copied: HashSet<String> = fetch_messages(self.dest.as_ref(), uaid)
+            .await?
+            .iter()
+            .map(|m| m.chidmessageid())
+            .collect();
+        // Messages may have expired since they were copied
+        let now = sec_since_epoch();
+        Ok(data
+            .messages
+            .iter()
+            .filter(|m| !m.expired(now))
+            .all(|m| copied.contains(&m.chidmessageid())))
+    }
+}
+
+/// Fetch all of the user's unexpired topic and timestamp messages
+async fn fetch_messages(db: &dyn DbClient, uaid: &Uuid) -> DbResult<Vec<Notification>> {
+    let now = sec_since_epoch();
+    let mut messages = db.fetch_topic_messages(uaid, 0).await?.messages;
+    messages.extend(db.fetch_timestamp_messages(uaid, None, 0).await?.messages);
+    messages.retain(|message| !message.expired(now));
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cadence::{NopMetricSink, StatsdClient};
+    use uuid::Uuid;
+
+    use autopush_common::db::client::DbClient;
+    use autopush_common::db::error::DbResult;
+    use autopush_common::db::memory::MemoryClientImpl;
+    use autopush_common::db::{ChannelRecord, DbSettings, User};
+    use autopush_common::notification::Notification;
+    use autopush_common::util::sec_since_epoch;
+
+    use super::{Checkpoint, Migrator};
+
+    fn new_client() -> Box<dyn DbClient> {
+        let metrics = Arc::new(StatsdClient::builder("", NopMetricSink).build());
+        Box::new(MemoryClientImpl::new(metrics, &DbSettings::default()).unwrap())
+    }
+
+    async fn populate(db: &dyn DbClient, count: usize) -> DbResult<Vec<Uuid>> {
+        let mut uaids = Vec::new();
+        for _ in 0..count {
+            let uaid = Uuid::new_v4();
+            let chid = Uuid::new_v4();
+            db.add_user(&User {
+                uaid,
+                ..Default::default()
+            })
+            .await?;
+            db.add_channel(&uaid, &ChannelRecord::from(chid)).await?;
+            db.save_message(
+                &uaid,
+                Notification {
+                    channel_id: chid,
+                    version: Uuid::new_v4().simple().to_string(),
+                    ttl: 300,
+                    timestamp: sec_since_epoch(),
+                    sortkey_timestamp: Some(sec_since_epoch() * 1000),
+                    ..Default::default()
+                },
+            )
+            .await?;
+            uaids.push(uaid);
+        }
+        Ok(uaids)
+    }
+
+    #[actix_rt::test]
+    async fn migrate() -> DbResult<()> {
+        let source = new_client();
+        let dest = new_client();
+        let uaids = populate(source.as_ref(), 20).await?;
+
+        let mut checkpoint = Checkpoint::load(None).unwrap();
+        let mut migrator = Migrator::new(source, dest.clone(), 0, false, true);
+        let report = migrator.run(1, &mut checkpoint).await.unwrap();
+        assert_eq!(report.users, 20);
+        assert_eq!(report.channels, 20);
+        assert_eq!(report.messages, 20);
+        assert_eq!(report.failed + report.mismatched, 0);
+        assert_eq!(checkpoint.completed(), 16);
+        for uaid in &uaids {
+            assert!(dest.get_user(uaid).await?.is_some());
+            assert_eq!(dest.get_channels(uaid).await?.len(), 1);
+        }
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn dry_run() -> DbResult<()> {
+        let source = new_client();
+        let dest = new_client();
+        let uaids = populate(source.as_ref(), 5).await?;
+
+        let mut checkpoint = Checkpoint::load(None).unwrap();
+        let mut migrator = Migrator::new(source, dest.clone(), 0, true, true);
+        let report = migrator.run(1, &mut checkpoint).await.unwrap();
+        assert_eq!(report.users, 5);
+        assert_eq!(checkpoint.completed(), 0);
+        for uaid in &uaids {
+            assert!(dest.get_user(uaid).await?.is_none());
+        }
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn resume() -> DbResult<()> {
+        let source = new_client();
+        let dest = new_client();
+        let uaids = populate(source.as_ref(), 20).await?;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("checkpoint");
+        let path = path.to_str().unwrap();
+
+        // Pretend a previous run completed the first user's prefix
+        let done = uaids[0].simple().to_string()[..1].to_owned();
+        Checkpoint::load(Some(path))
+            .unwrap()
+            .mark_done(&done)
+            .unwrap();
+
+        let mut checkpoint = Checkpoint::load(Some(path)).unwrap();
+        assert!(checkpoint.is_done(&done));
+        let mut migrator = Migrator::new(source, dest.clone(), 0, false, false);
+        let report = migrator.run(1, &mut checkpoint).await.unwrap();
+        assert_eq!(report.skipped_prefixes, 1);
+        assert!(dest.get_user(&uaids[0]).await?.is_none());
+        assert_eq!(Checkpoint::load(Some(path)).unwrap().completed(), 16);
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+#![warn(rust_2018_idioms)]
+#![forbid(unsafe_code)]
+
+#[macro_use]
+extern crate slog_scope;
+
+mod reaper;
+
+use std::error::Error;
+use std::sync::Arc;
+
+use docopt::Docopt;
+use serde_derive::Deserialize;
+
+use autopush_common::db::{client_from_settings, DbSettings, StorageType};
+use autopush_common::{logging, metrics};
+
+use crate::reaper::{Reaper, ReaperOptions};
+
+const USAGE: &str = "
+Remove inactive users along with their channels and messages, as well as any
+channels and messages left behind without a user.
+
+Usage: autopush-reaper [options] --dsn=DSN
+
+Options:
+    -h, --help                  Show this message
+    --dsn=DSN                   DSN of the data store to reap (not DynamoDB,
+                                which expires its records itself, nor dual).
+    --db-settings=JSON          db_settings of the data store [default: ].
+    --key-ring=JSON             Key ring decrypting the stored messages [default: ].
+    --max-inactive-days=N       Remove users that have neither connected nor been
+                                pushed to for this many days [default: 365].
+    --max-channel-idle-days=N   Remove the channels of the remaining users that
+                                haven't been pushed to for this many days, 0 to
+                                keep them [default: 0].
+    --skip-orphans              Don't remove the channels and messages of UAIDs
+                                without a user.
+    --prefix-len=N              Number of hex digits of the UAID prefixes the
+                                data store is scanned by (1-4) [default: 2].
+    --rate=N                    Maximum number of UAIDs checked per second, 0 for
+                                no limit [default: 100].
+    --dry-run                   Report what would be removed without removing it.
+    --statsd-host=HOST          StatsD host metrics are sent to.
+    --statsd-port=PORT          StatsD port [default: 8125].
+    --statsd-label=LABEL        Prefix of the metrics sent [default: autopush-reaper].
+    --human-logs                Use human readable logs.
+";
+
+/// Seconds in a day
+const DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_dsn: String,
+    flag_db_settings: String,
+    flag_key_ring: String,
+    flag_max_inactive_days: u64,
+    flag_max_channel_idle_days: u64,
+    flag_skip_orphans: bool,
+    flag_prefix_len: usize,
+    flag_rate: u32,
+    flag_dry_run: bool,
+    flag_statsd_host: Option<String>,
+    flag_statsd_port: u16,
+    flag_statsd_label: String,
+    flag_human_logs: bool,
+}
+
+#[actix_rt::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    logging::init_logging(
+        !args.flag_human_logs,
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+    )
+    .expect("Logging failed to initialize");
+    if !(1..=4).contains(&args.flag_prefix_len) {
+        return Err("--prefix-len must be between 1 and 4".into());
+    }
+    if args.flag_max_inactive_days == 0 {
+        return Err("--max-inactive-days must be greater than 0".into());
+    }
+    // Listing DynamoDB's UAIDs requires full table scans for every prefix,
+    // while it already expires inactive records on its own. Reap a dual data
+    // store's primary directly instead.
+    match StorageType::from_dsn(&Some(args.flag_dsn.clone())) {
+        #[cfg(feature = "dynamodb")]
+        StorageType::DynamoDb => return Err("DynamoDB can't be reaped".into()),
+        StorageType::Dual => return Err("Reap the primary data store instead of dual".into()),
+        _ => {}
+    }
+
+    let metrics = Arc::new(
+        metrics::builder(
+            &args.flag_statsd_label,
+            &args.flag_statsd_host,
+            args.flag_statsd_port,
+        )?
+        .build(),
+    );
+    let db = client_from_settings(
+        metrics.clone(),
+        &DbSettings {
+            dsn: Some(args.flag_dsn),
+            db_settings: args.flag_db_settings,
+            key_ring: args.flag_key_ring,
+            ..Default::default()
+        },
+    )?;
+    info!(
+        "Reaping {}{}",
+        db.name(),
+        if args.flag_dry_run { " (dry run)" } else { "" };
+        "max_inactive_days" => args.flag_max_inactive_days,
+        "max_channel_idle_days" => args.flag_max_channel_idle_days
+    );
+
+    let mut reaper = Reaper::new(
+        db,
+        metrics,
+        ReaperOptions {
+            max_inactive: args.flag_max_inactive_days * DAY,
+            max_channel_idle: (args.flag_max_channel_idle_days > 0)
+                .then(|| args.flag_max_channel_idle_days * DAY),
+            orphans: !args.flag_skip_orphans,
+            rate: args.flag_rate,
+            dry_run: args.flag_dry_run,
+        },
+    );
+    let report = reaper.run(args.flag_prefix_len).await;
+    info!("Reaping finished: {}", report);
+
+    logging::reset_logging();
+    if report.failed > 0 {
+        return Err(format!(
+            "{} UAID(s) or prefix(es) failed, rerun to retry them",
+            report.failed
+        )
+        .into());
+    }
+    Ok(())
+}
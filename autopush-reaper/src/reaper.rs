@@ -0,0 +1,387 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use cadence::{Counted, CountedExt, StatsdClient};
+use uuid::Uuid;
+
+use autopush_common::db::client::DbClient;
+use autopush_common::db::error::DbResult;
+use autopush_common::util::sec_since_epoch;
+
+/// Totals for a reaper run
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Users and orphaned UAIDs checked
+    pub checked: u64,
+    /// Inactive users removed (or that would have been, for a dry run)
+    pub inactive_users: u64,
+    /// Orphaned UAIDs whose channels and messages were removed
+    pub orphans: u64,
+    /// Channels removed along with inactive users and orphans
+    pub channels: u64,
+    /// Messages removed along with inactive users and orphans
+    pub messages: u64,
+    /// Idle channels removed from the remaining users
+    pub idle_channels: u64,
+    /// UAIDs (or prefixes that could not be listed) that failed
+    pub failed: u64,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "checked: {}, inactive users: {}, orphans: {}, channels: {}, messages: {}, \
+             idle channels: {}, failed: {}",
+            self.checked,
+            self.inactive_users,
+            self.orphans,
+            self.channels,
+            self.messages,
+            self.idle_channels,
+            self.failed
+        )
+    }
+}
+
+/// Limits the number of UAIDs checked per second
+struct RateLimiter {
+    interval: Option<Duration>,
+    next: Instant,
+}
+
+impl RateLimiter {
+    fn new(per_second: u32) -> Self {
+        Self {
+            interval: (per_second > 0).then(|| Duration::from_secs(1) / per_second),
+            next: Instant::now(),
+        }
+    }
+
+    async fn wait(&mut self) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        let now = Instant::now();
+        if self.next > now {
+            actix_rt::time::sleep(self.next - now).await;
+        }
+        self.next = self.next.max(now) + interval;
+    }
+}
+
+/// What to remove
+#[derive(Clone, Debug)]
+pub struct ReaperOptions {
+    /// Remove users that have neither connected nor been pushed to for
+    /// longer than this many seconds
+    pub max_inactive: u64,
+    /// Remove the channels of the remaining users that haven't been pushed
+    /// to for longer than this many seconds
+    pub max_channel_idle: Option<u64>,
+    /// Remove channels and messages stored without a user record
+    pub orphans: bool,
+    /// Maximum number of UAIDs checked per second, 0 for no limit
+    pub rate: u32,
+    /// Report what would be removed without removing it
+    pub dry_run: bool,
+}
+
+pub struct Reaper {
+    db: Box<dyn DbClient>,
+    metrics: Arc<StatsdClient>,
+    limiter: RateLimiter,
+    options: ReaperOptions,
+}
+
+impl Reaper {
+    pub fn new(db: Box<dyn DbClient>, metrics: Arc<StatsdClient>, options: ReaperOptions) -> Self {
+        Self {
+            db,
+            metrics,
+            limiter: RateLimiter::new(options.rate),
+            options,
+        }
+    }
+
+    /// Check the users (and orphans) of every UAID prefix of `prefix_len`
+    /// hex digits.
+    ///
+    /// Removal is idempotent, so an interrupted run may simply be rerun.
+    pub async fn run(&mut self, prefix_len: usize) -> Report {
+        let mut report = Report::default();
+        for i in 0..16u32.pow(prefix_len as u32) {
+            let prefix = format!("{:0width$x}", i, width = prefix_len);
+            match self.db.list_uaids(&prefix).await {
+                Ok(uaids) => {
+                    debug!("Reaping prefix {}", prefix; "users" => uaids.len());
+                    for uaid in uaids {
+                        self.limiter.wait().await;
+                        report.checked += 1;
+                        if let Err(e) = self.reap_user(&uaid, &mut report).await {
+                            warn!("Could not reap user {}: {:?}", uaid, e);
+                            self.error("user");
+                            report.failed += 1;
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("Could not list users for prefix {}: {:?}", prefix, e);
+                    self.error("list_uaids");
+                    report.failed += 1;
+                }
+            }
+            if !self.options.orphans {
+                continue;
+            }
+            match self.db.list_orphan_uaids(&prefix).await {
+                Ok(uaids) => {
+                    debug!("Reaping prefix {}", prefix; "orphans" => uaids.len());
+                    for uaid in uaids {
+                        self.limiter.wait().await;
+                        report.checked += 1;
+                        if let Err(e) = self.reap_orphan(&uaid, &mut report).await {
+                            warn!("Could not reap orphan {}: {:?}", uaid, e);
+                            self.error("orphan");
+                            report.failed += 1;
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("Could not list orphans for prefix {}: {:?}", prefix, e);
+                    self.error("list_orphan_uaids");
+                    report.failed += 1;
+                }
+            }
+        }
+        report
+    }
+
+    /// Remove the user if inactive, otherwise remove their idle channels
+    async fn reap_user(&self, uaid: &Uuid, report: &mut Report) -> DbResult<()> {
+        let Some(user) = self.db.get_user(uaid).await? else {
+            // Removed (or expired) since it was listed
+            return Ok(());
+        };
+        let channels = self.db.get_channel_records(uaid).await?;
+        let now = sec_since_epoch();
+        // Bridged (mobile) users don't connect, but are pushed to
+        let last_active = channels
+            .iter()
+            .filter_map(|channel| channel.last_active())
+            .fold(user.connected_at / 1000, u64::max);
+        if now.saturating_sub(last_active) > self.options.max_inactive {
+            // A client reconnecting meanwhile is treated like any other
+            // whose user was dropped: it's told to re-register
+            let channel_ids: Vec<Uuid> = channels.iter().map(|c| c.channel_id).collect();
+            self.purge(uaid, &channel_ids, "inactive", report).await?;
+            report.inactive_users += 1;
+            self.removed("user", "inactive", 1);
+            return Ok(());
+        }
+
+        let Some(max_idle) = self.options.max_channel_idle else {
+            return Ok(());
+        };
+        for channel in channels.iter().filter(|c| c.is_stale(now, max_idle)) {
+            if !self.options.dry_run {
+                self.db.remove_channel(uaid, &channel.channel_id).await?;
+            }
+            report.idle_channels += 1;
+            self.removed("channel", "idle", 1);
+        }
+        Ok(())
+    }
+
+    /// Remove the channels and messages left without a user record
+    async fn reap_orphan(&self, uaid: &Uuid, report: &mut Report) -> DbResult<()> {
+        if self.db.get_user(uaid).await?.is_some() {
+            // Registered since it was listed
+            return Ok(());
+        }
+        let channel_ids: Vec<Uuid> = self.db.get_channels(uaid).await?.into_iter().collect();
+        self.purge(uaid, &channel_ids, "orphan", report).await?;
+        report.orphans += 1;
+        Ok(())
+    }
+
+    /// Remove the UAID's messages, channels and user record. Not all data
+    /// stores remove a user's channels and messages along with the user.
+    async fn purge(
+        &self,
+        uaid: &Uuid,
+        channel_ids: &[Uuid],
+        reason: &str,
+        report: &mut Report,
+    ) -> DbResult<()> {
+        let mut sort_keys: Vec<String> = self
+            .db
+            .fetch_topic_messages(uaid, 0)
+            .await?
+            .messages
+            .iter()
+            .map(|m| m.chidmessageid())
+            .collect();
+        sort_keys.extend(
+            self.db
+                .fetch_timestamp_messages(uaid, None, 0)
+                .await?
+                .messages
+                .iter()
+                .map(|m| m.chidmessageid()),
+        );
+        if !self.options.dry_run {
+            if !sort_keys.is_empty() {
+                self.db.remove_messages(uaid, &sort_keys).await?;
+            }
+            for channel_id in channel_ids {
+                self.db.remove_channel(uaid, channel_id).await?;
+            }
+            self.db.remove_user(uaid).await?;
+        }
+        report.messages += sort_keys.len() as u64;
+        report.channels += channel_ids.len() as u64;
+        self.removed("message", reason, sort_keys.len());
+        self.removed("channel", reason, channel_ids.len());
+        Ok(())
+    }
+
+    fn removed(&self, record: &str, reason: &str, count: usize) {
+        if count == 0 {
+            return;
+        }
+        self.metrics
+            .count_with_tags("reaper.removed", count as i64)
+            .with_tag("record", record)
+            .with_tag("reason", reason)
+            .with_tag(
+                "dry_run",
+                if self.options.dry_run {
+                    "true"
+                } else {
+                    "false"
+                },
+            )
+            .send();
+    }
+
+    fn error(&self, stage: &str) {
+        self.metrics
+            .incr_with_tags("reaper.error")
+            .with_tag("stage", stage)
+            .send();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cadence::{NopMetricSink, StatsdClient};
+    use uuid::Uuid;
+
+    use autopush_common::db::client::DbClient;
+    use autopush_common::db::error::DbResult;
+    use autopush_common::db::memory::MemoryClientImpl;
+    use autopush_common::db::{ChannelRecord, DbSettings, User};
+    use autopush_common::notification::Notification;
+    use autopush_common::util::{ms_since_epoch, sec_since_epoch};
+
+    use super::{Reaper, ReaperOptions};
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn new_client() -> Box<dyn DbClient> {
+        let metrics = Arc::new(StatsdClient::builder("", NopMetricSink).build());
+        Box::new(MemoryClientImpl::new(metrics, &DbSettings::default()).unwrap())
+    }
+
+    fn new_reaper(db: Box<dyn DbClient>, dry_run: bool) -> Reaper {
+        Reaper::new(
+            db,
+            Arc::new(StatsdClient::builder("", NopMetricSink).build()),
+            ReaperOptions {
+                max_inactive: 30 * DAY,
+                max_channel_idle: Some(7 * DAY),
+                orphans: true,
+                rate: 0,
+                dry_run,
+            },
+        )
+    }
+
+    /// Add a user last connected `days` ago, with a channel and a message
+    async fn add_user(db: &dyn DbClient, days: u64, channel: ChannelRecord) -> DbResult<Uuid> {
+        let user = User {
+            connected_at: ms_since_epoch() - days * DAY * 1000,
+            ..Default::default()
+        };
+        let chid = channel.channel_id;
+        db.add_user_with_channels(&user, vec![channel]).await?;
+        db.save_message(&user.uaid, message(chid)).await?;
+        Ok(user.uaid)
+    }
+
+    fn message(channel_id: Uuid) -> Notification {
+        Notification {
+            channel_id,
+            version: Uuid::new_v4().simple().to_string(),
+            ttl: 300,
+            timestamp: sec_since_epoch(),
+            sortkey_timestamp: Some(sec_since_epoch() * 1000),
+            ..Default::default()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn reap() -> DbResult<()> {
+        let db = new_client();
+        let active = add_user(db.as_ref(), 1, ChannelRecord::from(Uuid::new_v4())).await?;
+        let inactive = add_user(db.as_ref(), 60, ChannelRecord::from(Uuid::new_v4())).await?;
+        // Never connected for long, but recently pushed to
+        let mut pushed = ChannelRecord::new(Uuid::new_v4(), None).unwrap();
+        pushed.record_push(sec_since_epoch() - DAY);
+        let bridged = add_user(db.as_ref(), 60, pushed).await?;
+        // An idle channel of an active user
+        let mut idle = ChannelRecord::new(Uuid::new_v4(), None).unwrap();
+        idle.created_at = Some(sec_since_epoch() - 10 * DAY);
+        db.add_channel(&active, &idle).await?;
+        // Channels and messages without a user
+        let orphan = Uuid::new_v4();
+        let chid = Uuid::new_v4();
+        db.add_channel(&orphan, &ChannelRecord::from(chid)).await?;
+        db.save_message(&orphan, message(chid)).await?;
+
+        let report = new_reaper(db.clone(), false).run(1).await;
+        assert_eq!(report.checked, 4);
+        assert_eq!(report.inactive_users, 1);
+        assert_eq!(report.orphans, 1);
+        assert_eq!(report.channels, 2);
+        assert_eq!(report.messages, 2);
+        assert_eq!(report.idle_channels, 1);
+        assert_eq!(report.failed, 0);
+
+        assert!(db.get_user(&active).await?.is_some());
+        assert_eq!(db.get_channels(&active).await?.len(), 1);
+        assert!(db.get_user(&bridged).await?.is_some());
+        assert!(db.get_user(&inactive).await?.is_none());
+        assert!(db.get_channels(&inactive).await?.is_empty());
+        assert!(db.list_orphan_uaids("").await?.is_empty());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn dry_run() -> DbResult<()> {
+        let db = new_client();
+        let inactive = add_user(db.as_ref(), 60, ChannelRecord::from(Uuid::new_v4())).await?;
+
+        let report = new_reaper(db.clone(), true).run(1).await;
+        assert_eq!(report.inactive_users, 1);
+        assert_eq!(report.channels, 1);
+        assert_eq!(report.messages, 1);
+        assert!(db.get_user(&inactive).await?.is_some());
+        assert_eq!(db.get_channels(&inactive).await?.len(), 1);
+        Ok(())
+    }
+}
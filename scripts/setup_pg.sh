@@ -0,0 +1,60 @@
+#!/bin/sh -eu
+# Usage: setup_pg.sh [DSN]
+#
+# Arguments:
+#   [DSN]  (default: postgres://postgres@localhost:5432/autopush)
+
+DSN=${1:-"postgres://postgres@localhost:5432/autopush"}
+
+ROUTER_TABLE=${ROUTER_TABLE:-"router"}
+MESSAGE_TABLE=${MESSAGE_TABLE:-"message"}
+CHANNEL_TABLE=${CHANNEL_TABLE:-"channel"}
+
+psql "$DSN" <<SQL
+CREATE TABLE IF NOT EXISTS $ROUTER_TABLE (
+    uaid UUID PRIMARY KEY,
+    connected_at BIGINT NOT NULL,
+    router_type TEXT NOT NULL,
+    router_data TEXT,
+    last_connect BIGINT,
+    node_id TEXT,
+    record_version BIGINT,
+    "current_timestamp" BIGINT,
+    version UUID
+);
+CREATE TABLE IF NOT EXISTS $CHANNEL_TABLE (
+    uaid UUID NOT NULL,
+    channel_id UUID NOT NULL,
+    expiry BIGINT NOT NULL,
+    created_at BIGINT,
+    app_server_key_hash TEXT,
+    last_push_at BIGINT,
+    push_count BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (uaid, channel_id)
+);
+-- Channel metadata columns, for tables created before they were added
+ALTER TABLE $CHANNEL_TABLE ADD COLUMN IF NOT EXISTS created_at BIGINT;
+ALTER TABLE $CHANNEL_TABLE ADD COLUMN IF NOT EXISTS app_server_key_hash TEXT;
+ALTER TABLE $CHANNEL_TABLE ADD COLUMN IF NOT EXISTS last_push_at BIGINT;
+ALTER TABLE $CHANNEL_TABLE ADD COLUMN IF NOT EXISTS push_count BIGINT NOT NULL DEFAULT 0;
+CREATE TABLE IF NOT EXISTS $MESSAGE_TABLE (
+    uaid UUID NOT NULL,
+    chidmessageid TEXT NOT NULL,
+    channel_id UUID NOT NULL,
+    version TEXT NOT NULL,
+    ttl BIGINT NOT NULL,
+    expiry BIGINT NOT NULL,
+    topic TEXT,
+    timestamp BIGINT NOT NULL,
+    sortkey_timestamp BIGINT,
+    data TEXT,
+    headers TEXT,
+    urgency TEXT,
+    receipt TEXT,
+    PRIMARY KEY (uaid, chidmessageid)
+);
+-- Message columns, for tables created before they were added
+ALTER TABLE $MESSAGE_TABLE ADD COLUMN IF NOT EXISTS urgency TEXT;
+ALTER TABLE $MESSAGE_TABLE ADD COLUMN IF NOT EXISTS receipt TEXT;
+CREATE INDEX IF NOT EXISTS ${MESSAGE_TABLE}_expiry_idx ON $MESSAGE_TABLE (expiry);
+SQL
@@ -171,6 +171,17 @@ keyid="http://example.org/bob/keys/123";salt="XZwpw6o37R-6qoZjw6KwAw=="\
         resp = requests.delete(url=url.geturl(), timeout=30)
         return resp
 
+    def get_notification_status(self, channel, message=None) -> requests.Response:
+        """Get the status of a sent notification."""
+        messages = self.messages[channel]
+        if not message:
+            message = random.choice(messages)
+
+        log.debug(f"Status: {message}")
+        url = urlparse(message)
+        resp = requests.get(url=url.geturl(), timeout=30)
+        return resp
+
     def send_notification(
         self,
         channel=None,
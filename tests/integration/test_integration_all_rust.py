@@ -1288,6 +1288,31 @@ class TestRustWebPush(unittest.TestCase):
         assert result is None
         yield self.shut_down(client)
 
+    @inlineCallbacks
+    def test_saved_notification_status(self):
+        """Test the status of a saved notification before and after delivery."""
+        client = yield self.quick_register()
+        yield client.disconnect()
+        chan = list(client.channels.keys())[0]
+        yield client.send_notification(data=b"foo", topic="status")
+        resp = yield client.get_notification_status(chan)
+        assert resp.status_code == 200
+        status = resp.json()
+        assert status["status"] == "pending"
+        assert status["topic"] == "status"
+        assert status["size"] == 3
+        assert 0 < status["ttl"] <= 200
+        yield client.connect()
+        yield client.hello()
+        result = yield client.get_notification()
+        assert result is not None
+        yield client.ack(result["channelID"], result["version"])
+        # The Ack is processed asynchronously
+        yield client.sleep(1)
+        resp = yield client.get_notification_status(chan)
+        assert resp.json() == {"status": "gone"}
+        yield self.shut_down(client)
+
     @inlineCallbacks
     def test_with_key(self):
         """Test getting a locked subscription with a valid VAPID public key."""
@@ -51,6 +51,9 @@ class StoredNotifAutopushUser(FastHttpUser):
     """StoredNotifAutopushUser class."""
 
     REST_HEADERS: dict[str, str] = {"TTL": "60", "Content-Encoding": "aes128gcm"}
+    # A valid aes128gcm header block (RFC 8188 §2.1) prefixing the random payloads:
+    # a salt, a 4096 record size and an uncompressed public key as the keyid
+    AES128GCM_HEADER: bytes = bytes(16) + (4096).to_bytes(4, "big") + bytes([65, 4]) + bytes(64)
     WEBSOCKET_HEADERS: dict[str, str] = {
         "User-Agent": "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.13; rv:61.0) "
         "Gecko/20100101 Firefox/61.0"
@@ -269,7 +272,7 @@ class StoredNotifAutopushUser(FastHttpUser):
         with self.client.post(
             url=endpoint_url,
             name=message_type,
-            data=data,
+            data=self.AES128GCM_HEADER + data.encode(),
             headers=self.REST_HEADERS,
             catch_response=True,
         ) as response:
@@ -313,9 +316,9 @@ class StoredNotifAutopushUser(FastHttpUser):
                 case "notification":
                     message = NotificationMessage(**message_dict)
                     message_data: str = message.data
-                    decode_data: str = base64.urlsafe_b64decode(message_data + "===").decode(
-                        "utf8"
-                    )
+                    decode_data: str = base64.urlsafe_b64decode(message_data + "===")[
+                        len(self.AES128GCM_HEADER) :
+                    ].decode("utf8")
                     record = self.notification_records.pop(
                         sha1(decode_data.encode(), usedforsecurity=False).digest(), None
                     )